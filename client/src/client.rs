@@ -0,0 +1,189 @@
+use bytes::Bytes;
+use reqwest::header::SET_COOKIE;
+use reqwest::{Method, RequestBuilder, Response, StatusCode, Url};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::types::{
+    AuthzCheckRequest, AuthzCheckResponse, BucketStats, CachePin, CachePinRequest, DelegatedKey, DiskUsage, IssuedKey,
+    KeyRequest, LegalHold, LegalHoldRequest, Part, PartsManifest, PinStatus, Session, UploadProgress,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid proxy URL: {0}")]
+    InvalidUrl(String),
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    /// The proxy answered with an error status; `code` is stable, such as PROXY_BUCKET_DENIED
+    #[error("{status} {code}: {message}")]
+    Status { status: u16, code: String, message: String },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Body of every error response of the proxy
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+    code: String,
+}
+
+/// A session as issued by POST /session, with the cookie that carries it
+#[derive(Debug, Clone)]
+pub struct SessionCookie {
+    pub session: Session,
+    /// `name=value`, ready for a Cookie header
+    pub cookie: String,
+}
+
+/// Calls the proxy's native endpoints with an API key
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    api_key: String,
+}
+
+impl Client {
+    pub fn new(base_url: &str, api_key: impl Into<String>) -> Result<Self> {
+        Self::with_http_client(reqwest::Client::new(), base_url, api_key)
+    }
+
+    /// Uses a preconfigured reqwest client, e.g. with timeouts or extra root certificates
+    pub fn with_http_client(http: reqwest::Client, base_url: &str, api_key: impl Into<String>) -> Result<Self> {
+        let base_url = Url::parse(base_url).map_err(|e| Error::InvalidUrl(format!("{}: {}", base_url, e)))?;
+        if base_url.cannot_be_a_base() {
+            return Err(Error::InvalidUrl(base_url.to_string()));
+        }
+        Ok(Self { http, base_url, api_key: api_key.into() })
+    }
+
+    /// The base URL followed by the segments, each percent-encoded
+    fn url<'a>(&self, segments: impl IntoIterator<Item = &'a str>) -> Url {
+        let mut url = self.base_url.clone();
+        // Checked in the constructor
+        url.path_segments_mut().expect("base URL").pop_if_empty().extend(segments);
+        url
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.http.request(method, url).header("x-api-key", &self.api_key)
+    }
+
+    async fn send(request: RequestBuilder) -> Result<Response> {
+        let response = request.send().await?;
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            let text = response.text().await.unwrap_or_default();
+            let (code, message) = match serde_json::from_str::<ErrorBody>(&text) {
+                Ok(body) => (body.code, body.error),
+                // E.g. from a load balancer in front of the proxy
+                Err(_) => (String::new(), text),
+            };
+            return Err(Error::Status { status: status.as_u16(), code, message });
+        }
+        Ok(response)
+    }
+
+    async fn json<T: DeserializeOwned>(request: RequestBuilder) -> Result<T> {
+        Ok(Self::send(request).await?.json().await?)
+    }
+
+    /// Byte ranges of an object with a signed URL each, see `download_part`
+    pub async fn parts(&self, bucket: &str, key: &str, parts: u64) -> Result<PartsManifest> {
+        let url = self.url(std::iter::once(bucket).chain(key.split('/')));
+        Self::json(self.request(Method::GET, url).query(&[("parts", parts)])).await
+    }
+
+    /// Fetches one part of a manifest through its signed URL, without the API key
+    pub async fn download_part(&self, part: &Part) -> Result<Bytes> {
+        // Part URLs are relative unless the proxy has a download_parts.base_url
+        let url = self.base_url.join(&part.url).map_err(|e| Error::InvalidUrl(format!("{}: {}", part.url, e)))?;
+        Ok(Self::send(self.http.get(url)).await?.bytes().await?)
+    }
+
+    /// Object count, total size and largest objects from the proxy's cached inventory; None while
+    /// the first inventory of the bucket is still running
+    pub async fn bucket_stats(&self, bucket: &str) -> Result<Option<BucketStats>> {
+        let response = Self::send(self.request(Method::GET, self.url([bucket])).query(&[("stats", "")])).await?;
+        if response.status() == StatusCode::ACCEPTED {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    /// Sizes one level under `prefix`, like `du`; None while the proxy is still listing it
+    pub async fn disk_usage(&self, bucket: &str, prefix: &str) -> Result<Option<DiskUsage>> {
+        let url = self.url([bucket]);
+        let response = Self::send(self.request(Method::GET, url).query(&[("du", ""), ("prefix", prefix)])).await?;
+        if response.status() == StatusCode::ACCEPTED {
+            return Ok(None);
+        }
+        Ok(Some(response.json().await?))
+    }
+
+    /// Bytes received, parts completed and time left of a resumable upload
+    pub async fn upload_progress(&self, upload_id: &str) -> Result<UploadProgress> {
+        Self::json(self.request(Method::GET, self.url(["uploads", upload_id, "status"]))).await
+    }
+
+    pub async fn create_session(&self) -> Result<SessionCookie> {
+        let response = Self::send(self.request(Method::POST, self.url(["session"]))).await?;
+        let cookie = response
+            .headers()
+            .get(SET_COOKIE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default()
+            .to_string();
+        Ok(SessionCookie { session: response.json().await?, cookie })
+    }
+
+    pub async fn issue_key(&self, request: &KeyRequest) -> Result<IssuedKey> {
+        Self::json(self.request(Method::POST, self.url(["keys"])).json(request)).await
+    }
+
+    /// The caller's own keys, or every user's for admins
+    pub async fn list_keys(&self) -> Result<Vec<DelegatedKey>> {
+        Self::json(self.request(Method::GET, self.url(["keys"]))).await
+    }
+
+    pub async fn revoke_key(&self, id: &str) -> Result<()> {
+        Self::send(self.request(Method::DELETE, self.url(["keys", id]))).await?;
+        Ok(())
+    }
+
+    /// Dry-runs an authorization decision without touching S3
+    pub async fn check_authz(&self, request: &AuthzCheckRequest) -> Result<AuthzCheckResponse> {
+        Self::json(self.request(Method::POST, self.url(["authz", "check"])).json(request)).await
+    }
+
+    pub async fn cache_pins(&self) -> Result<PinStatus> {
+        Self::json(self.request(Method::GET, self.url(["admin", "cache", "pins"]))).await
+    }
+
+    pub async fn pin(&self, request: &CachePinRequest) -> Result<()> {
+        Self::send(self.request(Method::PUT, self.url(["admin", "cache", "pins"])).json(request)).await?;
+        Ok(())
+    }
+
+    pub async fn unpin(&self, pin: &CachePin) -> Result<()> {
+        Self::send(self.request(Method::DELETE, self.url(["admin", "cache", "pins"])).json(pin)).await?;
+        Ok(())
+    }
+
+    pub async fn legal_holds(&self) -> Result<Vec<LegalHold>> {
+        Self::json(self.request(Method::GET, self.url(["admin", "holds"]))).await
+    }
+
+    pub async fn place_legal_hold(&self, request: &LegalHoldRequest) -> Result<LegalHold> {
+        Self::json(self.request(Method::PUT, self.url(["admin", "holds"])).json(request)).await
+    }
+
+    /// Releases the hold on `request.bucket` and `request.prefix`; the reason is ignored
+    pub async fn release_legal_hold(&self, request: &LegalHoldRequest) -> Result<()> {
+        Self::send(self.request(Method::DELETE, self.url(["admin", "holds"])).json(request)).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+//! Typed client for the native endpoints of s3-proxy; the S3-compatible API is served to any S3 SDK
+
+pub mod types;
+
+#[cfg(feature = "client")]
+mod client;
+
+#[cfg(feature = "client")]
+pub use client::{Client, Error, Result, SessionCookie};
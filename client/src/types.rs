@@ -0,0 +1,253 @@
+//! Request and response bodies of the native API, shared with the proxy so both sides agree on
+//! the wire format at compile time
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum UserRole {
+    Readonly,
+    User,
+    Admin,
+}
+
+impl UserRole {
+    pub fn can_write(&self) -> bool {
+        matches!(self, UserRole::Admin | UserRole::User)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Read,
+    List,
+    Write,
+}
+
+/// One byte range of an object and the URL that fetches it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Part {
+    pub index: u64,
+    pub start: u64,
+    /// Inclusive, as in a Range header
+    pub end: u64,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PartsManifest {
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+    /// Part URLs only serve this version of the object, later ones answer 412
+    pub etag: Option<String>,
+    pub expires_at: DateTime<Utc>,
+    pub parts: Vec<Part>,
+}
+
+/// What a session cookie grants, signed so clients cannot widen it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Session {
+    pub username: String,
+    /// Buckets the cookie may read, those of the session config the user had access to
+    pub buckets: Vec<String>,
+    pub expires_at: DateTime<Utc>,
+    /// Issued to an LDAP user, who has no config entry to check the session against
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ldap: bool,
+}
+
+/// A restricted API key a user issued from their own, e.g. for a CI job
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DelegatedKey {
+    pub id: String,
+    pub owner: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Never more than the owner currently has, whatever was granted at issue time
+    pub buckets: Vec<String>,
+    /// Keys the key is limited to inside its buckets; empty allows the whole buckets
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    pub role: UserRole,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl DelegatedKey {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires_at
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct KeyRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    pub buckets: Vec<String>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    /// Issue a key that cannot write even if the owner can
+    #[serde(default)]
+    pub readonly: bool,
+    /// Lifetime, capped at the configured maximum and the owner's own expiry
+    #[serde(default)]
+    pub ttl_secs: Option<u64>,
+}
+
+/// A newly issued key; the secret is only ever returned here
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct IssuedKey {
+    pub api_key: String,
+    #[serde(flatten)]
+    pub key: DelegatedKey,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AuthzCheckRequest {
+    /// Defaults to the caller; only admins may check other users
+    pub user: Option<String>,
+    /// Delegated key to check instead of a user, with its prefixes and role cap; only its owner
+    /// and admins may check it, and it is never returned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    pub operation: Operation,
+    pub bucket: String,
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct AuthzCheckResponse {
+    pub allowed: bool,
+    pub user: String,
+    pub operation: Operation,
+    pub bucket: String,
+    pub key: Option<String>,
+    pub rules: Vec<String>,
+}
+
+/// Objects in `bucket` whose key starts with `prefix` are never evicted
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CachePin {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct CachePinRequest {
+    #[serde(flatten)]
+    pub pin: CachePin,
+    /// Load the pinned objects into the cache now instead of on first read
+    #[serde(default)]
+    pub prefetch: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PinStatus {
+    pub pins: Vec<CachePin>,
+    pub pinned_bytes: u64,
+    pub max_pinned_bytes: u64,
+}
+
+/// A frozen bucket or prefix: no writes or deletes, whoever asks, until released
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LegalHold {
+    pub bucket: String,
+    /// Empty freezes the whole bucket
+    pub prefix: String,
+    pub reason: Option<String>,
+    pub placed_by: String,
+    pub placed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct LegalHoldRequest {
+    pub bucket: String,
+    /// Empty freezes the whole bucket
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// How far along a resumable upload is, for rendering progress
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct UploadProgress {
+    pub upload_id: String,
+    pub bucket: String,
+    pub key: String,
+    /// Received by the proxy, whether already sent upstream or still buffered
+    pub bytes_received: u64,
+    /// Declared with Upload-Length when the upload was started
+    pub bytes_total: Option<u64>,
+    /// Parts already stored upstream
+    pub parts_completed: u64,
+    /// Average rate since the upload started, pauses included
+    pub bytes_per_sec: u64,
+    /// Estimated at the average rate; unknown without a total or before any bytes arrive
+    pub eta_secs: Option<u64>,
+    pub started_at: DateTime<Utc>,
+}
+
+/// Totals of a bucket from the proxy's last inventory of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BucketStats {
+    pub bucket: String,
+    pub objects: u64,
+    pub total_bytes: u64,
+    /// Largest first
+    pub largest: Vec<ObjectSize>,
+    /// When the inventory finished; writes since then are not counted
+    pub computed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct ObjectSize {
+    pub key: String,
+    pub size: u64,
+}
+
+/// Sizes one level under a prefix, like `du`, from the proxy's cached listing of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct DiskUsage {
+    pub bucket: String,
+    pub prefix: String,
+    pub objects: u64,
+    pub total_bytes: u64,
+    /// Largest first
+    pub entries: Vec<UsageEntry>,
+    pub computed_at: DateTime<Utc>,
+}
+
+/// A directory or object directly under the prefix
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct UsageEntry {
+    /// Full key, or the prefix ending in `/` for a directory
+    pub name: String,
+    pub directory: bool,
+    pub objects: u64,
+    pub bytes: u64,
+}
@@ -0,0 +1,93 @@
+use futures::future::join_all;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::error::{AppError, Result};
+use crate::server::AppState;
+use crate::union;
+
+/// Buckets one aggregated listing may span
+pub const MAX_BUCKETS: usize = 32;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Entry {
+    pub key: String,
+    pub size: i64,
+    pub last_modified: Option<String>,
+    pub etag: Option<String>,
+    pub bucket: String,
+    /// Absent for virtual buckets, whose members may sit on different accounts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+}
+
+/// A bucket whose listing failed; the others are still returned
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SourceError {
+    pub bucket: String,
+    /// Stable error code, as in error responses
+    pub code: String,
+    /// Generic for internal errors unless the caller may see details
+    pub error: String,
+    /// Names the logged failure of internal errors
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+impl SourceError {
+    fn new(bucket: &str, error: AppError, reveal: bool) -> Self {
+        let code = error.code().to_string();
+        let (_, message, detail) = error.into_public();
+        let request_id = detail.as_ref().map(|detail| detail.request_id.clone());
+        let error = match detail {
+            Some(detail) if reveal => detail.message,
+            _ => message,
+        };
+        SourceError { bucket: bucket.to_string(), code, error, request_id }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AggregateListing {
+    pub prefix: String,
+    pub buckets: Vec<String>,
+    pub objects: Vec<Entry>,
+    pub errors: Vec<SourceError>,
+}
+
+async fn list_bucket(state: &AppState, bucket: &str, prefix: &str) -> Result<Vec<Entry>> {
+    let account = state.find_account_for_bucket(bucket);
+    let (objects, _) = union::list(state, bucket, prefix, false).await?;
+    Ok(objects
+        .into_iter()
+        .map(|object| Entry {
+            key: object.key().unwrap_or_default().to_string(),
+            size: object.size().unwrap_or(0),
+            last_modified: object.last_modified().map(|dt| dt.to_string()),
+            etag: object.e_tag().map(String::from),
+            bucket: bucket.to_string(),
+            account: account.clone(),
+        })
+        .collect())
+}
+
+/// Lists `prefix` in every bucket at once and merges the results by key; `reveal` shows the full
+/// message of internal errors, as error responses do for trusted admins
+pub async fn list(state: &AppState, buckets: Vec<String>, prefix: &str, reveal: bool) -> Result<AggregateListing> {
+    if buckets.is_empty() || buckets.len() > MAX_BUCKETS {
+        return Err(AppError::InvalidRequest(format!("Between 1 and {} buckets can be listed together", MAX_BUCKETS)));
+    }
+    let results = join_all(buckets.iter().map(|bucket| list_bucket(state, bucket, prefix))).await;
+
+    let mut objects = Vec::new();
+    let mut errors = Vec::new();
+    for (bucket, result) in buckets.iter().zip(results) {
+        match result {
+            Ok(entries) => objects.extend(entries),
+            Err(e) => errors.push(SourceError::new(bucket, e, reveal)),
+        }
+    }
+    // The same key in several buckets is listed once per bucket, in the order the buckets were given
+    objects.sort_by(|a, b| a.key.cmp(&b.key));
+    Ok(AggregateListing { prefix: prefix.to_string(), buckets, objects, errors })
+}
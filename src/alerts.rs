@@ -0,0 +1,203 @@
+use bytes::Bytes;
+use http::{header, Method, Request};
+use http_body_util::Full;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::{AlertFormat, AlertRule, Config};
+use crate::error::{AppError, Result};
+use crate::metrics::{self, RequestSnapshot};
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type WebhookClient = Client<HttpsConnector<HttpConnector>, Full<Bytes>>;
+
+/// Requests, errors and latency between two snapshots
+struct WindowStats {
+    requests: u64,
+    server_errors: u64,
+    p99_secs: Option<f64>,
+}
+
+impl WindowStats {
+    fn between(earlier: &RequestSnapshot, later: &RequestSnapshot) -> Self {
+        let requests = later.requests.saturating_sub(earlier.requests);
+        let latency: Vec<(f64, u64)> = later
+            .latency_buckets
+            .iter()
+            .enumerate()
+            .map(|(i, (bound, count))| {
+                let before = earlier.latency_buckets.get(i).map_or(0, |(_, c)| *c);
+                (*bound, count.saturating_sub(before))
+            })
+            .collect();
+
+        // Upper bound of the bucket holding the 99th percentile, so p99 is overestimated rather than missed;
+        // requests slower than every bucket yield None
+        let target = (requests as f64 * 0.99).ceil() as u64;
+        let p99_secs = latency
+            .iter()
+            .find(|(_, count)| *count >= target)
+            .map(|(bound, _)| *bound);
+
+        Self {
+            requests,
+            server_errors: later.server_errors.saturating_sub(earlier.server_errors),
+            p99_secs,
+        }
+    }
+
+    fn error_rate_percent(&self) -> f64 {
+        self.server_errors as f64 * 100.0 / self.requests as f64
+    }
+}
+
+/// Returns why the rule fires, or None when the window is healthy
+fn evaluate(rule: &AlertRule, stats: &WindowStats) -> Option<String> {
+    if stats.requests == 0 || stats.requests < rule.min_requests {
+        return None;
+    }
+
+    let mut reasons = Vec::new();
+    if let Some(threshold) = rule.error_rate_percent {
+        let rate = stats.error_rate_percent();
+        if rate > threshold {
+            reasons.push(format!("error rate {:.1}% > {}%", rate, threshold));
+        }
+    }
+    if let Some(threshold) = rule.p99_latency_ms {
+        match stats.p99_secs.map(|secs| (secs * 1000.0) as u64) {
+            Some(p99) if p99 <= threshold => {}
+            Some(p99) => reasons.push(format!("p99 latency {}ms > {}ms", p99, threshold)),
+            None => reasons.push(format!("p99 latency above histogram range > {}ms", threshold)),
+        }
+    }
+
+    if reasons.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "{} over the last {} minutes ({} requests)",
+            reasons.join(", "),
+            rule.window_minutes,
+            stats.requests
+        ))
+    }
+}
+
+pub fn webhook_client() -> Result<WebhookClient> {
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_native_roots()
+        .map_err(|e| AppError::InternalError(format!("Failed to load native root certificates: {}", e)))?
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(Client::builder(TokioExecutor::new()).build(https))
+}
+
+async fn notify(client: &WebhookClient, rule: &AlertRule, firing: bool, message: &str) -> Result<()> {
+    let state = if firing { "firing" } else { "resolved" };
+    let payload = match rule.format {
+        AlertFormat::Webhook => json!({
+            "alert": rule.name,
+            "state": state,
+            "message": message,
+        }),
+        AlertFormat::Slack => {
+            let icon = if firing { ":rotating_light:" } else { ":white_check_mark:" };
+            json!({ "text": format!("{} s3-proxy alert *{}* {}: {}", icon, rule.name, state, message) })
+        }
+    };
+    post_webhook(client, &rule.webhook_url, &payload).await
+}
+
+/// POSTs a JSON payload and fails on anything but a 2xx response
+pub async fn post_webhook(client: &WebhookClient, url: &str, payload: &serde_json::Value) -> Result<()> {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(payload.to_string())))
+        .map_err(|e| AppError::InternalError(format!("Invalid webhook URL: {}", e)))?;
+
+    let response = tokio::time::timeout(WEBHOOK_TIMEOUT, client.request(request))
+        .await
+        .map_err(|_| AppError::InternalError("Webhook timed out".to_string()))?
+        .map_err(|e| AppError::InternalError(format!("Webhook request failed: {}", e)))?;
+    if !response.status().is_success() {
+        return Err(AppError::InternalError(format!("Webhook returned {}", response.status())));
+    }
+    Ok(())
+}
+
+/// Periodically evaluates the configured alert rules and notifies on state changes
+pub fn spawn(config: Arc<Config>) -> Result<()> {
+    let Some(alerts) = &config.alerts else {
+        return Ok(());
+    };
+    if alerts.rules.is_empty() {
+        return Ok(());
+    }
+    info!("Evaluating {} alert rules every {}s", alerts.rules.len(), alerts.evaluation_interval_secs);
+
+    let client = webhook_client()?;
+    let interval = Duration::from_secs(alerts.evaluation_interval_secs.max(1));
+    let retention = alerts
+        .rules
+        .iter()
+        .map(|rule| Duration::from_secs(rule.window_minutes * 60))
+        .max()
+        .unwrap_or_default()
+        + interval;
+
+    tokio::spawn(async move {
+        let rules = &config.alerts.as_ref().unwrap().rules;
+        let mut history: VecDeque<(Instant, RequestSnapshot)> = VecDeque::new();
+        let mut firing = vec![false; rules.len()];
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let now = Instant::now();
+            let current = metrics::request_snapshot();
+            history.push_back((now, current.clone()));
+            while history.front().is_some_and(|(at, _)| now.duration_since(*at) > retention) {
+                history.pop_front();
+            }
+
+            for (rule, was_firing) in rules.iter().zip(firing.iter_mut()) {
+                let window = Duration::from_secs(rule.window_minutes * 60);
+                let Some((_, baseline)) = history.iter().find(|(at, _)| now.duration_since(*at) <= window) else {
+                    continue;
+                };
+                let stats = WindowStats::between(baseline, &current);
+
+                let reason = evaluate(rule, &stats);
+                if reason.is_some() == *was_firing {
+                    continue;
+                }
+                *was_firing = reason.is_some();
+
+                let message = match reason {
+                    Some(reason) => {
+                        warn!("Alert {} firing: {}", rule.name, reason);
+                        reason
+                    }
+                    None => {
+                        info!("Alert {} resolved", rule.name);
+                        format!("back within thresholds over the last {} minutes", rule.window_minutes)
+                    }
+                };
+                if let Err(e) = notify(&client, rule, *was_firing, &message).await {
+                    warn!("Failed to send alert {}: {}", rule.name, e);
+                }
+            }
+        }
+    });
+    Ok(())
+}
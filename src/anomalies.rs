@@ -0,0 +1,218 @@
+use axum::{
+    body::HttpBody,
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::alerts::{self, WebhookClient};
+use crate::auth::AuthState;
+use crate::config::{AlertFormat, AnomalyAction, AnomalyConfig, Config};
+use crate::error::AppError;
+
+lazy_static! {
+    static ref ACTIVITY: Mutex<HashMap<String, UserActivity>> = Mutex::new(HashMap::new());
+    static ref WEBHOOK_CLIENT: Option<WebhookClient> = alerts::webhook_client()
+        .map_err(|e| warn!("Anomaly notifications disabled: {}", e))
+        .ok();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Anomaly {
+    MassDownload,
+    DownloadVolume,
+    Enumeration,
+    OffHours,
+}
+
+impl Anomaly {
+    fn name(&self) -> &'static str {
+        match self {
+            Anomaly::MassDownload => "mass_download",
+            Anomaly::DownloadVolume => "download_volume",
+            Anomaly::Enumeration => "listing_enumeration",
+            Anomaly::OffHours => "off_hours_access",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+    Download,
+    Listing,
+    Other,
+}
+
+/// What a user did in the current window
+struct UserActivity {
+    window_start: Instant,
+    downloads: u64,
+    download_bytes: u64,
+    listings: u64,
+    /// Reported once per window so a burst raises one alert, not one per request
+    flagged: Vec<Anomaly>,
+    throttled_until: Option<Instant>,
+    next_allowed: Instant,
+}
+
+impl UserActivity {
+    fn new(now: Instant) -> Self {
+        Self {
+            window_start: now,
+            downloads: 0,
+            download_bytes: 0,
+            listings: 0,
+            flagged: Vec::new(),
+            throttled_until: None,
+            next_allowed: now,
+        }
+    }
+}
+
+fn classify(method: &Method, path: &str) -> Access {
+    if method != Method::GET {
+        return Access::Other;
+    }
+    let path = path.trim_start_matches('/');
+    if path == "metrics" || path.starts_with("admin/") {
+        return Access::Other;
+    }
+    match path.split_once('/') {
+        None => Access::Listing,
+        Some((_, key)) if key.is_empty() || key.ends_with('/') => Access::Listing,
+        Some(_) => Access::Download,
+    }
+}
+
+/// Spaces out the requests of a throttled user, None when the request may proceed
+fn check_throttle(config: &AnomalyConfig, username: &str) -> Option<AppError> {
+    let now = Instant::now();
+    let mut activity = ACTIVITY.lock().unwrap();
+    let activity = activity.get_mut(username)?;
+    let until = activity.throttled_until?;
+    if now >= until {
+        info!("Lifted anomaly throttling for user {}", username);
+        activity.throttled_until = None;
+        return None;
+    }
+    if now < activity.next_allowed {
+        return Some(AppError::TooManyRequests(
+            "Rate limited after unusual activity, retry later".to_string(),
+        ));
+    }
+    activity.next_allowed = now + Duration::from_secs_f64(1.0 / config.throttle_requests_per_sec.max(0.001));
+    None
+}
+
+/// Counts the request and returns anomalies newly detected in this window
+fn record(config: &AnomalyConfig, username: &str, access: Access, bytes: u64) -> Vec<(Anomaly, String)> {
+    let now = Instant::now();
+    let window = Duration::from_secs(config.window_minutes.max(1) * 60);
+    let mut users = ACTIVITY.lock().unwrap();
+    let activity = users.entry(username.to_string()).or_insert_with(|| UserActivity::new(now));
+    if now.duration_since(activity.window_start) >= window {
+        activity.window_start = now;
+        activity.downloads = 0;
+        activity.download_bytes = 0;
+        activity.listings = 0;
+        activity.flagged.clear();
+    }
+
+    match access {
+        Access::Download => {
+            activity.downloads += 1;
+            activity.download_bytes += bytes;
+        }
+        Access::Listing => activity.listings += 1,
+        Access::Other => {}
+    }
+
+    let minutes = config.window_minutes;
+    let mut detected = Vec::new();
+    if let Some(max) = config.max_downloads.filter(|max| activity.downloads > *max) {
+        let message = format!("{} downloads within {} minutes, limit {}", activity.downloads, minutes, max);
+        detected.push((Anomaly::MassDownload, message));
+    }
+    if let Some(max) = config.max_download_bytes.filter(|max| activity.download_bytes > *max) {
+        let message = format!("{} bytes downloaded within {} minutes, limit {}", activity.download_bytes, minutes, max);
+        detected.push((Anomaly::DownloadVolume, message));
+    }
+    if let Some(max) = config.max_listings.filter(|max| activity.listings > *max) {
+        let message = format!("{} listings within {} minutes, limit {}", activity.listings, minutes, max);
+        detected.push((Anomaly::Enumeration, message));
+    }
+    let utc = Utc::now();
+    if !config.normal_hours.is_empty() && !config.normal_hours.iter().any(|w| w.contains(utc)) {
+        detected.push((Anomaly::OffHours, format!("access at {} UTC outside normal hours", utc.format("%a %H:%M"))));
+    }
+
+    detected.retain(|(anomaly, _)| !activity.flagged.contains(anomaly));
+    activity.flagged.extend(detected.iter().map(|(anomaly, _)| *anomaly));
+    if config.action == AnomalyAction::Throttle && !detected.is_empty() {
+        activity.throttled_until = Some(now + Duration::from_secs(config.throttle_secs));
+    }
+    detected
+}
+
+fn report(config: &AnomalyConfig, username: &str, anomaly: Anomaly, message: String) {
+    let throttled = config.action == AnomalyAction::Throttle;
+    warn!(
+        "Anomaly {} for user {}: {}{}",
+        anomaly.name(),
+        username,
+        message,
+        if throttled { ", throttling" } else { "" }
+    );
+
+    let (Some(url), Some(client)) = (config.webhook_url.clone(), WEBHOOK_CLIENT.as_ref()) else {
+        return;
+    };
+    let payload = match config.format {
+        AlertFormat::Webhook => json!({
+            "alert": "anomaly",
+            "anomaly": anomaly.name(),
+            "user": username,
+            "message": message,
+            "throttled": throttled,
+        }),
+        AlertFormat::Slack => json!({
+            "text": format!(":warning: s3-proxy anomaly *{}* for user *{}*: {}", anomaly.name(), username, message)
+        }),
+    };
+    tokio::spawn(async move {
+        if let Err(e) = alerts::post_webhook(client, &url, &payload).await {
+            warn!("Failed to send anomaly notification: {}", e);
+        }
+    });
+}
+
+/// Flags unusual per-user access and, depending on the configured action, throttles the user
+pub async fn detect(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let Some(anomalies) = &config.anomalies else {
+        return next.run(request).await;
+    };
+    let Some(username) = request.extensions().get::<AuthState>().map(|auth| auth.username.clone()) else {
+        return next.run(request).await;
+    };
+    if let Some(e) = check_throttle(anomalies, &username) {
+        return e.into_response();
+    }
+
+    let access = classify(request.method(), request.uri().path());
+    let response = next.run(request).await;
+    // Buffered bodies know their size before hyper writes the Content-Length header
+    let bytes = response.body().size_hint().exact().unwrap_or(0);
+
+    for (anomaly, message) in record(anomalies, &username, access, bytes) {
+        report(anomalies, &username, anomaly, message);
+    }
+    response
+}
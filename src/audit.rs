@@ -0,0 +1,504 @@
+use axum::{
+    extract::{Path, Request, State},
+    middleware::Next,
+    response::Response,
+    RequestExt,
+};
+use chrono::{DateTime, Utc};
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::auth::AuthState;
+use crate::config::AuditConfig;
+use crate::error::{AppError, Result};
+use crate::parts;
+use crate::server::AppState;
+use crate::tenants;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS audit (
+    id INTEGER PRIMARY KEY,
+    time INTEGER NOT NULL,
+    user TEXT NOT NULL,
+    grant_source TEXT NOT NULL,
+    method TEXT NOT NULL,
+    operation TEXT NOT NULL,
+    path TEXT NOT NULL,
+    bucket TEXT,
+    key TEXT,
+    query TEXT,
+    status INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    tenant TEXT
+);
+CREATE INDEX IF NOT EXISTS audit_time ON audit (time);
+CREATE INDEX IF NOT EXISTS audit_user ON audit (user, id);
+CREATE INDEX IF NOT EXISTS audit_object ON audit (bucket, key, id);
+CREATE TABLE IF NOT EXISTS audit_chain (
+    sequence INTEGER PRIMARY KEY,
+    last_id INTEGER NOT NULL,
+    digest TEXT NOT NULL,
+    key TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS tenant_audit_chain (
+    tenant TEXT NOT NULL,
+    sequence INTEGER NOT NULL,
+    last_id INTEGER NOT NULL,
+    digest TEXT NOT NULL,
+    key TEXT NOT NULL,
+    PRIMARY KEY (tenant, sequence)
+);
+";
+
+/// Brings stores created before records carried their tenant up to date
+const TENANT_COLUMN: &str = "ALTER TABLE audit ADD COLUMN tenant TEXT";
+const TENANT_INDEX: &str = "CREATE INDEX IF NOT EXISTS audit_tenant ON audit (tenant, id)";
+
+const COLUMNS: &str = "id, time, user, grant_source, method, operation, path, bucket, key, query, status, duration_ms, tenant";
+
+/// How long a connection waits for the other one to finish writing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Records written per transaction at most
+const BATCH: usize = 500;
+
+/// How often records past their retention are removed
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// One authenticated request
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub time: DateTime<Utc>,
+    pub user: String,
+    /// Config rule the user's grants came from, e.g. "users.alice" or "keys.{id}"
+    pub grant_source: String,
+    pub method: String,
+    /// read, list, write or delete
+    pub operation: String,
+    pub path: String,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub query: Option<String>,
+    pub status: u16,
+    pub duration_ms: u64,
+    /// Absent for requests in the default namespace
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    pub tenant: Option<String>,
+    pub user: Option<String>,
+    pub bucket: Option<String>,
+    pub key: Option<String>,
+    pub operation: Option<String>,
+    /// A status code such as 404, or a class such as 4xx
+    pub status: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<usize>,
+    /// The next_cursor of the previous page
+    pub cursor: Option<i64>,
+}
+
+/// A sealed segment uploaded to the shipping bucket, as recorded locally
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainLink {
+    pub sequence: u64,
+    /// Last record in the segment; records after it are not shipped yet
+    pub last_id: i64,
+    /// SHA-256 of the uploaded segment, which the next segment repeats
+    pub digest: String,
+    pub key: String,
+}
+
+/// Newest records first
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditPage {
+    pub records: Vec<AuditRecord>,
+    /// Pass as cursor to get the next, older page; absent on the last page
+    pub next_cursor: Option<i64>,
+}
+
+fn store_error(e: rusqlite::Error) -> AppError {
+    AppError::InternalError(format!("Audit store: {}", e))
+}
+
+fn read_record(row: &rusqlite::Row) -> rusqlite::Result<AuditRecord> {
+    Ok(AuditRecord {
+        id: row.get(0)?,
+        time: DateTime::from_timestamp_millis(row.get(1)?).unwrap_or_default(),
+        user: row.get(2)?,
+        grant_source: row.get(3)?,
+        method: row.get(4)?,
+        operation: row.get(5)?,
+        path: row.get(6)?,
+        bucket: row.get(7)?,
+        key: row.get(8)?,
+        query: row.get(9)?,
+        status: row.get(10)?,
+        duration_ms: row.get(11)?,
+        tenant: row.get(12)?,
+    })
+}
+
+/// Requests recorded in a local SQLite database; writes go through a thread of their own so
+/// requests never wait on the disk
+pub struct AuditLog {
+    sender: Mutex<mpsc::Sender<AuditRecord>>,
+    /// For queries and the shipping chain, next to the writer thread's own connection
+    connection: Mutex<Connection>,
+}
+
+impl AuditLog {
+    /// `shipping_tenants` ship their records to buckets of their own, which purges wait for
+    pub fn new(config: &AuditConfig, shipping_tenants: Vec<String>) -> Result<Self> {
+        let writer = Connection::open(&config.path).map_err(store_error)?;
+        // WAL lets queries run while records are written
+        writer.pragma_update(None, "journal_mode", "WAL").map_err(store_error)?;
+        writer.execute_batch(SCHEMA).map_err(store_error)?;
+        let has_tenant: bool = writer
+            .query_row("SELECT COUNT(*) > 0 FROM pragma_table_info('audit') WHERE name = 'tenant'", [], |row| row.get(0))
+            .map_err(store_error)?;
+        if !has_tenant {
+            writer.execute(TENANT_COLUMN, []).map_err(store_error)?;
+        }
+        writer.execute(TENANT_INDEX, []).map_err(store_error)?;
+        writer.busy_timeout(BUSY_TIMEOUT).map_err(store_error)?;
+        let connection = Connection::open(&config.path).map_err(store_error)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(store_error)?;
+
+        let (sender, receiver) = mpsc::channel();
+        let retention = chrono::Duration::days(config.retention_days as i64);
+        let shipped_only = config.shipping.is_some();
+        std::thread::Builder::new()
+            .name("audit-writer".to_string())
+            .spawn(move || write_loop(writer, receiver, retention, shipped_only, shipping_tenants))?;
+        info!("Recording requests to the audit store {}", config.path);
+        Ok(Self {
+            sender: Mutex::new(sender),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn record(&self, record: AuditRecord) {
+        if self.sender.lock().unwrap().send(record).is_err() {
+            warn!("Audit writer stopped, dropping audit record");
+        }
+    }
+
+    pub fn query(&self, query: &AuditQuery) -> Result<AuditPage> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        let mut filter = |condition: &str, value: Value| {
+            conditions.push(condition.to_string());
+            params.push(value);
+        };
+        for (column, value) in [
+            ("tenant", &query.tenant),
+            ("user", &query.user),
+            ("bucket", &query.bucket),
+            ("key", &query.key),
+            ("operation", &query.operation),
+        ] {
+            if let Some(value) = value {
+                filter(&format!("{} = ?", column), Value::Text(value.clone()));
+            }
+        }
+        if let Some(status) = &query.status {
+            let (low, high) = status_range(status)?;
+            filter("status >= ?", Value::Integer(low));
+            filter("status <= ?", Value::Integer(high));
+        }
+        if let Some(from) = query.from {
+            filter("time >= ?", Value::Integer(from.timestamp_millis()));
+        }
+        if let Some(to) = query.to {
+            filter("time < ?", Value::Integer(to.timestamp_millis()));
+        }
+        if let Some(cursor) = query.cursor {
+            filter("id < ?", Value::Integer(cursor));
+        }
+        let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let mut sql = format!("SELECT {} FROM audit", COLUMNS);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        // One more than asked for tells whether there is another page
+        sql.push_str(&format!(" ORDER BY id DESC LIMIT {}", limit + 1));
+
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(&sql).map_err(store_error)?;
+        let mut records = statement
+            .query_map(rusqlite::params_from_iter(params), read_record)
+            .map_err(store_error)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(store_error)?;
+        let next_cursor = if records.len() > limit {
+            records.truncate(limit);
+            records.last().map(|record| record.id)
+        } else {
+            None
+        };
+        Ok(AuditPage { records, next_cursor })
+    }
+
+    /// Oldest records not yet shipped, up to `limit`; those of one tenant when given
+    pub fn records_after(&self, id: i64, limit: usize, tenant: Option<&str>) -> Result<Vec<AuditRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached(&format!(
+                "SELECT {} FROM audit WHERE id > ?1 AND (?3 IS NULL OR tenant = ?3) ORDER BY id LIMIT ?2",
+                COLUMNS
+            ))
+            .map_err(store_error)?;
+        let records = statement
+            .query_map(rusqlite::params![id, limit as i64, tenant], read_record)
+            .map_err(store_error)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(store_error)?;
+        Ok(records)
+    }
+
+    /// When each key of a bucket was last read or written successfully, as far back as records go
+    pub fn last_access(&self, bucket: &str) -> Result<HashMap<String, DateTime<Utc>>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached(
+                "SELECT key, MAX(time) FROM audit WHERE bucket = ?1 AND key IS NOT NULL \
+                 AND operation IN ('read', 'write') AND status < 400 GROUP BY key",
+            )
+            .map_err(store_error)?;
+        let accesses = statement
+            .query_map([bucket], |row| {
+                Ok((row.get(0)?, DateTime::from_timestamp_millis(row.get(1)?).unwrap_or_default()))
+            })
+            .map_err(store_error)?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()
+            .map_err(store_error)?;
+        Ok(accesses)
+    }
+
+    /// Time of the oldest record still kept
+    pub fn oldest(&self) -> Result<Option<DateTime<Utc>>> {
+        let connection = self.connection.lock().unwrap();
+        let oldest: Option<i64> = connection
+            .query_row("SELECT MIN(time) FROM audit", [], |row| row.get(0))
+            .map_err(store_error)?;
+        Ok(oldest.and_then(DateTime::from_timestamp_millis))
+    }
+
+    /// The latest shipped segment of the chain of all records, or of one tenant's
+    pub fn chain_head(&self, tenant: Option<&str>) -> Result<Option<ChainLink>> {
+        let connection = self.connection.lock().unwrap();
+        let head = match tenant {
+            None => connection.query_row(
+                "SELECT sequence, last_id, digest, key FROM audit_chain ORDER BY sequence DESC LIMIT 1",
+                [],
+                read_link,
+            ),
+            Some(tenant) => connection.query_row(
+                "SELECT sequence, last_id, digest, key FROM tenant_audit_chain WHERE tenant = ?1 \
+                 ORDER BY sequence DESC LIMIT 1",
+                [tenant],
+                read_link,
+            ),
+        };
+        match head {
+            Ok(head) => Ok(Some(head)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(store_error(e)),
+        }
+    }
+
+    pub fn extend_chain(&self, tenant: Option<&str>, link: &ChainLink) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        match tenant {
+            None => connection.execute(
+                "INSERT INTO audit_chain (sequence, last_id, digest, key) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![link.sequence, link.last_id, link.digest, link.key],
+            ),
+            Some(tenant) => connection.execute(
+                "INSERT INTO tenant_audit_chain (tenant, sequence, last_id, digest, key) VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![tenant, link.sequence, link.last_id, link.digest, link.key],
+            ),
+        }
+        .map_err(store_error)?;
+        Ok(())
+    }
+}
+
+fn read_link(row: &rusqlite::Row) -> rusqlite::Result<ChainLink> {
+    Ok(ChainLink {
+        sequence: row.get(0)?,
+        last_id: row.get(1)?,
+        digest: row.get(2)?,
+        key: row.get(3)?,
+    })
+}
+
+/// Inclusive bounds of a status filter such as "404" or "4xx"
+fn status_range(status: &str) -> Result<(i64, i64)> {
+    let invalid = || AppError::InvalidRequest(format!("Invalid status filter {}, use e.g. 404 or 4xx", status));
+    if let Some(class) = status.strip_suffix("xx") {
+        let class: i64 = class.parse().map_err(|_| invalid())?;
+        return Ok((class * 100, class * 100 + 99));
+    }
+    let code: i64 = status.parse().map_err(|_| invalid())?;
+    Ok((code, code))
+}
+
+fn write_loop(
+    mut connection: Connection,
+    receiver: mpsc::Receiver<AuditRecord>,
+    retention: chrono::Duration,
+    shipped_only: bool,
+    shipping_tenants: Vec<String>,
+) {
+    let mut purged = Instant::now() - PURGE_INTERVAL;
+    loop {
+        let mut batch = Vec::new();
+        match receiver.recv_timeout(PURGE_INTERVAL) {
+            Ok(record) => batch.push(record),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+        batch.extend(receiver.try_iter().take(BATCH - batch.len()));
+        if !batch.is_empty() {
+            if let Err(e) = insert(&mut connection, &batch) {
+                warn!("Failed to write {} audit records: {}", batch.len(), e);
+            }
+        }
+        if purged.elapsed() >= PURGE_INTERVAL {
+            purged = Instant::now();
+            let cutoff = (Utc::now() - retention).timestamp_millis();
+            match purge(&connection, cutoff, shipped_only, &shipping_tenants) {
+                Ok(0) => {}
+                Ok(removed) => info!("Removed {} audit records past their retention", removed),
+                Err(e) => warn!("Failed to remove old audit records: {}", e),
+            }
+        }
+    }
+}
+
+/// Removes records older than the cutoff, keeping those still to be shipped, to the audit bucket or
+/// to the bucket of their tenant
+fn purge(
+    connection: &Connection,
+    cutoff: i64,
+    shipped_only: bool,
+    shipping_tenants: &[String],
+) -> rusqlite::Result<usize> {
+    let shipped: i64 = if shipped_only {
+        connection.query_row("SELECT COALESCE(MAX(last_id), 0) FROM audit_chain", [], |row| row.get(0))?
+    } else {
+        i64::MAX
+    };
+    let mut removed = 0;
+    for tenant in shipping_tenants {
+        let tenant_shipped: i64 = connection.query_row(
+            "SELECT COALESCE(MAX(last_id), 0) FROM tenant_audit_chain WHERE tenant = ?1",
+            [tenant],
+            |row| row.get(0),
+        )?;
+        removed += connection.execute(
+            "DELETE FROM audit WHERE time < ?1 AND id <= ?2 AND tenant = ?3",
+            rusqlite::params![cutoff, shipped.min(tenant_shipped), tenant],
+        )?;
+    }
+    let others = format!(
+        "DELETE FROM audit WHERE time < ? AND id <= ? AND (tenant IS NULL OR tenant NOT IN ({}))",
+        vec!["?"; shipping_tenants.len()].join(", ")
+    );
+    let params = [Value::Integer(cutoff), Value::Integer(shipped)]
+        .into_iter()
+        .chain(shipping_tenants.iter().map(|tenant| Value::Text(tenant.clone())));
+    removed += connection.execute(&others, rusqlite::params_from_iter(params))?;
+    Ok(removed)
+}
+
+fn insert(connection: &mut Connection, batch: &[AuditRecord]) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT INTO audit (time, user, grant_source, method, operation, path, bucket, key, query, status, duration_ms, tenant)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )?;
+        for record in batch {
+            statement.execute(rusqlite::params![
+                record.time.timestamp_millis(),
+                record.user,
+                record.grant_source,
+                record.method,
+                record.operation,
+                record.path,
+                record.bucket,
+                record.key,
+                record.query,
+                record.status,
+                record.duration_ms,
+                record.tenant,
+            ])?;
+        }
+    }
+    transaction.commit()
+}
+
+fn operation(method: &http::Method, has_key: bool) -> &'static str {
+    match *method {
+        http::Method::GET | http::Method::HEAD if has_key => "read",
+        http::Method::GET | http::Method::HEAD => "list",
+        http::Method::DELETE => "delete",
+        _ => "write",
+    }
+}
+
+/// Records each authenticated request with its outcome once the response is ready
+pub async fn record(State(state): State<Arc<AppState>>, mut request: Request, next: Next) -> Response {
+    let Some(audit) = &state.audit else {
+        return next.run(request).await;
+    };
+    let Some(auth) = request.extensions().get::<AuthState>().cloned() else {
+        return next.run(request).await;
+    };
+    let params: HashMap<String, String> = request.extract_parts::<Path<_>>().await.map(|Path(params)| params).unwrap_or_default();
+    let bucket = params.get("bucket").cloned();
+    let key = params.get("key").cloned();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let query = request.uri().query().map(parts::redact_query);
+    let time = Utc::now();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    audit.record(AuditRecord {
+        id: 0,
+        time,
+        user: auth.username,
+        grant_source: auth.grant_source,
+        operation: operation(&method, key.is_some()).to_string(),
+        method: method.to_string(),
+        path,
+        bucket,
+        key,
+        query,
+        status: response.status().as_u16(),
+        duration_ms: started.elapsed().as_millis() as u64,
+        tenant: tenants::current(),
+    });
+    response
+}
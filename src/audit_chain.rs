@@ -0,0 +1,236 @@
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::audit::{AuditLog, ChainLink};
+use crate::config::{AuditShippingConfig, Config};
+use crate::error::{AppError, Result};
+use crate::kubernetes;
+use crate::server::AppState;
+use crate::tenants;
+
+/// Records sealed into one segment at most; a larger backlog becomes several segments
+const MAX_SEGMENT_RECORDS: usize = 10_000;
+
+/// First line of a segment, followed by one JSON record per line
+#[derive(Debug, Serialize, Deserialize)]
+struct SegmentHeader {
+    sequence: u64,
+    /// Digest of the previous segment, absent for the first one
+    previous_digest: Option<String>,
+    first_id: i64,
+    last_id: i64,
+    count: usize,
+    sealed_at: DateTime<Utc>,
+}
+
+/// Result of checking the uploaded segments against each other and the local chain
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChainReport {
+    pub prefix: String,
+    pub segments: u64,
+    pub intact: bool,
+    /// First problem found, segments after it are not checked
+    pub problem: Option<String>,
+}
+
+fn digest(body: &[u8]) -> String {
+    hex::encode(Sha256::digest(body))
+}
+
+/// Each replica ships its own chain
+fn prefix(shipping: &AuditShippingConfig) -> String {
+    match kubernetes::pod() {
+        Some(pod) => format!("{}{}/", shipping.prefix, pod.name),
+        None => shipping.prefix.clone(),
+    }
+}
+
+fn segment_key(prefix: &str, sequence: u64) -> String {
+    format!("{}{:012}.jsonl", prefix, sequence)
+}
+
+fn header(body: &[u8]) -> Option<SegmentHeader> {
+    let line = body.split(|b| *b == b'\n').next()?;
+    serde_json::from_slice(line).ok()
+}
+
+/// Runs a call to the SQLite store off the async workers
+async fn with_audit<T: Send + 'static>(
+    state: &Arc<AppState>,
+    call: impl FnOnce(&AuditLog) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || match &state.audit {
+        Some(audit) => call(audit),
+        None => Err(AppError::InvalidRequest("The audit log is not enabled".to_string())),
+    })
+    .await
+    .map_err(|e| AppError::InternalError(format!("Audit store task failed: {}", e)))?
+}
+
+/// Shipping of the chain of all records, or of one tenant's records
+fn shipping_of<'a>(config: &'a Config, tenant: Option<&str>) -> Option<&'a AuditShippingConfig> {
+    match tenant {
+        None => config.audit.as_ref().and_then(|audit| audit.shipping.as_ref()),
+        Some(tenant) => config.tenants.get(tenant).and_then(|tenant| tenant.audit_shipping.as_ref()),
+    }
+}
+
+/// Seals the records not yet shipped into segments and uploads them, each carrying the digest of
+/// the one before; returns the number of segments uploaded. A tenant's chain holds only its
+/// records and goes to a bucket of the tenant
+async fn seal(state: &Arc<AppState>, shipping: &AuditShippingConfig, tenant: Option<&str>) -> Result<u64> {
+    let (_, client) = &state.get_account_and_client(&shipping.bucket)?;
+    let prefix = prefix(shipping);
+    let mut sealed = 0;
+    loop {
+        let owned = tenant.map(String::from);
+        let head = with_audit(state, move |audit| audit.chain_head(owned.as_deref())).await?;
+        let after = head.as_ref().map_or(0, |head| head.last_id);
+        let owned = tenant.map(String::from);
+        let records =
+            with_audit(state, move |audit| audit.records_after(after, MAX_SEGMENT_RECORDS, owned.as_deref())).await?;
+        let (Some(first), Some(last)) = (records.first(), records.last()) else {
+            return Ok(sealed);
+        };
+
+        let segment = SegmentHeader {
+            sequence: head.as_ref().map_or(1, |head| head.sequence + 1),
+            previous_digest: head.as_ref().map(|head| head.digest.clone()),
+            first_id: first.id,
+            last_id: last.id,
+            count: records.len(),
+            sealed_at: Utc::now(),
+        };
+        let mut body = serde_json::to_vec(&segment).map_err(|e| AppError::InternalError(e.to_string()))?;
+        for record in &records {
+            body.push(b'\n');
+            body.extend(serde_json::to_vec(record).map_err(|e| AppError::InternalError(e.to_string()))?);
+        }
+        body.push(b'\n');
+
+        let key = segment_key(&prefix, segment.sequence);
+        let retain_until = shipping.lock_days.map(|days| Utc::now() + chrono::Duration::days(days as i64));
+        let content_type = Some("application/x-ndjson".to_string());
+        let link = if client
+            .put_object_write_once(&shipping.bucket, &key, Bytes::from(body.clone()), content_type, retain_until)
+            .await?
+        {
+            ChainLink { sequence: segment.sequence, last_id: segment.last_id, digest: digest(&body), key }
+        } else {
+            // Uploaded before it could be recorded locally, e.g. the proxy stopped in between
+            let existing = client.get_object_range(&shipping.bucket, &key, None, None).await?;
+            let existing_header = header(&existing.body)
+                .ok_or_else(|| AppError::InternalError(format!("Audit segment {} has no valid header", key)))?;
+            if existing_header.previous_digest != segment.previous_digest {
+                warn!("Audit segment {} already exists and does not continue the local chain", key);
+            }
+            ChainLink { sequence: segment.sequence, last_id: existing_header.last_id, digest: digest(&existing.body), key }
+        };
+        info!("Shipped audit segment {} with records {} to {}", link.key, segment.first_id, link.last_id);
+        let owned = tenant.map(String::from);
+        with_audit(state, move |audit| audit.extend_chain(owned.as_deref(), &link)).await?;
+        sealed += 1;
+    }
+}
+
+/// Periodically ships sealed segments of this replica's audit records, and of each tenant's
+pub fn spawn(state: Arc<AppState>) {
+    if state.config.audit.is_none() {
+        return;
+    }
+    let chains = std::iter::once(None).chain(state.config.tenants.keys().cloned().map(Some));
+    for tenant in chains {
+        let Some(shipping) = shipping_of(&state.config, tenant.as_deref()) else {
+            continue;
+        };
+        info!("Shipping audit segments to {}/{} every {}s", shipping.bucket, prefix(shipping), shipping.interval_secs);
+        let interval = Duration::from_secs(shipping.interval_secs.max(1));
+        let state = state.clone();
+        // In the tenant's scope, so its bucket is looked up among its accounts
+        tokio::spawn(tenants::scope(tenant.clone(), async move {
+            let Some(shipping) = shipping_of(&state.config, tenant.as_deref()) else {
+                return;
+            };
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = seal(&state, shipping, tenant.as_deref()).await {
+                    warn!("Failed to ship audit segments to {}, retrying in {:?}: {}", shipping.bucket, interval, e);
+                }
+            }
+        }));
+    }
+}
+
+/// Re-reads this replica's uploaded segments of the chain of all records, or of a tenant's, and
+/// checks that each one's digest is repeated by the next, and that the last one matches the digest
+/// recorded locally
+pub async fn verify(state: &Arc<AppState>, tenant: Option<String>) -> Result<ChainReport> {
+    tenants::scope(tenant.clone(), verify_chain(state, tenant)).await
+}
+
+async fn verify_chain(state: &Arc<AppState>, tenant: Option<String>) -> Result<ChainReport> {
+    let shipping = shipping_of(&state.config, tenant.as_deref())
+        .ok_or_else(|| AppError::InvalidRequest("Audit shipping is not enabled".to_string()))?;
+    let (_, client) = &state.get_account_and_client(&shipping.bucket)?;
+    let prefix = prefix(shipping);
+    let mut report = ChainReport { prefix: prefix.clone(), segments: 0, intact: true, problem: None };
+
+    let mut keys: Vec<String> = client
+        .list_objects(&shipping.bucket, Some(prefix.clone()))
+        .await?
+        .into_iter()
+        .filter_map(|object| object.key)
+        .filter(|key| key.ends_with(".jsonl") && !key[prefix.len()..].contains('/'))
+        .collect();
+    keys.sort();
+
+    let mut previous: Option<String> = None;
+    for key in keys {
+        let expected = report.segments + 1;
+        let body = client.get_object_range(&shipping.bucket, &key, None, None).await?.body;
+        let problem = match header(&body) {
+            None => Some(format!("{} has no valid header", key)),
+            Some(header) if header.sequence != expected => {
+                Some(format!("{} is segment {}, expected {}", key, header.sequence, expected))
+            }
+            Some(header) if header.previous_digest != previous => {
+                Some(format!("{} does not carry the digest of the segment before it", key))
+            }
+            Some(_) => None,
+        };
+        if problem.is_some() {
+            report.problem = problem;
+            break;
+        }
+        previous = Some(digest(&body));
+        report.segments = expected;
+    }
+
+    if report.problem.is_none() {
+        let head = with_audit(state, move |audit| audit.chain_head(tenant.as_deref())).await?;
+        report.problem = match head {
+            Some(head) if head.sequence != report.segments => Some(format!(
+                "{} segments uploaded, {} shipped according to the local chain",
+                report.segments, head.sequence
+            )),
+            Some(head) if Some(&head.digest) != previous.as_ref() => {
+                Some(format!("{} differs from the digest recorded when it was shipped", head.key))
+            }
+            None if report.segments > 0 => Some("No segments shipped according to the local chain".to_string()),
+            _ => None,
+        };
+    }
+    report.intact = report.problem.is_none();
+    if let Some(problem) = &report.problem {
+        warn!("Audit chain under {} is broken: {}", prefix, problem);
+    }
+    Ok(report)
+}
@@ -1,25 +1,160 @@
 use axum::{
-    extract::Request,
-    http::header,
+    body::Body,
+    extract::{OriginalUri, Path, Query, Request},
+    http::{header, HeaderMap, Method, Uri},
     middleware::Next,
     response::Response,
     extract::State,
     response::IntoResponse,
+    RequestExt,
 };
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::collections::HashMap;
 use tokio::sync::RwLock;
-use tracing::{info, warn};
+use base64::Engine;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{info, warn, Span};
 
-use crate::config::Config;
+use crate::config::{matching_bucket_grant, Config, SigningKeyConfig, UserConfig, UserRole};
 use crate::error::{AppError, Result};
+use crate::keys::{self, DelegatedKey};
+use crate::ldap;
+use crate::memory;
+use crate::metrics;
+use crate::package_index;
+use crate::parts;
+use crate::pkcs11;
+use crate::session::{self, Session};
+use crate::terraform;
+use crate::server::AppState;
+use crate::sigv4;
+use crate::tenants;
+
+pub use s3_proxy_client::types::Operation;
 
 #[derive(Debug, Clone)]
 pub struct AuthState {
     pub username: String,
-    #[allow(dead_code)]
-    pub role: String,
+    pub role: UserRole,
+    pub allowed_buckets: Vec<String>,
+    /// Config rule the user's grants come from, e.g. "users.alice"
+    pub grant_source: String,
+    pub strict: bool,
+    /// Keys the request may touch, set for delegated keys limited to prefixes; empty allows any
+    pub key_prefixes: Vec<String>,
+    /// Rules that granted or denied this request, reported to admins in X-Proxy-Authz
+    matched_rules: Arc<Mutex<Vec<String>>>,
+}
+
+impl AuthState {
+    pub fn new(username: String, role: UserRole, allowed_buckets: Vec<String>, grant_source: String) -> Self {
+        Self {
+            username,
+            role,
+            allowed_buckets,
+            grant_source,
+            strict: false,
+            key_prefixes: Vec::new(),
+            matched_rules: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn for_config_user(username: &str, user: &UserConfig) -> Self {
+        Self::new(
+            username.to_string(),
+            user.role,
+            user.allowed_buckets.clone(),
+            format!("users.{}", username),
+        )
+    }
+
+    /// Copy of this state with an empty rule trail
+    pub fn without_rules(&self) -> Self {
+        let mut auth = Self::new(
+            self.username.clone(),
+            self.role,
+            self.allowed_buckets.clone(),
+            self.grant_source.clone(),
+        );
+        auth.strict = self.strict;
+        auth.key_prefixes = self.key_prefixes.clone();
+        auth
+    }
+
+    /// Whether the grants come from LDAP groups rather than a user in the config
+    pub fn is_ldap(&self) -> bool {
+        self.grant_source.starts_with("ldap.")
+    }
+
+    pub fn record_rule(&self, rule: String) {
+        self.matched_rules.lock().unwrap().push(rule);
+    }
+
+    pub fn matched_rules(&self) -> Vec<String> {
+        self.matched_rules.lock().unwrap().clone()
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+enum SigningKey {
+    /// From the config file or the OS keychain
+    Secret(Vec<u8>),
+    Pkcs11(pkcs11::HmacKey),
+}
+
+/// Signs tokens the proxy issues, such as session cookies, with HMAC-SHA256 under a key that may
+/// live outside the config
+pub struct Signer {
+    key: SigningKey,
+}
+
+impl Signer {
+    pub fn new(config: &SigningKeyConfig) -> Result<Self> {
+        let key = match (&config.secret, &config.keychain, &config.pkcs11) {
+            (Some(secret), None, None) => SigningKey::Secret(secret.clone().into_bytes()),
+            (None, Some(keychain), None) => {
+                let secret = keyring::Entry::new(&keychain.service, &keychain.account)
+                    .and_then(|entry| entry.get_password())
+                    .map_err(|e| {
+                        AppError::InternalError(format!(
+                            "Cannot read signing key {}/{} from the keychain: {}",
+                            keychain.service, keychain.account, e
+                        ))
+                    })?;
+                info!("Signing with key {}/{} from the keychain", keychain.service, keychain.account);
+                SigningKey::Secret(secret.into_bytes())
+            }
+            (None, None, Some(pkcs11)) => SigningKey::Pkcs11(pkcs11::HmacKey::open(pkcs11)?),
+            _ => {
+                return Err(AppError::InternalError(
+                    "A signing key needs exactly one of secret, keychain and pkcs11".to_string(),
+                ))
+            }
+        };
+        Ok(Self { key })
+    }
+
+    pub fn sign(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        match &self.key {
+            SigningKey::Secret(secret) => {
+                let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key length");
+                mac.update(payload);
+                Ok(mac.finalize().into_bytes().to_vec())
+            }
+            SigningKey::Pkcs11(key) => key.sign(payload),
+        }
+    }
+
+    /// Compares in constant time, so signatures cannot be guessed byte by byte
+    pub fn verify(&self, payload: &[u8], signature: &[u8]) -> Result<bool> {
+        let expected = self.sign(payload)?;
+        Ok(expected.len() == signature.len()
+            && expected.iter().zip(signature).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0)
+    }
 }
 
 #[derive(Default)]
@@ -34,7 +169,8 @@ impl RateLimiter {
         }
     }
 
-    fn is_rate_limited(&mut self, username: &str) -> bool {
+    /// Counts a request of the user; how many more fit in the window, or None when over the limit
+    fn remaining(&mut self, username: &str) -> Option<usize> {
         let now = Instant::now();
         let window = Duration::from_secs(60); // 1 minute window
         let max_requests = 100; // max requests per minute
@@ -46,12 +182,12 @@ impl RateLimiter {
         
         // Check if rate limited
         if requests.len() >= max_requests {
-            return true;
+            return None;
         }
         
         // Add new request
         requests.push(now);
-        false
+        Some(max_requests - requests.len())
     }
 }
 
@@ -62,13 +198,6 @@ lazy_static::lazy_static! {
 fn validate_request(config: &Config, request: &Request) -> Result<()> {
     // Check content length for PUT requests
     if request.method() == http::Method::PUT {
-        // Check write permissions
-        if let Some(api_key) = request.headers().get("x-api-key").and_then(|v| v.to_str().ok()) {
-            if let Some((username, _)) = config.find_user_by_api_key(api_key) {
-                check_write_permission(config, &username)?;
-            }
-        }
-
         if let Some(content_length) = request.headers().get(header::CONTENT_LENGTH) {
             if let Ok(s) = content_length.to_str() {
                 if let Ok(length) = s.parse::<u64>() {
@@ -83,62 +212,338 @@ fn validate_request(config: &Config, request: &Request) -> Result<()> {
         }
     }
 
-    // Validate path components
-    if let Some(path) = request.uri().path().strip_prefix('/') {
-        let parts: Vec<&str> = path.split('/').collect();
-        if parts.is_empty() || parts.len() > 2 {
-            return Err(AppError::InvalidRequest("Invalid path format".to_string()));
+    // Validate path components, keys may be nested but must not climb out of the bucket
+    if request.uri().path().split('/').any(|part| part == "." || part == "..") {
+        return Err(AppError::InvalidRequest("Invalid path format".to_string()));
+    }
+
+    Ok(())
+}
+
+fn basic_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let encoded = value.strip_prefix("Basic ")?;
+    let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Realm for paths whose clients can only send Basic credentials, which then carry the API key
+fn basic_realm(config: &Config, path: &str) -> Option<String> {
+    match &config.registry {
+        Some(registry) if path.starts_with("/v2/") => Some(registry.realm.clone()),
+        _ => package_index::realm(config, path).or_else(|| terraform::realm(config, path)),
+    }
+}
+
+fn check_account_active(username: &str, user: &UserConfig) -> Result<()> {
+    let now = Utc::now();
+    if user.is_expired(now) {
+        warn!("Account {} is expired or not yet valid", username);
+        return Err(AppError::Unauthorized("Account expired".to_string()));
+    }
+    if !user.is_within_access_window(now) {
+        warn!("User {} outside allowed access window", username);
+        return Err(AppError::Unauthorized("Access not allowed at this time".to_string()));
+    }
+    Ok(())
+}
+
+/// Grants of a delegated key, narrowed to what its owner may do now
+pub fn for_delegated_key(config: &Config, key: DelegatedKey) -> Result<AuthState> {
+    if key.is_expired(Utc::now()) {
+        warn!("Key {} of {} is expired", key.id, key.owner);
+        return Err(AppError::Unauthorized("API key expired".to_string()));
+    }
+    let Some(owner) = config.find_user(&key.owner) else {
+        warn!("Key {} belongs to {}, who is no longer a user", key.id, key.owner);
+        return Err(AppError::Unauthorized("Invalid API key".to_string()));
+    };
+    check_account_active(&key.owner, &owner)?;
+    let buckets = key
+        .buckets
+        .into_iter()
+        .filter(|bucket| matching_bucket_grant(&owner.allowed_buckets, bucket, config.strict).is_some())
+        .collect();
+    let mut auth = AuthState::new(key.owner, key.role.min(owner.role), buckets, format!("keys.{}", key.id));
+    auth.key_prefixes = key.prefixes;
+    Ok(auth)
+}
+
+/// Read access to the buckets of a session, narrowed to what its user may read now; sessions of
+/// LDAP users keep their buckets until they expire, as the directory is only asked at login
+fn for_session(config: &Config, session: Session) -> Result<AuthState> {
+    let source = format!("sessions.{}", session.username);
+    if session.ldap {
+        return Ok(AuthState::new(session.username, UserRole::Readonly, session.buckets, source));
+    }
+    let Some(user) = config.find_user(&session.username) else {
+        warn!("Session of {}, who is no longer a user", session.username);
+        return Err(AppError::Unauthorized("Invalid session cookie".to_string()));
+    };
+    check_account_active(&session.username, &user)?;
+    let buckets = session
+        .buckets
+        .into_iter()
+        .filter(|bucket| matching_bucket_grant(&user.allowed_buckets, bucket, config.strict).is_some())
+        .collect();
+    Ok(AuthState::new(session.username, UserRole::Readonly, buckets, source))
+}
+
+/// Read access to the one bucket a part URL was issued for, while its user still has access;
+/// URLs of LDAP users are only checked at issue, as the directory is only asked at login
+fn for_signed_part(config: &Config, part: parts::SignedPart) -> Result<AuthState> {
+    if !part.ldap {
+        let Some(user) = config.find_user(&part.username) else {
+            warn!("Part URL of {}, who is no longer a user", part.username);
+            return Err(AppError::Unauthorized("Invalid part URL".to_string()));
+        };
+        check_account_active(&part.username, &user)?;
+        if matching_bucket_grant(&user.allowed_buckets, &part.bucket, config.strict).is_none() {
+            warn!("Part URL of {} for bucket {} outlived the grant", part.username, part.bucket);
+            return Err(AppError::BucketDenied(part.bucket));
+        }
+    }
+    let source = format!("parts.{}", part.username);
+    Ok(AuthState::new(part.username, UserRole::Readonly, vec![part.bucket], source))
+}
+
+async fn authenticate(
+    state: &AppState,
+    method: &Method,
+    uri: &Uri,
+    signed_uri: &Uri,
+    headers: &HeaderMap,
+) -> Result<AuthState> {
+    let config = &state.config;
+    // Get API key from header
+    if let Some(api_key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        // Find user by API key
+        if let Some((username, user)) = config.find_user_by_api_key(api_key) {
+            check_account_active(&username, &user)?;
+            return Ok(AuthState::for_config_user(&username, &user));
         }
+        if let Some(key) = state.keys.as_ref().and_then(|keys| keys.find(api_key)) {
+            return for_delegated_key(config, key);
+        }
+        warn!("Invalid API key");
+        return Err(AppError::Unauthorized("Invalid API key".to_string()));
     }
 
+    // Requests signed by S3 clients
+    if sigv4::is_sigv4(headers) {
+        let (username, user) = sigv4::authenticate(config, method, signed_uri, headers).await?;
+        check_account_active(&username, &user)?;
+        return Ok(AuthState::for_config_user(&username, &user));
+    }
+
+    // Registry, package index and Terraform clients only send Basic credentials, with the API key as password
+    if basic_realm(config, uri.path()).is_some() {
+        if let Some((username, password)) = basic_credentials(headers) {
+            if let Some(user) = config.find_user(&username).filter(|user| user.api_key == password) {
+                check_account_active(&username, &user)?;
+                return Ok(AuthState::for_config_user(&username, &user));
+            }
+        }
+    }
+
+    // Fall back to Basic credentials when an LDAP backend is configured
+    if let Some(ldap_config) = &config.ldap {
+        if let Some((username, password)) = basic_credentials(headers) {
+            return ldap::authenticate(ldap_config, &username, &password).await;
+        }
+    }
+
+    // Download accelerators fetch the signed part URLs of a manifest
+    if let Some(signer) = &state.part_signer {
+        if let Some(result) = parts::verify(signer, method, uri) {
+            return for_signed_part(config, result?);
+        }
+    }
+
+    // Browsers loading pages and their assets send the session cookie instead
+    if let (Some(sessions), Some(signer)) = (&config.sessions, &state.session_signer) {
+        if let Some(result) = session::authenticate(sessions, signer, method, headers) {
+            return for_session(config, result?);
+        }
+    }
+
+    warn!("No API key provided");
+    Err(AppError::Unauthorized("No API key provided".to_string()))
+}
+
+/// Keeps a key limited to prefixes to object requests and listings under them
+async fn check_key_prefixes(auth: &AuthState, request: &mut Request) -> Result<()> {
+    if auth.key_prefixes.is_empty() {
+        return Ok(());
+    }
+    let path: HashMap<String, String> = request.extract_parts::<Path<_>>().await.map(|Path(path)| path).unwrap_or_default();
+    let params: HashMap<String, String> = Query::try_from_uri(request.uri()).map(|Query(params)| params).unwrap_or_default();
+    let key = keys::routed_key(&path, request.uri().path());
+    let allowed = path.contains_key("bucket") && keys::within_prefixes(&auth.key_prefixes, request.method(), key, &params);
+    if !allowed {
+        auth.record_rule(format!("denied: outside {}.prefixes", auth.grant_source));
+        warn!("Key {} used outside its prefixes: {}", auth.grant_source, request.uri());
+        return Err(AppError::Unauthorized("Not allowed outside the key's prefixes".to_string()));
+    }
+    auth.record_rule(format!("{}.prefixes: {:?}", auth.grant_source, auth.key_prefixes));
+    Ok(())
+}
+
+/// Same check as `check_key_prefixes` for an operation on `key`, or for a listing under `key`
+pub fn check_operation_prefixes(auth: &AuthState, operation: Operation, key: Option<&str>) -> Result<()> {
+    if auth.key_prefixes.is_empty() {
+        return Ok(());
+    }
+    // Reads and writes without a key stand for the whole bucket, which no prefix covers
+    let (method, key, params) = match operation {
+        Operation::Read => (http::Method::GET, key, HashMap::new()),
+        Operation::Write => (http::Method::PUT, key, HashMap::new()),
+        Operation::List => {
+            let prefix = key.unwrap_or_default().to_string();
+            (http::Method::GET, None, HashMap::from([("prefix".to_string(), prefix)]))
+        }
+    };
+    if !keys::within_prefixes(&auth.key_prefixes, &method, key, &params) {
+        auth.record_rule(format!("denied: outside {}.prefixes", auth.grant_source));
+        return Err(AppError::Unauthorized("Not allowed outside the key's prefixes".to_string()));
+    }
+    auth.record_rule(format!("{}.prefixes: {:?}", auth.grant_source, auth.key_prefixes));
     Ok(())
 }
 
+/// Records the outcome and the rule behind it on the request span, and counts denials
+fn record_outcome(span: &Span, auth: &AuthState, outcome: &str) {
+    let rules = auth.matched_rules();
+    let rule = rules.iter().rev().find(|rule| rule.starts_with("denied:")).or(rules.last());
+    span.record("auth_outcome", outcome);
+    if let Some(rule) = rule {
+        span.record("auth_rule", rule.as_str());
+    }
+    match outcome {
+        "denied" => metrics::record_auth_denied(&auth.username, "rule"),
+        "rate_limited" => metrics::record_auth_denied(&auth.username, "rate_limit"),
+        _ => {}
+    }
+}
+
+fn was_denied(auth: &AuthState) -> bool {
+    auth.matched_rules().iter().any(|rule| rule.starts_with("denied:"))
+}
+
+/// SigV4 signatures cover the body only through its signed hash, so the body is read and checked
+async fn check_payload_hash(config: &Config, request: Request) -> Result<Request> {
+    let Some(signed) = sigv4::signed_payload_hash(request.headers()).map(str::to_string) else {
+        return Ok(request);
+    };
+    let limit = usize::try_from(config.max_file_size).unwrap_or(usize::MAX);
+    // Charged here, since the body is buffered before the memory guard further in sees it
+    let request = if config.memory.is_some() { memory::charge(request)? } else { request };
+    let (parts, body) = request.into_parts();
+    let body = match axum::body::to_bytes(body, limit).await {
+        Ok(body) => body,
+        Err(_) if memory::was_refused(&parts) => {
+            return Err(AppError::ServiceUnavailable("The proxy is out of buffer memory, retry later".to_string()))
+        }
+        Err(e) => return Err(AppError::InvalidRequest(format!("Failed to read request body: {}", e))),
+    };
+    sigv4::check_payload_hash(&signed, &body)?;
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
 pub async fn auth_middleware(
-    State(config): State<Arc<Config>>,
+    State(state): State<Arc<AppState>>,
     mut request: Request,
     next: Next,
 ) -> Response {
+    let config = &state.config;
+
     // Validate request
-    if let Err(e) = validate_request(&config, &request) {
+    if let Err(e) = validate_request(config, &request) {
         return e.into_response();
     }
 
-    // Get API key from header
-    let api_key = match request
-        .headers()
-        .get("x-api-key")
-        .and_then(|v| v.to_str().ok()) {
-        Some(key) => key,
-        None => {
-            warn!("No API key provided");
-            return AppError::Unauthorized("No API key provided".to_string()).into_response();
+    // Fields of the request span, so log queries can filter on who was let in and why
+    let span = Span::current();
+    // SigV4 signatures cover the path the client sent, before a tenant prefix was removed
+    let signed_uri = request.extensions().get::<OriginalUri>().map_or(request.uri(), |OriginalUri(uri)| uri);
+    // `authenticate` prefers x-api-key over a SigV4 signature
+    let signed = !request.headers().contains_key("x-api-key") && sigv4::is_sigv4(request.headers());
+    let mut auth = match authenticate(&state, request.method(), request.uri(), signed_uri, request.headers()).await {
+        Ok(auth) => auth,
+        Err(e) => {
+            span.record("auth_outcome", "unauthenticated");
+            let mut response = e.into_response();
+            // Registry, package index and Terraform clients only send credentials once challenged
+            let challenge = basic_realm(config, request.uri().path()).map(|realm| format!("Basic realm=\"{}\"", realm));
+            if let Some(challenge) = challenge.and_then(|challenge| challenge.parse().ok()) {
+                response.headers_mut().insert(header::WWW_AUTHENTICATE, challenge);
+            }
+            return response;
         }
     };
+    auth.strict = config.strict;
+    auth.record_rule(format!("authenticated by {}", auth.grant_source));
+    span.record("user", auth.username.as_str());
 
-    // Find user by API key
-    let (username, user) = match config.find_user_by_api_key(api_key) {
-        Some(u) => u,
-        None => {
-            warn!("Invalid API key");
-            return AppError::Unauthorized("Invalid API key".to_string()).into_response();
+    if !tenants::owns(config, &auth.username) {
+        warn!("User {} does not belong to the tenant of the request", auth.username);
+        record_outcome(&span, &auth, "denied");
+        return AppError::Unauthorized("Not a user of this tenant".to_string()).into_response();
+    }
+
+    if let Err(e) = check_key_prefixes(&auth, &mut request).await {
+        record_outcome(&span, &auth, "denied");
+        return e.into_response();
+    }
+
+    // Reject writes before the body is read
+    if request.method() == http::Method::PUT || request.method() == http::Method::DELETE {
+        if let Err(e) = check_write_permission(&auth) {
+            record_outcome(&span, &auth, "denied");
+            return e.into_response();
         }
-    };
+    }
 
     // Check rate limit
-    if RATE_LIMITER.write().await.is_rate_limited(&username) {
-        warn!("Rate limit exceeded for user {}", username);
-        return AppError::Unauthorized("Rate limit exceeded".to_string()).into_response();
+    let Some(remaining) = RATE_LIMITER.write().await.remaining(&auth.username) else {
+        warn!("Rate limit exceeded for user {}", auth.username);
+        span.record("rate_limit_remaining", 0);
+        record_outcome(&span, &auth, "rate_limited");
+        return AppError::TooManyRequests("Rate limit exceeded".to_string()).into_response();
+    };
+    span.record("rate_limit_remaining", remaining);
+
+    if signed {
+        request = match check_payload_hash(config, request).await {
+            Ok(request) => request,
+            Err(e @ AppError::ServiceUnavailable(_)) => return memory::retry_after(config, e.into_response()),
+            Err(e) => {
+                // A body that does not match its signature is no better than a bad signature
+                span.record("auth_outcome", "unauthenticated");
+                return e.into_response();
+            }
+        };
     }
 
+    let username = auth.username.clone();
+    let role = auth.role;
+
     // Add auth state to request extensions
-    request.extensions_mut().insert(AuthState {
-        username: username.to_string(),
-        role: format!("{:?}", user.role),
-    });
+    request.extensions_mut().insert(auth.clone());
 
     // Process the request
     let mut response = next.run(request).await;
+    // Handlers check bucket grants, so the decision is only complete once they ran
+    record_outcome(&span, &auth, if was_denied(&auth) { "denied" } else { "allowed" });
+
+    // Report the rules behind the decision to admins
+    if role == UserRole::Admin {
+        if let Ok(value) = auth.matched_rules().join("; ").parse() {
+            response.headers_mut().insert("X-Proxy-Authz", value);
+        }
+    }
 
     // Add secure headers
     let headers = response.headers_mut();
@@ -147,25 +552,38 @@ pub async fn auth_middleware(
     headers.insert("X-XSS-Protection", "1; mode=block".parse().unwrap());
     headers.insert("Strict-Transport-Security", "max-age=31536000; includeSubDomains".parse().unwrap());
 
-    info!("Authenticated user: {} with role: {:?}", username, user.role);
+    info!("Authenticated user: {} with role: {:?}", username, role);
     response
 }
 
-pub fn check_bucket_access(config: &Config, username: &str, bucket: &str) -> Result<()> {
-    if !config.is_bucket_allowed(username, bucket) {
-        warn!("User {} not allowed to access bucket {}", username, bucket);
-        return Err(AppError::Unauthorized(format!(
-            "Not allowed to access bucket: {}",
-            bucket
-        )));
+pub fn check_bucket_access(auth: &AuthState, bucket: &str) -> Result<()> {
+    match matching_bucket_grant(&auth.allowed_buckets, bucket, auth.strict) {
+        Some(grant) => {
+            auth.record_rule(format!("{}.allowed_buckets: {}", auth.grant_source, grant));
+            Ok(())
+        }
+        None => {
+            auth.record_rule(format!("denied: no grant for bucket {} in {}", bucket, auth.grant_source));
+            warn!("User {} not allowed to access bucket {}", auth.username, bucket);
+            Err(AppError::BucketDenied(bucket.to_string()))
+        }
+    }
+}
+
+pub fn check_write_permission(auth: &AuthState) -> Result<()> {
+    if !auth.role.can_write() {
+        auth.record_rule(format!("denied: {}.role {:?} cannot write", auth.grant_source, auth.role));
+        warn!("User {} not allowed to write", auth.username);
+        return Err(AppError::Unauthorized("Write permission denied".to_string()));
     }
+    auth.record_rule(format!("{}.role: {:?}", auth.grant_source, auth.role));
     Ok(())
 }
 
-pub fn check_write_permission(config: &Config, username: &str) -> Result<()> {
-    if !config.can_write(username) {
-        warn!("User {} not allowed to write", username);
-        return Err(AppError::Unauthorized("Write permission denied".to_string()));
+pub fn check_operation(auth: &AuthState, operation: Operation, bucket: &str) -> Result<()> {
+    check_bucket_access(auth, bucket)?;
+    if operation == Operation::Write {
+        check_write_permission(auth)?;
     }
     Ok(())
-} 
\ No newline at end of file
+}
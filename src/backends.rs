@@ -0,0 +1,242 @@
+use aws_smithy_runtime_api::client::http::{HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::concurrency;
+use crate::config::{CircuitBreakerConfig, Config};
+
+/// How far back error counts and latency are reported
+const WINDOW: Duration = Duration::from_secs(300);
+
+/// Samples kept per account within the window
+const MAX_SAMPLES: usize = 10_000;
+
+lazy_static! {
+    static ref BACKENDS: Mutex<BTreeMap<String, Backend>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Timeout,
+    Io,
+    Connector,
+    /// 429 and 503 Slow Down
+    Throttled,
+    AccessDenied,
+    ServerError,
+    /// Refused by the open circuit breaker without reaching the endpoint
+    CircuitOpen,
+}
+
+impl ErrorKind {
+    /// Whether the error says the endpoint itself is unhealthy
+    fn trips_breaker(self) -> bool {
+        !matches!(self, ErrorKind::AccessDenied | ErrorKind::CircuitOpen)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Disabled,
+    Closed,
+    Open,
+    /// The open period is over and the next request decides whether the circuit closes
+    HalfOpen,
+}
+
+struct Sample {
+    at: Instant,
+    latency: Duration,
+    error: Option<ErrorKind>,
+}
+
+#[derive(Default)]
+struct Backend {
+    samples: VecDeque<Sample>,
+    last_success: Option<DateTime<Utc>>,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+impl Backend {
+    fn push(&mut self, sample: Sample) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|oldest| oldest.at.elapsed() >= WINDOW || self.samples.len() >= MAX_SAMPLES)
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    fn breaker_state(&self, breaker: Option<&CircuitBreakerConfig>) -> BreakerState {
+        match (breaker, self.opened_at) {
+            (None, _) => BreakerState::Disabled,
+            (Some(_), None) => BreakerState::Closed,
+            (Some(breaker), Some(opened_at)) if opened_at.elapsed() < Duration::from_secs(breaker.open_secs) => {
+                BreakerState::Open
+            }
+            (Some(_), Some(_)) => BreakerState::HalfOpen,
+        }
+    }
+}
+
+fn classify_status(status: u16) -> Option<ErrorKind> {
+    match status {
+        429 | 503 => Some(ErrorKind::Throttled),
+        401 | 403 => Some(ErrorKind::AccessDenied),
+        500..=599 => Some(ErrorKind::ServerError),
+        _ => None,
+    }
+}
+
+fn classify_error(error: &ConnectorError) -> ErrorKind {
+    if error.is_timeout() {
+        ErrorKind::Timeout
+    } else if error.is_io() {
+        ErrorKind::Io
+    } else {
+        ErrorKind::Connector
+    }
+}
+
+fn record(account_id: &str, breaker: Option<&CircuitBreakerConfig>, latency: Duration, error: Option<ErrorKind>) {
+    let mut backends = BACKENDS.lock().unwrap();
+    let backend = backends.entry(account_id.to_string()).or_default();
+    backend.push(Sample { at: Instant::now(), latency, error });
+    match error {
+        None => {
+            backend.last_success = Some(Utc::now());
+            backend.consecutive_failures = 0;
+            backend.opened_at = None;
+        }
+        Some(kind) if kind.trips_breaker() => {
+            backend.consecutive_failures += 1;
+            let Some(breaker) = breaker else {
+                return;
+            };
+            // A failed trial in the half-open state opens the circuit again
+            if backend.consecutive_failures >= breaker.failure_threshold
+                && backend.breaker_state(Some(breaker)) != BreakerState::Open
+            {
+                warn!(
+                    "Circuit opened for account {} after {} consecutive failures",
+                    account_id, backend.consecutive_failures
+                );
+                backend.opened_at = Some(Instant::now());
+            }
+        }
+        Some(_) => {}
+    }
+}
+
+/// Whether a request may be sent to the endpoint now
+fn admit(account_id: &str, breaker: Option<&CircuitBreakerConfig>) -> bool {
+    let mut backends = BACKENDS.lock().unwrap();
+    let backend = backends.entry(account_id.to_string()).or_default();
+    if backend.breaker_state(breaker) != BreakerState::Open {
+        return true;
+    }
+    backend.push(Sample { at: Instant::now(), latency: Duration::ZERO, error: Some(ErrorKind::CircuitOpen) });
+    false
+}
+
+/// Records the outcome and latency of every request to an account's endpoint
+#[derive(Debug, Clone)]
+struct MonitoredConnector {
+    account_id: Arc<str>,
+    breaker: Option<CircuitBreakerConfig>,
+    inner: SharedHttpConnector,
+}
+
+impl HttpConnector for MonitoredConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        if !admit(&self.account_id, self.breaker.as_ref()) {
+            let message = format!("circuit breaker for account {} is open", self.account_id);
+            return HttpConnectorFuture::new(async move { Err(ConnectorError::other(message.into(), None)) });
+        }
+        let started = Instant::now();
+        let response = self.inner.call(request);
+        let connector = self.clone();
+        HttpConnectorFuture::new(async move {
+            let response = response.await;
+            let error = match &response {
+                Ok(response) => classify_status(response.status().as_u16()),
+                Err(e) => Some(classify_error(e)),
+            };
+            record(&connector.account_id, connector.breaker.as_ref(), started.elapsed(), error);
+            response
+        })
+    }
+}
+
+pub fn wrap(account_id: &str, breaker: Option<CircuitBreakerConfig>, inner: SharedHttpConnector) -> SharedHttpConnector {
+    SharedHttpConnector::new(MonitoredConnector { account_id: account_id.into(), breaker, inner })
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BackendReport {
+    pub endpoint_url: String,
+    pub window_secs: u64,
+    pub requests: usize,
+    pub errors: BTreeMap<ErrorKind, u64>,
+    pub average_latency_ms: Option<f64>,
+    pub last_success: Option<DateTime<Utc>>,
+    pub circuit_breaker: BreakerState,
+    pub consecutive_failures: u32,
+    /// Adaptive limit on requests in flight, for accounts with `concurrency`
+    pub concurrency_limit: Option<usize>,
+    pub in_flight: Option<usize>,
+}
+
+/// Recent health of every configured account's endpoint
+pub fn report(config: &Config) -> BTreeMap<String, BackendReport> {
+    let backends = BACKENDS.lock().unwrap();
+    let empty = Backend::default();
+    config
+        .accounts
+        .current()
+        .iter()
+        .map(|(account_id, account)| {
+            let backend = backends.get(account_id).unwrap_or(&empty);
+            let recent: Vec<&Sample> = backend.samples.iter().filter(|s| s.at.elapsed() < WINDOW).collect();
+            let mut errors = BTreeMap::new();
+            for kind in recent.iter().filter_map(|s| s.error) {
+                *errors.entry(kind).or_insert(0) += 1;
+            }
+            // Refused requests never reached the endpoint and have no latency
+            let sent: Vec<Duration> = recent
+                .iter()
+                .filter(|s| s.error != Some(ErrorKind::CircuitOpen))
+                .map(|s| s.latency)
+                .collect();
+            let average_latency_ms = (!sent.is_empty())
+                .then(|| sent.iter().sum::<Duration>().as_secs_f64() * 1000.0 / sent.len() as f64);
+            let concurrency = concurrency::current(account_id);
+            let report = BackendReport {
+                endpoint_url: account.endpoint_url.clone(),
+                window_secs: WINDOW.as_secs(),
+                requests: sent.len(),
+                errors,
+                average_latency_ms,
+                last_success: backend.last_success,
+                circuit_breaker: backend.breaker_state(account.circuit_breaker.as_ref()),
+                consecutive_failures: backend.consecutive_failures,
+                concurrency_limit: concurrency.map(|(limit, _)| limit),
+                in_flight: concurrency.map(|(_, in_flight)| in_flight),
+            };
+            (account_id.clone(), report)
+        })
+        .collect()
+}
@@ -0,0 +1,155 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{stream, StreamExt};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::auth::AuthState;
+use crate::config::{BandwidthProfile, Config};
+
+/// Large bodies are released in slices this size so transfers flow evenly instead of in bursts
+const SLICE_SIZE: usize = 64 * 1024;
+
+lazy_static! {
+    static ref LIMITERS: Mutex<HashMap<String, Arc<Mutex<TokenBucket>>>> = Mutex::new(HashMap::new());
+}
+
+/// Bytes available to send, allowed to go negative so large slices wait proportionally
+struct TokenBucket {
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Takes `bytes` at `rate` bytes per second, with at most one second of burst, and returns how long to wait
+    fn take(&mut self, rate: u64, bytes: usize) -> Duration {
+        let now = Instant::now();
+        let rate = rate as f64;
+        self.available = (self.available + now.duration_since(self.updated).as_secs_f64() * rate).min(rate);
+        self.updated = now;
+        self.available -= bytes as f64;
+        if self.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.available / rate)
+        }
+    }
+}
+
+/// The limit that applies right now: the first profile whose window contains the current time
+fn current_rate(profiles: &[BandwidthProfile]) -> Option<u64> {
+    let now = Utc::now();
+    profiles
+        .iter()
+        .find(|profile| profile.window.as_ref().is_none_or(|window| window.contains(now)))
+        .map(|profile| profile.bytes_per_sec.max(1))
+}
+
+fn limiter(name: String) -> Arc<Mutex<TokenBucket>> {
+    LIMITERS
+        .lock()
+        .unwrap()
+        .entry(name)
+        .or_insert_with(|| {
+            Arc::new(Mutex::new(TokenBucket {
+                available: 0.0,
+                updated: Instant::now(),
+            }))
+        })
+        .clone()
+}
+
+/// User and bucket limiters that apply to a request, shared by all of that user's or bucket's transfers
+#[derive(Clone)]
+struct Schedules {
+    config: Arc<Config>,
+    limits: Vec<(Arc<Mutex<TokenBucket>>, Scope)>,
+}
+
+#[derive(Clone)]
+enum Scope {
+    User(String),
+    Bucket(String),
+}
+
+impl Schedules {
+    fn profiles(&self, scope: &Scope) -> &[BandwidthProfile] {
+        let bandwidth = &self.config.bandwidth;
+        let profiles = match scope {
+            Scope::User(user) => bandwidth.users.get(user),
+            Scope::Bucket(bucket) => bandwidth.buckets.get(bucket),
+        };
+        profiles.map(Vec::as_slice).unwrap_or_default()
+    }
+
+    /// Waits until every applicable limit allows `bytes` more
+    async fn acquire(&self, bytes: usize) {
+        let mut wait = Duration::ZERO;
+        for (limiter, scope) in &self.limits {
+            if let Some(rate) = current_rate(self.profiles(scope)) {
+                wait = wait.max(limiter.lock().unwrap().take(rate, bytes));
+            }
+        }
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn throttle(self, body: Body) -> Body {
+        let slices = body.into_data_stream().flat_map(|chunk| {
+            let slices: Vec<Result<Bytes, axum::Error>> = match chunk {
+                Ok(chunk) => (0..chunk.len())
+                    .step_by(SLICE_SIZE)
+                    .map(|start| Ok(chunk.slice(start..(start + SLICE_SIZE).min(chunk.len()))))
+                    .collect(),
+                Err(e) => vec![Err(e)],
+            };
+            stream::iter(slices)
+        });
+        Body::from_stream(slices.then(move |slice| {
+            let schedules = self.clone();
+            async move {
+                if let Ok(slice) = &slice {
+                    schedules.acquire(slice.len()).await;
+                }
+                slice
+            }
+        }))
+    }
+}
+
+/// Throttles request and response bodies to the bandwidth schedules of the user and the bucket
+pub async fn throttle(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let bandwidth = &config.bandwidth;
+    if bandwidth.users.is_empty() && bandwidth.buckets.is_empty() {
+        return next.run(request).await;
+    }
+
+    let mut limits = Vec::new();
+    if let Some(auth) = request.extensions().get::<AuthState>() {
+        if bandwidth.users.contains_key(&auth.username) {
+            limits.push((limiter(format!("user:{}", auth.username)), Scope::User(auth.username.clone())));
+        }
+    }
+    let bucket = request.uri().path().trim_start_matches('/').split('/').next().unwrap_or_default();
+    if bandwidth.buckets.contains_key(bucket) {
+        limits.push((limiter(format!("bucket:{}", bucket)), Scope::Bucket(bucket.to_string())));
+    }
+    if limits.is_empty() {
+        return next.run(request).await;
+    }
+
+    let schedules = Schedules { config: config.clone(), limits };
+    let (parts, body) = request.into_parts();
+    let request = Request::from_parts(parts, schedules.clone().throttle(body));
+    let (parts, body) = next.run(request).await.into_parts();
+    Response::from_parts(parts, schedules.throttle(body))
+}
@@ -0,0 +1,276 @@
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::Bytes;
+use http::{Method, Request};
+use http_body_util::{BodyExt, Full};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::{connect::HttpConnector, Client};
+use hyper_util::rt::TokioExecutor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::s3::S3Client;
+
+const USAGE: &str = "Usage: s3-proxy bench --bucket <bucket> [--op get|put|list] [--requests N] [--concurrency N]
+                       [--size BYTES] [--url URL] [--api-key KEY] [--direct] [--config PATH]
+
+Drives load against a running proxy at --url (default http://127.0.0.1:8080) with --api-key,
+or with --direct straight through the proxy's S3 client for the bucket's account in --config.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BenchOp {
+    Get,
+    Put,
+    List,
+}
+
+#[derive(Debug)]
+pub struct BenchOptions {
+    url: String,
+    api_key: Option<String>,
+    bucket: String,
+    op: BenchOp,
+    requests: usize,
+    concurrency: usize,
+    size: usize,
+    direct: bool,
+    config_path: String,
+}
+
+fn invalid(message: String) -> AppError {
+    AppError::InvalidRequest(format!("{}\n\n{}", message, USAGE))
+}
+
+pub fn parse_args(mut args: impl Iterator<Item = String>) -> Result<BenchOptions> {
+    let mut options = BenchOptions {
+        url: "http://127.0.0.1:8080".to_string(),
+        api_key: None,
+        bucket: String::new(),
+        op: BenchOp::Get,
+        requests: 1000,
+        concurrency: 16,
+        size: 1024,
+        direct: false,
+        config_path: "config.json".to_string(),
+    };
+
+    while let Some(arg) = args.next() {
+        if arg == "--direct" {
+            options.direct = true;
+            continue;
+        }
+        let value = args.next().ok_or_else(|| invalid(format!("Missing value for {}", arg)))?;
+        let number = || value.parse::<usize>().map_err(|_| invalid(format!("Invalid number for {}: {}", arg, value)));
+        match arg.as_str() {
+            "--url" => options.url = value.trim_end_matches('/').to_string(),
+            "--api-key" => options.api_key = Some(value),
+            "--bucket" => options.bucket = value,
+            "--requests" => options.requests = number()?,
+            "--concurrency" => options.concurrency = number()?.max(1),
+            "--size" => options.size = number()?,
+            "--config" => options.config_path = value,
+            "--op" => {
+                options.op = match value.as_str() {
+                    "get" => BenchOp::Get,
+                    "put" => BenchOp::Put,
+                    "list" => BenchOp::List,
+                    _ => return Err(invalid(format!("Unknown operation: {}", value))),
+                }
+            }
+            _ => return Err(invalid(format!("Unknown argument: {}", arg))),
+        }
+    }
+
+    if options.bucket.is_empty() {
+        return Err(invalid("--bucket is required".to_string()));
+    }
+    if !options.direct && options.api_key.is_none() {
+        return Err(invalid("--api-key is required unless --direct is given".to_string()));
+    }
+    Ok(options)
+}
+
+enum Target {
+    Proxy {
+        client: Box<Client<HttpsConnector<HttpConnector>, Full<Bytes>>>,
+        url: String,
+        api_key: String,
+    },
+    Direct(S3Client),
+}
+
+impl Target {
+    async fn new(options: &BenchOptions) -> Result<Self> {
+        if options.direct {
+            let config = Config::load(&options.config_path)?;
+            let account_id = config
+                .find_account_for_bucket(&options.bucket)
+                .ok_or_else(|| AppError::BucketNotFound(options.bucket.clone()))?;
+            let accounts = config.accounts.current();
+            let account = &accounts[&account_id];
+            return Ok(Target::Direct(S3Client::new(&account_id, account, None, &config.upstream_tracing).await?));
+        }
+
+        let https = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .map_err(|e| AppError::InternalError(format!("Failed to load native root certificates: {}", e)))?
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Target::Proxy {
+            client: Box::new(Client::builder(TokioExecutor::new()).build(https)),
+            url: options.url.clone(),
+            api_key: options.api_key.clone().unwrap_or_default(),
+        })
+    }
+
+    /// Runs one operation and returns the number of object bytes transferred
+    async fn execute(&self, op: BenchOp, bucket: &str, key: &str, payload: &Bytes) -> Result<usize> {
+        match self {
+            Target::Direct(client) => match op {
+                BenchOp::Get => {
+                    let body = client.get_object(bucket, key).await?;
+                    let bytes = body.collect().await.map_err(|e| AppError::InternalError(e.to_string()))?;
+                    Ok(bytes.into_bytes().len())
+                }
+                BenchOp::Put => {
+                    let body = ByteStream::from(payload.clone());
+                    client.put_object(bucket, key, body, None).await?;
+                    Ok(payload.len())
+                }
+                BenchOp::List => {
+                    client.list_objects(bucket, Some("bench-".to_string())).await?;
+                    Ok(0)
+                }
+            },
+            Target::Proxy { client, url, api_key } => {
+                let (method, uri, body) = match op {
+                    BenchOp::Get => (Method::GET, format!("{}/{}/{}", url, bucket, key), Bytes::new()),
+                    BenchOp::Put => (Method::PUT, format!("{}/{}/{}", url, bucket, key), payload.clone()),
+                    BenchOp::List => (Method::GET, format!("{}/{}?prefix=bench-", url, bucket), Bytes::new()),
+                };
+                let request = Request::builder()
+                    .method(method)
+                    .uri(uri)
+                    .header("x-api-key", api_key.as_str())
+                    .header(http::header::CONTENT_TYPE, "application/octet-stream")
+                    .body(Full::new(body))
+                    .map_err(|e| AppError::InvalidRequest(e.to_string()))?;
+
+                let response = client
+                    .request(request)
+                    .await
+                    .map_err(|e| AppError::InternalError(format!("Request failed: {}", e)))?;
+                let status = response.status();
+                let body = response
+                    .into_body()
+                    .collect()
+                    .await
+                    .map_err(|e| AppError::InternalError(e.to_string()))?
+                    .to_bytes();
+                if !status.is_success() {
+                    return Err(AppError::InternalError(format!("Proxy returned {}", status)));
+                }
+                Ok(match op {
+                    BenchOp::Get => body.len(),
+                    BenchOp::Put => payload.len(),
+                    BenchOp::List => 0,
+                })
+            }
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let index = ((sorted.len() as f64 * p).ceil() as usize).clamp(1, sorted.len()) - 1;
+    sorted[index]
+}
+
+/// Runs the benchmark and prints throughput and latency percentiles
+pub async fn run(options: BenchOptions) -> Result<()> {
+    let target = Arc::new(Target::new(&options).await?);
+    let payload = Bytes::from(vec![b'x'; options.size]);
+
+    // GETs all read one object written up front
+    if options.op == BenchOp::Get {
+        target.execute(BenchOp::Put, &options.bucket, "bench-object", &payload).await?;
+    }
+
+    println!(
+        "Running {} {:?} requests against {} with concurrency {}",
+        options.requests,
+        options.op,
+        if options.direct { "the S3 client directly" } else { options.url.as_str() },
+        options.concurrency
+    );
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+    let workers: Vec<_> = (0..options.concurrency.min(options.requests.max(1)))
+        .map(|_| {
+            let target = target.clone();
+            let next = next.clone();
+            let payload = payload.clone();
+            let bucket = options.bucket.clone();
+            let (op, requests) = (options.op, options.requests);
+            tokio::spawn(async move {
+                let mut latencies = Vec::new();
+                let mut errors = Vec::new();
+                let mut bytes = 0;
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= requests {
+                        break;
+                    }
+                    let key = match op {
+                        BenchOp::Put => format!("bench-{}", i),
+                        _ => "bench-object".to_string(),
+                    };
+                    let request_started = Instant::now();
+                    match target.execute(op, &bucket, &key, &payload).await {
+                        Ok(transferred) => {
+                            latencies.push(request_started.elapsed());
+                            bytes += transferred;
+                        }
+                        Err(e) => errors.push(e.to_string()),
+                    }
+                }
+                (latencies, errors, bytes)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::new();
+    let mut errors = Vec::new();
+    let mut bytes = 0;
+    for worker in workers {
+        let (worker_latencies, worker_errors, worker_bytes) = worker
+            .await
+            .map_err(|e| AppError::InternalError(format!("Benchmark worker failed: {}", e)))?;
+        latencies.extend(worker_latencies);
+        errors.extend(worker_errors);
+        bytes += worker_bytes;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+
+    let secs = elapsed.as_secs_f64();
+    println!("Completed:   {} ok, {} failed in {:.2}s", latencies.len(), errors.len(), secs);
+    println!("Throughput:  {:.1} req/s, {:.2} MiB/s", latencies.len() as f64 / secs, bytes as f64 / secs / 1_048_576.0);
+    println!(
+        "Latency:     p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.90),
+        percentile(&latencies, 0.99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    if let Some(error) = errors.first() {
+        println!("First error: {}", error);
+    }
+    Ok(())
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::server::AppState;
+
+/// Bucket to account routes changed at runtime, layered over the accounts in config.json
+#[derive(Default)]
+pub struct BucketRegistry {
+    // None marks a configured bucket that has since been removed
+    routes: RwLock<HashMap<String, Option<String>>>,
+}
+
+impl BucketRegistry {
+    pub fn register(&self, bucket: &str, account_id: &str) {
+        info!("Registering bucket {} on account {}", bucket, account_id);
+        self.routes.write().unwrap().insert(bucket.to_string(), Some(account_id.to_string()));
+    }
+
+    pub fn deregister(&self, bucket: &str) {
+        info!("Deregistering bucket {}", bucket);
+        self.routes.write().unwrap().insert(bucket.to_string(), None);
+    }
+
+    /// Drops a runtime route so config.json decides again
+    pub fn remove(&self, bucket: &str) {
+        self.routes.write().unwrap().remove(bucket);
+    }
+
+    /// Returns None when the registry has no opinion and config.json should decide
+    pub fn lookup(&self, bucket: &str) -> Option<Option<String>> {
+        self.routes.read().unwrap().get(bucket).cloned()
+    }
+
+    /// Buckets routed at runtime, discovered or created through the proxy
+    pub fn registered(&self) -> Vec<String> {
+        self.routes
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, account_id)| account_id.is_some())
+            .map(|(bucket, _)| bucket.clone())
+            .collect()
+    }
+}
+
+/// Periodically lists buckets on accounts with discovery enabled and routes new ones
+pub fn spawn_discovery(state: Arc<AppState>) {
+    for (account_id, account) in state.config.accounts.current().iter() {
+        let Some(discovery) = &account.discovery else {
+            continue;
+        };
+        info!("Enabling bucket discovery for account {} every {}s", account_id, discovery.interval_secs);
+
+        let state = state.clone();
+        let account_id = account_id.clone();
+        let interval = Duration::from_secs(discovery.interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut discovered = HashSet::new();
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                if let Err(e) = discover_account(&state, &account_id, &mut discovered).await {
+                    warn!("Bucket discovery failed for account {}: {}", account_id, e);
+                }
+            }
+        });
+    }
+}
+
+async fn discover_account(
+    state: &AppState,
+    account_id: &str,
+    discovered: &mut HashSet<String>,
+) -> crate::error::Result<()> {
+    let Some(client) = state.clients.read().unwrap().get(account_id).cloned() else {
+        return Ok(());
+    };
+    let accounts = state.config.accounts.current();
+    let Some(discovery) = accounts.get(account_id).and_then(|a| a.discovery.as_ref()) else {
+        return Ok(());
+    };
+
+    let upstream: HashSet<String> = client
+        .list_buckets()
+        .await?
+        .into_iter()
+        .filter(|bucket| discovery.accepts(bucket))
+        .collect();
+
+    for bucket in upstream.difference(discovered) {
+        // Buckets mapped explicitly in config.json or at runtime keep their route
+        if state.find_account_for_bucket(bucket).is_none() {
+            state.buckets.register(bucket, account_id);
+        }
+    }
+    for bucket in discovered.difference(&upstream) {
+        if state.buckets.lookup(bucket) == Some(Some(account_id.to_string())) {
+            info!("Bucket {} disappeared from account {}", bucket, account_id);
+            state.buckets.remove(bucket);
+        }
+    }
+
+    *discovered = upstream;
+    Ok(())
+}
@@ -0,0 +1,752 @@
+use bytes::{Bytes, BytesMut};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::config::{BucketCacheConfig, CacheConfig, CachePin, EvictionPolicy};
+use crate::error::{AppError, Result};
+use crate::metrics;
+use crate::routing;
+use crate::s3::{ObjectPart, S3Client};
+use crate::server::AppState;
+
+pub use s3_proxy_client::types::PinStatus;
+
+/// Objects are cached per account, as routing rules may send one bucket name to several accounts
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct ObjectKey {
+    account: String,
+    bucket: String,
+    key: String,
+}
+
+type ObjectId = Arc<ObjectKey>;
+
+impl ObjectKey {
+    fn new(account: &str, bucket: &str, key: &str) -> ObjectId {
+        Arc::new(ObjectKey { account: account.to_string(), bucket: bucket.to_string(), key: key.to_string() })
+    }
+}
+
+/// A single range from a Range header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// First byte and optional inclusive last byte
+    Bounded(u64, Option<u64>),
+    /// The last N bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Parses "bytes=a-b", "bytes=a-" and "bytes=-n"; multiple ranges yield None and are served whole
+    pub fn parse(header: &str) -> Option<Self> {
+        let spec = header.trim().strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+        if start.is_empty() {
+            return end.parse().ok().map(ByteRange::Suffix);
+        }
+        let start = start.parse().ok()?;
+        let end = if end.is_empty() { None } else { Some(end.parse().ok()?) };
+        if end.is_some_and(|end| end < start) {
+            return None;
+        }
+        Some(ByteRange::Bounded(start, end))
+    }
+
+    /// First and last byte within an object of `size` bytes, None when unsatisfiable
+    pub fn resolve(self, size: u64) -> Option<(u64, u64)> {
+        match self {
+            ByteRange::Bounded(start, _) if start >= size => None,
+            ByteRange::Bounded(start, end) => Some((start, end.map_or(size - 1, |end| end.min(size - 1)))),
+            ByteRange::Suffix(len) if len == 0 || size == 0 => None,
+            ByteRange::Suffix(len) => Some((size.saturating_sub(len), size - 1)),
+        }
+    }
+
+    fn header(self) -> String {
+        match self {
+            ByteRange::Bounded(start, Some(end)) => format!("bytes={}-{}", start, end),
+            ByteRange::Bounded(start, None) => format!("bytes={}-", start),
+            ByteRange::Suffix(len) => format!("bytes=-{}", len),
+        }
+    }
+}
+
+/// Eviction order of a block: policy priority, then last access
+type Rank = (u64, u64);
+
+struct Block {
+    data: Bytes,
+    rank: Rank,
+    hits: u64,
+    /// Pinned blocks are kept out of the eviction order and never evicted
+    pinned: bool,
+}
+
+struct CachedObject {
+    etag: String,
+    /// ETag stored by the proxy in the object's metadata, learned from the first GET
+    proxy_etag: Option<String>,
+    size: u64,
+    validated_at: Instant,
+    blocks: HashMap<u64, Block>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    objects: HashMap<ObjectId, CachedObject>,
+    /// Unpinned blocks, next to be evicted first
+    order: BTreeMap<Rank, (ObjectId, u64)>,
+    policy: EvictionPolicy,
+    /// GDSF aging: the priority of the last evicted block
+    inflation: f64,
+    used_bytes: u64,
+    tick: u64,
+    pins: HashSet<CachePin>,
+    pinned_bytes: u64,
+}
+
+impl CacheState {
+    fn next_rank(&mut self, hits: u64, object_size: u64) -> Rank {
+        self.tick += 1;
+        let priority = match self.policy {
+            EvictionPolicy::Lru => 0,
+            EvictionPolicy::Lfu => hits,
+            // Non-negative floats order like their bit patterns
+            EvictionPolicy::Gdsf => (self.inflation + hits as f64 / object_size.max(1) as f64).to_bits(),
+        };
+        (priority, self.tick)
+    }
+
+    fn is_pinned(&self, bucket: &str, key: &str) -> bool {
+        self.pins.iter().any(|pin| pin.bucket == bucket && key.starts_with(&pin.prefix))
+    }
+
+    fn forget_block(&mut self, block: &Block) {
+        if block.pinned {
+            self.pinned_bytes -= block.data.len() as u64;
+        } else {
+            self.order.remove(&block.rank);
+        }
+        self.used_bytes -= block.data.len() as u64;
+    }
+
+    fn remove_object(&mut self, id: &ObjectId) {
+        if let Some(object) = self.objects.remove(id) {
+            for block in object.blocks.values() {
+                self.forget_block(block);
+            }
+        }
+    }
+
+    /// Drops the block with the lowest rank, never the metadata of `keep`
+    fn evict_one(&mut self, keep: &ObjectId) -> bool {
+        let Some(((priority, _), (id, index))) = self.order.pop_first() else {
+            return false;
+        };
+        if self.policy == EvictionPolicy::Gdsf {
+            self.inflation = f64::from_bits(priority);
+        }
+        if let Some(object) = self.objects.get_mut(&id) {
+            if let Some(block) = object.blocks.remove(&index) {
+                self.used_bytes -= block.data.len() as u64;
+            }
+            if object.blocks.is_empty() && id != *keep {
+                self.objects.remove(&id);
+            }
+        }
+        true
+    }
+
+    /// Caches a block, evicting others to make room; when only pinned blocks are left to evict the
+    /// block is not cached and the caller serves it uncached
+    fn insert_block(&mut self, id: &ObjectId, index: u64, data: Bytes, max_bytes: u64, max_pinned_bytes: u64) {
+        let len = data.len() as u64;
+        if let Some(old) = self.objects.get_mut(id).and_then(|object| object.blocks.remove(&index)) {
+            self.forget_block(&old);
+        }
+        while self.used_bytes + len > max_bytes && self.evict_one(id) {}
+        if self.used_bytes + len > max_bytes {
+            debug!("Not caching block {} of {}/{}, the cache is full of pinned blocks", index, id.bucket, id.key);
+            return;
+        }
+
+        let Some(size) = self.objects.get(id).map(|object| object.size) else {
+            return;
+        };
+        // Pinned objects beyond the pinned budget are cached like any other
+        let pinned = self.is_pinned(&id.bucket, &id.key) && self.pinned_bytes + len <= max_pinned_bytes;
+        let rank = self.next_rank(1, size);
+        let block = Block { data, rank, hits: 1, pinned };
+        if let Some(object) = self.objects.get_mut(id) {
+            object.blocks.insert(index, block);
+        }
+        if pinned {
+            self.pinned_bytes += len;
+        } else {
+            self.order.insert(rank, (id.clone(), index));
+        }
+        self.used_bytes += len;
+    }
+
+    /// Moves the cached blocks of newly pinned objects out of the eviction order, within the pinned budget
+    fn pin_cached(&mut self, max_pinned_bytes: u64) {
+        let mut pinned_bytes = self.pinned_bytes;
+        let mut to_pin = Vec::new();
+        for (id, object) in &self.objects {
+            if !self.is_pinned(&id.bucket, &id.key) {
+                continue;
+            }
+            for block in object.blocks.values().filter(|block| !block.pinned) {
+                if pinned_bytes + block.data.len() as u64 > max_pinned_bytes {
+                    break;
+                }
+                pinned_bytes += block.data.len() as u64;
+                to_pin.push(block.rank);
+            }
+        }
+        for rank in to_pin {
+            if let Some((id, index)) = self.order.remove(&rank) {
+                if let Some(block) = self.objects.get_mut(&id).and_then(|object| object.blocks.get_mut(&index)) {
+                    block.pinned = true;
+                }
+            }
+        }
+        self.pinned_bytes = pinned_bytes;
+    }
+
+    /// Returns blocks of objects no longer covered by any pin to the eviction order
+    fn unpin_uncovered(&mut self) {
+        let ids: Vec<ObjectId> = self
+            .objects
+            .keys()
+            .filter(|id| !self.is_pinned(&id.bucket, &id.key))
+            .cloned()
+            .collect();
+        for id in ids {
+            let Some(object) = self.objects.get(&id) else {
+                continue;
+            };
+            let size = object.size;
+            let released: Vec<(u64, u64)> = object
+                .blocks
+                .iter()
+                .filter(|(_, block)| block.pinned)
+                .map(|(index, block)| (*index, block.hits))
+                .collect();
+            for (index, hits) in released {
+                let rank = self.next_rank(hits, size);
+                if let Some(block) = self.objects.get_mut(&id).and_then(|object| object.blocks.get_mut(&index)) {
+                    block.pinned = false;
+                    block.rank = rank;
+                    self.pinned_bytes -= block.data.len() as u64;
+                    self.order.insert(rank, (id.clone(), index));
+                }
+            }
+        }
+    }
+
+    fn touch_block(&mut self, id: &ObjectId, index: u64) -> Option<Bytes> {
+        let object = self.objects.get_mut(id)?;
+        let size = object.size;
+        let block = object.blocks.get_mut(&index)?;
+        block.hits += 1;
+        if block.pinned {
+            return Some(block.data.clone());
+        }
+        let (old, hits, data) = (block.rank, block.hits, block.data.clone());
+        let rank = self.next_rank(hits, size);
+        if let Some(block) = self.objects.get_mut(id).and_then(|object| object.blocks.get_mut(&index)) {
+            block.rank = rank;
+        }
+        self.order.remove(&old);
+        self.order.insert(rank, (id.clone(), index));
+        Some(data)
+    }
+}
+
+/// In-memory read cache holding objects as aligned blocks, evicted by the configured policy
+pub struct ObjectCache {
+    max_bytes: u64,
+    max_pinned_bytes: u64,
+    max_object_size: Option<u64>,
+    buckets: HashMap<String, BucketCacheConfig>,
+    block_size: u64,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+impl ObjectCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        info!(
+            "Caching up to {} bytes in {} byte blocks with {:?} eviction, revalidating after {}s",
+            config.max_bytes, config.block_size, config.eviction, config.ttl_secs
+        );
+        Self {
+            max_bytes: config.max_bytes,
+            max_pinned_bytes: config.max_pinned_bytes,
+            max_object_size: config.max_object_size,
+            buckets: config.buckets.clone(),
+            block_size: config.block_size.max(1),
+            ttl: Duration::from_secs(config.ttl_secs),
+            state: Mutex::new(CacheState {
+                pins: config.pins.iter().cloned().collect(),
+                policy: config.eviction,
+                ..CacheState::default()
+            }),
+        }
+    }
+
+    fn is_enabled_for(&self, bucket: &str) -> bool {
+        self.buckets.get(bucket).is_none_or(|b| b.enabled)
+    }
+
+    /// Largest object of `bucket` that is cached, never more than the whole cache
+    fn max_object_size_for(&self, bucket: &str) -> u64 {
+        self.buckets
+            .get(bucket)
+            .and_then(|b| b.max_object_size)
+            .or(self.max_object_size)
+            .map_or(self.max_bytes, |size| size.min(self.max_bytes))
+    }
+
+    pub fn pin(&self, pin: CachePin) {
+        let mut state = self.state.lock().unwrap();
+        state.pins.insert(pin);
+        state.pin_cached(self.max_pinned_bytes);
+    }
+
+    pub fn unpin(&self, pin: &CachePin) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let removed = state.pins.remove(pin);
+        state.unpin_uncovered();
+        removed
+    }
+
+    pub fn pin_status(&self) -> PinStatus {
+        let state = self.state.lock().unwrap();
+        let mut pins: Vec<CachePin> = state.pins.iter().cloned().collect();
+        pins.sort_by(|a, b| (&a.bucket, &a.prefix).cmp(&(&b.bucket, &b.prefix)));
+        PinStatus {
+            pins,
+            pinned_bytes: state.pinned_bytes,
+            max_pinned_bytes: self.max_pinned_bytes,
+        }
+    }
+
+    fn pinned_budget_left(&self) -> bool {
+        self.state.lock().unwrap().pinned_bytes < self.max_pinned_bytes
+    }
+
+    /// Drops an object from every account holding a bucket of that name, since writes and
+    /// invalidation messages do not say which account they went to
+    pub fn invalidate(&self, bucket: &str, key: &str) {
+        self.invalidate_stale(bucket, key, None);
+    }
+
+    /// Drops cached copies of an object unless they already are the version with `etag`
+    pub fn invalidate_stale(&self, bucket: &str, key: &str, etag: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<ObjectId> = state
+            .objects
+            .iter()
+            .filter(|(id, object)| id.bucket == bucket && id.key == key && (etag.is_none() || Some(object.etag.as_str()) != etag))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in ids {
+            state.remove_object(&id);
+        }
+        metrics::set_cache_bytes(state.used_bytes);
+    }
+
+    /// Drops every cached object, pins stay in place
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<ObjectId> = state.objects.keys().cloned().collect();
+        for id in ids {
+            state.remove_object(&id);
+        }
+        metrics::set_cache_bytes(state.used_bytes);
+    }
+
+    pub fn invalidate_bucket(&self, bucket: &str) {
+        let mut state = self.state.lock().unwrap();
+        let ids: Vec<ObjectId> = state.objects.keys().filter(|id| id.bucket == bucket).cloned().collect();
+        for id in ids {
+            state.remove_object(&id);
+        }
+        metrics::set_cache_bytes(state.used_bytes);
+    }
+
+    /// Whether the block holding `offset` of the object is cached from `account`
+    pub fn contains(&self, account: &str, bucket: &str, key: &str, offset: u64) -> bool {
+        let state = self.state.lock().unwrap();
+        let id = ObjectKey::new(account, bucket, key);
+        state
+            .objects
+            .get(&id)
+            .is_some_and(|object| object.blocks.contains_key(&(offset / self.block_size)))
+    }
+
+    /// Serves a GET from cached blocks, fetching only the blocks the range touches on a miss
+    pub async fn read(
+        &self,
+        client: &S3Client,
+        bucket: &str,
+        key: &str,
+        range: Option<ByteRange>,
+    ) -> Result<ObjectPart> {
+        if !self.is_enabled_for(bucket) {
+            return client.get_object_range(bucket, key, range.map(ByteRange::header), None).await;
+        }
+        let id = ObjectKey::new(client.account_id(), bucket, key);
+        match self.try_read(client, &id, range).await {
+            // The object changed between blocks, start over against the new version
+            Err(AppError::PreconditionFailed(_)) => {
+                debug!("{}/{} changed while filling the cache, retrying", bucket, key);
+                self.state.lock().unwrap().remove_object(&id);
+                self.try_read(client, &id, range).await
+            }
+            result => result,
+        }
+    }
+
+    async fn try_read(&self, client: &S3Client, id: &ObjectId, range: Option<ByteRange>) -> Result<ObjectPart> {
+        let (bucket, key) = (id.bucket.as_str(), id.key.as_str());
+
+        let (fresh, known) = {
+            let state = self.state.lock().unwrap();
+            match state.objects.get(id) {
+                Some(object) => (
+                    (object.validated_at.elapsed() < self.ttl).then(|| (object.etag.clone(), object.size)),
+                    true,
+                ),
+                None => (None, false),
+            }
+        };
+        let (etag, size) = match fresh {
+            Some(metadata) => metadata,
+            // Whole-object misses are filled with a single GET
+            None if range.is_none() && !known => return self.fill_whole(client, id).await,
+            None => match self.revalidate(client, id).await? {
+                Some(metadata) => metadata,
+                None => return client.get_object_range(bucket, key, range.map(ByteRange::header), None).await,
+            },
+        };
+
+        // Only the metadata of oversized objects is kept, so repeated reads skip the HEAD
+        if size > self.max_object_size_for(bucket) {
+            return client.get_object_range(bucket, key, range.map(ByteRange::header), Some(etag)).await;
+        }
+
+        let (start, end) = match range {
+            Some(range) => range
+                .resolve(size)
+                .ok_or_else(|| AppError::RangeNotSatisfiable(format!("{}/{} is {} bytes", bucket, key, size)))?,
+            None if size == 0 => {
+                let proxy_etag = self.state.lock().unwrap().objects.get(id).and_then(|object| object.proxy_etag.clone());
+                return Ok(ObjectPart { body: Bytes::new(), etag: Some(etag), content_range: None, total_size: 0, proxy_etag });
+            }
+            None => (0, size - 1),
+        };
+
+        let (first, last) = (start / self.block_size, end / self.block_size);
+        let mut blocks = BTreeMap::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            for index in first..=last {
+                if let Some(data) = state.touch_block(id, index) {
+                    blocks.insert(index, data);
+                }
+            }
+        }
+        let hit = blocks.len() as u64 == last - first + 1;
+        metrics::record_cache_lookup(bucket, hit);
+
+        // Fetch each run of missing blocks with one ranged GET pinned to the cached ETag
+        let mut index = first;
+        while index <= last {
+            if blocks.contains_key(&index) {
+                index += 1;
+                continue;
+            }
+            let run_start = index;
+            while index <= last && !blocks.contains_key(&index) {
+                index += 1;
+            }
+            let byte_start = run_start * self.block_size;
+            let byte_end = (index * self.block_size).min(size) - 1;
+            let (part, _fill) = client
+                .get_object_reserved(
+                    bucket,
+                    key,
+                    Some(format!("bytes={}-{}", byte_start, byte_end)),
+                    Some(etag.clone()),
+                )
+                .await?;
+            if part.body.len() as u64 != byte_end - byte_start + 1 {
+                return Err(AppError::PreconditionFailed(format!("{}/{} changed size", bucket, key)));
+            }
+
+            let mut state = self.state.lock().unwrap();
+            if let Some(object) = state.objects.get_mut(id) {
+                object.proxy_etag = part.proxy_etag.clone();
+            }
+            for (offset, chunk) in (run_start..index).zip(part.body.chunks(self.block_size as usize)) {
+                let data = part.body.slice_ref(chunk);
+                state.insert_block(id, offset, data.clone(), self.max_bytes, self.max_pinned_bytes);
+                blocks.insert(offset, data);
+            }
+            metrics::set_cache_bytes(state.used_bytes);
+        }
+
+        let mut parts = blocks.iter().map(|(index, data)| {
+            let block_start = index * self.block_size;
+            let from = start.max(block_start) - block_start;
+            let to = end.min(block_start + data.len() as u64 - 1) - block_start;
+            data.slice(from as usize..=to as usize)
+        });
+        // Ranges within one block, the common case for small objects, share the cached bytes
+        let body = match (parts.next(), blocks.len()) {
+            (Some(part), 1) => part,
+            (first, _) => {
+                let mut body = BytesMut::with_capacity((end - start + 1) as usize);
+                for part in first.into_iter().chain(parts) {
+                    body.extend_from_slice(&part);
+                }
+                body.freeze()
+            }
+        };
+
+        let proxy_etag = self.state.lock().unwrap().objects.get(id).and_then(|object| object.proxy_etag.clone());
+        Ok(ObjectPart {
+            body,
+            etag: Some(etag),
+            content_range: range.map(|_| format!("bytes {}-{}/{}", start, end, size)),
+            total_size: size,
+            proxy_etag,
+        })
+    }
+
+    /// Checks the object's ETag upstream, dropping cached blocks of an older version
+    async fn revalidate(&self, client: &S3Client, id: &ObjectId) -> Result<Option<(String, u64)>> {
+        let (etag, size) = client.head_object(&id.bucket, &id.key).await?;
+        // Without an ETag there is no way to tell versions apart
+        let Some(etag) = etag else {
+            return Ok(None);
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let unchanged = state.objects.get(id).is_some_and(|object| object.etag == etag && object.size == size);
+        if !unchanged {
+            state.remove_object(id);
+            state.objects.insert(id.clone(), CachedObject {
+                etag: etag.clone(),
+                proxy_etag: None,
+                size,
+                validated_at: Instant::now(),
+                blocks: HashMap::new(),
+            });
+        } else if let Some(object) = state.objects.get_mut(id) {
+            object.validated_at = Instant::now();
+        }
+        metrics::set_cache_bytes(state.used_bytes);
+        Ok(Some((etag, size)))
+    }
+
+    async fn fill_whole(&self, client: &S3Client, id: &ObjectId) -> Result<ObjectPart> {
+        // Charged while it is read and until its blocks are stored
+        let (part, _fill) = client.get_object_reserved(&id.bucket, &id.key, None, None).await?;
+        metrics::record_cache_lookup(&id.bucket, false);
+
+        let Some(etag) = part.etag.clone() else {
+            return Ok(part);
+        };
+        if part.total_size > self.max_object_size_for(&id.bucket) {
+            return Ok(part);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.remove_object(id);
+        state.objects.insert(id.clone(), CachedObject {
+            etag,
+            proxy_etag: part.proxy_etag.clone(),
+            size: part.total_size,
+            validated_at: Instant::now(),
+            blocks: HashMap::new(),
+        });
+        for (index, chunk) in part.body.chunks(self.block_size as usize).enumerate() {
+            state.insert_block(id, index as u64, part.body.slice_ref(chunk), self.max_bytes, self.max_pinned_bytes);
+        }
+        metrics::set_cache_bytes(state.used_bytes);
+        Ok(part)
+    }
+}
+
+/// Loads the objects under a pin into the cache in the background until the pinned budget is used
+pub fn spawn_prefetch(state: Arc<AppState>, pin: CachePin) {
+    tokio::spawn(routing::scope(routing::current(), async move {
+        let Some(cache) = &state.cache else {
+            return;
+        };
+        let client = match state.get_account_and_client(&pin.bucket) {
+            Ok((_, client)) => client,
+            Err(e) => {
+                warn!("Cannot prefetch pinned {}/{}: {}", pin.bucket, pin.prefix, e);
+                return;
+            }
+        };
+        let objects = match client.list_objects(&pin.bucket, Some(pin.prefix.clone())).await {
+            Ok(objects) => objects,
+            Err(e) => {
+                warn!("Cannot list pinned {}/{}: {}", pin.bucket, pin.prefix, e);
+                return;
+            }
+        };
+
+        let mut loaded = 0;
+        for key in objects.iter().filter_map(|object| object.key()) {
+            if !cache.pinned_budget_left() {
+                warn!("Pinned cache budget exhausted while prefetching {}/{}", pin.bucket, pin.prefix);
+                break;
+            }
+            match cache.read(&client, &pin.bucket, key, None).await {
+                Ok(_) => loaded += 1,
+                Err(e) => warn!("Failed to prefetch {}/{}: {}", pin.bucket, key, e),
+            }
+        }
+        info!("Prefetched {} pinned objects under {}/{}", loaded, pin.bucket, pin.prefix);
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use std::collections::HashMap;
+    use std::time::Instant;
+
+    use super::{CacheState, CachedObject, ObjectCache, ObjectId, ObjectKey};
+    use crate::config::{CachePin, EvictionPolicy};
+
+    fn object(state: &mut CacheState, key: &str) -> ObjectId {
+        object_of(state, "main", key)
+    }
+
+    fn object_of(state: &mut CacheState, account: &str, key: &str) -> ObjectId {
+        let id = ObjectKey::new(account, "b", key);
+        state.objects.insert(id.clone(), CachedObject {
+            etag: "etag".to_string(),
+            proxy_etag: None,
+            size: 64,
+            validated_at: Instant::now(),
+            blocks: HashMap::new(),
+        });
+        id
+    }
+
+    #[test]
+    fn pinned_blocks_do_not_grow_the_cache_past_max_bytes() {
+        let mut state = CacheState::default();
+        state.pins.insert(CachePin { bucket: "b".to_string(), prefix: "pinned/".to_string() });
+        let pinned = object(&mut state, "pinned/a");
+        for index in 0..4 {
+            state.insert_block(&pinned, index, Bytes::from_static(b"0123"), 16, 64);
+        }
+        assert_eq!(state.pinned_bytes, 16);
+
+        let other = object(&mut state, "other");
+        state.insert_block(&other, 0, Bytes::from_static(b"0123"), 16, 64);
+        state.insert_block(&pinned, 4, Bytes::from_static(b"0123"), 16, 64);
+        assert_eq!(state.used_bytes, 16);
+        assert!(state.objects[&other].blocks.is_empty());
+        assert!(!state.objects[&pinned].blocks.contains_key(&4));
+    }
+
+    #[test]
+    fn accounts_sharing_a_bucket_name_are_cached_apart() {
+        let cache = ObjectCache::new(&serde_json::from_value(serde_json::json!({ "max_bytes": 64, "block_size": 4 })).unwrap());
+        {
+            let mut state = cache.state.lock().unwrap();
+            let eu = object_of(&mut state, "eu", "a");
+            state.insert_block(&eu, 0, Bytes::from_static(b"eu!!"), 64, 0);
+            let us = object_of(&mut state, "us", "a");
+            state.insert_block(&us, 0, Bytes::from_static(b"us!!"), 64, 0);
+            assert_eq!(state.touch_block(&eu, 0).as_deref(), Some(&b"eu!!"[..]));
+            assert_eq!(state.touch_block(&us, 0).as_deref(), Some(&b"us!!"[..]));
+        }
+        assert!(cache.contains("eu", "b", "a", 0));
+        assert!(!cache.contains("ap", "b", "a", 0));
+        // Writes do not say which account they went to, so every copy goes
+        cache.invalidate("b", "a");
+        assert!(!cache.contains("eu", "b", "a", 0));
+        assert!(!cache.contains("us", "b", "a", 0));
+        assert_eq!(cache.state.lock().unwrap().used_bytes, 0);
+    }
+
+    const BLOCK: Bytes = Bytes::from_static(b"0123");
+
+    #[test]
+    fn lru_evicts_the_block_used_longest_ago() {
+        let mut state = CacheState::default();
+        let (a, b, c) = (object(&mut state, "a"), object(&mut state, "b"), object(&mut state, "c"));
+        state.insert_block(&a, 0, BLOCK, 8, 0);
+        state.insert_block(&b, 0, BLOCK, 8, 0);
+        state.touch_block(&a, 0);
+        state.insert_block(&c, 0, BLOCK, 8, 0);
+        assert_eq!(state.used_bytes, 8);
+        assert!(state.objects[&a].blocks.contains_key(&0));
+        assert!(!state.objects.contains_key(&b), "an object without blocks is forgotten");
+        assert!(state.objects[&c].blocks.contains_key(&0));
+    }
+
+    #[test]
+    fn lfu_evicts_the_block_used_least_often() {
+        let mut state = CacheState { policy: EvictionPolicy::Lfu, ..CacheState::default() };
+        let (a, b, c) = (object(&mut state, "a"), object(&mut state, "b"), object(&mut state, "c"));
+        state.insert_block(&a, 0, BLOCK, 8, 0);
+        state.touch_block(&a, 0);
+        state.touch_block(&a, 0);
+        state.insert_block(&b, 0, BLOCK, 8, 0);
+        state.insert_block(&c, 0, BLOCK, 8, 0);
+        assert!(state.objects[&a].blocks.contains_key(&0), "the oldest block is kept for its hits");
+        assert!(!state.objects.contains_key(&b));
+    }
+
+    #[test]
+    fn pins_hold_cached_blocks_only_within_the_pinned_budget() {
+        let mut state = CacheState::default();
+        let pinned = object(&mut state, "pinned/a");
+        for index in 0..3 {
+            state.insert_block(&pinned, index, BLOCK, 64, 8);
+        }
+        assert_eq!(state.pinned_bytes, 0);
+
+        let pin = CachePin { bucket: "b".to_string(), prefix: "pinned/".to_string() };
+        state.pins.insert(pin.clone());
+        state.pin_cached(8);
+        assert_eq!(state.pinned_bytes, 8);
+        assert_eq!(state.order.len(), 1, "the block over the pinned budget stays evictable");
+
+        // Under pressure only the unpinned block goes
+        let other = object(&mut state, "other");
+        for index in 0..4 {
+            state.insert_block(&other, index, BLOCK, 12, 8);
+        }
+        assert_eq!(state.objects[&pinned].blocks.len(), 2);
+        assert_eq!(state.used_bytes, 12);
+
+        state.pins.remove(&pin);
+        state.unpin_uncovered();
+        assert_eq!(state.pinned_bytes, 0);
+        assert_eq!(state.order.len(), 3);
+        for index in 4..7 {
+            state.insert_block(&other, index, BLOCK, 12, 8);
+        }
+        assert!(state.objects.get(&pinned).is_none_or(|object| object.blocks.is_empty()), "unpinned blocks are evicted again");
+    }
+}
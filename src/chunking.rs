@@ -0,0 +1,194 @@
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::{info, warn};
+
+use crate::cache::ByteRange;
+use crate::config::{ChunkingConfig, Config};
+use crate::error::{AppError, Result};
+use crate::s3::{ObjectPart, S3Client};
+
+/// Metadata marking an object whose body is a chunk manifest
+const MANIFEST_METADATA: &str = "chunk-manifest";
+
+/// Chunks uploaded or fetched at the same time for one object
+const CONCURRENCY: usize = 4;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Chunk {
+    key: String,
+    size: u64,
+}
+
+/// Stored at the object's key in place of its content
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkManifest {
+    size: u64,
+    etag: String,
+    chunks: Vec<Chunk>,
+}
+
+/// Chunks belong to the manifests that list them, so clients may not write them directly
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    match config.chunking.get(bucket) {
+        Some(chunking) if key.starts_with(&chunking.prefix) => Err(AppError::InvalidRequest(format!(
+            "{} is reserved for chunks of large objects",
+            chunking.prefix
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether an upload of `size` bytes to `bucket` is split into chunks
+pub fn accepts(config: &Config, bucket: &str, size: usize) -> bool {
+    config.chunking.get(bucket).is_some_and(|chunking| size as u64 > chunking.threshold)
+}
+
+/// The chunk manifest stored at `key`, None for plain or missing objects
+pub async fn manifest(client: &S3Client, config: &Config, bucket: &str, key: &str) -> Result<Option<ChunkManifest>> {
+    if !config.chunking.contains_key(bucket) {
+        return Ok(None);
+    }
+    let metadata = match client.object_metadata(bucket, key).await {
+        Ok(metadata) => metadata,
+        Err(AppError::ObjectNotFound(_, _)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if !metadata.contains_key(MANIFEST_METADATA) {
+        return Ok(None);
+    }
+    let part = client.get_object_range(bucket, key, None, None).await?;
+    serde_json::from_slice(&part.body)
+        .map(Some)
+        .map_err(|e| AppError::InternalError(format!("Invalid chunk manifest {}/{}: {}", bucket, key, e)))
+}
+
+async fn delete_chunks(client: &S3Client, bucket: &str, chunks: &[Chunk]) {
+    for chunk in chunks {
+        if let Err(e) = client.delete_object(bucket, &chunk.key).await {
+            warn!("Deleting chunk {}/{} failed, it is left behind: {}", bucket, chunk.key, e);
+        }
+    }
+}
+
+/// Uploads the body as chunks, then the manifest that makes them visible at `key`
+pub async fn put(
+    client: &S3Client,
+    config: &ChunkingConfig,
+    bucket: &str,
+    key: &str,
+    body: Bytes,
+    content_type: Option<String>,
+) -> Result<Option<String>> {
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    let chunk_size = config.chunk_size.max(1) as usize;
+    let chunks: Vec<Chunk> = (0..body.len().div_ceil(chunk_size))
+        .map(|index| Chunk {
+            key: format!("{}{}/{:06}", config.prefix, id, index),
+            size: body.len().min((index + 1) * chunk_size) as u64 - (index * chunk_size) as u64,
+        })
+        .collect();
+
+    let keys: Vec<String> = chunks.iter().map(|chunk| chunk.key.clone()).collect();
+    let uploads = stream::iter(keys.into_iter().enumerate())
+        .map(|(index, chunk)| {
+            let data = body.slice(index * chunk_size..body.len().min((index + 1) * chunk_size));
+            async move { client.put_object(bucket, &chunk, data.into(), None).await }
+        })
+        .buffer_unordered(CONCURRENCY)
+        .try_collect::<Vec<_>>()
+        .await;
+    if let Err(e) = uploads {
+        delete_chunks(client, bucket, &chunks).await;
+        return Err(e);
+    }
+
+    let manifest = ChunkManifest {
+        size: body.len() as u64,
+        etag: format!("\"{}-{}\"", &hex::encode(Sha256::digest(&body))[..32], chunks.len()),
+        chunks,
+    };
+    let data = serde_json::to_vec(&manifest).map_err(|e| AppError::InternalError(e.to_string()))?;
+    let metadata = HashMap::from([(MANIFEST_METADATA.to_string(), "1".to_string())]);
+    if let Err(e) = client.put_object_with_metadata(bucket, key, data.into(), metadata, content_type).await {
+        delete_chunks(client, bucket, &manifest.chunks).await;
+        return Err(e);
+    }
+    info!("Stored {}/{} as {} chunks", bucket, key, manifest.chunks.len());
+    Ok(Some(manifest.etag))
+}
+
+/// Permanently deletes an object, and its chunks if it is chunked
+pub async fn delete(client: &S3Client, config: &Config, bucket: &str, key: &str) -> Result<()> {
+    let manifest = manifest(client, config, bucket, key).await?;
+    client.delete_object(bucket, key).await?;
+    if let Some(manifest) = manifest {
+        delete_chunks(client, bucket, &manifest.chunks).await;
+    }
+    Ok(())
+}
+
+/// Drops the chunks of a manifest that has since been overwritten
+pub async fn release(client: &S3Client, bucket: &str, replaced: Option<ChunkManifest>) {
+    if let Some(manifest) = replaced {
+        delete_chunks(client, bucket, &manifest.chunks).await;
+    }
+}
+
+/// Reassembles a chunked object, or the requested range of it, from the chunks it covers
+pub async fn read(
+    client: &S3Client,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+    range: Option<ByteRange>,
+) -> Result<Option<ObjectPart>> {
+    let Some(manifest) = manifest(client, config, bucket, key).await? else {
+        return Ok(None);
+    };
+    if manifest.size == 0 {
+        return Ok(Some(ObjectPart { body: Bytes::new(), etag: Some(manifest.etag), content_range: None, total_size: 0, proxy_etag: None }));
+    }
+
+    let (first, last, content_range) = match range {
+        Some(range) => {
+            let (first, last) = range.resolve(manifest.size).ok_or_else(|| {
+                AppError::RangeNotSatisfiable(format!("{}/{} does not contain the requested range", bucket, key))
+            })?;
+            (first, last, Some(format!("bytes {}-{}/{}", first, last, manifest.size)))
+        }
+        None => (0, manifest.size - 1, None),
+    };
+
+    // Byte ranges within each chunk that overlaps [first, last]
+    let mut reads = Vec::new();
+    let mut start = 0;
+    for chunk in &manifest.chunks {
+        let end = start + chunk.size;
+        if end > first && start <= last {
+            let from = first.max(start) - start;
+            let to = last.min(end - 1) - start;
+            reads.push((chunk.key.clone(), format!("bytes={}-{}", from, to)));
+        }
+        start = end;
+    }
+    let parts: Vec<ObjectPart> = stream::iter(reads)
+        .map(|(chunk, range)| async move { client.get_object_range(bucket, &chunk, Some(range), None).await })
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let mut body = BytesMut::with_capacity((last - first + 1) as usize);
+    for part in parts {
+        body.extend_from_slice(&part.body);
+    }
+    Ok(Some(ObjectPart {
+        body: body.freeze(),
+        etag: Some(manifest.etag),
+        content_range,
+        total_size: manifest.size,
+        proxy_etag: None,
+    }))
+}
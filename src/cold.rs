@@ -0,0 +1,97 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+use std::sync::Arc;
+
+use crate::error::{AppError, Result};
+use crate::server::AppState;
+use crate::union;
+
+const DEFAULT_DAYS: u64 = 90;
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10_000;
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ColdQuery {
+    pub bucket: String,
+    /// Objects neither read nor written for this many days are cold, 90 by default
+    pub days: Option<u64>,
+    /// Cold objects listed at most, coldest first
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ColdObject {
+    pub key: String,
+    pub size: u64,
+    pub last_modified: Option<DateTime<Utc>>,
+    /// Last successful read or write through the proxy, if the audit log still has one
+    pub last_accessed: Option<DateTime<Utc>>,
+}
+
+/// Objects of a bucket not accessed through the proxy lately, candidates for archival
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ColdReport {
+    pub bucket: String,
+    pub days: u64,
+    /// Oldest access the audit log still knows of; objects last used before it count as never accessed
+    pub tracked_since: Option<DateTime<Utc>>,
+    pub objects: u64,
+    pub total_bytes: u64,
+    pub cold_objects: u64,
+    pub cold_bytes: u64,
+    /// Coldest first, at most `limit`
+    pub cold: Vec<ColdObject>,
+}
+
+/// Joins a listing of the bucket with the last access of each key in the audit log
+pub async fn report(state: &Arc<AppState>, query: ColdQuery) -> Result<ColdReport> {
+    if state.audit.is_none() {
+        return Err(AppError::InvalidRequest("Cold data reports need the audit log".to_string()));
+    }
+    let days = query.days.unwrap_or(DEFAULT_DAYS);
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let (objects, _) = union::list(state, &query.bucket, "", false).await?;
+
+    // SQLite blocks, so the lookup runs off the async workers
+    let lookup = state.clone();
+    let bucket = query.bucket.clone();
+    let (accesses, tracked_since) = tokio::task::spawn_blocking(move || {
+        let audit = lookup.audit.as_ref().expect("checked above");
+        Ok::<_, AppError>((audit.last_access(&bucket)?, audit.oldest()?))
+    })
+    .await
+    .map_err(|e| AppError::InternalError(format!("Audit lookup failed: {}", e)))??;
+
+    let cutoff = Utc::now() - chrono::Duration::days(days as i64);
+    let mut report = ColdReport {
+        bucket: query.bucket,
+        days,
+        tracked_since,
+        objects: objects.len() as u64,
+        total_bytes: 0,
+        cold_objects: 0,
+        cold_bytes: 0,
+        cold: Vec::new(),
+    };
+    for object in &objects {
+        let key = object.key().unwrap_or_default();
+        let size = object.size().unwrap_or_default().max(0) as u64;
+        let last_modified = object
+            .last_modified()
+            .and_then(|time| DateTime::from_timestamp(time.secs(), time.subsec_nanos()));
+        let last_accessed = accesses.get(key).copied();
+        report.total_bytes += size;
+        // Writes through other clients still show in LastModified
+        if last_accessed.max(last_modified).is_some_and(|last| last >= cutoff) {
+            continue;
+        }
+        report.cold_objects += 1;
+        report.cold_bytes += size;
+        report.cold.push(ColdObject { key: key.to_string(), size, last_modified, last_accessed });
+    }
+    report.cold.sort_by_key(|object| object.last_accessed.max(object.last_modified));
+    report.cold.truncate(limit);
+    Ok(report)
+}
@@ -0,0 +1,360 @@
+use axum::body::Body;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use futures::channel::mpsc;
+use futures::SinkExt;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::auth::AuthState;
+use crate::config::matching_bucket_grant;
+use crate::consistency;
+use crate::chunking;
+use crate::error::{AppError, Result};
+use crate::routing;
+use crate::server::AppState;
+use crate::trash;
+
+/// How long a plan can be confirmed
+const TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+
+lazy_static! {
+    static ref PENDING: Mutex<HashMap<String, PendingRequest>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ComplianceAction {
+    Export,
+    Erase,
+}
+
+/// Objects a data subject request applies to; a prefix, a tag or both must be given
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct Selector {
+    #[serde(default)]
+    pub prefix: Option<String>,
+    #[serde(default)]
+    pub tag: Option<TagSelector>,
+    /// Limit to these buckets, by default every bucket the admin may access
+    #[serde(default)]
+    pub buckets: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct TagSelector {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct MatchedObject {
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+}
+
+/// First step of a request: what would be affected and the token that confirms it
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Plan {
+    pub token: String,
+    pub action: ComplianceAction,
+    pub selector: Selector,
+    pub objects: Vec<MatchedObject>,
+    pub total_bytes: u64,
+    /// Buckets that could not be searched, so the request does not cover them
+    pub skipped_buckets: Vec<SkippedBucket>,
+    pub expires_in_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct SkippedBucket {
+    pub bucket: String,
+    pub error: String,
+}
+
+struct PendingRequest {
+    action: ComplianceAction,
+    selector: Selector,
+    requested_by: String,
+    requested_at: DateTime<Utc>,
+    objects: Vec<MatchedObject>,
+    skipped_buckets: Vec<SkippedBucket>,
+    created: Instant,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ObjectOutcome {
+    pub bucket: String,
+    pub key: String,
+    pub size: u64,
+    pub succeeded: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditReport {
+    pub request_id: String,
+    pub action: ComplianceAction,
+    pub selector: Selector,
+    pub requested_by: String,
+    pub requested_at: DateTime<Utc>,
+    pub confirmed_by: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped_buckets: Vec<SkippedBucket>,
+    pub objects: Vec<ObjectOutcome>,
+}
+
+impl AuditReport {
+    fn new(request_id: String, pending: &PendingRequest, confirmed_by: &str, started_at: DateTime<Utc>) -> Self {
+        Self {
+            request_id,
+            action: pending.action,
+            selector: pending.selector.clone(),
+            requested_by: pending.requested_by.clone(),
+            requested_at: pending.requested_at,
+            confirmed_by: confirmed_by.to_string(),
+            started_at,
+            finished_at: started_at,
+            succeeded: 0,
+            failed: 0,
+            skipped_buckets: pending.skipped_buckets.clone(),
+            objects: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, object: &MatchedObject, result: Result<()>) {
+        let error = result.err().map(|e| e.to_string());
+        if error.is_some() {
+            self.failed += 1;
+        } else {
+            self.succeeded += 1;
+        }
+        self.objects.push(ObjectOutcome {
+            bucket: object.bucket.clone(),
+            key: object.key.clone(),
+            size: object.size,
+            succeeded: error.is_none(),
+            error,
+        });
+    }
+
+    /// Audit reports are kept in the log as well as returned to the caller
+    fn finish(&mut self) {
+        self.finished_at = Utc::now();
+        info!(
+            "Compliance {:?} {} confirmed by {}: {} objects succeeded, {} failed",
+            self.action, self.request_id, self.confirmed_by, self.succeeded, self.failed
+        );
+        match serde_json::to_string(self) {
+            Ok(report) => info!("Compliance audit report: {}", report),
+            Err(e) => warn!("Failed to encode compliance audit report {}: {}", self.request_id, e),
+        }
+    }
+}
+
+/// Every routed bucket the admin may access, narrowed to the selector's buckets if given
+fn target_buckets(state: &AppState, auth: &AuthState, selector: &Selector) -> Result<Vec<String>> {
+    let mut buckets: BTreeSet<String> = state
+        .config
+        .accounts
+        .current()
+        .values()
+        .flat_map(|account| account.buckets.iter().cloned())
+        .chain(state.buckets.registered())
+        .filter(|bucket| state.find_account_for_bucket(bucket).is_some())
+        .filter(|bucket| matching_bucket_grant(&auth.allowed_buckets, bucket, auth.strict).is_some())
+        .collect();
+    if !selector.buckets.is_empty() {
+        if let Some(bucket) = selector.buckets.iter().find(|bucket| !buckets.contains(*bucket)) {
+            return Err(AppError::BucketNotFound(bucket.clone()));
+        }
+        buckets.retain(|bucket| selector.buckets.contains(bucket));
+    }
+    Ok(buckets.into_iter().collect())
+}
+
+async fn find_objects(state: &AppState, bucket: &str, selector: &Selector) -> Result<Vec<MatchedObject>> {
+    let (_, client) = &state.get_account_and_client(bucket)?;
+
+    let mut prefixes = vec![selector.prefix.clone()];
+    // Soft-deleted copies are personal data too
+    if let (Some(prefix), Some(trash)) = (&selector.prefix, state.config.soft_delete.buckets.get(bucket)) {
+        prefixes.push(Some(trash::trash_key(trash, prefix)));
+    }
+
+    let mut matched = Vec::new();
+    for prefix in prefixes {
+        for object in client.list_objects(bucket, prefix).await? {
+            let Some(key) = object.key() else {
+                continue;
+            };
+            if let Some(tag) = &selector.tag {
+                let tags = client.get_object_tags(bucket, key).await?;
+                if !tags.iter().any(|(k, v)| *k == tag.key && *v == tag.value) {
+                    continue;
+                }
+            }
+            matched.push(MatchedObject {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                size: object.size().unwrap_or(0).max(0) as u64,
+            });
+        }
+    }
+    Ok(matched)
+}
+
+/// Lists what the action would affect and issues a token to confirm it with
+pub async fn plan(state: &AppState, auth: &AuthState, action: ComplianceAction, selector: Selector) -> Result<Plan> {
+    if selector.prefix.as_deref().unwrap_or_default().is_empty() && selector.tag.is_none() {
+        return Err(AppError::InvalidRequest("A non-empty prefix or a tag is required".to_string()));
+    }
+
+    let mut objects = Vec::new();
+    let mut skipped_buckets = Vec::new();
+    for bucket in target_buckets(state, auth, &selector)? {
+        match find_objects(state, &bucket, &selector).await {
+            Ok(found) => objects.extend(found),
+            Err(e) => {
+                warn!("Compliance search skipped bucket {}: {}", bucket, e);
+                skipped_buckets.push(SkippedBucket { bucket, error: e.to_string() });
+            }
+        }
+    }
+    let total_bytes = objects.iter().map(|object| object.size).sum();
+
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    info!(
+        "Compliance {:?} {} requested by {}: {} objects, {} bytes",
+        action, token, auth.username, objects.len(), total_bytes
+    );
+
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, request| request.created.elapsed() < TOKEN_TTL);
+    pending.insert(
+        token.clone(),
+        PendingRequest {
+            action,
+            selector: selector.clone(),
+            requested_by: auth.username.clone(),
+            requested_at: Utc::now(),
+            objects: objects.clone(),
+            skipped_buckets: skipped_buckets.clone(),
+            created: Instant::now(),
+        },
+    );
+    Ok(Plan {
+        token,
+        action,
+        selector,
+        objects,
+        total_bytes,
+        skipped_buckets,
+        expires_in_secs: TOKEN_TTL.as_secs(),
+    })
+}
+
+/// Redeems a token, which only works once, for the same action and by the admin who requested it
+fn confirm(token: &str, action: ComplianceAction, auth: &AuthState) -> Result<PendingRequest> {
+    let mut pending = PENDING.lock().unwrap();
+    let valid = pending
+        .get(token)
+        .is_some_and(|request| request.action == action && request.created.elapsed() < TOKEN_TTL);
+    if !valid {
+        return Err(AppError::InvalidRequest("Unknown or expired confirmation token".to_string()));
+    }
+    if pending[token].requested_by != auth.username {
+        return Err(AppError::Unauthorized("Only the admin who requested it can confirm".to_string()));
+    }
+    Ok(pending.remove(token).unwrap())
+}
+
+/// Permanently deletes the planned objects, including any soft-deleted copies
+pub async fn erase(state: &AppState, auth: &AuthState, token: &str) -> Result<AuditReport> {
+    let pending = confirm(token, ComplianceAction::Erase, auth)?;
+    let mut report = AuditReport::new(token.to_string(), &pending, &auth.username, Utc::now());
+
+    for object in &pending.objects {
+        let result = match state
+            .holds
+            .check(&object.bucket, Some(&object.key))
+            .and_then(|_| state.get_account_and_client(&object.bucket))
+        {
+            Ok((account, client)) => {
+                chunking::delete(&client, &state.config, &object.bucket, &object.key).await.map(|_| account)
+            }
+            Err(e) => Err(e),
+        };
+        if let Ok(account) = &result {
+            consistency::deleted(&state.config, account, &object.bucket, &object.key);
+            state.invalidate_cache(&object.bucket, Some(&object.key), None);
+        }
+        report.record(object, result.map(|_| ()));
+    }
+    report.finish();
+    Ok(report)
+}
+
+/// Appends a file to the archive and returns the bytes written so far
+fn archive_entry(archive: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) -> Result<Bytes> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().max(0) as u64);
+    archive
+        .append_data(&mut header, path, data)
+        .map_err(|e| AppError::InternalError(format!("Failed to archive {}: {}", path, e)))?;
+    Ok(Bytes::from(std::mem::take(archive.get_mut())))
+}
+
+/// Streams the planned objects as a tar archive of bucket/key entries, ending with audit-report.json
+pub fn export(state: Arc<AppState>, auth: &AuthState, token: &str) -> Result<Body> {
+    let pending = confirm(token, ComplianceAction::Export, auth)?;
+    let mut report = AuditReport::new(token.to_string(), &pending, &auth.username, Utc::now());
+    let (mut sender, receiver) = mpsc::channel::<std::io::Result<Bytes>>(4);
+
+    tokio::spawn(routing::scope(routing::current(), async move {
+        let mut archive = tar::Builder::new(Vec::new());
+        for object in &pending.objects {
+            let result = match state.get_account_and_client(&object.bucket) {
+                Ok((_, client)) => state.read_object(&client, &object.bucket, &object.key, None).await,
+                Err(e) => Err(e),
+            };
+            let entry = result.and_then(|part| {
+                archive_entry(&mut archive, &format!("{}/{}", object.bucket, object.key), &part.body)
+            });
+            match entry {
+                Ok(bytes) => {
+                    report.record(object, Ok(()));
+                    if sender.send(Ok(bytes)).await.is_err() {
+                        warn!("Compliance export {} aborted by the client", report.request_id);
+                        return;
+                    }
+                }
+                Err(e) => report.record(object, Err(e)),
+            }
+        }
+
+        report.finish();
+        let trailer = serde_json::to_vec_pretty(&report)
+            .map_err(|e| AppError::InternalError(e.to_string()))
+            .and_then(|json| archive_entry(&mut archive, "audit-report.json", &json))
+            .and_then(|bytes| {
+                let end = archive.into_inner().map_err(|e| AppError::InternalError(e.to_string()))?;
+                Ok([bytes, Bytes::from(end)].concat())
+            });
+        let chunk = trailer.map(Bytes::from).map_err(|e| std::io::Error::other(e.to_string()));
+        let _ = sender.send(chunk).await;
+    }));
+    Ok(Body::from_stream(receiver))
+}
@@ -0,0 +1,187 @@
+use aws_smithy_runtime_api::client::http::{HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tracing::debug;
+
+use crate::config::ConcurrencyConfig;
+use crate::metrics;
+
+/// Samples the long-term round trip averages over, the baseline of an unloaded endpoint
+const LONG_WINDOW: f64 = 500.0;
+
+/// Samples the short-term round trip averages over, the endpoint's current latency
+const SHORT_WINDOW: f64 = 10.0;
+
+/// How much slower than the baseline the endpoint may get before the limit shrinks
+const TOLERANCE: f64 = 1.5;
+
+/// Weight of each new estimate in the limit
+const SMOOTHING: f64 = 0.2;
+
+/// Uploads larger than this take as long as their transfer, which says nothing about load
+const MAX_SAMPLED_BODY: u64 = 1024 * 1024;
+
+lazy_static! {
+    /// Limiters by account, kept while their config is unchanged when clients are rebuilt on reload
+    static ref LIMITERS: Mutex<HashMap<String, Arc<Limiter>>> = Mutex::new(HashMap::new());
+}
+
+struct Window {
+    limit: f64,
+    in_flight: usize,
+    long_rtt: Option<f64>,
+    short_rtt: Option<f64>,
+}
+
+/// Gradient concurrency limit: grows while latency stays near its long-term baseline, shrinks as
+/// requests queue up at the endpoint, and backs off multiplicatively when it throttles or fails
+struct Limiter {
+    account_id: String,
+    config: ConcurrencyConfig,
+    window: Mutex<Window>,
+    released: Notify,
+}
+
+/// A slot of the limit, given back when dropped
+struct Permit {
+    limiter: Arc<Limiter>,
+    /// Requests in flight when this one was sent, itself included
+    in_flight: usize,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.window.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_one();
+    }
+}
+
+fn average(previous: Option<f64>, sample: f64, window: f64) -> f64 {
+    previous.map_or(sample, |previous| previous + (sample - previous) / window)
+}
+
+impl Limiter {
+    /// Waits for a free slot, or None when none frees up before `deadline`
+    async fn acquire(self: &Arc<Self>, deadline: tokio::time::Instant) -> Option<Permit> {
+        loop {
+            let released = self.released.notified();
+            {
+                let mut window = self.window.lock().unwrap();
+                if (window.in_flight as f64) < window.limit.floor().max(1.0) {
+                    window.in_flight += 1;
+                    // The limit may have grown by more than the slot that woke this request
+                    if (window.in_flight as f64) < window.limit.floor().max(1.0) {
+                        self.released.notify_one();
+                    }
+                    return Some(Permit { limiter: self.clone(), in_flight: window.in_flight });
+                }
+            }
+            tokio::time::timeout_at(deadline, released).await.ok()?;
+        }
+    }
+
+    fn sample(&self, rtt: Option<Duration>, in_flight: usize, overloaded: bool) {
+        let mut window = self.window.lock().unwrap();
+        let previous = window.limit;
+        if overloaded {
+            window.limit = (window.limit * self.config.backoff).max(self.config.min_limit as f64);
+        } else if let Some(rtt) = rtt {
+            let rtt = rtt.as_secs_f64();
+            let long_rtt = average(window.long_rtt, rtt, LONG_WINDOW);
+            let short_rtt = average(window.short_rtt, rtt, SHORT_WINDOW);
+            window.long_rtt = Some(long_rtt);
+            window.short_rtt = Some(short_rtt);
+            // A limit the traffic never came close to says nothing about whether it could be higher
+            if (in_flight as f64) < window.limit / 2.0 {
+                return;
+            }
+            let gradient = (TOLERANCE * long_rtt / short_rtt).clamp(0.5, 1.0);
+            let target = window.limit * gradient + window.limit.sqrt();
+            window.limit = (window.limit * (1.0 - SMOOTHING) + target * SMOOTHING)
+                .clamp(self.config.min_limit as f64, self.config.max_limit as f64);
+        }
+        if window.limit.floor() != previous.floor() {
+            debug!("Concurrency limit of account {} is now {}", self.account_id, window.limit.floor());
+            metrics::set_upstream_concurrency_limit(&self.account_id, window.limit.floor() as i64);
+        }
+    }
+}
+
+fn overloaded(status: u16) -> bool {
+    status == 429 || status == 503
+}
+
+/// Current limit and requests in flight of an account, when it has a limiter
+pub fn current(account_id: &str) -> Option<(usize, usize)> {
+    let limiters = LIMITERS.lock().unwrap();
+    let window = limiters.get(account_id)?.window.lock().unwrap();
+    Some((window.limit.floor() as usize, window.in_flight))
+}
+
+/// Holds requests to an account's endpoint back while its adaptive concurrency limit is reached
+#[derive(Clone)]
+struct LimitedConnector {
+    limiter: Arc<Limiter>,
+    inner: SharedHttpConnector,
+}
+
+impl std::fmt::Debug for LimitedConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LimitedConnector").field("account_id", &self.limiter.account_id).finish()
+    }
+}
+
+impl LimitedConnector {
+    async fn send(self, request: HttpRequest) -> Result<HttpResponse, ConnectorError> {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(self.limiter.config.max_wait_ms);
+        let permit = self.limiter.acquire(deadline).await.ok_or_else(|| {
+            let message = format!("account {} is at its concurrency limit", self.limiter.account_id);
+            ConnectorError::other(message.into(), None)
+        })?;
+        let sampled = request.body().content_length().is_some_and(|length| length <= MAX_SAMPLED_BODY);
+        let started = Instant::now();
+        let response = self.inner.call(request).await;
+        let overloaded = match &response {
+            Ok(response) => overloaded(response.status().as_u16()),
+            Err(e) => e.is_timeout() || e.is_io(),
+        };
+        let rtt = sampled.then(|| started.elapsed());
+        self.limiter.sample(rtt, permit.in_flight, overloaded);
+        response
+    }
+}
+
+impl HttpConnector for LimitedConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        HttpConnectorFuture::new(self.clone().send(request))
+    }
+}
+
+pub fn wrap(account_id: &str, config: ConcurrencyConfig, inner: SharedHttpConnector) -> SharedHttpConnector {
+    let mut limiters = LIMITERS.lock().unwrap();
+    let limiter = match limiters.get(account_id) {
+        Some(limiter) if limiter.config == config => limiter.clone(),
+        _ => {
+            metrics::set_upstream_concurrency_limit(account_id, config.initial_limit as i64);
+            let limiter = Arc::new(Limiter {
+                account_id: account_id.to_string(),
+                window: Mutex::new(Window {
+                    limit: config.initial_limit as f64,
+                    in_flight: 0,
+                    long_rtt: None,
+                    short_rtt: None,
+                }),
+                config,
+                released: Notify::new(),
+            });
+            limiters.insert(account_id.to_string(), limiter.clone());
+            limiter
+        }
+    };
+    SharedHttpConnector::new(LimitedConnector { limiter, inner })
+}
@@ -0,0 +1,294 @@
+use rusqlite::{Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{Config, ConfigStoreConfig, UserConfig};
+use crate::error::{AppError, Result};
+use crate::keys::DelegatedKey;
+use crate::kubernetes;
+use crate::server::AppState;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS users (
+    name TEXT PRIMARY KEY,
+    config TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS bucket_routes (
+    bucket TEXT PRIMARY KEY,
+    account TEXT
+);
+CREATE TABLE IF NOT EXISTS delegated_keys (
+    hash TEXT PRIMARY KEY,
+    key TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS meta (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+";
+
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn store_error(e: rusqlite::Error) -> AppError {
+    AppError::InternalError(format!("Config store: {}", e))
+}
+
+fn json_error(e: serde_json::Error) -> AppError {
+    AppError::InternalError(format!("Config store: {}", e))
+}
+
+/// Users, bucket routes and delegated keys kept in SQLite, so changes made through the admin API
+/// survive restarts and reach every replica sharing the database
+pub struct ConfigStore {
+    connection: Mutex<Connection>,
+}
+
+impl ConfigStore {
+    /// Opens the store, seeding it with the users of config.json the first time
+    pub fn open(config: &ConfigStoreConfig, seed: &Config) -> Result<Self> {
+        let connection = Connection::open(&config.path).map_err(store_error)?;
+        // WAL lets replicas read while one of them writes
+        connection.pragma_update(None, "journal_mode", "WAL").map_err(store_error)?;
+        connection.busy_timeout(BUSY_TIMEOUT).map_err(store_error)?;
+        connection.execute_batch(SCHEMA).map_err(store_error)?;
+        let store = Self { connection: Mutex::new(connection) };
+
+        let mut connection = store.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(store_error)?;
+        let seeded: bool = transaction
+            .query_row("SELECT 1 FROM meta WHERE name = 'seeded'", [], |_| Ok(true))
+            .optional()
+            .map_err(store_error)?
+            .unwrap_or(false);
+        if seeded {
+            info!("Reading users, bucket routes and delegated keys from the config store {}", config.path);
+        } else {
+            let users = seed.users.current();
+            for (name, user) in users.iter() {
+                transaction
+                    .execute(
+                        "INSERT OR IGNORE INTO users (name, config) VALUES (?1, ?2)",
+                        rusqlite::params![name, serde_json::to_string(user).map_err(json_error)?],
+                    )
+                    .map_err(store_error)?;
+            }
+            transaction
+                .execute("INSERT INTO meta (name, value) VALUES ('seeded', 1), ('revision', 0)", [])
+                .map_err(store_error)?;
+            info!("Seeded the config store {} with {} users from config.json", config.path, users.len());
+        }
+        transaction.commit().map_err(store_error)?;
+        drop(connection);
+        Ok(store)
+    }
+
+    /// Runs `call` off the async workers, as queries wait up to the busy timeout while another
+    /// replica writes
+    pub async fn run<T: Send + 'static>(
+        self: &Arc<Self>,
+        call: impl FnOnce(&ConfigStore) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || call(&store))
+            .await
+            .map_err(|e| AppError::InternalError(format!("Config store task failed: {}", e)))?
+    }
+
+    /// Counts writes, so replicas notice changes made by the others
+    pub fn revision(&self) -> Result<i64> {
+        self.connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT value FROM meta WHERE name = 'revision'", [], |row| row.get(0))
+            .map_err(store_error)
+    }
+
+    /// Runs `write` in a transaction that also bumps the revision
+    fn write<T>(&self, write: impl FnOnce(&rusqlite::Transaction) -> rusqlite::Result<T>) -> Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction().map_err(store_error)?;
+        let result = write(&transaction).map_err(store_error)?;
+        transaction
+            .execute("UPDATE meta SET value = value + 1 WHERE name = 'revision'", [])
+            .map_err(store_error)?;
+        transaction.commit().map_err(store_error)?;
+        Ok(result)
+    }
+
+    pub fn users(&self) -> Result<HashMap<String, UserConfig>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT name, config FROM users").map_err(store_error)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(store_error)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(store_error)?;
+        let mut users = HashMap::new();
+        for (name, config) in rows {
+            match serde_json::from_str(&config) {
+                Ok(user) => {
+                    users.insert(name, user);
+                }
+                Err(e) => warn!("Skipping user {} of the config store: {}", name, e),
+            }
+        }
+        Ok(users)
+    }
+
+    pub fn put_user(&self, name: &str, user: &UserConfig) -> Result<()> {
+        let config = serde_json::to_string(user).map_err(json_error)?;
+        self.write(|transaction| {
+            transaction.execute(
+                "INSERT INTO users (name, config) VALUES (?1, ?2) ON CONFLICT (name) DO UPDATE SET config = ?2",
+                rusqlite::params![name, config],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// False when there was no such user
+    pub fn delete_user(&self, name: &str) -> Result<bool> {
+        let deleted = self.write(|transaction| transaction.execute("DELETE FROM users WHERE name = ?1", [name]))?;
+        Ok(deleted > 0)
+    }
+
+    /// Bucket to account routes; None marks a bucket removed from the account config.json lists it under
+    pub fn routes(&self) -> Result<HashMap<String, Option<String>>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT bucket, account FROM bucket_routes").map_err(store_error)?;
+        let routes = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .map_err(store_error)?
+            .collect::<rusqlite::Result<HashMap<_, _>>>()
+            .map_err(store_error)?;
+        Ok(routes)
+    }
+
+    pub fn put_route(&self, bucket: &str, account_id: Option<&str>) -> Result<()> {
+        self.write(|transaction| {
+            transaction.execute(
+                "INSERT INTO bucket_routes (bucket, account) VALUES (?1, ?2) \
+                 ON CONFLICT (bucket) DO UPDATE SET account = ?2",
+                rusqlite::params![bucket, account_id],
+            )
+        })?;
+        Ok(())
+    }
+
+    /// False when the bucket had no route
+    pub fn delete_route(&self, bucket: &str) -> Result<bool> {
+        let deleted =
+            self.write(|transaction| transaction.execute("DELETE FROM bucket_routes WHERE bucket = ?1", [bucket]))?;
+        Ok(deleted > 0)
+    }
+
+    /// Delegated keys by hash of their secret
+    pub fn delegated_keys(&self) -> Result<HashMap<String, DelegatedKey>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT hash, key FROM delegated_keys").map_err(store_error)?;
+        let rows = statement
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(store_error)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(store_error)?;
+        rows.into_iter()
+            .map(|(hash, key)| Ok((hash, serde_json::from_str(&key).map_err(json_error)?)))
+            .collect()
+    }
+
+    /// Writes only the keys that differ between the two sets, so replicas issuing and revoking
+    /// keys at once do not undo each other's changes
+    pub fn save_delegated_keys(
+        &self,
+        previous: &HashMap<String, DelegatedKey>,
+        updated: &HashMap<String, DelegatedKey>,
+    ) -> Result<()> {
+        let added = updated
+            .iter()
+            .filter(|(hash, _)| !previous.contains_key(*hash))
+            .map(|(hash, key)| Ok((hash.clone(), serde_json::to_string(key).map_err(json_error)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let removed: Vec<&String> = previous.keys().filter(|hash| !updated.contains_key(*hash)).collect();
+        self.write(|transaction| {
+            for (hash, key) in &added {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO delegated_keys (hash, key) VALUES (?1, ?2)",
+                    rusqlite::params![hash, key],
+                )?;
+            }
+            for hash in &removed {
+                transaction.execute("DELETE FROM delegated_keys WHERE hash = ?1", [hash])?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Replaces the users, bucket routes and delegated keys in use by those in the store
+fn apply(state: &AppState, store: &ConfigStore, applied_routes: &mut HashMap<String, Option<String>>) -> Result<()> {
+    kubernetes::apply_users(state, store.users()?, "the config store");
+
+    let routes = store.routes()?;
+    for bucket in applied_routes.keys().filter(|bucket| !routes.contains_key(*bucket)) {
+        state.buckets.remove(bucket);
+    }
+    for (bucket, account_id) in &routes {
+        if applied_routes.get(bucket) != Some(account_id) {
+            match account_id {
+                Some(account_id) => state.buckets.register(bucket, account_id),
+                None => state.buckets.deregister(bucket),
+            }
+        }
+    }
+    *applied_routes = routes;
+
+    if let Some(keys) = &state.keys {
+        keys.replace(store.delegated_keys()?);
+    }
+    Ok(())
+}
+
+/// Applies the store before requests are served, then checks it for changes made by other
+/// replicas; a change that fails to load keeps the previous version
+pub fn start(state: Arc<AppState>) -> Result<()> {
+    let (Some(config), Some(store)) = (&state.config.config_store, state.config_store.clone()) else {
+        return Ok(());
+    };
+    let mut applied_routes = HashMap::new();
+    let mut revision = store.revision()?;
+    apply(&state, &store, &mut applied_routes)?;
+
+    let interval = Duration::from_secs(config.reload_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            // A task that fails loses the routes applied so far, so the next poll applies them all
+            let mut routes = std::mem::take(&mut applied_routes);
+            let (poll_state, poll_store) = (state.clone(), store.clone());
+            let poll = tokio::task::spawn_blocking(move || {
+                let result = poll_store.revision().and_then(|current| {
+                    if current != revision {
+                        apply(&poll_state, &poll_store, &mut routes)?;
+                    }
+                    Ok(current)
+                });
+                (result, routes)
+            });
+            match poll.await {
+                Ok((result, routes)) => {
+                    applied_routes = routes;
+                    match result {
+                        Ok(current) => revision = current,
+                        Err(e) => warn!("Keeping the previous users, routes and keys: {}", e),
+                    }
+                }
+                Err(e) => warn!("Config store poll failed: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
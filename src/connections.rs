@@ -0,0 +1,291 @@
+use axum::extract::ConnectInfo;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Router;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use hyper_util::service::TowerToHyperService;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, Sleep};
+use tower::ServiceExt;
+use tracing::{debug, warn};
+
+use crate::config::{Config, ConnectionConfig, PlaintextMode};
+use crate::error::AppError;
+use crate::tls;
+
+/// Streams a connection may be served over, plain or TLS
+trait ClientIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> ClientIo for T {}
+
+/// Serves `app` until `shutdown` resolves, then waits for open connections to finish their requests;
+/// with `tls`, connections starting a TLS handshake are served over TLS and the others as plain HTTP
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: &ConnectionConfig,
+    tls: Option<tls::Acceptor>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let mut builder = Builder::new(TokioExecutor::new());
+    let header_timeout = (config.header_read_timeout_secs > 0).then(|| Duration::from_secs(config.header_read_timeout_secs));
+    if let Some(header_timeout) = header_timeout {
+        builder.http1().timer(TokioTimer::new()).header_read_timeout(header_timeout);
+    }
+    // Parsers stop at the limits rather than buffering what `limit_request` would refuse anyway;
+    // HTTP/2 counts 32 bytes of overhead per header on top of its name and value
+    builder.http1().max_headers(config.max_headers.max(1));
+    let header_list_size = config.max_header_bytes.saturating_add(32 * config.max_headers);
+    builder.http2().max_header_list_size(u32::try_from(header_list_size).unwrap_or(u32::MAX));
+    let read_idle = config.read_idle_secs.map(|secs| Duration::from_secs(secs.max(1)));
+    let write_idle = config.write_idle_secs.map(|secs| Duration::from_secs(secs.max(1)));
+    let redirect = tls.as_ref().map(|_| tls::redirect_router());
+
+    let limit = config.max_connections.map(|max| Arc::new(Semaphore::new(max.max(1))));
+
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        // Taken before accepting, so clients over the limit wait in the kernel's backlog
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.expect("the semaphore is never closed")),
+                _ = &mut shutdown => break,
+            },
+            None => None,
+        };
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) if is_connection_error(&e) => continue,
+                Err(e) => {
+                    // E.g. out of file descriptors; retrying at once would spin
+                    warn!("Failed to accept a connection: {}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let builder = builder.clone();
+        let watcher = graceful.watcher();
+        let (tls, app, redirect) = (tls.clone(), app.clone(), redirect.clone());
+        tokio::spawn(async move {
+            let _permit = permit;
+            let stream = IdleTimeout::new(stream, peer, read_idle, write_idle);
+            // Sniffed here rather than in the accept loop, so slow clients only stall themselves
+            let (io, app): (Box<dyn ClientIo>, Router) = match &tls {
+                None => (Box::new(stream), app),
+                Some(tls) => match accept(tls, stream, header_timeout).await {
+                    Ok((io, false)) if tls.plaintext == PlaintextMode::Redirect => {
+                        (io, redirect.expect("set along with TLS"))
+                    }
+                    Ok((io, _)) => (io, app),
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                },
+            };
+            serve_client(builder, watcher, app, peer, io).await;
+        });
+    }
+
+    // Stop accepting, so a process taking over the socket gets every new connection
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+/// Wraps a connection in TLS when its first byte starts a handshake; the flag tells whether it did
+async fn accept(
+    tls: &tls::Acceptor,
+    stream: IdleTimeout,
+    timeout: Option<Duration>,
+) -> io::Result<(Box<dyn ClientIo>, bool)> {
+    let handshake = async {
+        let mut first = [0u8; 1];
+        if stream.inner.peek(&mut first).await? == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        if !tls::is_client_hello(first[0]) {
+            return Ok((Box::new(stream) as Box<dyn ClientIo>, false));
+        }
+        Ok((Box::new(tls.acceptor.accept(stream).await?) as Box<dyn ClientIo>, true))
+    };
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "no handshake in time"))),
+        None => handshake.await,
+    }
+}
+
+async fn serve_client(
+    builder: Builder<TokioExecutor>,
+    watcher: Watcher,
+    app: Router,
+    peer: SocketAddr,
+    io: Box<dyn ClientIo>,
+) {
+    // Read by routing rules matching client networks
+    let service = app.map_request(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(peer));
+        request
+    });
+    let connection = builder.serve_connection_with_upgrades(TokioIo::new(io), TowerToHyperService::new(service));
+    if let Err(e) = watcher.watch(connection.into_owned()).await {
+        debug!("Connection from {} closed: {}", peer, e);
+    }
+}
+
+/// Refuses requests over the header and URI limits of `server.connections` before any other
+/// middleware sees them, whichever protocol they came over
+pub async fn limit_request(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let limits = &config.server.connections;
+    let uri_length = request.uri().path_and_query().map_or(0, |path| path.as_str().len());
+    if uri_length > limits.max_uri_length {
+        debug!("Refusing a request with a {} byte URI", uri_length);
+        let message = format!("The URI is {} bytes, over the limit of {}", uri_length, limits.max_uri_length);
+        return AppError::UriTooLong(message).into_response();
+    }
+    let headers = request.headers();
+    if headers.len() > limits.max_headers {
+        debug!("Refusing a request with {} headers", headers.len());
+        let message = format!("{} headers, over the limit of {}", headers.len(), limits.max_headers);
+        return AppError::HeadersTooLarge(message).into_response();
+    }
+    let header_bytes: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+    if header_bytes > limits.max_header_bytes {
+        debug!("Refusing a request with {} bytes of headers", header_bytes);
+        let message = format!("{} bytes of headers, over the limit of {}", header_bytes, limits.max_header_bytes);
+        return AppError::HeadersTooLarge(message).into_response();
+    }
+    next.run(request).await
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Fails reads and writes of a connection that stop making progress
+struct IdleTimeout {
+    inner: TcpStream,
+    peer: SocketAddr,
+    read_idle: Option<Duration>,
+    write_idle: Option<Duration>,
+    /// Last time a byte moved in either direction
+    progress: Instant,
+    read_timer: Option<Pin<Box<Sleep>>>,
+    write_timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl IdleTimeout {
+    fn new(inner: TcpStream, peer: SocketAddr, read_idle: Option<Duration>, write_idle: Option<Duration>) -> Self {
+        Self { inner, peer, read_idle, write_idle, progress: Instant::now(), read_timer: None, write_timer: None }
+    }
+}
+
+/// Called while the inner stream is pending: errors once `idle` has passed since the last progress,
+/// otherwise registers a wakeup for when it will have
+fn expire(
+    timer: &mut Option<Pin<Box<Sleep>>>,
+    idle: Option<Duration>,
+    progress: Instant,
+    peer: SocketAddr,
+    direction: &str,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<()>> {
+    let Some(idle) = idle else {
+        return Poll::Pending;
+    };
+    let deadline = progress + idle;
+    let timer = timer.get_or_insert_with(|| Box::pin(tokio::time::sleep_until(deadline)));
+    if timer.deadline() != deadline {
+        timer.as_mut().reset(deadline);
+    }
+    match timer.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            warn!("Closing connection from {}: no data {} for {}s", peer, direction, idle.as_secs());
+            Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} idle timeout", direction))))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl AsyncRead for IdleTimeout {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        match Pin::new(&mut this.inner).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                if buf.filled().len() > filled {
+                    this.progress = Instant::now();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => expire(&mut this.read_timer, this.read_idle, this.progress, this.peer, "received", cx),
+        }
+    }
+}
+
+impl AsyncWrite for IdleTimeout {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(result) => {
+                if matches!(result, Ok(written) if written > 0) {
+                    this.progress = Instant::now();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => expire(&mut this.write_timer, this.write_idle, this.progress, this.peer, "sent", cx)
+                .map_ok(|()| 0),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.inner).poll_write_vectored(cx, bufs) {
+            Poll::Ready(result) => {
+                if matches!(result, Ok(written) if written > 0) {
+                    this.progress = Instant::now();
+                }
+                Poll::Ready(result)
+            }
+            Poll::Pending => expire(&mut this.write_timer, this.write_idle, this.progress, this.peer, "sent", cx)
+                .map_ok(|()| 0),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,222 @@
+use aws_sdk_s3::primitives::DateTime as S3DateTime;
+use aws_sdk_s3::types::Object;
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cache::ByteRange;
+use crate::config::{Config, ReadAfterWriteConfig};
+use crate::error::{AppError, Result};
+use crate::s3::ObjectPart;
+
+lazy_static! {
+    /// Recent writes and deletes by account, bucket and key; routing rules may send one bucket
+    /// name to several accounts
+    static ref RECENT: Mutex<BTreeMap<ObjectId, Record>> = Mutex::new(BTreeMap::new());
+}
+
+enum Change {
+    Written {
+        last_modified: DateTime<Utc>,
+        etag: Option<String>,
+        size: u64,
+        /// Kept for objects up to max_object_size
+        body: Option<Bytes>,
+    },
+    Deleted,
+}
+
+type ObjectId = (String, String, String);
+
+fn id(account: &str, bucket: &str, key: &str) -> ObjectId {
+    (account.to_string(), bucket.to_string(), key.to_string())
+}
+
+struct Record {
+    at: Instant,
+    change: Change,
+}
+
+fn remember(config: &ReadAfterWriteConfig, account: &str, bucket: &str, key: &str, change: Change) {
+    let mut recent = RECENT.lock().unwrap();
+    if recent.len() >= config.max_entries {
+        let window = Duration::from_secs(config.window_secs);
+        recent.retain(|_, record| record.at.elapsed() < window);
+        while recent.len() >= config.max_entries.max(1) {
+            let oldest = recent.iter().min_by_key(|(_, record)| record.at).map(|(id, _)| id.clone());
+            match oldest {
+                Some(oldest) => recent.remove(&oldest),
+                None => break,
+            };
+        }
+    }
+    recent.insert(id(account, bucket, key), Record { at: Instant::now(), change });
+}
+
+/// Records an object this replica has just written to `account`
+pub fn written(config: &Config, account: &str, bucket: &str, key: &str, body: &Bytes, etag: Option<&str>) {
+    let Some(read_after_write) = &config.read_after_write else {
+        return;
+    };
+    let change = Change::Written {
+        last_modified: Utc::now(),
+        etag: etag.map(String::from),
+        size: body.len() as u64,
+        body: (body.len() as u64 <= read_after_write.max_object_size).then(|| body.clone()),
+    };
+    remember(read_after_write, account, bucket, key, change);
+}
+
+/// Records an object this replica has just deleted
+pub fn deleted(config: &Config, account: &str, bucket: &str, key: &str) {
+    if let Some(read_after_write) = &config.read_after_write {
+        remember(read_after_write, account, bucket, key, Change::Deleted);
+    }
+}
+
+/// Drops what is known about an object written in a way the proxy cannot replay, such as a resumable upload
+pub fn forget(account: &str, bucket: &str, key: &str) {
+    RECENT.lock().unwrap().remove(&id(account, bucket, key));
+}
+
+/// Moves the record of a recent write along with a renamed object
+pub fn renamed(config: &Config, account: &str, bucket: &str, key: &str, destination: &str) {
+    let Some(read_after_write) = &config.read_after_write else {
+        return;
+    };
+    let moved = RECENT.lock().unwrap().remove(&id(account, bucket, key));
+    match moved {
+        Some(Record { change: change @ Change::Written { .. }, .. }) => {
+            remember(read_after_write, account, bucket, destination, change)
+        }
+        _ => forget(account, bucket, destination),
+    }
+    remember(read_after_write, account, bucket, key, Change::Deleted);
+}
+
+/// Whether this replica changed `key` within the read-after-write window
+pub fn is_recent(config: &Config, account: &str, bucket: &str, key: &str) -> bool {
+    let Some(read_after_write) = &config.read_after_write else {
+        return false;
+    };
+    RECENT
+        .lock()
+        .unwrap()
+        .get(&id(account, bucket, key))
+        .is_some_and(|record| record.at.elapsed() < Duration::from_secs(read_after_write.window_secs))
+}
+
+/// Serves a recent write from memory, or reports a recent delete as missing
+pub fn read(config: &Config, account: &str, bucket: &str, key: &str, range: Option<ByteRange>) -> Result<Option<ObjectPart>> {
+    let Some(read_after_write) = &config.read_after_write else {
+        return Ok(None);
+    };
+    let recent = RECENT.lock().unwrap();
+    let Some(record) = recent.get(&id(account, bucket, key)) else {
+        return Ok(None);
+    };
+    if record.at.elapsed() >= Duration::from_secs(read_after_write.window_secs) {
+        return Ok(None);
+    }
+    let (body, etag) = match &record.change {
+        Change::Deleted => return Err(AppError::ObjectNotFound(bucket.to_string(), key.to_string())),
+        Change::Written { body: Some(body), etag, .. } => (body.clone(), etag.clone()),
+        Change::Written { body: None, .. } => return Ok(None),
+    };
+
+    let size = body.len() as u64;
+    let part = match range {
+        Some(range) if size > 0 => {
+            let (first, last) = range.resolve(size).ok_or_else(|| {
+                AppError::RangeNotSatisfiable(format!("{}/{} does not contain the requested range", bucket, key))
+            })?;
+            ObjectPart {
+                body: body.slice(first as usize..=last as usize),
+                etag,
+                content_range: Some(format!("bytes {}-{}/{}", first, last, size)),
+                total_size: size,
+                proxy_etag: None,
+            }
+        }
+        _ => ObjectPart { body, etag, content_range: None, total_size: size, proxy_etag: None },
+    };
+    Ok(Some(part))
+}
+
+/// Adds recent writes missing from a listing and drops recent deletes still in it
+pub fn merge_listing(
+    config: &Config,
+    account: &str,
+    bucket: &str,
+    prefix: &str,
+    directories: bool,
+    objects: &mut Vec<Object>,
+    prefixes: &mut Vec<String>,
+) {
+    let Some(read_after_write) = &config.read_after_write else {
+        return;
+    };
+    let window = Duration::from_secs(read_after_write.window_secs);
+    let recent = RECENT.lock().unwrap();
+    let changes = recent
+        .range(id(account, bucket, prefix)..)
+        .take_while(|((a, b, key), _)| a == account && b == bucket && key.starts_with(prefix))
+        .filter(|(_, record)| record.at.elapsed() < window);
+
+    let mut changed = false;
+    for ((_, _, key), record) in changes {
+        objects.retain(|object| object.key() != Some(key.as_str()));
+        let Change::Written { last_modified, etag, size, .. } = &record.change else {
+            changed = true;
+            continue;
+        };
+        match key[prefix.len()..].find('/') {
+            Some(slash) if directories => {
+                let common = key[..prefix.len() + slash + 1].to_string();
+                if !prefixes.contains(&common) {
+                    prefixes.push(common);
+                }
+            }
+            _ => objects.push(
+                Object::builder()
+                    .key(key)
+                    .size(*size as i64)
+                    .set_e_tag(etag.clone())
+                    .last_modified(S3DateTime::from_secs(last_modified.timestamp()))
+                    .build(),
+            ),
+        }
+        changed = true;
+    }
+    if changed {
+        objects.sort_by(|a, b| a.key().cmp(&b.key()));
+        prefixes.sort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use serde_json::json;
+
+    use super::{read, written};
+    use crate::config::Config;
+
+    #[test]
+    fn recent_writes_are_served_only_from_the_account_they_went_to() {
+        let config: Config = serde_json::from_value(json!({
+            "accounts": {},
+            "users": {},
+            "server": { "host": "127.0.0.1", "port": 8080 },
+            "read_after_write": { "window_secs": 60 }
+        }))
+        .unwrap();
+        written(&config, "eu", "routed", "a", &Bytes::from_static(b"eu"), Some("\"e\""));
+        let part = read(&config, "eu", "routed", "a", None).unwrap().expect("the write is recent");
+        assert_eq!(part.body, Bytes::from_static(b"eu"));
+        assert!(read(&config, "us", "routed", "a", None).unwrap().is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use bytes::Bytes;
+use tracing::debug;
+
+use crate::config::{Config, ContentPolicy};
+use crate::error::{AppError, Result};
+
+/// Bytes expected at an offset, all of which must match
+type Signature = &'static [(usize, &'static [u8])];
+
+const ICO: &[Signature] = &[&[(0, b"\0\0\x01\0")]];
+const GZIP: &[Signature] = &[&[(0, b"\x1f\x8b")]];
+const WAV: &[Signature] = &[&[(0, b"RIFF"), (8, b"WAVE")]];
+
+const JPEG_SOI: &[u8] = &[0xFF, 0xD8];
+const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// No signature extends past this many bytes into the object
+pub const SIGNATURE_LENGTH: u64 = 12;
+
+/// Media types with a well-known signature and the alternatives they may start with
+const SIGNATURES: &[(&str, &[Signature])] = &[
+    ("image/png", &[&[(0, b"\x89PNG\r\n\x1a\n")]]),
+    ("image/jpeg", &[&[(0, b"\xff\xd8\xff")]]),
+    ("image/gif", &[&[(0, b"GIF87a")], &[(0, b"GIF89a")]]),
+    ("image/webp", &[&[(0, b"RIFF"), (8, b"WEBP")]]),
+    ("image/bmp", &[&[(0, b"BM")]]),
+    ("image/tiff", &[&[(0, b"II*\0")], &[(0, b"MM\0*")]]),
+    ("image/x-icon", ICO),
+    ("image/vnd.microsoft.icon", ICO),
+    ("application/pdf", &[&[(0, b"%PDF-")]]),
+    ("application/zip", &[&[(0, b"PK\x03\x04")], &[(0, b"PK\x05\x06")]]),
+    ("application/gzip", GZIP),
+    ("application/x-gzip", GZIP),
+    ("application/wasm", &[&[(0, b"\0asm")]]),
+    ("audio/mpeg", &[&[(0, b"ID3")], &[(0, b"\xff\xfb")], &[(0, b"\xff\xf3")], &[(0, b"\xff\xf2")]]),
+    ("audio/wav", WAV),
+    ("audio/x-wav", WAV),
+    ("video/mp4", &[&[(4, b"ftyp")]]),
+];
+
+/// The media type without parameters such as charset, lowercased
+fn media_type(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+fn type_allowed(policy: &ContentPolicy, content_type: Option<&str>) -> bool {
+    if policy.allowed_types.is_empty() {
+        return true;
+    }
+    let Some(content_type) = content_type.map(media_type) else {
+        return false;
+    };
+    policy.allowed_types.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_suffix("/*") {
+            Some(top_level) => content_type.split('/').next() == Some(top_level),
+            None => content_type == allowed,
+        }
+    })
+}
+
+fn extension_allowed(policy: &ContentPolicy, key: &str) -> bool {
+    if policy.allowed_extensions.is_empty() {
+        return true;
+    }
+    let name = key.rsplit('/').next().unwrap_or(key);
+    let Some((_, extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    policy
+        .allowed_extensions
+        .iter()
+        .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(extension))
+}
+
+/// Compares the part of the signature that falls within `data`, which starts at `start` in the object.
+/// A `complete` body must also be long enough to hold the whole signature.
+fn signature_matches(signature: Signature, data: &[u8], start: usize, complete: bool) -> bool {
+    let data_end = start.saturating_add(data.len());
+    signature.iter().all(|(offset, expected)| {
+        let end = offset + expected.len();
+        let from = (*offset).max(start);
+        let to = end.min(data_end);
+        if from < to && data[from - start..to - start] != expected[from - offset..to - offset] {
+            return false;
+        }
+        !complete || data_end >= end
+    })
+}
+
+/// Rejects a body, or a chunk of one starting at `start`, whose bytes contradict its declared Content-Type
+pub fn verify_magic_bytes(
+    config: &Config,
+    bucket: &str,
+    content_type: Option<&str>,
+    data: &[u8],
+    start: usize,
+    complete: bool,
+) -> Result<()> {
+    if !config.content_policies.get(bucket).is_some_and(|policy| policy.verify_magic_bytes) {
+        return Ok(());
+    }
+    let Some(content_type) = content_type.map(media_type) else {
+        return Ok(());
+    };
+    // Types without a known signature, such as text, cannot be verified
+    let Some((_, signatures)) = SIGNATURES.iter().find(|(media_type, _)| *media_type == content_type) else {
+        return Ok(());
+    };
+    if !signatures.iter().any(|signature| signature_matches(signature, data, start, complete)) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "Content does not match the declared Content-Type {}",
+            content_type
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects keys with an extension the bucket does not accept
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    let Some(policy) = config.content_policies.get(bucket) else {
+        return Ok(());
+    };
+    if !extension_allowed(policy, key) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "Bucket {} does not accept the file extension of {}",
+            bucket, key
+        )));
+    }
+    Ok(())
+}
+
+/// Rejects uploads whose key extension or Content-Type the bucket does not accept
+pub fn check_upload(config: &Config, bucket: &str, key: &str, content_type: Option<&str>) -> Result<()> {
+    check_key(config, bucket, key)?;
+    let Some(policy) = config.content_policies.get(bucket) else {
+        return Ok(());
+    };
+    if !type_allowed(policy, content_type) {
+        return Err(AppError::UnsupportedMediaType(format!(
+            "Bucket {} does not accept Content-Type {}",
+            bucket,
+            content_type.unwrap_or("(none)")
+        )));
+    }
+    Ok(())
+}
+
+fn strips_metadata(config: &Config, bucket: &str) -> bool {
+    config.content_policies.get(bucket).is_some_and(|policy| policy.strip_exif)
+}
+
+/// Removes image metadata from an upload to a bucket that asks for it. Malformed images are
+/// rejected rather than stored with their metadata.
+pub fn strip_upload(config: &Config, bucket: &str, body: Bytes) -> Result<Bytes> {
+    if !strips_metadata(config, bucket) {
+        return Ok(body);
+    }
+    let size = body.len();
+    let stripped = strip_metadata(body).ok_or_else(|| {
+        AppError::UnsupportedMediaType("Malformed image, its metadata cannot be removed".to_string())
+    })?;
+    if stripped.len() != size {
+        debug!("Removed {} bytes of image metadata from an upload to {}", size - stripped.len(), bucket);
+    }
+    Ok(stripped)
+}
+
+/// Rejects resumable uploads to buckets that strip image metadata, since chunks are stored as they
+/// arrive and the metadata can span several of them
+pub fn check_resumable(config: &Config, bucket: &str) -> Result<()> {
+    if strips_metadata(config, bucket) {
+        return Err(AppError::InvalidRequest(format!(
+            "Bucket {} removes image metadata on upload and only accepts single PUT uploads",
+            bucket
+        )));
+    }
+    Ok(())
+}
+
+/// Drops EXIF and XMP metadata from JPEG and PNG images, which may hold locations, device serial
+/// numbers and names; other content is left as is. None if the image is malformed.
+pub fn strip_metadata(body: Bytes) -> Option<Bytes> {
+    if body.starts_with(JPEG_SOI) {
+        strip_jpeg(&body).map(Bytes::from)
+    } else if body.starts_with(PNG_SIGNATURE) {
+        strip_png(&body).map(Bytes::from)
+    } else {
+        Some(body)
+    }
+}
+
+/// Copies a JPEG without its APP1 (EXIF, XMP) and APP13 (IPTC) segments
+fn strip_jpeg(body: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = JPEG_SOI.to_vec();
+    let mut at = JPEG_SOI.len();
+    loop {
+        let marker = *body.get(at + 1)?;
+        if body[at] != 0xFF {
+            return None;
+        }
+        // Fill bytes may pad the space between segments
+        if marker == 0xFF {
+            at += 1;
+            continue;
+        }
+        // Start of scan: the compressed image data follows until the end
+        if marker == 0xDA {
+            stripped.extend_from_slice(&body[at..]);
+            return Some(stripped);
+        }
+        let length = u16::from_be_bytes([*body.get(at + 2)?, *body.get(at + 3)?]) as usize;
+        let end = at + 2 + length;
+        if length < 2 || end > body.len() {
+            return None;
+        }
+        if marker == 0xE1 || marker == 0xED {
+            debug!("Stripped JPEG segment {:02X} of {} bytes", marker, length);
+        } else {
+            stripped.extend_from_slice(&body[at..end]);
+        }
+        at = end;
+    }
+}
+
+/// Copies a PNG without its eXIf chunk and text chunks, which is where XMP is kept
+fn strip_png(body: &[u8]) -> Option<Vec<u8>> {
+    let mut stripped = PNG_SIGNATURE.to_vec();
+    let mut at = PNG_SIGNATURE.len();
+    while at < body.len() {
+        let length = u32::from_be_bytes(body.get(at..at + 4)?.try_into().ok()?) as usize;
+        let kind = body.get(at + 4..at + 8)?;
+        // Length, type, data and CRC
+        let end = at + 12 + length;
+        if end > body.len() {
+            return None;
+        }
+        if matches!(kind, b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt") {
+            debug!("Stripped PNG chunk {} of {} bytes", String::from_utf8_lossy(kind), length);
+        } else {
+            stripped.extend_from_slice(&body[at..end]);
+        }
+        at = end;
+    }
+    Some(stripped)
+}
@@ -0,0 +1,187 @@
+use aws_smithy_runtime_api::client::http::{HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use http::{HeaderValue, Uri};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::auth::AuthState;
+use crate::config::{Config, CostConfig};
+
+const BYTES_PER_GB: f64 = 1_073_741_824.0;
+
+tokio::task_local! {
+    /// Upstream usage of the request being handled on this task
+    static USAGE: Arc<Mutex<Usage>>;
+}
+
+lazy_static! {
+    static ref TOTALS: Mutex<BTreeMap<String, UserCost>> = Mutex::new(BTreeMap::new());
+}
+
+/// Upstream requests by pricing class and bytes transferred
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct Usage {
+    pub class_a_requests: u64,
+    pub class_b_requests: u64,
+    pub free_requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.class_a_requests += other.class_a_requests;
+        self.class_b_requests += other.class_b_requests;
+        self.free_requests += other.free_requests;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+
+    fn cost(&self, config: &CostConfig) -> f64 {
+        self.class_a_requests as f64 * config.class_a_per_1000 / 1000.0
+            + self.class_b_requests as f64 * config.class_b_per_1000 / 1000.0
+            + self.bytes_in as f64 * config.ingress_per_gb / BYTES_PER_GB
+            + self.bytes_out as f64 * config.egress_per_gb / BYTES_PER_GB
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, ToSchema)]
+pub struct UserCost {
+    /// Client requests, each of which may have made several upstream requests
+    pub requests: u64,
+    #[serde(flatten)]
+    pub usage: Usage,
+    pub cost: f64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CostReport {
+    pub currency: String,
+    pub users: BTreeMap<String, UserCost>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum RequestClass {
+    A,
+    B,
+    Free,
+}
+
+/// Classifies an upstream request the way S3 prices it
+fn classify(method: &str, uri: &str) -> RequestClass {
+    match method {
+        "DELETE" => RequestClass::Free,
+        "PUT" | "POST" => RequestClass::A,
+        "GET" => {
+            let uri: Option<Uri> = uri.parse().ok();
+            let path = uri.as_ref().map(Uri::path).unwrap_or("/");
+            let query = uri.as_ref().and_then(Uri::query).unwrap_or_default();
+            let listing = query
+                .split('&')
+                .any(|param| matches!(param.split('=').next(), Some("list-type" | "uploads" | "versions")));
+            if listing || path == "/" {
+                RequestClass::A
+            } else {
+                RequestClass::B
+            }
+        }
+        _ => RequestClass::B,
+    }
+}
+
+fn request_bytes(request: &HttpRequest) -> u64 {
+    // Streaming uploads are sent aws-chunked and only announce their decoded length
+    ["x-amz-decoded-content-length", "content-length"]
+        .iter()
+        .find_map(|name| request.headers().get(*name).and_then(|v| v.parse().ok()))
+        .or_else(|| request.body().content_length())
+        .unwrap_or(0)
+}
+
+/// Attributes every upstream request to the client request that caused it
+#[derive(Debug, Clone)]
+struct CountingConnector(SharedHttpConnector);
+
+impl HttpConnector for CountingConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let class = classify(request.method(), request.uri());
+        let bytes_in = request_bytes(&request);
+        let response = self.0.call(request);
+        HttpConnectorFuture::new(async move {
+            let response = response.await?;
+            let bytes_out = response
+                .headers()
+                .get("content-length")
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            // Background work such as prefetching runs outside any client request
+            let _ = USAGE.try_with(|usage| {
+                let mut usage = usage.lock().unwrap();
+                match class {
+                    RequestClass::A => usage.class_a_requests += 1,
+                    RequestClass::B => usage.class_b_requests += 1,
+                    RequestClass::Free => usage.free_requests += 1,
+                }
+                usage.bytes_in += bytes_in;
+                usage.bytes_out += bytes_out;
+            });
+            Ok(response)
+        })
+    }
+}
+
+pub fn wrap(inner: SharedHttpConnector) -> SharedHttpConnector {
+    SharedHttpConnector::new(CountingConnector(inner))
+}
+
+/// Estimates the upstream cost of each request, adds it to the user's total and optionally to the response headers
+pub async fn track(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let Some(costs) = &config.costs else {
+        return next.run(request).await;
+    };
+    let username = request.extensions().get::<AuthState>().map(|auth| auth.username.clone());
+
+    let usage = Arc::new(Mutex::new(Usage::default()));
+    let mut response = USAGE.scope(usage.clone(), next.run(request)).await;
+    let usage = usage.lock().unwrap().clone();
+    let cost = usage.cost(costs);
+
+    if let Some(username) = username {
+        let mut totals = TOTALS.lock().unwrap();
+        let total = totals.entry(username).or_default();
+        total.requests += 1;
+        total.usage.add(&usage);
+        total.cost = total.usage.cost(costs);
+    }
+
+    if costs.response_headers {
+        let headers = response.headers_mut();
+        if let Ok(value) = HeaderValue::from_str(&format!("{:.8} {}", cost, costs.currency)) {
+            headers.insert("X-Proxy-Cost-Estimate", value);
+        }
+        let detail = format!(
+            "class-a={}, class-b={}, bytes-in={}, bytes-out={}",
+            usage.class_a_requests, usage.class_b_requests, usage.bytes_in, usage.bytes_out
+        );
+        if let Ok(value) = HeaderValue::from_str(&detail) {
+            headers.insert("X-Proxy-Cost-Usage", value);
+        }
+    }
+    response
+}
+
+/// Accumulated usage and estimated cost per user since startup
+pub fn report(config: &CostConfig) -> CostReport {
+    CostReport {
+        currency: config.currency.clone(),
+        users: TOTALS.lock().unwrap().clone(),
+    }
+}
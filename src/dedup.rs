@@ -0,0 +1,62 @@
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tracing::debug;
+
+use crate::config::{Config, DedupConfig};
+use crate::error::{AppError, Result};
+use crate::s3::S3Client;
+
+/// Metadata on a pointer object naming the blob that holds its content
+const HASH_METADATA: &str = "content-sha256";
+
+fn blob_key(config: &DedupConfig, hash: &str) -> String {
+    format!("{}{}", config.prefix, hash)
+}
+
+/// Blobs are shared by every key with the same content, so clients may not write them directly
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    match config.dedup.get(bucket) {
+        Some(dedup) if key.starts_with(&dedup.prefix) => Err(AppError::InvalidRequest(format!(
+            "{} is reserved for deduplicated content",
+            dedup.prefix
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Stores the body under its hash unless an identical blob exists, then points `key` at it
+pub async fn put(
+    client: &S3Client,
+    config: &DedupConfig,
+    bucket: &str,
+    key: &str,
+    body: Bytes,
+    content_type: Option<String>,
+) -> Result<Option<String>> {
+    let hash = hex::encode(Sha256::digest(&body));
+    let blob = blob_key(config, &hash);
+    match client.head_object(bucket, &blob).await {
+        Ok(_) => debug!("{}/{} deduplicated to existing blob {}", bucket, key, blob),
+        Err(AppError::ObjectNotFound(_, _)) => {
+            client.put_object(bucket, &blob, ByteStream::from(body), content_type.clone()).await?;
+        }
+        Err(e) => return Err(e),
+    }
+    let metadata = HashMap::from([(HASH_METADATA.to_string(), hash)]);
+    client.put_object_with_metadata(bucket, key, Bytes::new(), metadata, content_type).await
+}
+
+/// The key holding the content of `key`: its blob for pointers, the key itself otherwise
+pub async fn resolve(client: &S3Client, config: &Config, bucket: &str, key: &str) -> Result<String> {
+    let Some(dedup) = config.dedup.get(bucket) else {
+        return Ok(key.to_string());
+    };
+    let metadata = client.object_metadata(bucket, key).await?;
+    // Objects written before deduplication was enabled are not pointers
+    Ok(match metadata.get(HASH_METADATA) {
+        Some(hash) if hash.len() == 64 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => blob_key(dedup, hash),
+        _ => key.to_string(),
+    })
+}
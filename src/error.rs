@@ -1,15 +1,31 @@
 use axum::{
+    extract::{Request, State},
     http::StatusCode,
+    middleware::Next,
     response::{IntoResponse, Response},
 };
+use std::any::Any;
+use std::sync::Arc;
 use thiserror::Error;
+use tracing::warn;
 use aws_sdk_s3::error::SdkError;
 use aws_sdk_s3::operation::{
     list_objects_v2::ListObjectsV2Error,
     get_object::GetObjectError,
     put_object::PutObjectError,
+    list_buckets::ListBucketsError,
+    create_bucket::CreateBucketError,
+    delete_bucket::DeleteBucketError,
+    head_object::HeadObjectError,
+    delete_object::DeleteObjectError,
+    copy_object::CopyObjectError,
 };
 
+use crate::auth::AuthState;
+use crate::config::{Config, UserRole};
+use crate::metrics;
+use crate::trace_context;
+
 #[derive(Error, Debug)]
 pub enum AppError {
     // S3 operation errors
@@ -24,6 +40,24 @@ pub enum AppError {
     
     #[error("S3 PutObject error: {0}")]
     PutObjectError(#[from] SdkError<PutObjectError>),
+
+    #[error("S3 ListBuckets error: {0}")]
+    ListBucketsError(#[from] SdkError<ListBucketsError>),
+
+    #[error("S3 CreateBucket error: {0}")]
+    CreateBucketError(#[from] SdkError<CreateBucketError>),
+
+    #[error("S3 DeleteBucket error: {0}")]
+    DeleteBucketError(#[from] SdkError<DeleteBucketError>),
+
+    #[error("S3 HeadObject error: {0}")]
+    HeadObjectError(#[from] SdkError<HeadObjectError>),
+
+    #[error("S3 DeleteObject error: {0}")]
+    DeleteObjectError(#[from] SdkError<DeleteObjectError>),
+
+    #[error("S3 CopyObject error: {0}")]
+    CopyObjectError(#[from] SdkError<CopyObjectError>),
     
     // Resource not found errors
     #[error("Bucket not found: {0}")]
@@ -31,6 +65,15 @@ pub enum AppError {
     
     #[error("Object not found: {0}/{1}")]
     ObjectNotFound(String, String),
+
+    #[error("Upload session not found: {0}")]
+    UploadNotFound(String),
+
+    #[error("Manifest not found: {0}")]
+    ManifestNotFound(String),
+
+    #[error("User not found: {0}")]
+    UserNotFound(String),
     
     // System errors
     #[error("Configuration error: {0}")]
@@ -43,13 +86,120 @@ pub enum AppError {
     #[error("Unauthorized: {0}")]
     Unauthorized(String),
 
+    #[error("Not allowed to access bucket: {0}")]
+    BucketDenied(String),
+
+    #[error("Blocked: {0}")]
+    Blocked(String),
+
     #[error("Invalid request: {0}")]
     InvalidRequest(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("Range not satisfiable: {0}")]
+    RangeNotSatisfiable(String),
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String),
+
+    #[error("Locked: {0}")]
+    Locked(String),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Service unavailable: {0}")]
+    ServiceUnavailable(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Headers too large: {0}")]
+    HeadersTooLarge(String),
+
+    #[error("URI too long: {0}")]
+    UriTooLong(String),
 }
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+impl AppError {
+    /// Stable code for clients to branch on; messages may change between releases
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::S3Error(_)
+            | AppError::ListObjectsError(_)
+            | AppError::GetObjectError(_)
+            | AppError::PutObjectError(_)
+            | AppError::ListBucketsError(_)
+            | AppError::CreateBucketError(_)
+            | AppError::DeleteBucketError(_)
+            | AppError::HeadObjectError(_)
+            | AppError::DeleteObjectError(_)
+            | AppError::CopyObjectError(_) => "PROXY_UPSTREAM_ERROR",
+            AppError::BucketNotFound(_) => "PROXY_BUCKET_NOT_FOUND",
+            AppError::ObjectNotFound(_, _) => "PROXY_OBJECT_NOT_FOUND",
+            AppError::UploadNotFound(_) => "PROXY_UPLOAD_NOT_FOUND",
+            AppError::ManifestNotFound(_) => "PROXY_MANIFEST_NOT_FOUND",
+            AppError::UserNotFound(_) => "PROXY_USER_NOT_FOUND",
+            AppError::ConfigError(_) => "PROXY_CONFIG_ERROR",
+            AppError::InternalError(_) => "PROXY_INTERNAL_ERROR",
+            AppError::Unauthorized(_) => "PROXY_UNAUTHORIZED",
+            AppError::BucketDenied(_) => "PROXY_BUCKET_DENIED",
+            AppError::Blocked(_) => "PROXY_BLOCKED",
+            AppError::InvalidRequest(_) => "PROXY_INVALID_REQUEST",
+            AppError::Conflict(_) => "PROXY_CONFLICT",
+            AppError::PreconditionFailed(_) => "PROXY_PRECONDITION_FAILED",
+            AppError::RangeNotSatisfiable(_) => "PROXY_RANGE_NOT_SATISFIABLE",
+            AppError::TooManyRequests(_) => "PROXY_RATE_LIMITED",
+            AppError::Locked(_) => "PROXY_LOCKED",
+            AppError::UnsupportedMediaType(_) => "PROXY_UNSUPPORTED_MEDIA_TYPE",
+            AppError::ServiceUnavailable(_) => "PROXY_UNAVAILABLE",
+            AppError::Timeout(_) => "PROXY_TIMEOUT",
+            AppError::HeadersTooLarge(_) => "PROXY_HEADERS_TOO_LARGE",
+            AppError::UriTooLong(_) => "PROXY_URI_TOO_LONG",
+        }
+    }
+}
+
+/// What an internal error's response leaves out, for admins trusted with it
+#[derive(Debug, Clone)]
+pub struct ErrorDetail {
+    pub request_id: String,
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl AppError {
+    /// Whether the message may carry upstream endpoints, request ids or local paths
+    fn is_internal(&self) -> bool {
+        matches!(self.code(), "PROXY_UPSTREAM_ERROR") || matches!(self, AppError::ConfigError(_) | AppError::InternalError(_))
+    }
+
+    /// The status and message the client gets. Internal errors are logged in full under a request
+    /// id instead and answered with a generic message naming it.
+    pub fn into_public(self) -> (StatusCode, String, Option<ErrorDetail>) {
+        let code = self.code();
+        if !self.is_internal() {
+            let (status, message) = self.into_parts();
+            return (status, message, None);
+        }
+        let request_id = trace_context::trace_id().unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+        let (status, message) = self.into_parts();
+        warn!("Request {} failed: {}", request_id, message);
+        let public = match code {
+            "PROXY_UPSTREAM_ERROR" => "Upstream storage request failed",
+            _ => "Internal error",
+        };
+        let detail = ErrorDetail { request_id: request_id.clone(), code, message };
+        (status, format!("{}, request id {}", public, request_id), Some(detail))
+    }
+
+    fn into_parts(self) -> (StatusCode, String) {
+        match self {
             // Not found errors
             AppError::BucketNotFound(bucket) => (
                 StatusCode::NOT_FOUND,
@@ -59,6 +209,18 @@ impl IntoResponse for AppError {
                 StatusCode::NOT_FOUND,
                 format!("Object not found: {}/{}", bucket, key)
             ),
+            AppError::UploadNotFound(upload_id) => (
+                StatusCode::NOT_FOUND,
+                format!("Upload session not found: {}", upload_id)
+            ),
+            AppError::ManifestNotFound(manifest_id) => (
+                StatusCode::NOT_FOUND,
+                format!("Manifest not found: {}", manifest_id)
+            ),
+            AppError::UserNotFound(user) => (
+                StatusCode::NOT_FOUND,
+                format!("User not found: {}", user)
+            ),
             
             // S3 operation errors
             AppError::S3Error(e) => (
@@ -77,6 +239,30 @@ impl IntoResponse for AppError {
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("S3 PutObject error: {}", e)
             ),
+            AppError::ListBucketsError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("S3 ListBuckets error: {}", e)
+            ),
+            AppError::CreateBucketError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("S3 CreateBucket error: {}", e)
+            ),
+            AppError::DeleteBucketError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("S3 DeleteBucket error: {}", e)
+            ),
+            AppError::HeadObjectError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("S3 HeadObject error: {}", e)
+            ),
+            AppError::DeleteObjectError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("S3 DeleteObject error: {}", e)
+            ),
+            AppError::CopyObjectError(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("S3 CopyObject error: {}", e)
+            ),
             
             // System errors
             AppError::ConfigError(e) => (
@@ -93,25 +279,121 @@ impl IntoResponse for AppError {
                 StatusCode::UNAUTHORIZED,
                 e
             ),
+            AppError::BucketDenied(bucket) => (
+                StatusCode::UNAUTHORIZED,
+                format!("Not allowed to access bucket: {}", bucket)
+            ),
+            AppError::Blocked(e) => (
+                StatusCode::FORBIDDEN,
+                e
+            ),
             AppError::InvalidRequest(e) => (
                 StatusCode::BAD_REQUEST,
                 e
             ),
-        };
+            AppError::Conflict(e) => (
+                StatusCode::CONFLICT,
+                e
+            ),
+            AppError::PreconditionFailed(e) => (
+                StatusCode::PRECONDITION_FAILED,
+                e
+            ),
+            AppError::RangeNotSatisfiable(e) => (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                e
+            ),
+            AppError::TooManyRequests(e) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                e
+            ),
+            AppError::Locked(e) => (
+                StatusCode::LOCKED,
+                e
+            ),
+            AppError::UnsupportedMediaType(e) => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                e
+            ),
+            AppError::ServiceUnavailable(e) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                e
+            ),
+            AppError::Timeout(e) => (
+                StatusCode::GATEWAY_TIMEOUT,
+                e
+            ),
+            AppError::HeadersTooLarge(e) => (
+                StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+                e
+            ),
+            AppError::UriTooLong(e) => (
+                StatusCode::URI_TOO_LONG,
+                e
+            ),
+        }
+    }
+}
+
+fn error_response(status: StatusCode, code: &str, message: String, request_id: Option<&str>) -> Response {
+    // Messages may echo keys and query parameters, so they must be escaped
+    let request_id = request_id.map(|id| format!(r#", "request_id": "{}""#, id)).unwrap_or_default();
+    let body = format!(
+        r#"{{"error": {}, "code": "{}", "status": {}{}}}"#,
+        serde_json::Value::String(message),
+        code,
+        status.as_u16(),
+        request_id
+    );
 
-        let body = format!(
-            r#"{{"error": "{}", "status": {}}}"#,
-            error_message,
-            status.as_u16()
-        );
-
-        let mut response = (status, body).into_response();
-        response.headers_mut().insert(
-            "content-type",
-            "application/json".parse().unwrap()
-        );
+    let mut response = (status, body).into_response();
+    response.headers_mut().insert(
+        "content-type",
+        "application/json".parse().unwrap()
+    );
+    response
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let code = self.code();
+        let (status, message, detail) = self.into_public();
+        let mut response = error_response(status, code, message, detail.as_ref().map(|d| d.request_id.as_str()));
+        if let Some(detail) = detail {
+            response.extensions_mut().insert(detail);
+        }
         response
     }
 }
 
+/// Answers a request whose handler panicked with a 500 like any other internal error, instead of
+/// dropping the connection
+pub fn recover_panic(panic: Box<dyn Any + Send + 'static>) -> Response {
+    metrics::record_panic();
+    let message = panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown cause");
+    AppError::InternalError(format!("Handler panicked: {}", message)).into_response()
+}
+
+/// Whether `auth` may see the full message of internal errors
+pub fn details_allowed(config: &Config, auth: &AuthState) -> bool {
+    auth.role == UserRole::Admin && config.errors.admin_details
+}
+
+/// Shows admins the full message of internal errors when the config allows it
+pub async fn reveal_details(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let allowed = request.extensions().get::<AuthState>().is_some_and(|auth| details_allowed(&config, auth));
+    let response = next.run(request).await;
+    if !allowed {
+        return response;
+    }
+    match response.extensions().get::<ErrorDetail>().cloned() {
+        Some(detail) => error_response(response.status(), detail.code, detail.message, Some(&detail.request_id)),
+        None => response,
+    }
+}
+
 pub type Result<T> = std::result::Result<T, AppError>; 
\ No newline at end of file
@@ -0,0 +1,63 @@
+use axum::http::{header, HeaderMap};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::error::{AppError, Result};
+use crate::s3::ObjectPart;
+
+/// Metadata holding the ETag the proxy generated when the object was written
+pub const METADATA: &str = "proxy-etag";
+
+/// Whether writes to `bucket` store a stable ETag
+pub fn is_stable(config: &Config, bucket: &str) -> bool {
+    config.etags.stable.iter().any(|stable| stable == bucket)
+}
+
+/// A quoted ETag derived from a SHA-256 digest of the content, the same on every backend
+pub fn from_digest(digest: &[u8]) -> String {
+    format!("\"{}\"", &hex::encode(digest)[..32])
+}
+
+pub fn from_body(body: &[u8]) -> String {
+    from_digest(&Sha256::digest(body))
+}
+
+pub fn metadata(etag: &str) -> HashMap<String, String> {
+    HashMap::from([(METADATA.to_string(), etag.to_string())])
+}
+
+/// `"abc"` for `W/"abc"`, `abc` and `"abc"` alike
+pub fn normalize(etag: &str) -> String {
+    let etag = etag.trim();
+    let etag = etag.strip_prefix("W/").unwrap_or(etag);
+    format!("\"{}\"", etag.trim_matches('"'))
+}
+
+/// The ETag sent to clients for an object read from `bucket`
+pub fn served(config: &Config, part: &ObjectPart) -> Option<String> {
+    match (&part.proxy_etag, &part.etag) {
+        (Some(proxy_etag), _) => Some(proxy_etag.clone()),
+        (None, Some(etag)) if config.etags.normalize => Some(normalize(etag)),
+        (None, etag) => etag.clone(),
+    }
+}
+
+fn list_matches(header: &str, etag: Option<&str>) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || etag.is_some_and(|etag| normalize(candidate) == normalize(etag))
+    })
+}
+
+/// Evaluates If-Match and If-None-Match against the served ETag; true means 304 Not Modified
+pub fn not_modified(headers: &HeaderMap, etag: Option<&str>) -> Result<bool> {
+    if let Some(if_match) = headers.get(header::IF_MATCH).and_then(|v| v.to_str().ok()) {
+        // If-Match uses the strong comparison, which weak ETags never pass
+        let strong = etag.filter(|etag| !etag.trim_start().starts_with("W/"));
+        if !list_matches(if_match, strong) {
+            return Err(AppError::PreconditionFailed("If-Match does not match the current ETag".to_string()));
+        }
+    }
+    let if_none_match = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    Ok(if_none_match.is_some_and(|if_none_match| list_matches(if_none_match, etag)))
+}
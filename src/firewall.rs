@@ -0,0 +1,165 @@
+use axum::{
+    extract::{Query, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::config::{wildcard_match, Config, FirewallAction, FirewallRule};
+use crate::error::AppError;
+use crate::metrics;
+use crate::server;
+use crate::sigv4;
+
+fn matches(rule: &FirewallRule, request: &Request, paths: &[String]) -> bool {
+    let method = rule.methods.is_empty()
+        || rule.methods.iter().any(|method| method.eq_ignore_ascii_case(request.method().as_str()));
+    let path = rule.paths.is_empty()
+        || paths.iter().any(|path| rule.paths.iter().any(|pattern| wildcard_match(pattern, path)));
+    let user_agent = rule.user_agents.is_empty()
+        || request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .is_some_and(|agent| {
+                let agent = agent.to_ascii_lowercase();
+                rule.user_agents.iter().any(|pattern| wildcard_match(&pattern.to_ascii_lowercase(), &agent))
+            });
+    let missing = rule.require_headers.is_empty()
+        || rule.require_headers.iter().any(|name| !request.headers().contains_key(name.as_str()));
+    method && path && user_agent && missing
+}
+
+/// The path with its %XX escapes decoded, None when an escape is malformed or the result is not UTF-8
+fn decode_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let escape = bytes.get(i + 1..i + 3)?;
+            if !escape.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    String::from_utf8(sigv4::percent_decode_bytes(path)).ok()
+}
+
+/// The paths rules are matched against: the decoded request path and, for object requests, the
+/// key it is served from after rewrites, plus a rename's destination the same two ways
+fn request_paths(config: &Config, request: &Request, path: &str) -> Vec<String> {
+    let mut paths = vec![path.to_string()];
+    let Some((bucket, key)) = path.strip_prefix('/').and_then(|path| path.split_once('/')) else {
+        return paths;
+    };
+    if key.is_empty() {
+        return paths;
+    }
+    let write = request.method() == http::Method::PUT;
+    let mut push = |path: String| {
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    };
+    push(format!("/{}/{}", bucket, server::request_key(config, bucket, key, write)));
+    if let Ok(Query(params)) = Query::<HashMap<String, String>>::try_from_uri(request.uri()) {
+        if let Some(destination) = params.get("rename") {
+            push(format!("/{}/{}", bucket, destination));
+            push(format!("/{}/{}", bucket, server::request_key(config, bucket, destination, false)));
+        }
+    }
+    paths
+}
+
+/// The first rule matching the request, if any
+fn matching_rule<'a>(config: &'a Config, request: &Request, path: &str) -> Option<&'a FirewallRule> {
+    let firewall = config.firewall.as_ref()?;
+    let paths = request_paths(config, request, path);
+    firewall.rules.iter().find(|rule| matches(rule, request, &paths))
+}
+
+/// Decides requests by the first matching firewall rule, before they are authenticated; paths are
+/// matched decoded, so percent-encoding a key does not slip past a pattern, and so are the keys a
+/// request ends up writing
+pub async fn check(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let Some(firewall) = &config.firewall else {
+        return next.run(request).await;
+    };
+    let Some(path) = decode_path(request.uri().path()) else {
+        metrics::record_firewall_hit("invalid_path", "deny");
+        return AppError::InvalidRequest("The path is not valid percent-encoded UTF-8".to_string()).into_response();
+    };
+    let rule = matching_rule(&config, &request, &path);
+    let action = rule.map_or(firewall.default_action, |rule| rule.action);
+    let name = rule.map_or("default", |rule| rule.name.as_str());
+    // Requests passing by default are the normal case, not hits
+    if rule.is_some() || action == FirewallAction::Deny {
+        let label = match action {
+            FirewallAction::Allow => "allow",
+            FirewallAction::Deny => "deny",
+        };
+        metrics::record_firewall_hit(name, label);
+    }
+    if action == FirewallAction::Deny {
+        debug!("Firewall rule {} blocked {} {}", name, request.method(), path);
+        return AppError::Blocked(format!("Refused by firewall rule {}", name)).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::Request};
+    use serde_json::json;
+
+    use super::{decode_path, matching_rule};
+    use crate::config::Config;
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "accounts": {},
+            "users": {},
+            "server": { "host": "127.0.0.1", "port": 8080 },
+            "key_rewrites": { "b": [{ "pattern": "^(.*)\\.txt$", "template": "$1.php" }] },
+            "firewall": { "rules": [{ "name": "php", "paths": ["*.php"] }] }
+        }))
+        .unwrap()
+    }
+
+    fn request(method: &str, uri: &str) -> Request {
+        Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn decode_path_refuses_malformed_escapes_and_invalid_utf8() {
+        assert_eq!(decode_path("/b/x%2Ephp").as_deref(), Some("/b/x.php"));
+        assert_eq!(decode_path("/b/caf%C3%A9").as_deref(), Some("/b/caf\u{e9}"));
+        assert_eq!(decode_path("/b/%a\u{e9}"), None);
+        assert_eq!(decode_path("/b/x%2"), None);
+        assert_eq!(decode_path("/b/%FF.php"), None);
+    }
+
+    #[test]
+    fn rules_match_rename_destinations() {
+        let config = config();
+        let rename = request("POST", "/b/a.md?rename=shell%2Ephp");
+        assert!(matching_rule(&config, &rename, "/b/a.md").is_some());
+        let rename = request("POST", "/b/a.md?rename=notes.md");
+        assert!(matching_rule(&config, &rename, "/b/a.md").is_none());
+    }
+
+    #[test]
+    fn rules_match_rewritten_keys() {
+        let config = config();
+        let put = request("PUT", "/b/shell.txt");
+        assert!(matching_rule(&config, &put, "/b/shell.txt").is_some());
+        let other = request("PUT", "/other/shell.txt");
+        assert!(matching_rule(&config, &other, "/other/shell.txt").is_none());
+    }
+}
@@ -0,0 +1,36 @@
+use futures::future::{self, Either};
+use std::future::Future;
+use std::time::Duration;
+
+use crate::error::Result;
+use crate::metrics;
+
+/// Awaits `original`, also sending `hedge` once `original` has run for `delay`; the first success
+/// wins and the other request is dropped. An error only counts once both requests failed, and is
+/// then the original's
+pub async fn race<T>(
+    bucket: &str,
+    delay: Duration,
+    original: impl Future<Output = Result<T>>,
+    hedge: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    // Reads are large futures, two of them would not fit on the stack
+    let mut original = Box::pin(original);
+    if let Ok(result) = tokio::time::timeout(delay, &mut original).await {
+        return result;
+    }
+    let (result, winner) = match future::select(original, Box::pin(hedge)).await {
+        Either::Left((Ok(value), _)) => (Ok(value), "original"),
+        Either::Right((Ok(value), _)) => (Ok(value), "hedge"),
+        Either::Left((Err(e), hedge)) => match hedge.await {
+            Ok(value) => (Ok(value), "hedge"),
+            Err(_) => (Err(e), "neither"),
+        },
+        Either::Right((Err(_), original)) => match original.await {
+            Ok(value) => (Ok(value), "original"),
+            Err(e) => (Err(e), "neither"),
+        },
+    };
+    metrics::record_hedged_read(bucket, winner);
+    result
+}
@@ -0,0 +1,120 @@
+use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tracing::{info, warn};
+
+use crate::config::LegalHoldConfig;
+use crate::error::{AppError, Result};
+
+pub use s3_proxy_client::types::LegalHold;
+
+fn covers(hold: &LegalHold, bucket: &str, key: Option<&str>) -> bool {
+    hold.bucket == bucket && key.is_none_or(|key| key.starts_with(&hold.prefix))
+}
+
+pub struct LegalHolds {
+    /// Holds are saved here on every change so they survive restarts
+    state_file: Option<PathBuf>,
+    holds: RwLock<Vec<LegalHold>>,
+}
+
+impl LegalHolds {
+    pub fn new(config: &LegalHoldConfig) -> Result<Self> {
+        let state_file = config.state_file.as_ref().map(PathBuf::from);
+        let holds: Vec<LegalHold> = match &state_file {
+            Some(path) if path.exists() => {
+                let data = std::fs::read(path)?;
+                serde_json::from_slice(&data).map_err(|e| {
+                    AppError::InternalError(format!("Invalid legal hold state file {}: {}", path.display(), e))
+                })?
+            }
+            _ => Vec::new(),
+        };
+        for hold in &holds {
+            info!("Legal hold on {}/{} in effect since {}", hold.bucket, hold.prefix, hold.placed_at);
+        }
+        Ok(Self {
+            state_file,
+            holds: RwLock::new(holds),
+        })
+    }
+
+    /// Writes the new set of holds before it takes effect, so a hold is never only in memory
+    fn save(&self, holds: &[LegalHold]) -> Result<()> {
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(holds).map_err(|e| AppError::InternalError(e.to_string()))?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<LegalHold> {
+        self.holds.read().unwrap().clone()
+    }
+
+    pub fn place(&self, bucket: &str, prefix: &str, reason: Option<String>, placed_by: &str) -> Result<LegalHold> {
+        let mut holds = self.holds.write().unwrap();
+        if holds.iter().any(|hold| hold.bucket == bucket && hold.prefix == prefix) {
+            return Err(AppError::Conflict(format!("{}/{} is already under legal hold", bucket, prefix)));
+        }
+        let hold = LegalHold {
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            reason,
+            placed_by: placed_by.to_string(),
+            placed_at: Utc::now(),
+        };
+        let mut updated = holds.clone();
+        updated.push(hold.clone());
+        self.save(&updated)?;
+        *holds = updated;
+
+        warn!(
+            "Legal hold placed on {}/{} by {}: {}",
+            bucket,
+            prefix,
+            placed_by,
+            hold.reason.as_deref().unwrap_or("no reason given")
+        );
+        Ok(hold)
+    }
+
+    pub fn release(&self, bucket: &str, prefix: &str, released_by: &str) -> Result<LegalHold> {
+        let mut holds = self.holds.write().unwrap();
+        let index = holds
+            .iter()
+            .position(|hold| hold.bucket == bucket && hold.prefix == prefix)
+            .ok_or_else(|| AppError::InvalidRequest(format!("No legal hold on {}/{}", bucket, prefix)))?;
+        let mut updated = holds.clone();
+        let hold = updated.remove(index);
+        self.save(&updated)?;
+        *holds = updated;
+
+        warn!(
+            "Legal hold on {}/{} placed by {} at {} released by {}",
+            bucket, prefix, hold.placed_by, hold.placed_at, released_by
+        );
+        Ok(hold)
+    }
+
+    pub fn is_held(&self, bucket: &str, key: &str) -> bool {
+        self.holds.read().unwrap().iter().any(|hold| covers(hold, bucket, Some(key)))
+    }
+
+    /// Rejects a write or delete of `key`, or with None of anything in the bucket, under a hold
+    pub fn check(&self, bucket: &str, key: Option<&str>) -> Result<()> {
+        let holds = self.holds.read().unwrap();
+        let Some(hold) = holds.iter().find(|hold| covers(hold, bucket, key)) else {
+            return Ok(());
+        };
+        let target = match key {
+            Some(key) => format!("{}/{}", bucket, key),
+            None => bucket.to_string(),
+        };
+        warn!("Rejected change to {} under the legal hold on {}/{}", target, hold.bucket, hold.prefix);
+        Err(AppError::Locked(format!("{} is under legal hold", target)))
+    }
+}
@@ -0,0 +1,171 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+    Router,
+};
+use bytes::{Buf, Bytes};
+use http::{header, HeaderValue};
+use http_body_util::BodyExt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tower::ServiceExt;
+use tracing::{debug, info};
+
+use crate::config::{Config, ServerConfig};
+use crate::error::{AppError, Result};
+use crate::tls;
+
+type RequestStream<S> = h3::server::RequestStream<S, Bytes>;
+
+/// Headers of HTTP/1.1 connections that HTTP/3 forbids
+const CONNECTION_HEADERS: [header::HeaderName; 4] =
+    [header::CONNECTION, header::TRANSFER_ENCODING, header::UPGRADE, header::HeaderName::from_static("keep-alive")];
+
+/// Binds the UDP socket like the TCP listener, so a replacement process can bind it alongside
+fn bind(server: &ServerConfig, port: u16) -> Result<std::net::UdpSocket> {
+    let addr = format!("{}:{}", server.host, port);
+    let failed = |e: std::io::Error| AppError::InternalError(format!("Failed to bind HTTP/3 to {}: {}", addr, e));
+    let resolved = std::net::ToSocketAddrs::to_socket_addrs(&addr)
+        .map_err(failed)?
+        .next()
+        .ok_or_else(|| AppError::InternalError(format!("Failed to bind HTTP/3 to {}: no address", addr)))?;
+    let socket = socket2::Socket::new(socket2::Domain::for_address(resolved), socket2::Type::DGRAM, None).map_err(failed)?;
+    #[cfg(unix)]
+    if server.reuse_port {
+        socket.set_reuse_port(true).map_err(failed)?;
+    }
+    socket.bind(&resolved.into()).map_err(failed)?;
+    Ok(socket.into())
+}
+
+/// Serves `app` over HTTP/3 when configured; the endpoint is returned to be closed on shutdown
+pub fn spawn(app: Router, server: &ServerConfig) -> Result<Option<quinn::Endpoint>> {
+    let Some(config) = &server.http3 else {
+        return Ok(None);
+    };
+    let tls = tls::server_config(&config.cert_path, &config.key_path, &[&rustls::version::TLS13], &[b"h3"])?;
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+        .map_err(|e| AppError::InternalError(format!("Failed to set up HTTP/3: {}", e)))?;
+    let port = config.port.unwrap_or(server.port);
+    let endpoint = quinn::Endpoint::new(
+        quinn::EndpointConfig::default(),
+        Some(quinn::ServerConfig::with_crypto(Arc::new(crypto))),
+        bind(server, port)?,
+        Arc::new(quinn::TokioRuntime),
+    )
+    .map_err(|e| AppError::InternalError(format!("Failed to start HTTP/3: {}", e)))?;
+    info!("Serving HTTP/3 on {}:{} (UDP)", server.host, port);
+
+    let accepting = endpoint.clone();
+    tokio::spawn(async move {
+        while let Some(incoming) = accepting.accept().await {
+            tokio::spawn(serve_connection(incoming, app.clone()));
+        }
+    });
+    Ok(Some(endpoint))
+}
+
+async fn serve_connection(incoming: quinn::Incoming, app: Router) {
+    let connection = match incoming.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("HTTP/3 handshake failed: {}", e);
+            return;
+        }
+    };
+    let peer = connection.remote_address();
+    let mut connection = match h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(connection)).await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("HTTP/3 connection from {} failed: {}", peer, e);
+            return;
+        }
+    };
+    loop {
+        match connection.accept().await {
+            Ok(Some(resolver)) => {
+                let app = app.clone();
+                tokio::spawn(async move {
+                    let (request, stream) = match resolver.resolve_request().await {
+                        Ok(resolved) => resolved,
+                        Err(e) => {
+                            debug!("Invalid HTTP/3 request from {}: {}", peer, e);
+                            return;
+                        }
+                    };
+                    let (send, recv) = stream.split();
+                    if let Err(e) = serve_request(app, peer, request, send, recv).await {
+                        debug!("HTTP/3 request from {} failed: {}", peer, e);
+                    }
+                });
+            }
+            Ok(None) => break,
+            Err(e) => {
+                if !e.is_h3_no_error() {
+                    debug!("HTTP/3 connection from {} closed: {}", peer, e);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Runs one request through the same router, middlewares and auth as HTTP/1.1 and HTTP/2
+async fn serve_request(
+    app: Router,
+    peer: SocketAddr,
+    request: http::Request<()>,
+    mut send: RequestStream<h3_quinn::SendStream<Bytes>>,
+    recv: RequestStream<h3_quinn::RecvStream>,
+) -> std::result::Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let body = futures::stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+        match recv.recv_data().await {
+            Ok(Some(mut data)) => Some((Ok(data.copy_to_bytes(data.remaining())), Some(recv))),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    let (mut parts, ()) = request.into_parts();
+    // Signatures cover Host, which HTTP/3 carries as the :authority pseudo-header instead
+    if let Some(authority) = parts.uri.authority() {
+        if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+            parts.headers.entry(header::HOST).or_insert(host);
+        }
+    }
+    parts.extensions.insert(ConnectInfo(peer));
+    let request = Request::from_parts(parts, Body::from_stream(body));
+
+    let response = app.oneshot(request).await?;
+    let (mut parts, mut body) = response.into_parts();
+    for name in &CONNECTION_HEADERS {
+        parts.headers.remove(name);
+    }
+    send.send_response(http::Response::from_parts(parts, ())).await?;
+    while let Some(frame) = body.frame().await {
+        match frame?.into_data() {
+            Ok(data) => send.send_data(data).await?,
+            Err(frame) => {
+                if let Ok(trailers) = frame.into_trailers() {
+                    send.send_trailers(trailers).await?;
+                }
+            }
+        }
+    }
+    send.finish().await?;
+    Ok(())
+}
+
+/// Points HTTP/1.1 and HTTP/2 clients to the HTTP/3 endpoint
+pub async fn advertise(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    if let Some(http3) = &config.server.http3 {
+        let port = http3.port.unwrap_or(config.server.port);
+        if let Ok(value) = HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", port)) {
+            response.headers_mut().insert(header::ALT_SVC, value);
+        }
+    }
+    response
+}
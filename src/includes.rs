@@ -0,0 +1,112 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::config::{self, wildcard_match};
+use crate::error::{AppError, Result};
+
+fn invalid(message: String) -> AppError {
+    AppError::ConfigError(std::io::Error::new(std::io::ErrorKind::InvalidData, message))
+}
+
+/// Files an include names, relative to the including file; "*" in the file name matches any run
+/// of characters, and its matches are taken in name order
+fn expand(base: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let path = base.join(pattern);
+    let Some(name) = path.file_name().and_then(|name| name.to_str()).filter(|name| name.contains('*')) else {
+        return Ok(vec![path]);
+    };
+    let dir = path.parent().unwrap_or(base);
+    if dir.to_string_lossy().contains('*') {
+        return Err(invalid(format!("Include {}: only the file name may contain *", pattern)));
+    }
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|file| file.is_file())
+        .filter(|file| file.file_name().and_then(|n| n.to_str()).is_some_and(|n| wildcard_match(name, n)))
+        .collect();
+    files.sort();
+    if files.is_empty() {
+        warn!("Include {} matches no files", pattern);
+    }
+    Ok(files)
+}
+
+/// Merges the files listed in `include` of the config at `path` into it. Object sections such as
+/// users and accounts are merged key by key; a key, or any other section, set in two files is a
+/// conflict and fails the load
+pub fn merge(path: &str, config: &mut Value) -> Result<()> {
+    let Some(root) = config.as_object_mut() else {
+        return Ok(());
+    };
+    let includes = match root.shift_remove("include") {
+        None => return Ok(()),
+        Some(Value::Array(includes)) => includes,
+        Some(_) => return Err(invalid(format!("{}: include must be a list of paths", path))),
+    };
+
+    // File each section, and each key of an object section, was set in
+    let mut sources: HashMap<String, String> = HashMap::new();
+    for (section, value) in root.iter() {
+        sources.insert(section.clone(), path.to_string());
+        if let Value::Object(entries) = value {
+            for name in entries.keys() {
+                sources.insert(format!("{}.{}", section, name), path.to_string());
+            }
+        }
+    }
+
+    let base = Path::new(path).parent().unwrap_or(Path::new("."));
+    for include in includes {
+        let Value::String(pattern) = include else {
+            return Err(invalid(format!("{}: include must be a list of paths", path)));
+        };
+        for file in expand(base, &pattern)? {
+            let file = file.to_string_lossy().into_owned();
+            let Value::Object(fragment) = config::read_json(&file)? else {
+                return Err(invalid(format!("{}: not a JSON object", file)));
+            };
+            merge_fragment(root, &mut sources, &file, fragment)?;
+            info!("Included {} in {}", file, path);
+        }
+    }
+    Ok(())
+}
+
+fn merge_fragment(
+    root: &mut Map<String, Value>,
+    sources: &mut HashMap<String, String>,
+    file: &str,
+    fragment: Map<String, Value>,
+) -> Result<()> {
+    let conflict = |what: String, first: &str| invalid(format!("{} is set in both {} and {}", what, first, file));
+    for (section, value) in fragment {
+        if section == "include" || section == "version" {
+            return Err(invalid(format!("{}: {} may only be set in the main config file", file, section)));
+        }
+        match (root.get_mut(&section), value) {
+            (Some(Value::Object(entries)), Value::Object(added)) => {
+                for (name, entry) in added {
+                    let key = format!("{}.{}", section, name);
+                    if let Some(first) = sources.get(&key) {
+                        return Err(conflict(key, first));
+                    }
+                    sources.insert(key, file.to_string());
+                    entries.insert(name, entry);
+                }
+            }
+            (Some(_), _) => return Err(conflict(section.clone(), &sources[&section])),
+            (None, value) => {
+                if let Value::Object(entries) = &value {
+                    for name in entries.keys() {
+                        sources.insert(format!("{}.{}", section, name), file.to_string());
+                    }
+                }
+                sources.insert(section.clone(), file.to_string());
+                root.insert(section, value);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::Utc;
+use flate2::{write::GzEncoder, Compression};
+use serde::Serialize;
+use utoipa::ToSchema;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::IngestConfig;
+use crate::error::{AppError, Result};
+use crate::metrics;
+use crate::server::AppState;
+
+const FLUSH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Events of one stream waiting to be written
+pub struct Batch {
+    data: Vec<u8>,
+    events: usize,
+    opened: Instant,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Accepted {
+    pub bucket: String,
+    pub stream: String,
+    pub events: usize,
+    /// Bytes of the stream not yet written, including these events
+    pub buffered_bytes: usize,
+}
+
+fn valid_stream(stream: &str) -> bool {
+    !stream.is_empty()
+        && stream.len() <= 128
+        && !stream.starts_with('.')
+        && stream.bytes().all(|b| b.is_ascii_alphanumeric() || b"._-".contains(&b))
+}
+
+/// Buffered events by bucket and stream
+#[derive(Default)]
+pub struct Ingestor {
+    batches: Mutex<HashMap<(String, String), Batch>>,
+}
+
+impl Ingestor {
+    /// Buffers the newline-delimited events of `body`, returning the stream's batch when it is due for writing
+    pub fn append(&self, config: &IngestConfig, bucket: &str, stream: &str, body: &[u8]) -> Result<(Accepted, Option<Batch>)> {
+        if !valid_stream(stream) {
+            return Err(AppError::InvalidRequest(format!("Invalid stream name: {}", stream)));
+        }
+        let text = std::str::from_utf8(body)
+            .map_err(|_| AppError::InvalidRequest("Events must be UTF-8 text".to_string()))?;
+        // Either every event of the request is buffered or none is
+        let events: Vec<&str> = text.lines().filter(|line| !line.trim().is_empty()).collect();
+        if let Some(event) = events.iter().find(|event| event.len() > config.max_event_bytes) {
+            return Err(AppError::InvalidRequest(format!(
+                "Event of {} bytes exceeds the limit of {} bytes",
+                event.len(),
+                config.max_event_bytes
+            )));
+        }
+
+        let mut batches = self.batches.lock().unwrap();
+        let buffered: usize = batches.values().map(|batch| batch.data.len()).sum();
+        if buffered + body.len() > config.max_buffered_bytes {
+            return Err(AppError::TooManyRequests("Too many events are waiting to be written".to_string()));
+        }
+        let id = (bucket.to_string(), stream.to_string());
+        let batch = batches.entry(id.clone()).or_insert_with(|| Batch {
+            data: Vec::new(),
+            events: 0,
+            opened: Instant::now(),
+        });
+        for event in &events {
+            batch.data.extend_from_slice(event.as_bytes());
+            batch.data.push(b'\n');
+        }
+        batch.events += events.len();
+
+        let accepted = Accepted {
+            bucket: bucket.to_string(),
+            stream: stream.to_string(),
+            events: events.len(),
+            buffered_bytes: batch.data.len(),
+        };
+        let full = if batch.data.len() >= config.batch_bytes { batches.remove(&id) } else { None };
+        Ok((accepted, full))
+    }
+
+    fn take(&self, due: impl Fn(&Batch) -> bool) -> Vec<((String, String), Batch)> {
+        let mut batches = self.batches.lock().unwrap();
+        let ids: Vec<_> = batches.iter().filter(|(_, batch)| due(batch)).map(|(id, _)| id.clone()).collect();
+        ids.into_iter()
+            .filter_map(|id| batches.remove(&id).map(|batch| (id, batch)))
+            .collect()
+    }
+
+    /// Puts a batch that could not be written back in front of events buffered since
+    fn restore(&self, bucket: &str, stream: &str, mut batch: Batch) {
+        let mut batches = self.batches.lock().unwrap();
+        if let Some(newer) = batches.remove(&(bucket.to_string(), stream.to_string())) {
+            batch.data.extend_from_slice(&newer.data);
+            batch.events += newer.events;
+        }
+        batches.insert((bucket.to_string(), stream.to_string()), batch);
+    }
+}
+
+fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).map_err(|e| AppError::InternalError(e.to_string()))?;
+    encoder.finish().map_err(|e| AppError::InternalError(e.to_string()))
+}
+
+async fn write(state: &AppState, config: &IngestConfig, bucket: &str, stream: &str, batch: &Batch) -> Result<String> {
+    let now = Utc::now();
+    let key = format!(
+        "{}{}/{}/{}-{}.ndjson.gz",
+        config.prefix,
+        stream,
+        now.format("%Y/%m/%d"),
+        now.format("%Y%m%dT%H%M%SZ"),
+        &uuid::Uuid::new_v4().simple().to_string()[..8]
+    );
+    state.holds.check(bucket, Some(&key))?;
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    let body = compress(&batch.data)?;
+    let size = body.len();
+    client
+        .put_object(bucket, &key, ByteStream::from(body), Some("application/gzip".to_string()))
+        .await?;
+    metrics::record_upload(bucket, size);
+    Ok(key)
+}
+
+/// Writes a batch as one compressed object; on failure the events stay buffered for the next flush
+pub async fn flush(state: &AppState, bucket: &str, stream: &str, batch: Batch) {
+    let Some(config) = &state.config.ingest else {
+        return;
+    };
+    match write(state, config, bucket, stream, &batch).await {
+        Ok(key) => info!(
+            "Wrote {} events ({} bytes) of stream {} to {}/{}",
+            batch.events,
+            batch.data.len(),
+            stream,
+            bucket,
+            key
+        ),
+        Err(e) => {
+            warn!("Failed to write {} events of stream {} to {}: {}", batch.events, stream, bucket, e);
+            state.ingest.restore(bucket, stream, batch);
+        }
+    }
+}
+
+/// Writes every buffered batch, e.g. before shutting down
+pub async fn flush_all(state: &AppState) {
+    for ((bucket, stream), batch) in state.ingest.take(|_| true) {
+        flush(state, &bucket, &stream, batch).await;
+    }
+}
+
+/// Periodically writes batches whose oldest event has waited for the flush interval
+pub fn spawn_flush(state: Arc<AppState>) {
+    let Some(config) = &state.config.ingest else {
+        return;
+    };
+    let interval = Duration::from_secs(config.flush_interval_secs);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(FLUSH_CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            for ((bucket, stream), batch) in state.ingest.take(|batch| batch.opened.elapsed() >= interval) {
+                flush(&state, &bucket, &stream, batch).await;
+            }
+        }
+    });
+}
@@ -0,0 +1,152 @@
+use futures::StreamExt;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::config::InvalidationConfig;
+use crate::error::{AppError, Result};
+use crate::server::AppState;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// A write on one replica; `key` None means the whole bucket
+#[derive(Debug, Serialize, Deserialize)]
+struct Invalidation {
+    origin: String,
+    bucket: String,
+    key: Option<String>,
+    /// ETag of the new version, replicas already holding it keep their entry
+    etag: Option<String>,
+}
+
+/// Publishes local writes to other replicas and applies theirs to the local cache
+pub struct InvalidationBus {
+    client: redis::Client,
+    channel: String,
+    /// Identifies this replica so it ignores its own messages
+    origin: String,
+    sender: mpsc::UnboundedSender<Invalidation>,
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Invalidation>>>,
+}
+
+impl InvalidationBus {
+    pub fn new(config: &InvalidationConfig) -> Result<Self> {
+        let client = redis::Client::open(config.redis_url.as_str())
+            .map_err(|e| AppError::InternalError(format!("Invalid invalidation redis_url: {}", e)))?;
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok(Self {
+            client,
+            channel: config.channel.clone(),
+            origin: format!("{:x}-{:x}", std::process::id(), nanos),
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+        })
+    }
+
+    /// Queues a broadcast; never blocks the request on Redis
+    pub fn publish(&self, bucket: &str, key: Option<&str>, etag: Option<&str>) {
+        let _ = self.sender.send(Invalidation {
+            origin: self.origin.clone(),
+            bucket: bucket.to_string(),
+            key: key.map(String::from),
+            etag: etag.map(String::from),
+        });
+    }
+}
+
+async fn publish_loop(client: redis::Client, channel: String, mut receiver: mpsc::UnboundedReceiver<Invalidation>) {
+    let mut connection = None;
+    while let Some(message) = receiver.recv().await {
+        let payload = match serde_json::to_string(&message) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Failed to encode cache invalidation: {}", e);
+                continue;
+            }
+        };
+        if connection.is_none() {
+            match client.get_multiplexed_async_connection().await {
+                Ok(c) => connection = Some(c),
+                Err(e) => {
+                    warn!("Dropping cache invalidation for {}, Redis unavailable: {}", message.bucket, e);
+                    continue;
+                }
+            }
+        }
+        if let Some(c) = connection.as_mut() {
+            if let Err(e) = c.publish::<_, _, ()>(&channel, payload).await {
+                warn!("Failed to publish cache invalidation for {}: {}", message.bucket, e);
+                connection = None;
+            }
+        }
+    }
+}
+
+async fn subscribe_loop(state: Arc<AppState>, client: redis::Client, channel: String, origin: String) {
+    let Some(cache) = &state.cache else {
+        return;
+    };
+    let mut connected_before = false;
+    loop {
+        match client.get_async_pubsub().await {
+            Ok(mut pubsub) => match pubsub.subscribe(&channel).await {
+                Ok(()) => {
+                    // Invalidations sent while disconnected are lost, so nothing cached can be trusted
+                    if connected_before {
+                        info!("Resubscribed to cache invalidations, dropping cached objects");
+                        cache.clear();
+                    } else {
+                        info!("Subscribed to cache invalidations on {}", channel);
+                    }
+                    connected_before = true;
+
+                    let mut messages = pubsub.on_message();
+                    while let Some(message) = messages.next().await {
+                        let payload: String = match message.get_payload() {
+                            Ok(payload) => payload,
+                            Err(e) => {
+                                warn!("Invalid cache invalidation payload: {}", e);
+                                continue;
+                            }
+                        };
+                        let invalidation: Invalidation = match serde_json::from_str(&payload) {
+                            Ok(invalidation) => invalidation,
+                            Err(e) => {
+                                warn!("Invalid cache invalidation {}: {}", payload, e);
+                                continue;
+                            }
+                        };
+                        if invalidation.origin == origin {
+                            continue;
+                        }
+                        debug!("Invalidating {}/{:?} from {}", invalidation.bucket, invalidation.key, invalidation.origin);
+                        match &invalidation.key {
+                            Some(key) => cache.invalidate_stale(&invalidation.bucket, key, invalidation.etag.as_deref()),
+                            None => cache.invalidate_bucket(&invalidation.bucket),
+                        }
+                    }
+                    warn!("Lost the cache invalidation subscription");
+                }
+                Err(e) => warn!("Failed to subscribe to cache invalidations: {}", e),
+            },
+            Err(e) => warn!("Failed to connect to Redis for cache invalidations: {}", e),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Starts publishing local writes and applying writes of other replicas
+pub fn spawn(state: Arc<AppState>) {
+    let Some(bus) = &state.invalidation else {
+        return;
+    };
+    let Some(receiver) = bus.receiver.lock().unwrap().take() else {
+        return;
+    };
+    tokio::spawn(publish_loop(bus.client.clone(), bus.channel.clone(), receiver));
+    tokio::spawn(subscribe_loop(state.clone(), bus.client.clone(), bus.channel.clone(), bus.origin.clone()));
+}
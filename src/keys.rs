@@ -0,0 +1,229 @@
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn};
+
+use crate::config::{matching_bucket_grant, DelegatedKeysConfig, UserConfig, UserRole};
+use crate::config_store::ConfigStore;
+use crate::error::{AppError, Result};
+
+pub use s3_proxy_client::types::{DelegatedKey, IssuedKey, KeyRequest};
+
+/// Only a hash of each key is kept, so the state file holds no usable credentials
+fn hash(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+pub struct DelegatedKeys {
+    max_ttl_secs: u64,
+    max_per_user: usize,
+    /// Keys are saved here on every change so they survive restarts
+    state_file: Option<PathBuf>,
+    /// Takes the place of the state file, shared with other replicas
+    store: Option<Arc<ConfigStore>>,
+    /// By hash of the secret
+    keys: RwLock<HashMap<String, DelegatedKey>>,
+}
+
+impl DelegatedKeys {
+    pub fn new(config: &DelegatedKeysConfig, store: Option<Arc<ConfigStore>>) -> Result<Self> {
+        let state_file = config.state_file.as_ref().map(PathBuf::from);
+        if let (Some(path), Some(_)) = (&state_file, &store) {
+            warn!("Delegated keys are kept in the config store, {} is ignored", path.display());
+        }
+        let keys: HashMap<String, DelegatedKey> = match (&state_file, &store) {
+            (_, Some(store)) => store.delegated_keys()?,
+            (Some(path), None) if path.exists() => {
+                let data = std::fs::read(path)?;
+                serde_json::from_slice(&data).map_err(|e| {
+                    AppError::InternalError(format!("Invalid delegated key state file {}: {}", path.display(), e))
+                })?
+            }
+            _ => HashMap::new(),
+        };
+        info!("Loaded {} delegated API keys", keys.len());
+        Ok(Self {
+            max_ttl_secs: config.max_ttl_secs,
+            max_per_user: config.max_per_user,
+            state_file,
+            store,
+            keys: RwLock::new(keys),
+        })
+    }
+
+    /// Writes the new set of keys before it takes effect, so a revoked key cannot come back
+    fn save(&self, previous: &HashMap<String, DelegatedKey>, keys: &HashMap<String, DelegatedKey>) -> Result<()> {
+        if let Some(store) = &self.store {
+            return store.save_delegated_keys(previous, keys);
+        }
+        let Some(path) = &self.state_file else {
+            return Ok(());
+        };
+        let data = serde_json::to_vec_pretty(keys).map_err(|e| AppError::InternalError(e.to_string()))?;
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    /// Issues a key with a subset of the owner's buckets and role
+    pub fn issue(&self, owner: &str, user: &UserConfig, request: KeyRequest, strict: bool) -> Result<IssuedKey> {
+        if request.buckets.is_empty() {
+            return Err(AppError::InvalidRequest("A key needs at least one bucket".to_string()));
+        }
+        for bucket in &request.buckets {
+            // Wildcards would grow with the owner's grants, so buckets are always named
+            if bucket == "*" || matching_bucket_grant(&user.allowed_buckets, bucket, strict).is_none() {
+                return Err(AppError::BucketDenied(bucket.to_string()));
+            }
+        }
+        if request.prefixes.iter().any(|prefix| prefix.is_empty()) {
+            return Err(AppError::InvalidRequest("Prefixes must not be empty".to_string()));
+        }
+
+        let now = Utc::now();
+        let ttl = request.ttl_secs.unwrap_or(self.max_ttl_secs).min(self.max_ttl_secs);
+        let mut expires_at = now + chrono::Duration::seconds(ttl as i64);
+        if let Some(until) = user.valid_until {
+            expires_at = expires_at.min(until);
+        }
+        let role = if request.readonly || !user.role.can_write() { UserRole::Readonly } else { UserRole::User };
+
+        let api_key = format!("dk-{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+        let key = DelegatedKey {
+            id: uuid::Uuid::new_v4().simple().to_string()[..12].to_string(),
+            owner: owner.to_string(),
+            name: request.name,
+            buckets: request.buckets,
+            prefixes: request.prefixes,
+            role,
+            created_at: now,
+            expires_at,
+        };
+
+        let mut keys = self.keys.write().unwrap();
+        let mut updated = keys.clone();
+        updated.retain(|_, key| !key.is_expired(now));
+        if updated.values().filter(|key| key.owner == owner).count() >= self.max_per_user {
+            return Err(AppError::Conflict(format!(
+                "{} already has {} keys, revoke one first",
+                owner, self.max_per_user
+            )));
+        }
+        updated.insert(hash(&api_key), key.clone());
+        self.save(&keys, &updated)?;
+        *keys = updated;
+
+        info!(
+            "{} issued key {} for {:?} {:?} as {:?} until {}",
+            owner, key.id, key.buckets, key.prefixes, key.role, key.expires_at
+        );
+        Ok(IssuedKey { api_key, key })
+    }
+
+    /// Unexpired keys, of one owner or of everyone
+    pub fn list(&self, owner: Option<&str>) -> Vec<DelegatedKey> {
+        let now = Utc::now();
+        let mut keys: Vec<DelegatedKey> = self
+            .keys
+            .read()
+            .unwrap()
+            .values()
+            .filter(|key| !key.is_expired(now) && owner.is_none_or(|owner| key.owner == owner))
+            .cloned()
+            .collect();
+        keys.sort_by_key(|key| key.created_at);
+        keys
+    }
+
+    /// Revokes a key, only among the given owner's keys unless None
+    pub fn revoke(&self, id: &str, owner: Option<&str>, revoked_by: &str) -> Result<DelegatedKey> {
+        let mut keys = self.keys.write().unwrap();
+        let hash = keys
+            .iter()
+            .find(|(_, key)| key.id == id && owner.is_none_or(|owner| key.owner == owner))
+            .map(|(hash, _)| hash.clone())
+            .ok_or_else(|| AppError::InvalidRequest(format!("No key {}", id)))?;
+        let mut updated = keys.clone();
+        let key = updated.remove(&hash).expect("found above");
+        self.save(&keys, &updated)?;
+        *keys = updated;
+
+        warn!("Key {} of {} revoked by {}", key.id, key.owner, revoked_by);
+        Ok(key)
+    }
+
+    /// Takes the keys other replicas saved to the config store
+    pub fn replace(&self, keys: HashMap<String, DelegatedKey>) {
+        *self.keys.write().unwrap() = keys;
+    }
+
+    pub fn find(&self, api_key: &str) -> Option<DelegatedKey> {
+        self.keys.read().unwrap().get(&hash(api_key)).cloned()
+    }
+}
+
+/// The key an object route addresses; the bucket root `/{bucket}/` is the key "" rather than a
+/// bucket request, as it lists or serves the root whatever `?prefix=` says
+pub fn routed_key<'a>(path: &'a HashMap<String, String>, uri_path: &str) -> Option<&'a str> {
+    match path.get("key") {
+        Some(key) => Some(key),
+        None if uri_path.ends_with('/') => Some(""),
+        None => None,
+    }
+}
+
+/// Whether a request stays inside a key's prefixes: object requests by their key, including a
+/// rename's destination, and bucket requests only as listings under a prefix
+pub fn within_prefixes(prefixes: &[String], method: &http::Method, key: Option<&str>, params: &HashMap<String, String>) -> bool {
+    let allowed = |key: &str| prefixes.iter().any(|prefix| key.starts_with(prefix.as_str()));
+    match key {
+        Some(key) => allowed(key) && params.get("rename").is_none_or(|destination| allowed(destination)),
+        None => method == http::Method::GET && params.get("prefix").is_some_and(|prefix| allowed(prefix)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{routed_key, within_prefixes};
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn object_requests_stay_under_the_prefixes() {
+        let prefixes = vec!["ci/".to_string()];
+        let get = http::Method::GET;
+        assert!(within_prefixes(&prefixes, &get, Some("ci/build.log"), &params(&[])));
+        assert!(!within_prefixes(&prefixes, &get, Some("secrets/key"), &params(&[])));
+        assert!(!within_prefixes(&prefixes, &get, Some("c"), &params(&[])));
+        assert!(within_prefixes(&prefixes, &http::Method::POST, Some("ci/a"), &params(&[("rename", "ci/b")])));
+        assert!(!within_prefixes(&prefixes, &http::Method::POST, Some("ci/a"), &params(&[("rename", "out/b")])));
+    }
+
+    #[test]
+    fn bucket_requests_are_only_listings_under_the_prefixes() {
+        let prefixes = vec!["ci/".to_string()];
+        assert!(within_prefixes(&prefixes, &http::Method::GET, None, &params(&[("prefix", "ci/2026/")])));
+        assert!(!within_prefixes(&prefixes, &http::Method::GET, None, &params(&[("prefix", "")])));
+        assert!(!within_prefixes(&prefixes, &http::Method::GET, None, &params(&[])));
+        assert!(!within_prefixes(&prefixes, &http::Method::DELETE, None, &params(&[("prefix", "ci/")])));
+    }
+
+    #[test]
+    fn the_bucket_root_is_refused_whatever_the_prefix_param() {
+        let prefixes = vec!["ci/".to_string()];
+        let path = params(&[("bucket", "b")]);
+        let key = routed_key(&path, "/b/");
+        assert_eq!(key, Some(""));
+        assert!(!within_prefixes(&prefixes, &http::Method::GET, key, &params(&[("prefix", "ci/")])));
+        assert_eq!(routed_key(&path, "/b"), None);
+        let path = params(&[("bucket", "b"), ("key", "ci/x")]);
+        assert_eq!(routed_key(&path, "/b/ci/x"), Some("ci/x"));
+    }
+}
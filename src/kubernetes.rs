@@ -0,0 +1,115 @@
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::config::{self, AccountConfig, UserConfig};
+use crate::error::Result;
+use crate::s3;
+use crate::server::AppState;
+use crate::tenants;
+
+/// The pod this replica runs in, from environment variables set through the downward API
+#[derive(Debug)]
+pub struct Pod {
+    pub name: String,
+    pub namespace: Option<String>,
+}
+
+/// None outside Kubernetes, or when POD_NAME is not passed in
+pub fn pod() -> Option<&'static Pod> {
+    static POD: OnceLock<Option<Pod>> = OnceLock::new();
+    POD.get_or_init(|| {
+        let name = std::env::var("POD_NAME").ok().filter(|name| !name.is_empty())?;
+        let namespace = std::env::var("POD_NAMESPACE").ok().filter(|namespace| !namespace.is_empty());
+        Some(Pod { name, namespace })
+    })
+    .as_ref()
+}
+
+impl Pod {
+    /// Labels added to every metric, so series of replicas stay apart when scraped together
+    pub fn labels(&self) -> Vec<(&'static str, &str)> {
+        let mut labels = vec![("pod", self.name.as_str())];
+        labels.extend(self.namespace.as_deref().map(|namespace| ("namespace", namespace)));
+        labels
+    }
+}
+
+/// Hash of a watched file's content, None while it is missing; kubelet swaps mounted files
+/// through symlinks, so the modification time is not reliable
+fn digest(path: &str) -> Option<Vec<u8>> {
+    std::fs::read(path).ok().map(|content| Sha256::digest(content).to_vec())
+}
+
+fn reload_users(state: &AppState, path: &str) -> Result<()> {
+    apply_users(state, config::read_json(path)?, path);
+    Ok(())
+}
+
+/// Replaces the users in use by those read from `source`
+pub fn apply_users(state: &AppState, users: HashMap<String, UserConfig>, source: &str) {
+    config::check_users(&state.config, &users);
+    info!("Reloaded {} users from {}", users.len(), source);
+    state.config.users.replace(users);
+}
+
+async fn reload_accounts(state: &AppState, path: &str) -> Result<()> {
+    apply_accounts(state, config::read_json(path)?, path).await
+}
+
+/// Replaces the accounts in use by those read from `source`, with a client for each
+pub async fn apply_accounts(state: &AppState, accounts: HashMap<String, AccountConfig>, source: &str) -> Result<()> {
+    config::check_accounts(&accounts);
+    tenants::check_buckets(&state.config, &accounts)?;
+    let clients = s3::connect(&state.config, &accounts).await?;
+    let ids: HashSet<String> = accounts.keys().cloned().collect();
+    // New clients go in before the accounts that route to them, removed ones only after
+    state.clients.write().unwrap().extend(clients);
+    info!("Reloaded {} accounts from {}", accounts.len(), source);
+    state.config.accounts.replace(accounts);
+    state.clients.write().unwrap().retain(|account_id, _| ids.contains(account_id));
+    Ok(())
+}
+
+/// Periodically checks the users and accounts files and applies changes without a restart; a
+/// file that fails to parse is reported and the previous version stays in use
+pub fn spawn_reload(state: Arc<AppState>) {
+    let Some(files) = &state.config.config_files else {
+        return;
+    };
+    if files.users.is_none() && files.accounts.is_none() {
+        return;
+    }
+    let interval = Duration::from_secs(files.reload_interval_secs.max(1));
+    let mut users = files.users.as_deref().and_then(digest);
+    let mut accounts = files.accounts.as_deref().and_then(digest);
+    tokio::spawn(async move {
+        let Some(files) = &state.config.config_files else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            if let Some(path) = &files.users {
+                let current = digest(path);
+                if current.is_some() && current != users {
+                    users = current;
+                    if let Err(e) = reload_users(&state, path) {
+                        warn!("Keeping the previous users, failed to reload {}: {}", path, e);
+                    }
+                }
+            }
+            if let Some(path) = &files.accounts {
+                let current = digest(path);
+                if current.is_some() && current != accounts {
+                    accounts = current;
+                    if let Err(e) = reload_accounts(&state, path).await {
+                        warn!("Keeping the previous accounts, failed to reload {}: {}", path, e);
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,132 @@
+use ldap3::{ldap_escape, LdapConnAsync, Scope, SearchEntry};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tracing::{info, instrument, warn};
+
+use crate::auth::AuthState;
+use crate::config::{LdapConfig, UserRole};
+use crate::error::{AppError, Result};
+
+struct LdapUser {
+    role: UserRole,
+    allowed_buckets: Vec<String>,
+    groups: Vec<String>,
+}
+
+impl LdapUser {
+    fn to_auth_state(&self, username: &str) -> AuthState {
+        AuthState::new(
+            username.to_string(),
+            self.role,
+            self.allowed_buckets.clone(),
+            format!("ldap.group_mappings[{}]", self.groups.join(", ")),
+        )
+    }
+}
+
+struct CachedLogin {
+    password_hash: String,
+    user: LdapUser,
+    expires_at: Instant,
+}
+
+lazy_static::lazy_static! {
+    static ref LOGIN_CACHE: RwLock<HashMap<String, CachedLogin>> = RwLock::new(HashMap::new());
+}
+
+fn hash_password(password: &str) -> String {
+    hex::encode(Sha256::digest(password.as_bytes()))
+}
+
+fn ldap_error(e: ldap3::LdapError) -> AppError {
+    AppError::InternalError(format!("LDAP error: {}", e))
+}
+
+/// Validates Basic credentials against LDAP and maps the user's groups to a proxy role
+pub async fn authenticate(config: &LdapConfig, username: &str, password: &str) -> Result<AuthState> {
+    // An empty password would turn the user bind into an anonymous bind
+    if username.is_empty() || password.is_empty() {
+        return Err(AppError::Unauthorized("Invalid credentials".to_string()));
+    }
+
+    let password_hash = hash_password(password);
+    if let Some(cached) = LOGIN_CACHE.read().await.get(username) {
+        if cached.password_hash == password_hash && cached.expires_at > Instant::now() {
+            return Ok(cached.user.to_auth_state(username));
+        }
+    }
+
+    let user = bind_and_resolve(config, username, password).await?;
+    let auth = user.to_auth_state(username);
+
+    LOGIN_CACHE.write().await.insert(username.to_string(), CachedLogin {
+        password_hash,
+        user,
+        expires_at: Instant::now() + Duration::from_secs(config.cache_ttl_secs),
+    });
+    Ok(auth)
+}
+
+#[instrument(skip(config, password))]
+async fn bind_and_resolve(config: &LdapConfig, username: &str, password: &str) -> Result<LdapUser> {
+    let (conn, mut ldap) = LdapConnAsync::new(&config.url).await.map_err(ldap_error)?;
+    ldap3::drive!(conn);
+
+    ldap.simple_bind(&config.bind_dn, &config.bind_password)
+        .await
+        .and_then(|r| r.success())
+        .map_err(ldap_error)?;
+
+    let filter = config.user_filter.replace("{username}", &ldap_escape(username));
+    let (entries, _) = ldap
+        .search(&config.base_dn, Scope::Subtree, &filter, vec![config.group_attribute.as_str()])
+        .await
+        .and_then(|r| r.success())
+        .map_err(ldap_error)?;
+
+    let entry = match entries.into_iter().next() {
+        Some(entry) => SearchEntry::construct(entry),
+        None => {
+            warn!("LDAP user {} not found", username);
+            return Err(AppError::Unauthorized("Invalid credentials".to_string()));
+        }
+    };
+
+    if ldap.simple_bind(&entry.dn, password).await.and_then(|r| r.success()).is_err() {
+        warn!("LDAP bind failed for user {}", username);
+        return Err(AppError::Unauthorized("Invalid credentials".to_string()));
+    }
+    let _ = ldap.unbind().await;
+
+    let groups = entry.attrs.get(&config.group_attribute).cloned().unwrap_or_default();
+    let mut role = None;
+    let mut allowed_buckets = Vec::new();
+    let mut matched_groups = Vec::new();
+    for group in &groups {
+        let mapping = config.group_mappings.iter().find(|(dn, _)| dn.eq_ignore_ascii_case(group));
+        if let Some((dn, mapping)) = mapping {
+            role = role.max(Some(mapping.role));
+            allowed_buckets.extend(mapping.allowed_buckets.iter().cloned());
+            matched_groups.push(dn.clone());
+        }
+    }
+
+    let role: UserRole = match role {
+        Some(role) => role,
+        None => {
+            warn!("LDAP user {} is not a member of any mapped group", username);
+            return Err(AppError::Unauthorized("No proxy access granted".to_string()));
+        }
+    };
+    allowed_buckets.sort();
+    allowed_buckets.dedup();
+
+    info!("LDAP user {} authenticated with role {:?}", username, role);
+    Ok(LdapUser {
+        role,
+        allowed_buckets,
+        groups: matched_groups,
+    })
+}
@@ -0,0 +1,228 @@
+use bytes::Bytes;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::LeaderElectionConfig;
+use crate::error::{AppError, Result};
+use crate::kubernetes;
+use crate::metrics;
+use crate::server::AppState;
+
+/// Takes the lease if it is free or already ours, and extends it
+const ACQUIRE: &str = r"
+local holder = redis.call('GET', KEYS[1])
+if holder == false or holder == ARGV[1] then
+  redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+  return 1
+end
+return 0
+";
+
+/// Gives the lease up only if it is still ours
+const RELEASE: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+/// Content of the lease object in the bucket backend
+#[derive(Debug, Serialize, Deserialize)]
+struct Lease {
+    holder: String,
+    /// Milliseconds since the epoch
+    expires_at: i64,
+}
+
+enum Backend {
+    Bucket { bucket: String, key: String },
+    Redis { client: redis::Client, key: String },
+}
+
+/// A lease shared by all replicas, so tasks that act on the whole fleet's data run on one of them
+pub struct Leader {
+    backend: Backend,
+    /// Identifies this replica as the lease holder
+    id: String,
+    lease: Duration,
+    /// Until when this replica may act as leader, None while it is not
+    until: Mutex<Option<Instant>>,
+}
+
+impl Leader {
+    pub fn new(config: &LeaderElectionConfig) -> Result<Self> {
+        let backend = match (&config.bucket, &config.redis_url) {
+            (Some(bucket), None) => Backend::Bucket { bucket: bucket.clone(), key: config.key.clone() },
+            (None, Some(redis_url)) => Backend::Redis {
+                client: redis::Client::open(redis_url.as_str())
+                    .map_err(|e| AppError::InternalError(format!("Invalid leader_election redis_url: {}", e)))?,
+                key: config.key.clone(),
+            },
+            _ => {
+                return Err(AppError::InternalError(
+                    "leader_election needs exactly one of bucket and redis_url".to_string(),
+                ))
+            }
+        };
+        let host = kubernetes::pod().map_or_else(|| std::process::id().to_string(), |pod| pod.name.clone());
+        Ok(Self {
+            backend,
+            id: format!("{}-{}", host, uuid::Uuid::new_v4().simple()),
+            lease: Duration::from_secs(config.lease_secs.max(3)),
+            until: Mutex::new(None),
+        })
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.until.lock().unwrap().is_some_and(|until| Instant::now() < until)
+    }
+
+    /// Takes or extends the lease; leadership counts from before the attempt, so it ends no later
+    /// than the lease other replicas see
+    async fn renew(&self, state: &AppState) {
+        let started = Instant::now();
+        let was_leader = self.is_leader();
+        let acquired = match &self.backend {
+            Backend::Bucket { bucket, key } => self.renew_object(state, bucket, key).await,
+            Backend::Redis { client, key } => self.renew_redis(client, key).await,
+        };
+        let leader = match acquired {
+            Ok(true) => {
+                *self.until.lock().unwrap() = Some(started + self.lease);
+                true
+            }
+            Ok(false) => {
+                *self.until.lock().unwrap() = None;
+                false
+            }
+            // The lease may still be held, it runs out by itself otherwise
+            Err(e) => {
+                warn!("Failed to renew the leader lease: {}", e);
+                self.is_leader()
+            }
+        };
+        match (was_leader, leader) {
+            (false, true) => info!("Became leader as {}", self.id),
+            (true, false) => warn!("No longer leader as {}", self.id),
+            _ => {}
+        }
+        metrics::set_leader(leader);
+    }
+
+    async fn renew_object(&self, state: &AppState, bucket: &str, key: &str) -> Result<bool> {
+        let (_, client) = &state.get_account_and_client(bucket)?;
+        let now = Utc::now().timestamp_millis();
+        let lease = Lease { holder: self.id.clone(), expires_at: now + self.lease.as_millis() as i64 };
+        let body = Bytes::from(serde_json::to_vec(&lease).map_err(|e| AppError::InternalError(e.to_string()))?);
+        let content_type = Some("application/json".to_string());
+        let current = match client.get_object_range(bucket, key, None, None).await {
+            Ok(current) => current,
+            Err(AppError::ObjectNotFound(_, _)) => return client.put_object_if_absent(bucket, key, body, content_type).await,
+            Err(e) => return Err(e),
+        };
+        // An unreadable lease is treated as expired
+        let held: Option<Lease> = serde_json::from_slice(&current.body).ok();
+        if held.is_some_and(|held| held.holder != self.id && held.expires_at > now) {
+            return Ok(false);
+        }
+        let etag = current
+            .etag
+            .ok_or_else(|| AppError::InternalError(format!("Lease {}/{} has no ETag", bucket, key)))?;
+        // Another replica taking the lease in between changes the ETag
+        client.put_object_if_match(bucket, key, body, content_type, &etag).await
+    }
+
+    async fn renew_redis(&self, client: &redis::Client, key: &str) -> Result<bool> {
+        let mut connection = client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+        let acquired: i64 = redis::cmd("EVAL")
+            .arg(ACQUIRE)
+            .arg(1)
+            .arg(key)
+            .arg(&self.id)
+            .arg(self.lease.as_millis() as u64)
+            .query_async(&mut connection)
+            .await
+            .map_err(redis_error)?;
+        Ok(acquired == 1)
+    }
+
+    /// Hands the lease over on shutdown instead of leaving the fleet without a leader until it expires
+    async fn release(&self, state: &AppState) {
+        if !self.is_leader() {
+            return;
+        }
+        *self.until.lock().unwrap() = None;
+        let released = match &self.backend {
+            Backend::Bucket { bucket, key } => self.release_object(state, bucket, key).await,
+            Backend::Redis { client, key } => self.release_redis(client, key).await,
+        };
+        match released {
+            Ok(()) => info!("Released the leader lease"),
+            Err(e) => warn!("Failed to release the leader lease, it expires in {:?}: {}", self.lease, e),
+        }
+    }
+
+    async fn release_object(&self, state: &AppState, bucket: &str, key: &str) -> Result<()> {
+        let (_, client) = &state.get_account_and_client(bucket)?;
+        let current = client.get_object_range(bucket, key, None, None).await?;
+        let held: Option<Lease> = serde_json::from_slice(&current.body).ok();
+        let (Some(held), Some(etag)) = (held, current.etag) else {
+            return Ok(());
+        };
+        if held.holder != self.id {
+            return Ok(());
+        }
+        // An expired lease rather than a delete, which cannot be made conditional on the ETag
+        let lease = Lease { holder: self.id.clone(), expires_at: 0 };
+        let body = Bytes::from(serde_json::to_vec(&lease).map_err(|e| AppError::InternalError(e.to_string()))?);
+        client
+            .put_object_if_match(bucket, key, body, Some("application/json".to_string()), &etag)
+            .await?;
+        Ok(())
+    }
+
+    async fn release_redis(&self, client: &redis::Client, key: &str) -> Result<()> {
+        let mut connection = client.get_multiplexed_async_connection().await.map_err(redis_error)?;
+        redis::cmd("EVAL")
+            .arg(RELEASE)
+            .arg(1)
+            .arg(key)
+            .arg(&self.id)
+            .query_async::<i64>(&mut connection)
+            .await
+            .map_err(redis_error)?;
+        Ok(())
+    }
+}
+
+fn redis_error(e: redis::RedisError) -> AppError {
+    AppError::InternalError(format!("Redis: {}", e))
+}
+
+/// Keeps trying to take the lease, and extends it while held, three times per lease period
+pub fn spawn(state: Arc<AppState>) {
+    let Some(leader) = &state.leader else {
+        return;
+    };
+    info!("Leader election enabled, this replica is {}", leader.id);
+    let interval = leader.lease / 3;
+    tokio::spawn(async move {
+        let Some(leader) = &state.leader else {
+            return;
+        };
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            leader.renew(&state).await;
+        }
+    });
+}
+
+pub async fn release(state: &AppState) {
+    if let Some(leader) = &state.leader {
+        leader.release(state).await;
+    }
+}
@@ -0,0 +1,158 @@
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::config::ServerConfig;
+use crate::error::{AppError, Result};
+
+/// Listening socket passed on by the proxy process being replaced
+const HANDOVER_FD: &str = "S3_PROXY_LISTEN_FD";
+
+/// First descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Pending connections the kernel queues while the proxy is busy or restarting
+const BACKLOG: i32 = 1024;
+
+/// Where the listening socket came from; inherited sockets are never closed during an upgrade
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Bound,
+    Systemd,
+    Handover,
+}
+
+/// Takes over a socket from systemd or a previous proxy process, or binds the configured address
+pub async fn open(config: &ServerConfig) -> Result<(TcpListener, Source)> {
+    #[cfg(unix)]
+    if let Some((fd, source)) = inherited_fd() {
+        use std::os::fd::FromRawFd;
+        // SAFETY: the descriptor was handed to this process as its listening socket and nothing
+        // else in the process owns it
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        return Ok((from_std(listener)?, source));
+    }
+
+    let addr = format!("{}:{}", config.host, config.port);
+    let failed = |e: std::io::Error| AppError::InternalError(format!("Failed to bind to {}: {}", addr, e));
+    let resolved = tokio::net::lookup_host(&addr)
+        .await
+        .map_err(failed)?
+        .next()
+        .ok_or_else(|| AppError::InternalError(format!("Failed to bind to {}: no address", addr)))?;
+    let socket = socket2::Socket::new(socket2::Domain::for_address(resolved), socket2::Type::STREAM, None).map_err(failed)?;
+    socket.set_reuse_address(true).map_err(failed)?;
+    #[cfg(unix)]
+    if config.reuse_port {
+        socket.set_reuse_port(true).map_err(failed)?;
+    }
+    socket.bind(&resolved.into()).map_err(failed)?;
+    socket.listen(BACKLOG).map_err(failed)?;
+    Ok((from_std(socket.into())?, Source::Bound))
+}
+
+fn from_std(listener: std::net::TcpListener) -> Result<TcpListener> {
+    listener
+        .set_nonblocking(true)
+        .and_then(|_| TcpListener::from_std(listener))
+        .map_err(|e| AppError::InternalError(format!("Failed to use the listening socket: {}", e)))
+}
+
+/// Descriptor of a socket passed through the environment, which is cleared so it is not passed on
+#[cfg(unix)]
+fn inherited_fd() -> Option<(i32, Source)> {
+    if let Ok(fd) = std::env::var(HANDOVER_FD) {
+        std::env::remove_var(HANDOVER_FD);
+        return fd.parse().ok().map(|fd| (fd, Source::Handover));
+    }
+    // Only the process systemd started may use the sockets, not its children
+    let pid = std::env::var("LISTEN_PID").ok()?;
+    let fds = std::env::var("LISTEN_FDS").ok()?;
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if pid.parse() != Ok(std::process::id()) {
+        return None;
+    }
+    match fds.parse::<i32>() {
+        Ok(1) => Some((SD_LISTEN_FDS_START, Source::Systemd)),
+        _ => {
+            warn!("Expected one socket from systemd, got {}; binding instead", fds);
+            None
+        }
+    }
+}
+
+/// Records this process's id for scripts that send it SIGUSR2
+pub fn write_pid_file(config: &ServerConfig) -> Result<()> {
+    if let Some(path) = &config.pid_file {
+        std::fs::write(path, format!("{}\n", std::process::id()))?;
+    }
+    Ok(())
+}
+
+/// Tells the process that handed over the socket to stop accepting and drain its connections
+pub fn finish_handover(source: Source) {
+    #[cfg(unix)]
+    if source == Source::Handover {
+        // SAFETY: plain system calls without memory arguments
+        let parent = unsafe { libc::getppid() };
+        info!("Took over the listening socket, asking process {} to drain", parent);
+        if unsafe { libc::kill(parent, libc::SIGTERM) } != 0 {
+            warn!("Failed to signal process {}: {}", parent, std::io::Error::last_os_error());
+        }
+    }
+}
+
+/// On SIGUSR2, starts the binary now on disk with the same arguments and hands it the listening
+/// socket; the new process takes over once it has started, and this one keeps serving if it fails
+#[cfg(unix)]
+pub fn spawn_handover(listener: &TcpListener) {
+    use std::os::fd::AsRawFd;
+    let fd = listener.as_raw_fd();
+    tokio::spawn(async move {
+        let mut signal = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2()) {
+            Ok(signal) => signal,
+            Err(e) => {
+                warn!("Failed to listen for SIGUSR2, socket handover disabled: {}", e);
+                return;
+            }
+        };
+        while signal.recv().await.is_some() {
+            match handover(fd) {
+                Ok(mut child) => {
+                    info!("Started process {} to take over the listening socket", child.id().unwrap_or_default());
+                    // Only returns early when the new process fails, as this one exits once it takes over
+                    match child.wait().await {
+                        Ok(status) => warn!("New process exited with {} before taking over", status),
+                        Err(e) => warn!("Failed to wait for the new process: {}", e),
+                    }
+                }
+                Err(e) => warn!("Failed to start a new process for the socket handover: {}", e),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_handover(_listener: &TcpListener) {}
+
+#[cfg(unix)]
+fn handover(fd: i32) -> std::io::Result<tokio::process::Child> {
+    // The path this process was started with, as current_exe() points to the replaced binary
+    let mut args = std::env::args_os();
+    let program = args.next().ok_or_else(|| std::io::Error::other("no program name"))?;
+    let mut command = tokio::process::Command::new(program);
+    command.args(args).env(HANDOVER_FD, fd.to_string());
+    // SAFETY: only clears close-on-exec on the listening socket in the forked child, which is
+    // async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
@@ -0,0 +1,103 @@
+use aws_sdk_s3::types::Object;
+use http::{header, HeaderMap};
+
+use crate::sigv4::uri_encode;
+
+/// Browsers ask for text/html, S3 clients don't
+pub fn wants_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/html"))
+}
+
+pub fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+fn link(bucket: &str, key: &str, label: &str) -> String {
+    format!(
+        r#"<a href="/{}/{}">{}</a>"#,
+        uri_encode(bucket, true),
+        uri_encode(key, false),
+        html_escape(label)
+    )
+}
+
+/// Renders a bucket or directory listing as a simple HTML table
+pub fn render_html(bucket: &str, prefix: &str, objects: &[Object], prefixes: &[String]) -> String {
+    let mut rows = Vec::new();
+
+    if !prefix.is_empty() {
+        let parent = match prefix.trim_end_matches('/').rfind('/') {
+            Some(i) => &prefix[..=i],
+            None => "",
+        };
+        rows.push(format!("<tr><td>{}</td><td></td><td></td></tr>", link(bucket, parent, "../")));
+    }
+    for sub in prefixes {
+        let name = sub.strip_prefix(prefix).unwrap_or(sub);
+        rows.push(format!("<tr><td>{}</td><td>-</td><td></td></tr>", link(bucket, sub, name)));
+    }
+    for obj in objects {
+        let key = obj.key().unwrap_or_default();
+        // Folder marker objects are already shown as the directory itself
+        if key == prefix {
+            continue;
+        }
+        let name = key.strip_prefix(prefix).unwrap_or(key);
+        rows.push(format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            link(bucket, key, name),
+            format_size(obj.size().unwrap_or(0)),
+            obj.last_modified().map(|dt| dt.to_string()).unwrap_or_default()
+        ));
+    }
+
+    let title = html_escape(&format!("{}/{}", bucket, prefix));
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Index of {title}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+th, td {{ padding: 0.2em 1.5em 0.2em 0; text-align: left; }}
+td:nth-child(2) {{ text-align: right; }}
+</style>
+</head>
+<body>
+<h1>Index of {title}</h1>
+<table>
+<tr><th>Name</th><th>Size</th><th>Last modified</th></tr>
+{rows}
+</table>
+</body>
+</html>
+"#,
+        title = title,
+        rows = rows.join("\n")
+    )
+}
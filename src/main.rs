@@ -1,34 +1,183 @@
+#![allow(clippy::result_large_err)]
+
 mod config;
 mod s3;
 mod server;
 mod error;
 mod auth;
+mod buckets;
+mod ldap;
+mod sigv4;
+mod upstream;
+mod metrics;
+mod alerts;
+mod recording;
+mod bench;
+mod listing;
+mod cache;
+mod invalidation;
+mod trash;
+mod uploads;
+mod bandwidth;
+mod costs;
+mod anomalies;
+mod compliance;
+mod holds;
+mod content;
+mod dedup;
+mod manifests;
+mod packing;
+mod chunking;
+mod consistency;
+mod etags;
+mod trace_context;
+mod backends;
+mod pacing;
+mod aggregate;
+mod union;
+mod rewrite;
+mod publishing;
+mod registry;
+mod package_index;
+mod terraform;
+mod ingest;
+mod preview;
+mod media;
+mod session;
+mod referrers;
+mod sampling;
+mod memory;
+mod listener;
+mod systemd;
+mod kubernetes;
+mod leader;
+mod keys;
+mod audit;
+mod audit_chain;
+mod redaction;
+mod pkcs11;
+mod parts;
+mod openapi;
+mod timeouts;
+mod connections;
+mod resumption;
+mod stats;
+mod cold;
+mod replicas;
+mod routing;
+mod hedging;
+mod concurrency;
+mod http3;
+mod tls;
+mod migrations;
+mod includes;
+mod remote_config;
+mod config_store;
+mod tenants;
+mod firewall;
 
-use std::collections::HashMap;
-use std::sync::Arc;
-use tracing::{info, Level};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
-use tower_http::trace::{TraceLayer, DefaultMakeSpan, DefaultOnResponse};
+use std::sync::{Arc, RwLock};
+use tracing::{info, warn, Level};
+use tracing_subscriber::{
+    filter::{dynamic_filter_fn, FilterExt},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
+use tower_http::trace::{TraceLayer, DefaultOnResponse};
 use tower_http::classify::{ServerErrorsAsFailures, SharedClassifier};
 use axum::extract::Request;
 
+use crate::config::RuntimeConfig;
 use crate::error::{AppError, Result};
 
-fn redact_sensitive_data(headers: &http::HeaderMap) -> String {
-    let mut redacted = String::new();
-    for (name, value) in headers.iter() {
-        let value = if name == "x-api-key" {
-            "***REDACTED***"
-        } else {
-            value.to_str().unwrap_or("***INVALID***")
-        };
-        redacted.push_str(&format!("{}: {}\n", name, value));
+/// Tokio's own limit on blocking threads, used when `max_blocking_threads` is unset
+const DEFAULT_MAX_BLOCKING_THREADS: usize = 512;
+
+/// Request headers with credentials masked, written straight into the log line and only when it is
+/// actually logged
+struct RedactedHeaders<'a>(&'a http::HeaderMap);
+
+impl std::fmt::Display for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, value) in self.0.iter() {
+            let secret = name == "x-api-key"
+                || name == http::header::AUTHORIZATION
+                || name == http::header::COOKIE
+                || name == http::header::SET_COOKIE;
+            let value = if secret {
+                "***REDACTED***"
+            } else {
+                value.to_str().unwrap_or("***INVALID***")
+            };
+            writeln!(f, "{}: {}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A request URI for logs, without the signature of part URLs
+struct RedactedUri<'a>(&'a http::Uri);
+
+impl std::fmt::Display for RedactedUri<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0.query() {
+            Some(query) => write!(f, "{}?{}", self.0.path(), parts::redact_query(query)),
+            None => write!(f, "{}", self.0.path()),
+        }
+    }
+}
+
+/// Resolves on Ctrl+C or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    info!("Shutting down");
+}
+
+/// Multi-threaded runtime sized by `server.runtime`, tokio's defaults where unset
+fn build_runtime(config: &RuntimeConfig) -> Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all();
+    if let Some(worker_threads) = config.worker_threads {
+        builder.worker_threads(worker_threads.max(1));
     }
-    redacted
+    if let Some(max_blocking_threads) = config.max_blocking_threads {
+        builder.max_blocking_threads(max_blocking_threads.max(1));
+    }
+    builder
+        .build()
+        .map_err(|e| AppError::InternalError(format!("Failed to start the runtime: {}", e)))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+    let bench = command.as_deref() == Some("bench");
+
     // Initialize tracing with custom format
     let fmt_layer = tracing_subscriber::fmt::layer()
         .with_target(false)
@@ -37,66 +186,244 @@ async fn main() -> Result<()> {
         .with_line_number(true);
 
     let filter_layer = EnvFilter::try_from_default_env()
-        .or_else(|_| EnvFilter::try_new("info"))
+        .or_else(|_| EnvFilter::try_new(if bench { "warn" } else { "info" }))
         .unwrap();
 
+    // Unsampled requests keep only warnings and errors, admins can ask for debug output per request
+    let sampled = filter_layer.and(dynamic_filter_fn(|metadata, _| sampling::sampled(metadata)));
     tracing_subscriber::registry()
-        .with(filter_layer)
-        .with(fmt_layer)
+        .with(fmt_layer.with_filter(sampled.or(dynamic_filter_fn(|metadata, _| sampling::elevated(metadata)))))
         .init();
 
+    if bench {
+        return build_runtime(&RuntimeConfig::default())?.block_on(bench::run(bench::parse_args(args)?));
+    }
+
+    if command.as_deref() == Some("migrate-config") {
+        return migrations::run(args);
+    }
+
     info!("Starting S3 proxy server");
 
     // Load configuration
     let config = Arc::new(config::Config::load("config.json")?);
-    info!("Loaded configuration with {} accounts and {} users", 
-        config.accounts.len(),
-        config.users.len()
+    info!("Loaded configuration version {} with {} accounts and {} users",
+        config.version,
+        config.accounts.current().len(),
+        config.users.current().len()
     );
 
+    // Sized from the config, so it is built only once that is loaded
+    let runtime = build_runtime(&config.server.runtime)?;
+    info!(
+        "Runtime with {} worker threads, up to {} blocking threads and {} client connections",
+        runtime.metrics().num_workers(),
+        config.server.runtime.max_blocking_threads.unwrap_or(DEFAULT_MAX_BLOCKING_THREADS),
+        config.server.connections.max_connections.map_or("unlimited".to_string(), |max| max.to_string()),
+    );
+    runtime.block_on(run(config))
+}
+
+async fn run(config: Arc<config::Config>) -> Result<()> {
+    if let Some(memory) = &config.memory {
+        memory::init(memory);
+    }
+
     // Initialize S3 clients for each account
-    let mut clients = HashMap::new();
-    for (account_id, account_config) in &config.accounts {
-        info!("Initializing S3 client for account {}", account_id);
-        let client = s3::S3Client::new(
-            account_config.endpoint_url.clone(),
-            account_config.region.clone(),
-            account_config.access_key_id.clone(),
-            account_config.secret_access_key.clone(),
-        ).await?;
-        clients.insert(account_id.clone(), Arc::new(client));
+    let clients = s3::connect(&config, &config.accounts.current()).await?;
+
+    let config_store = config
+        .config_store
+        .as_ref()
+        .map(|store| config_store::ConfigStore::open(store, &config))
+        .transpose()?
+        .map(Arc::new);
+
+    let state = Arc::new(server::AppState {
+        config: config.clone(),
+        clients: RwLock::new(clients),
+        buckets: buckets::BucketRegistry::default(),
+        cache: config.cache.as_ref().map(cache::ObjectCache::new),
+        uploads: uploads::UploadSessions::new(&config.uploads),
+        bucket_stats: stats::StatsCache::new(&config.bucket_stats),
+        holds: holds::LegalHolds::new(&config.legal_holds)?,
+        ingest: ingest::Ingestor::default(),
+        invalidation: config
+            .cache
+            .as_ref()
+            .and_then(|cache| cache.invalidation.as_ref())
+            .map(invalidation::InvalidationBus::new)
+            .transpose()?,
+        leader: config.leader_election.as_ref().map(leader::Leader::new).transpose()?,
+        keys: config
+            .delegated_keys
+            .as_ref()
+            .map(|keys| keys::DelegatedKeys::new(keys, config_store.clone()))
+            .transpose()?,
+        config_store,
+        audit: config
+            .audit
+            .as_ref()
+            .map(|audit| {
+                let shipping_tenants = config
+                    .tenants
+                    .iter()
+                    .filter(|(_, tenant)| tenant.audit_shipping.is_some())
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                audit::AuditLog::new(audit, shipping_tenants)
+            })
+            .transpose()?,
+        session_signer: config
+            .sessions
+            .as_ref()
+            .map(|sessions| auth::Signer::new(&sessions.signing_key))
+            .transpose()?,
+        part_signer: config
+            .download_parts
+            .as_ref()
+            .map(|parts| auth::Signer::new(&parts.signing_key))
+            .transpose()?,
+    });
+
+    // Remote users and accounts replace the local ones before anything uses them
+    remote_config::start(state.clone()).await?;
+
+    // Users, routes and keys changed through the admin API, shared by the replicas
+    config_store::start(state.clone())?;
+
+    // Packed objects are only reachable through the index of their segments
+    packing::load(&state).await?;
+
+    // Keep account to bucket routes in sync with upstream
+    buckets::spawn_discovery(state.clone());
+
+    // Route reads of replicated buckets to the nearest copy
+    replicas::spawn_probes(state.clone());
+
+    // Pick the replica that runs fleet-wide tasks
+    leader::spawn(state.clone());
+
+    // Permanently remove soft-deleted objects past their retention
+    trash::spawn_purge(state.clone());
+
+    // Abort resumable uploads abandoned by their clients
+    uploads::spawn_expiry(state.clone());
+
+    // Write buffered log events once their batch is old enough
+    ingest::spawn_flush(state.clone());
+
+    // Apply changes to mounted users and accounts files
+    kubernetes::spawn_reload(state.clone());
+
+    // Upload sealed, hash-chained audit segments
+    audit_chain::spawn(state.clone());
+
+    // Keep caches of all replicas consistent on writes
+    invalidation::spawn(state.clone());
+
+    // Warm the cache with pinned objects
+    for pin in config.cache.iter().flat_map(|cache| &cache.pins) {
+        cache::spawn_prefetch(state.clone(), pin.clone());
     }
 
+    // Notify on SLO breaches without an external monitoring stack
+    alerts::spawn(config.clone())?;
+
     // Create router with request logging
-    let app = server::create_router(server::AppState {
-        config: config.clone(),
-        clients,
-    }).await
+    let app = server::create_router(state.clone()).await
     .layer(
         TraceLayer::new(SharedClassifier::new(ServerErrorsAsFailures::new()))
-            .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+            // Same fields as DefaultMakeSpan, plus the pod so logs of replicas can be told apart
+            .make_span_with(|request: &Request<_>| {
+                tracing::info_span!(
+                    "request",
+                    method = %request.method(),
+                    uri = %RedactedUri(request.uri()),
+                    version = ?request.version(),
+                    pod = kubernetes::pod().map(|pod| pod.name.as_str()),
+                    // Filled in when the request is for a tenant
+                    tenant = tracing::field::Empty,
+                    // Filled in by the auth middleware
+                    user = tracing::field::Empty,
+                    auth_outcome = tracing::field::Empty,
+                    auth_rule = tracing::field::Empty,
+                    rate_limit_remaining = tracing::field::Empty,
+                )
+            })
             .on_response(DefaultOnResponse::new().level(Level::INFO))
             .on_request(|request: &Request<_>, _span: &tracing::Span| {
                 info!(
                     method = %request.method(),
-                    uri = %request.uri(),
-                    headers = %redact_sensitive_data(request.headers()),
+                    uri = %RedactedUri(request.uri()),
+                    headers = %RedactedHeaders(request.headers()),
                     "Request started"
                 );
             })
-    );
+    )
+    // Outermost, so the decision covers the request span
+    .layer(axum::middleware::from_fn_with_state(config.clone(), sampling::sample))
+    .layer(axum::middleware::from_fn_with_state(config.clone(), http3::advertise))
+    // Before anything else, so oversized requests cost no more than reading them
+    .layer(axum::middleware::from_fn_with_state(config.clone(), connections::limit_request));
 
     // Start server
-    let addr = format!("{}:{}", config.server.host, config.server.port);
-    info!("Starting server on {}", addr);
-    
-    let listener = tokio::net::TcpListener::bind(&addr)
-        .await
-        .map_err(|e| AppError::InternalError(format!("Failed to bind to {}: {}", addr, e)))?;
-    
-    axum::serve(listener, app.into_make_service())
-        .await
-        .map_err(|e| AppError::InternalError(format!("Server error: {}", e)))?;
+    let (listener, source) = listener::open(&config.server).await?;
+    match listener.local_addr() {
+        Ok(addr) => info!("Starting server on {} ({:?} socket)", addr, source),
+        Err(e) => warn!("Starting server on a socket without a local address: {}", e),
+    }
+    listener::write_pid_file(&config.server)?;
+
+    // Upgrades start the new binary with this socket, which then asks this process to drain
+    listener::spawn_handover(&listener);
+    // Before the previous process is told to exit, so systemd follows this one
+    systemd::ready();
+    systemd::spawn_watchdog();
+    listener::finish_handover(source);
+
+    // Same router, so HTTP/3 requests pass the same middlewares and auth
+    let http3 = http3::spawn(app.clone(), &config.server)?;
+
+    let tls = config.server.tls.as_ref().map(tls::Acceptor::new).transpose()?;
+    connections::serve(listener, app, &config.server.connections, tls, shutdown_signal()).await;
+
+    // After HTTP/1.1 and HTTP/2 drained; QUIC clients retry on another connection
+    if let Some(endpoint) = http3 {
+        endpoint.close(0u32.into(), b"shutting down");
+        endpoint.wait_idle().await;
+    }
+
+    // Buffered events would otherwise be lost
+    ingest::flush_all(&state).await;
+
+    leader::release(&state).await;
 
     Ok(())
-} 
\ No newline at end of file
+} 
+
+#[cfg(test)]
+mod tests {
+    use super::{RedactedHeaders, RedactedUri};
+
+    #[test]
+    fn redacted_headers_hide_session_cookies() {
+        let mut headers = http::HeaderMap::new();
+        headers.insert(http::header::COOKIE, "s3proxy_session=secret-token".parse().unwrap());
+        headers.insert(http::header::SET_COOKIE, "s3proxy_session=other-token; HttpOnly".parse().unwrap());
+        headers.insert(http::header::USER_AGENT, "curl/8.0".parse().unwrap());
+        let formatted = RedactedHeaders(&headers).to_string();
+        assert!(!formatted.contains("secret-token"));
+        assert!(!formatted.contains("other-token"));
+        assert!(formatted.contains("cookie: ***REDACTED***"));
+        assert!(formatted.contains("user-agent: curl/8.0"));
+    }
+
+    #[test]
+    fn redacted_uri_hides_part_url_signatures() {
+        let uri: http::Uri = "/b/video.mp4?range=0-9&expires=1&user=alice&signature=c2VjcmV0".parse().unwrap();
+        let formatted = RedactedUri(&uri).to_string();
+        assert!(!formatted.contains("c2VjcmV0"));
+        assert_eq!(formatted, "/b/video.mp4?range=0-9&expires=1&user=alice&signature=***REDACTED***");
+    }
+}
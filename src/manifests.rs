@@ -0,0 +1,200 @@
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use tracing::{info, warn};
+
+use crate::chunking;
+use crate::dedup;
+use crate::error::{AppError, Result};
+use crate::packing;
+use crate::routing;
+use crate::server::AppState;
+
+/// Objects hashed at the same time by one verification
+const CONCURRENCY: usize = 4;
+
+/// Finished reports kept for retrieval; the oldest are dropped first
+const MAX_REPORTS: usize = 100;
+
+lazy_static! {
+    static ref REPORTS: Mutex<HashMap<String, Arc<Mutex<VerificationReport>>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationStatus {
+    Running,
+    Completed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Mismatch {
+    pub key: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedCheck {
+    pub key: String,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerificationReport {
+    pub manifest_id: String,
+    pub bucket: String,
+    pub status: VerificationStatus,
+    pub started_by: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub total: usize,
+    pub checked: usize,
+    pub matched: usize,
+    pub mismatched: Vec<Mismatch>,
+    pub missing: Vec<String>,
+    pub errors: Vec<FailedCheck>,
+}
+
+/// Parses `{"key": "sha256"}` JSON or `sha256sum` output ("<hex>  <key>" per line)
+pub fn parse(body: &[u8], json: bool) -> Result<BTreeMap<String, String>> {
+    let invalid = |message: String| AppError::InvalidRequest(format!("Invalid manifest: {}", message));
+    let entries: BTreeMap<String, String> = if json {
+        serde_json::from_slice(body).map_err(|e| invalid(e.to_string()))?
+    } else {
+        let text = std::str::from_utf8(body).map_err(|e| invalid(e.to_string()))?;
+        text.lines()
+            .map(str::trim_end)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let (hash, key) = line
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| invalid(format!("expected \"<sha256>  <key>\", got {}", line)))?;
+                // sha256sum marks binary mode with '*' before the file name
+                let key = key.trim_start().trim_start_matches('*');
+                Ok((key.to_string(), hash.to_string()))
+            })
+            .collect::<Result<_>>()?
+    };
+
+    if entries.is_empty() {
+        return Err(invalid("no entries".to_string()));
+    }
+    entries
+        .into_iter()
+        .map(|(key, hash)| {
+            let hash = hash.to_ascii_lowercase();
+            if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(invalid(format!("{} is not a SHA-256 hex digest for {}", hash, key)));
+            }
+            Ok((key, hash))
+        })
+        .collect()
+}
+
+async fn object_sha256(state: &AppState, bucket: &str, key: &str) -> Result<String> {
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    if let Some(part) = packing::read(client, &state.config, bucket, key, None).await? {
+        return Ok(hex::encode(Sha256::digest(&part.body)));
+    }
+    if let Some(part) = chunking::read(client, &state.config, bucket, key, None).await? {
+        return Ok(hex::encode(Sha256::digest(&part.body)));
+    }
+    let key = dedup::resolve(client, &state.config, bucket, key).await?;
+    let mut body = client.get_object(bucket, &key).await?;
+    let mut hasher = Sha256::new();
+    while let Some(chunk) = body.next().await {
+        hasher.update(chunk.map_err(|e| AppError::InternalError(e.to_string()))?);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+/// Starts hashing the stored objects in the background and returns the initial report
+pub fn start(state: Arc<AppState>, bucket: &str, started_by: &str, entries: BTreeMap<String, String>) -> VerificationReport {
+    let report = VerificationReport {
+        manifest_id: uuid::Uuid::new_v4().simple().to_string(),
+        bucket: bucket.to_string(),
+        status: VerificationStatus::Running,
+        started_by: started_by.to_string(),
+        started_at: Utc::now(),
+        finished_at: None,
+        total: entries.len(),
+        checked: 0,
+        matched: 0,
+        mismatched: Vec::new(),
+        missing: Vec::new(),
+        errors: Vec::new(),
+    };
+    info!(
+        "Verifying {} objects in {} against manifest {} for {}",
+        report.total, bucket, report.manifest_id, started_by
+    );
+
+    let shared = Arc::new(Mutex::new(report.clone()));
+    {
+        let mut reports = REPORTS.lock().unwrap();
+        if reports.len() >= MAX_REPORTS {
+            let oldest = reports
+                .iter()
+                .filter(|(_, report)| report.lock().unwrap().status == VerificationStatus::Completed)
+                .min_by_key(|(_, report)| report.lock().unwrap().started_at)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                reports.remove(&oldest);
+            }
+        }
+        reports.insert(report.manifest_id.clone(), shared.clone());
+    }
+
+    let bucket = bucket.to_string();
+    tokio::spawn(routing::scope(routing::current(), async move {
+        let checks = stream::iter(entries).map(|(key, expected)| {
+            let state = state.clone();
+            let bucket = bucket.clone();
+            async move {
+                let result = object_sha256(&state, &bucket, &key).await;
+                (key, expected, result)
+            }
+        });
+        let mut checks = checks.buffer_unordered(CONCURRENCY);
+        while let Some((key, expected, result)) = checks.next().await {
+            let mut report = shared.lock().unwrap();
+            report.checked += 1;
+            match result {
+                Ok(actual) if actual == expected => report.matched += 1,
+                Ok(actual) => report.mismatched.push(Mismatch { key, expected, actual }),
+                Err(AppError::ObjectNotFound(_, _)) => report.missing.push(key),
+                Err(e) => report.errors.push(FailedCheck { key, error: e.to_string() }),
+            }
+        }
+
+        let mut report = shared.lock().unwrap();
+        report.status = VerificationStatus::Completed;
+        report.finished_at = Some(Utc::now());
+        let problems = report.mismatched.len() + report.missing.len() + report.errors.len();
+        if problems > 0 {
+            warn!(
+                "Manifest {} for {}: {} of {} objects failed verification",
+                report.manifest_id, report.bucket, problems, report.total
+            );
+        } else {
+            info!("Manifest {} for {}: all {} objects verified", report.manifest_id, report.bucket, report.total);
+        }
+    }));
+    report
+}
+
+/// The report of a verification of objects in `bucket`
+pub fn report(bucket: &str, manifest_id: &str) -> Result<VerificationReport> {
+    REPORTS
+        .lock()
+        .unwrap()
+        .get(manifest_id)
+        .map(|report| report.lock().unwrap().clone())
+        .filter(|report| report.bucket == bucket)
+        .ok_or_else(|| AppError::ManifestNotFound(manifest_id.to_string()))
+}
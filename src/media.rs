@@ -0,0 +1,151 @@
+use std::sync::Arc;
+use tracing::{debug, info};
+
+use crate::cache::ByteRange;
+use crate::config::{Config, MediaConfig};
+use crate::error::{AppError, Result};
+use crate::routing;
+use crate::server::AppState;
+use crate::union;
+
+/// Top-level boxes walked looking for moov before giving up
+const MAX_BOXES: usize = 32;
+
+/// Box header: 32-bit size and type, then a 64-bit size when the first is 1
+const BOX_HEADER: u64 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Container {
+    /// ISO base media (mp4, mov, m4a): the moov box holds the index players need before any frame
+    Mp4,
+    /// Matroska and WebM: the Cues used for seeking are usually written at the end
+    Matroska,
+}
+
+fn container(media: &MediaConfig, key: &str) -> Option<Container> {
+    let (_, extension) = key.rsplit_once('.')?;
+    let extension = extension.to_ascii_lowercase();
+    if !media.extensions.contains(&extension) {
+        return None;
+    }
+    match extension.as_str() {
+        "webm" | "mkv" | "mka" => Some(Container::Matroska),
+        _ => Some(Container::Mp4),
+    }
+}
+
+/// Limits open-ended and oversized ranges on media objects, so a player's `bytes=0-` fetches a
+/// window rather than the whole video; players then ask for the next range as they need it
+pub fn range(config: &Config, key: &str, range: Option<&str>) -> Option<String> {
+    let Some(media) = &config.media else {
+        return range.map(String::from);
+    };
+    let requested = range?;
+    if container(media, key).is_none() {
+        return Some(requested.to_string());
+    }
+    match ByteRange::parse(requested) {
+        Some(ByteRange::Bounded(start, end)) => {
+            // The read clamps the end to the object's size
+            let last = start.saturating_add(media.max_range_bytes.max(1) - 1);
+            let end = end.map_or(last, |end| end.min(last));
+            Some(format!("bytes={}-{}", start, end))
+        }
+        _ => Some(requested.to_string()),
+    }
+}
+
+/// Type and size of a top-level box from its header; size 0 means the box runs to the end
+fn box_header(data: &[u8], offset: u64, size: u64) -> Option<(&[u8], u64)> {
+    if data.len() < 8 {
+        return None;
+    }
+    let kind = &data[4..8];
+    let length = match u32::from_be_bytes(data[0..4].try_into().unwrap()) {
+        0 => size - offset,
+        1 if data.len() >= 16 => u64::from_be_bytes(data[8..16].try_into().unwrap()),
+        1 => return None,
+        length => length as u64,
+    };
+    (length >= 8).then_some((kind, length))
+}
+
+async fn read(state: &AppState, bucket: &str, key: &str, start: u64, end: u64) -> Result<(bytes::Bytes, u64)> {
+    let part = union::read(state, bucket, key, Some(&format!("bytes={}-{}", start, end))).await?;
+    Ok((part.body, part.total_size))
+}
+
+/// Walks the top-level boxes of an mp4 from the head and reads the moov box into the cache,
+/// wherever it is; files not prepared for streaming keep it after the media data
+async fn prefetch_mp4(state: &AppState, media: &MediaConfig, bucket: &str, key: &str) -> Result<()> {
+    let (head, size) = read(state, bucket, key, 0, media.header_bytes.max(BOX_HEADER) - 1).await?;
+    let mut offset: u64 = 0;
+    for _ in 0..MAX_BOXES {
+        if offset.checked_add(8).is_none_or(|end| end > size) {
+            break;
+        }
+        let header = match head.get(offset as usize..) {
+            Some(rest) if rest.len() as u64 >= BOX_HEADER.min(size - offset) => rest.to_vec(),
+            // The box starts past the head, read just its header
+            _ => read(state, bucket, key, offset, offset.saturating_add(BOX_HEADER).min(size) - 1).await?.0.to_vec(),
+        };
+        let Some((kind, length)) = box_header(&header, offset, size) else {
+            break;
+        };
+        // Box lengths come from the object, so a corrupt file must not overflow the offset
+        let next = offset
+            .checked_add(length)
+            .ok_or_else(|| AppError::InvalidRequest(format!("{}/{} has a malformed box at byte {}", bucket, key, offset)))?;
+        if kind == b"moov" {
+            let end = next.min(size) - 1;
+            if end >= head.len() as u64 {
+                read(state, bucket, key, offset, end).await?;
+            }
+            debug!("Prefetched moov of {}/{} at bytes {}-{}", bucket, key, offset, end);
+            return Ok(());
+        }
+        offset = next;
+    }
+    debug!("No moov box found in {}/{}", bucket, key);
+    Ok(())
+}
+
+/// Reads the head and tail of a Matroska file, which hold the headers and the seek index
+async fn prefetch_matroska(state: &AppState, media: &MediaConfig, bucket: &str, key: &str) -> Result<()> {
+    let (_, size) = read(state, bucket, key, 0, media.header_bytes.max(1) - 1).await?;
+    if size > media.header_bytes {
+        union::read(state, bucket, key, Some(&format!("bytes=-{}", media.header_bytes))).await?;
+    }
+    Ok(())
+}
+
+/// Whether this is the first read of a media object, whose head is not cached yet
+pub fn needs_prefetch(state: &AppState, bucket: &str, key: &str) -> bool {
+    let (Some(media), Some(cache)) = (&state.config.media, &state.cache) else {
+        return false;
+    };
+    let account = state.find_account_for_bucket(bucket).unwrap_or_default();
+    container(media, key).is_some() && !cache.contains(&account, bucket, key, 0)
+}
+
+/// Loads the parts of a media object players fetch before playing or seeking into the cache in
+/// the background, so scrubbing does not wait on the upstream
+pub fn spawn_prefetch(state: Arc<AppState>, bucket: &str, key: &str) {
+    let Some(container) = state.config.media.as_ref().and_then(|media| container(media, key)) else {
+        return;
+    };
+    let (bucket, key) = (bucket.to_string(), key.to_string());
+    tokio::spawn(routing::scope(routing::current(), async move {
+        let Some(media) = &state.config.media else {
+            return;
+        };
+        let result = match container {
+            Container::Mp4 => prefetch_mp4(&state, media, &bucket, &key).await,
+            Container::Matroska => prefetch_matroska(&state, media, &bucket, &key).await,
+        };
+        match result {
+            Ok(()) => info!("Prefetched media headers of {}/{}", bucket, key),
+            Err(e) => debug!("Failed to prefetch media headers of {}/{}: {}", bucket, key, e),
+        }
+    }));
+}
@@ -0,0 +1,300 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::warn;
+
+use crate::config::{Config, MemoryConfig};
+use crate::error::{AppError, Result};
+use crate::metrics;
+
+/// Limit set from the config at startup; without one, bytes are counted but never refused
+static LIMIT: OnceLock<u64> = OnceLock::new();
+
+/// Request bodies, upload buffers and cache fills currently held in memory
+static BUFFERED: AtomicU64 = AtomicU64::new(0);
+
+pub fn init(config: &MemoryConfig) {
+    let _ = LIMIT.set(config.max_buffered_bytes);
+}
+
+/// Bytes counted against the budget until dropped
+#[derive(Debug)]
+pub struct Reservation(u64);
+
+impl Reservation {
+    /// Nothing reserved yet, for buffers that start empty
+    pub fn empty() -> Self {
+        Reservation(0)
+    }
+
+    /// Changes the reservation to bytes already held, which cannot be refused any more
+    pub fn resize(&mut self, bytes: u64) {
+        if bytes >= self.0 {
+            BUFFERED.fetch_add(bytes - self.0, Ordering::Relaxed);
+        } else {
+            BUFFERED.fetch_sub(self.0 - bytes, Ordering::Relaxed);
+        }
+        self.0 = bytes;
+        metrics::set_buffered_bytes(BUFFERED.load(Ordering::Relaxed));
+    }
+
+    /// Adds bytes about to be buffered, refused like [`reserve`] when the budget is used up
+    pub fn grow(&mut self, bytes: u64) -> Result<()> {
+        admit(bytes, self.0)?;
+        self.0 += bytes;
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.resize(0);
+    }
+}
+
+/// Reserves bytes about to be buffered, refusing them when the budget is used up. With nothing
+/// else buffered a reservation is always granted, so requests larger than the budget still run
+/// one at a time instead of never.
+pub fn reserve(bytes: u64) -> Result<Reservation> {
+    admit(bytes, 0)?;
+    Ok(Reservation(bytes))
+}
+
+/// Counts `bytes` more for a holder of `held`, unless that overruns the budget while anything
+/// else is buffered
+fn admit(bytes: u64, held: u64) -> Result<()> {
+    let limit = LIMIT.get().copied().unwrap_or(u64::MAX);
+    let admitted = BUFFERED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |buffered| {
+        (buffered <= held || buffered.saturating_add(bytes) <= limit).then(|| buffered + bytes)
+    });
+    match admitted {
+        Ok(buffered) => {
+            metrics::set_buffered_bytes(buffered + bytes);
+            Ok(())
+        }
+        Err(buffered) => {
+            warn!("Refused to buffer {} bytes with {} of {} in use", bytes, buffered, limit);
+            Err(AppError::ServiceUnavailable("The proxy is out of buffer memory, retry later".to_string()))
+        }
+    }
+}
+
+/// What a request body holds of the budget: its declared length up front, grown by whatever
+/// arrives beyond it, until the request and its response are dropped
+#[derive(Debug)]
+pub struct BodyCharge {
+    reservation: Mutex<Reservation>,
+    refused: AtomicBool,
+}
+
+/// Reserves the declared body of a request and wraps the body to charge the bytes actually read.
+/// The charge goes into the request's extensions, so a body that is buffered and handed on is
+/// counted once.
+pub fn charge(request: Request) -> Result<Request> {
+    if request.extensions().get::<Arc<BodyCharge>>().is_some() {
+        return Ok(request);
+    }
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(0);
+    let charge = Arc::new(BodyCharge { reservation: Mutex::new(reserve(length)?), refused: AtomicBool::new(false) });
+    let (mut parts, body) = request.into_parts();
+    parts.extensions.insert(charge.clone());
+    Ok(Request::from_parts(parts, counted(body, charge)))
+}
+
+/// Whether reading the request's body was stopped for running out of budget
+pub fn was_refused(request: &http::request::Parts) -> bool {
+    request.extensions.get::<Arc<BodyCharge>>().is_some_and(|charge| charge.refused.load(Ordering::Relaxed))
+}
+
+/// Ends a body with an error once more of it arrives than the budget can hold. Chunked bodies and
+/// bodies longer than their Content-Length are charged as they are read.
+fn counted(body: Body, charge: Arc<BodyCharge>) -> Body {
+    let mut received = 0u64;
+    Body::from_stream(body.into_data_stream().map(move |chunk| {
+        let chunk = chunk?;
+        received += chunk.len() as u64;
+        let mut reservation = charge.reservation.lock().unwrap();
+        if received > reservation.0 {
+            let extra = received - reservation.0;
+            if let Err(e) = reservation.grow(extra) {
+                charge.refused.store(true, Ordering::Relaxed);
+                return Err(axum::Error::new(e));
+            }
+        }
+        Ok(chunk)
+    }))
+}
+
+/// Largest buffer allocated up front from a declared length. Longer bodies grow it as they arrive,
+/// so a wrong or hostile length cannot claim memory the body never fills.
+pub const MAX_PREALLOCATION: usize = 8 * 1024 * 1024;
+
+/// Gathers a body arriving in chunks into one buffer. A body of a single chunk is kept as is. Longer
+/// ones are copied once into a buffer sized from the declared length, so each chunk is released as
+/// soon as it is copied instead of all of them being held until the end.
+#[derive(Debug)]
+pub struct BodyBuffer {
+    preallocate: usize,
+    first: Option<Bytes>,
+    buffer: BytesMut,
+    reservation: Option<Reservation>,
+}
+
+impl BodyBuffer {
+    /// For bodies already charged as they arrive, like request bodies behind [`guard`]
+    pub fn new(declared: Option<u64>) -> Self {
+        let preallocate = declared.map_or(0, |length| usize::try_from(length).unwrap_or(usize::MAX).min(MAX_PREALLOCATION));
+        BodyBuffer { preallocate, first: None, buffer: BytesMut::new(), reservation: None }
+    }
+
+    /// Charges the memory the buffer holds, refusing chunks once the budget is used up
+    pub fn charged(declared: Option<u64>) -> Self {
+        BodyBuffer { reservation: Some(Reservation::empty()), ..Self::new(declared) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.first.as_ref().map_or(self.buffer.len(), Bytes::len)
+    }
+
+    pub fn push(&mut self, chunk: Bytes) -> Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        match self.first.take() {
+            None if self.buffer.is_empty() => self.first = Some(chunk),
+            None => self.buffer.extend_from_slice(&chunk),
+            Some(first) => {
+                self.buffer = BytesMut::with_capacity(self.preallocate.max(first.len() + chunk.len()));
+                self.buffer.extend_from_slice(&first);
+                self.buffer.extend_from_slice(&chunk);
+            }
+        }
+        let held = self.first.as_ref().map_or(self.buffer.capacity(), Bytes::len) as u64;
+        match &mut self.reservation {
+            Some(reservation) if held > reservation.0 => {
+                let more = held - reservation.0;
+                reservation.grow(more)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The body, and what it holds of the budget for as long as the caller keeps it
+    pub fn finish(self) -> (Bytes, Reservation) {
+        let body = self.first.unwrap_or_else(|| self.buffer.freeze());
+        (body, self.reservation.unwrap_or_else(Reservation::empty))
+    }
+}
+
+/// Tells clients shed for memory when to come back
+pub fn retry_after(config: &Config, mut response: Response) -> Response {
+    if let Some(memory) = &config.memory {
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+            response
+                .headers_mut()
+                .entry(header::RETRY_AFTER)
+                .or_insert_with(|| HeaderValue::from(memory.retry_after_secs));
+        }
+    }
+    response
+}
+
+/// Charges the body of each request until its response is ready, so bursts of large uploads are
+/// shed with 503 instead of growing the process until it is killed
+pub async fn guard(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    if config.memory.is_none() {
+        return next.run(request).await;
+    }
+    let request = match charge(request) {
+        Ok(request) => request,
+        Err(e) => return retry_after(&config, e.into_response()),
+    };
+    let charge = request.extensions().get::<Arc<BodyCharge>>().cloned();
+    let mut response = next.run(request).await;
+    // Handlers see a refused body as one that failed to arrive
+    if charge.is_some_and(|charge| charge.refused.load(Ordering::Relaxed)) {
+        response = AppError::ServiceUnavailable("The proxy is out of buffer memory, retry later".to_string()).into_response();
+    }
+    // Also covers cache fills refused inside handlers
+    retry_after(&config, response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(request: &Request) -> u64 {
+        request.extensions().get::<Arc<BodyCharge>>().unwrap().reservation.lock().unwrap().0
+    }
+
+    #[tokio::test]
+    async fn bodies_are_charged_for_what_arrives_not_what_was_declared() {
+        let declared = Request::builder().header(header::CONTENT_LENGTH, "4").body(Body::from("0123456789")).unwrap();
+        let declared = charge(declared).unwrap();
+        assert_eq!(held(&declared), 4);
+        let (parts, body) = declared.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let declared = Request::from_parts(parts, Body::from(body));
+        assert_eq!(held(&declared), 10);
+
+        let chunked = charge(Request::new(Body::from("0123456789"))).unwrap();
+        assert_eq!(held(&chunked), 0);
+        let (parts, body) = chunked.into_parts();
+        axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(held(&Request::from_parts(parts, Body::empty())), 10);
+    }
+
+    #[tokio::test]
+    async fn buffered_bodies_are_not_charged_twice() {
+        let request = charge(Request::new(Body::from("0123456789"))).unwrap();
+        let (parts, body) = request.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let request = charge(Request::from_parts(parts, Body::from(body))).unwrap();
+        let (parts, body) = request.into_parts();
+        axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert_eq!(held(&Request::from_parts(parts, Body::empty())), 10);
+    }
+
+    #[test]
+    fn single_chunk_bodies_are_kept_without_a_copy() {
+        let chunk = Bytes::from_static(b"0123456789");
+        let mut buffer = BodyBuffer::charged(Some(10));
+        buffer.push(chunk.clone()).unwrap();
+        let (body, reservation) = buffer.finish();
+        assert_eq!(body.as_ptr(), chunk.as_ptr());
+        assert_eq!(reservation.0, 10);
+    }
+
+    #[test]
+    fn preallocation_is_capped_and_charged() {
+        let mut buffer = BodyBuffer::charged(Some(u64::MAX));
+        buffer.push(Bytes::from_static(b"01234")).unwrap();
+        buffer.push(Bytes::from_static(b"56789")).unwrap();
+        assert_eq!(buffer.buffer.capacity(), MAX_PREALLOCATION);
+        let (body, reservation) = buffer.finish();
+        assert_eq!(&body[..], b"0123456789");
+        assert_eq!(reservation.0, MAX_PREALLOCATION as u64);
+
+        let mut buffer = BodyBuffer::new(Some(4));
+        for chunk in [&b"0123"[..], b"4567", b"89"] {
+            buffer.push(Bytes::copy_from_slice(chunk)).unwrap();
+        }
+        let (body, reservation) = buffer.finish();
+        assert_eq!(&body[..], b"0123456789");
+        assert_eq!(reservation.0, 0);
+    }
+}
+
@@ -0,0 +1,258 @@
+use axum::{extract::Request, middleware::Next, response::Response};
+use prometheus::core::Collector;
+use prometheus::proto::LabelPair;
+use prometheus::{
+    exponential_buckets, linear_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+use std::time::{Duration, Instant};
+
+use crate::kubernetes;
+use crate::tenants;
+
+lazy_static::lazy_static! {
+    static ref REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_requests_total",
+        "Requests handled by the proxy",
+        &["method", "status", "tenant"]
+    ).unwrap();
+    static ref REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "s3_proxy_request_duration_seconds",
+        "Time to produce a response",
+        &["method", "tenant"]
+    ).unwrap();
+    static ref BYTES_UPLOADED: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_bytes_uploaded_total",
+        "Object bytes received from clients",
+        &["bucket", "tenant"]
+    ).unwrap();
+    static ref BYTES_DOWNLOADED: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_bytes_downloaded_total",
+        "Object bytes sent to clients",
+        &["bucket", "tenant"]
+    ).unwrap();
+    // 1 KiB up to 16 GiB in powers of four
+    static ref OBJECT_SIZE: HistogramVec = register_histogram_vec!(
+        "s3_proxy_object_size_bytes",
+        "Size of objects uploaded and downloaded",
+        &["bucket", "operation", "tenant"],
+        exponential_buckets(1024.0, 4.0, 13).unwrap()
+    ).unwrap();
+    static ref CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_cache_lookups_total",
+        "Cached reads by whether every block was already cached",
+        &["bucket", "result", "tenant"]
+    ).unwrap();
+    static ref CACHE_BYTES: IntGauge = register_int_gauge!(
+        "s3_proxy_cache_bytes",
+        "Object data currently held in the cache"
+    ).unwrap();
+    static ref BUFFERED_BYTES: IntGauge = register_int_gauge!(
+        "s3_proxy_buffered_bytes",
+        "Request bodies, upload buffers and cache fills currently held in memory"
+    ).unwrap();
+    static ref LEADER: IntGauge = register_int_gauge!(
+        "s3_proxy_leader",
+        "1 while this replica holds the leader lease"
+    ).unwrap();
+    static ref UPSTREAM_TTFB: HistogramVec = register_histogram_vec!(
+        "s3_proxy_upstream_ttfb_seconds",
+        "Time until upstream returned response headers",
+        &["account", "operation"]
+    ).unwrap();
+    static ref RANGE_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_range_requests_total",
+        "Object reads by how they continue a user's download: full, initial, resume or seek",
+        &["bucket", "kind", "tenant"]
+    ).unwrap();
+    static ref UPSTREAM_CONCURRENCY_LIMIT: IntGaugeVec = register_int_gauge_vec!(
+        "s3_proxy_upstream_concurrency_limit",
+        "Requests currently allowed in flight to an account's endpoint",
+        &["account"]
+    ).unwrap();
+    static ref HEDGED_READS: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_hedged_reads_total",
+        "Object reads that outlasted the hedge delay, by which request answered: original, hedge or neither",
+        &["bucket", "winner", "tenant"]
+    ).unwrap();
+    static ref RESUMED_DOWNLOADS: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_resumed_downloads_total",
+        "Finished or abandoned downloads that were resumed at least once",
+        &["bucket", "tenant"]
+    ).unwrap();
+    static ref DOWNLOAD_COMPLETION: HistogramVec = register_histogram_vec!(
+        "s3_proxy_download_completion_ratio",
+        "Share of the object a download fetched from its start without gaps",
+        &["bucket", "tenant"],
+        linear_buckets(0.1, 0.1, 10).unwrap()
+    ).unwrap();
+    static ref AUTH_DENIED: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_auth_denied_total",
+        "Requests of authenticated users refused by a grant rule or the rate limit",
+        &["user", "reason", "tenant"]
+    ).unwrap();
+    static ref FIREWALL_HITS: IntCounterVec = register_int_counter_vec!(
+        "s3_proxy_firewall_hits_total",
+        "Requests decided by a firewall rule, or by the default action as rule \"default\"",
+        &["rule", "action", "tenant"]
+    ).unwrap();
+    static ref PANICS: IntCounter = register_int_counter!(
+        "s3_proxy_panics_total",
+        "Handler panics answered with a 500"
+    ).unwrap();
+}
+
+/// Label of the tenant of the request being handled; empty in the default namespace, which
+/// Prometheus treats like a missing label
+fn tenant() -> String {
+    tenants::current().unwrap_or_default()
+}
+
+pub fn record_upload(bucket: &str, bytes: usize) {
+    let tenant = tenant();
+    BYTES_UPLOADED.with_label_values(&[bucket, &tenant]).inc_by(bytes as u64);
+    OBJECT_SIZE.with_label_values(&[bucket, "put", &tenant]).observe(bytes as f64);
+}
+
+pub fn record_download(bucket: &str, bytes: usize) {
+    let tenant = tenant();
+    BYTES_DOWNLOADED.with_label_values(&[bucket, &tenant]).inc_by(bytes as u64);
+    OBJECT_SIZE.with_label_values(&[bucket, "get", &tenant]).observe(bytes as f64);
+}
+
+pub fn record_range_request(bucket: &str, kind: &str) {
+    RANGE_REQUESTS.with_label_values(&[bucket, kind, &tenant()]).inc();
+}
+
+pub fn set_upstream_concurrency_limit(account_id: &str, limit: i64) {
+    UPSTREAM_CONCURRENCY_LIMIT.with_label_values(&[account_id]).set(limit);
+}
+
+pub fn record_hedged_read(bucket: &str, winner: &str) {
+    HEDGED_READS.with_label_values(&[bucket, winner, &tenant()]).inc();
+}
+
+/// Takes the tenant of the download, as it may finish while another request is handled
+pub fn record_download_completion(tenant: Option<&str>, bucket: &str, ratio: f64, resumed: bool) {
+    let tenant = tenant.unwrap_or_default();
+    DOWNLOAD_COMPLETION.with_label_values(&[bucket, tenant]).observe(ratio);
+    if resumed {
+        RESUMED_DOWNLOADS.with_label_values(&[bucket, tenant]).inc();
+    }
+}
+
+pub fn record_auth_denied(user: &str, reason: &str) {
+    AUTH_DENIED.with_label_values(&[user, reason, &tenant()]).inc();
+}
+
+pub fn record_firewall_hit(rule: &str, action: &str) {
+    FIREWALL_HITS.with_label_values(&[rule, action, &tenant()]).inc();
+}
+
+pub fn record_cache_lookup(bucket: &str, hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    CACHE_LOOKUPS.with_label_values(&[bucket, result, &tenant()]).inc();
+}
+
+pub fn set_cache_bytes(bytes: u64) {
+    CACHE_BYTES.set(bytes as i64);
+}
+
+pub fn set_buffered_bytes(bytes: u64) {
+    BUFFERED_BYTES.set(bytes as i64);
+}
+
+pub fn set_leader(leader: bool) {
+    LEADER.set(leader as i64);
+}
+
+pub fn record_panic() {
+    PANICS.inc();
+}
+
+pub fn record_upstream_ttfb(account_id: &str, operation: &str, elapsed: Duration) {
+    UPSTREAM_TTFB
+        .with_label_values(&[account_id, operation])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Counts every request and its latency, including ones rejected by auth
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    // Cloning a standard method does not allocate, unlike formatting it
+    let method = request.method().clone();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let tenant = tenant();
+    REQUESTS_TOTAL
+        .with_label_values(&[method.as_str(), response.status().as_str(), &tenant])
+        .inc();
+    REQUEST_DURATION
+        .with_label_values(&[method.as_str(), &tenant])
+        .observe(started.elapsed().as_secs_f64());
+    response
+}
+
+/// Cumulative request totals across all methods, used to evaluate alert windows
+#[derive(Debug, Clone, Default)]
+pub struct RequestSnapshot {
+    pub requests: u64,
+    pub server_errors: u64,
+    /// Cumulative request count per latency bucket upper bound in seconds
+    pub latency_buckets: Vec<(f64, u64)>,
+}
+
+pub fn request_snapshot() -> RequestSnapshot {
+    let mut snapshot = RequestSnapshot::default();
+
+    for family in REQUESTS_TOTAL.collect() {
+        for metric in family.get_metric() {
+            let is_server_error = metric
+                .get_label()
+                .iter()
+                .any(|label| label.get_name() == "status" && label.get_value().starts_with('5'));
+            let count = metric.get_counter().get_value() as u64;
+            snapshot.requests += count;
+            if is_server_error {
+                snapshot.server_errors += count;
+            }
+        }
+    }
+
+    for family in REQUEST_DURATION.collect() {
+        for metric in family.get_metric() {
+            for (i, bucket) in metric.get_histogram().get_bucket().iter().enumerate() {
+                match snapshot.latency_buckets.get_mut(i) {
+                    Some((_, count)) => *count += bucket.get_cumulative_count(),
+                    None => snapshot
+                        .latency_buckets
+                        .push((bucket.get_upper_bound(), bucket.get_cumulative_count())),
+                }
+            }
+        }
+    }
+    snapshot
+}
+
+/// Renders all registered metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut families = prometheus::gather();
+    if let Some(pod) = kubernetes::pod() {
+        for metric in families.iter_mut().flat_map(|family| family.mut_metric().iter_mut()) {
+            let mut labels = metric.take_label();
+            for (name, value) in pod.labels() {
+                let mut label = LabelPair::default();
+                label.set_name(name.to_string());
+                label.set_value(value.to_string());
+                labels.push(label);
+            }
+            metric.set_label(labels);
+        }
+    }
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&families, &mut buffer)
+        .expect("text encoding of gathered metrics");
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,80 @@
+use serde_json::{Map, Value};
+use std::fs;
+use tracing::info;
+
+use crate::config::{self, Config};
+use crate::error::{AppError, Result};
+use crate::includes;
+
+/// Rewrites a config of the version before it into its own version
+type Migration = fn(&mut Map<String, Value>) -> Result<()>;
+
+/// Steps from version 1, the flat users and accounts layout, each to the next version; a layout
+/// change such as roles and groups appends its step here
+const MIGRATIONS: &[Migration] = &[];
+
+/// Version of the config layout this build reads; files without a version are version 1
+pub const CURRENT_VERSION: u64 = 1 + MIGRATIONS.len() as u64;
+
+fn invalid(path: &str, message: &str) -> AppError {
+    AppError::ConfigError(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{}: {}", path, message),
+    ))
+}
+
+/// Brings a parsed config file to the current version and returns the version it was written in
+pub fn upgrade(path: &str, config: &mut Value) -> Result<u64> {
+    let object = config.as_object_mut().ok_or_else(|| invalid(path, "not a JSON object"))?;
+    let version = match object.get("version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .filter(|version| *version >= 1)
+            .ok_or_else(|| invalid(path, "version must be a positive integer"))?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(invalid(
+            path,
+            &format!("version {} is newer than {}, the latest this proxy reads", version, CURRENT_VERSION),
+        ));
+    }
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+        migration(object)?;
+        info!("Migrated {} from config version {} to {}", path, from + 1, from + 2);
+    }
+    match object.get_mut("version") {
+        Some(stamped) => *stamped = CURRENT_VERSION.into(),
+        None => {
+            object.shift_insert(0, "version".to_string(), CURRENT_VERSION.into());
+        }
+    }
+    Ok(version)
+}
+
+/// `migrate-config [path] [output]`: writes the config at `path`, config.json by default, in the
+/// current version; when rewritten in place the original is kept as `path`.bak
+pub fn run(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let path = args.next().unwrap_or_else(|| "config.json".to_string());
+    let output = args.next().unwrap_or_else(|| path.clone());
+    let mut config: Value = config::read_json(&path)?;
+    let stamped = config.get("version").is_some();
+    let version = upgrade(&path, &mut config)?;
+    // Refuse to write a file the proxy would not start with; included files stay where they are
+    let mut merged = config.clone();
+    includes::merge(&path, &mut merged)?;
+    serde_json::from_value::<Config>(merged).map_err(|e| invalid(&path, &e.to_string()))?;
+
+    if output == path {
+        if stamped && version == CURRENT_VERSION {
+            println!("{} is already at config version {}", path, CURRENT_VERSION);
+            return Ok(());
+        }
+        fs::copy(&path, format!("{}.bak", path))?;
+    }
+    let mut contents = serde_json::to_string_pretty(&config).map_err(|e| invalid(&path, &e.to_string()))?;
+    contents.push('\n');
+    fs::write(&output, contents)?;
+    println!("Wrote {} at config version {} (was {})", output, CURRENT_VERSION, version);
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use crate::config::Config;
+use crate::server;
+
+/// The proxy's own endpoints; the S3-compatible object and bucket API is described by S3 itself
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "s3-proxy", description = "Native endpoints of the S3 proxy"),
+    paths(
+        server::get_object,
+        server::aggregate_listing,
+        server::upload_progress,
+        server::authz_check,
+        server::create_session,
+        server::issue_key,
+        server::list_keys,
+        server::revoke_key,
+        server::ingest_events,
+        server::prometheus_metrics,
+        server::list_cache_pins,
+        server::add_cache_pin,
+        server::remove_cache_pin,
+        server::cost_report,
+        server::cold_report,
+        server::audit_log,
+        server::verify_audit_chain,
+        server::backend_report,
+        server::rewrite_check,
+        server::list_stored_users,
+        server::put_stored_user,
+        server::delete_stored_user,
+        server::put_bucket_route,
+        server::delete_bucket_route,
+        server::list_legal_holds,
+        server::place_legal_hold,
+        server::release_legal_hold,
+        server::compliance_export,
+        server::compliance_erase,
+    ),
+    modifiers(&ApiKeyAuth),
+    security(("api_key" = [])),
+    tags(
+        (name = "objects", description = "Reading objects beyond plain S3"),
+        (name = "access", description = "Sessions, delegated keys and permission checks"),
+        (name = "ingest", description = "Batching events into objects"),
+        (name = "admin", description = "Operations that require the admin role"),
+    )
+)]
+struct ApiDoc;
+
+struct ApiKeyAuth;
+
+impl Modify for ApiKeyAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-api-key"))));
+    }
+}
+
+/// The document served at /openapi.json, without the endpoints of features this config leaves off
+pub fn document(config: &Config) -> utoipa::openapi::OpenApi {
+    let mut openapi = ApiDoc::openapi();
+    openapi.info.version = env!("CARGO_PKG_VERSION").to_string();
+    openapi.info.license = None;
+    openapi.paths.paths.retain(|path, _| {
+        (config.sessions.is_some() || path != "/session")
+            && (config.delegated_keys.is_some() || !path.starts_with("/keys"))
+            && (config.ingest.is_some() || !path.starts_with("/ingest/"))
+            && (config.config_store.is_some()
+                || !(path.starts_with("/admin/users") || path.starts_with("/admin/buckets/")))
+    });
+    openapi
+}
@@ -0,0 +1,117 @@
+use aws_smithy_runtime_api::client::http::{HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+use crate::config::PacingConfig;
+
+lazy_static! {
+    /// Current request rate and next free send slot by account
+    static ref PACES: Mutex<HashMap<String, Pace>> = Mutex::new(HashMap::new());
+}
+
+struct Pace {
+    rate: f64,
+    next_slot: Instant,
+}
+
+fn throttled(status: u16) -> bool {
+    status == 503 || status == 429
+}
+
+/// Claims the next send slot, or None when it lies beyond `deadline`
+fn reserve(account_id: &str, config: &PacingConfig, deadline: Instant) -> Option<Duration> {
+    let mut paces = PACES.lock().unwrap();
+    let now = Instant::now();
+    let pace = paces
+        .entry(account_id.to_string())
+        .or_insert_with(|| Pace { rate: config.max_rate, next_slot: now });
+    let slot = pace.next_slot.max(now);
+    if slot > deadline {
+        return None;
+    }
+    pace.next_slot = slot + Duration::from_secs_f64(1.0 / pace.rate.max(f64::MIN_POSITIVE));
+    Some(slot - now)
+}
+
+fn adjust(account_id: &str, config: &PacingConfig, throttled: bool) {
+    let mut paces = PACES.lock().unwrap();
+    let Some(pace) = paces.get_mut(account_id) else {
+        return;
+    };
+    let previous = pace.rate;
+    pace.rate = if throttled {
+        (pace.rate * config.decrease).max(config.min_rate)
+    } else {
+        (pace.rate + config.increase).min(config.max_rate)
+    };
+    if throttled && pace.rate < previous {
+        info!("Account {} is throttling, pacing to {:.1} requests/s", account_id, pace.rate);
+    }
+}
+
+/// Exponential backoff with full jitter
+fn backoff(config: &PacingConfig, attempt: u32) -> Duration {
+    let ceiling = config
+        .base_delay_ms
+        .saturating_mul(1u64 << attempt.min(20))
+        .min(config.max_delay_ms);
+    let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u64;
+    Duration::from_millis(ceiling * jitter / 1000)
+}
+
+/// Spaces requests to an account's endpoint and retries throttled ones within a latency budget
+#[derive(Debug, Clone)]
+struct PacedConnector {
+    account_id: Arc<str>,
+    config: Arc<PacingConfig>,
+    inner: SharedHttpConnector,
+}
+
+impl PacedConnector {
+    async fn send(self, mut request: HttpRequest) -> Result<HttpResponse, ConnectorError> {
+        let deadline = Instant::now() + Duration::from_millis(self.config.budget_ms);
+        let mut attempt = 0;
+        loop {
+            let wait = reserve(&self.account_id, &self.config, deadline).ok_or_else(|| {
+                let message = format!("account {} is paced beyond the latency budget", self.account_id);
+                ConnectorError::other(message.into(), None)
+            })?;
+            tokio::time::sleep(wait).await;
+
+            // Streaming bodies cannot be replayed and are sent only once
+            let retry = request.try_clone();
+            let response = self.inner.call(request).await?;
+            let status = response.status().as_u16();
+            adjust(&self.account_id, &self.config, throttled(status));
+            if !throttled(status) {
+                return Ok(response);
+            }
+
+            let delay = backoff(&self.config, attempt);
+            match retry {
+                Some(next) if Instant::now() + delay < deadline => {
+                    debug!("Account {} answered {}, retrying in {:?}", self.account_id, status, delay);
+                    tokio::time::sleep(delay).await;
+                    request = next;
+                    attempt += 1;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
+}
+
+impl HttpConnector for PacedConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        HttpConnectorFuture::new(self.clone().send(request))
+    }
+}
+
+pub fn wrap(account_id: &str, config: PacingConfig, inner: SharedHttpConnector) -> SharedHttpConnector {
+    SharedHttpConnector::new(PacedConnector { account_id: account_id.into(), config: Arc::new(config), inner })
+}
@@ -0,0 +1,128 @@
+use axum::{
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use std::collections::BTreeSet;
+
+use crate::config::{Config, PackageIndexConfig};
+use crate::error::{AppError, Result};
+use crate::listing::html_escape;
+use crate::server::AppState;
+use crate::sigv4::uri_encode;
+use crate::union;
+
+/// Project names are compared as PEP 503 normalizes them: lowercase with runs of -_. as one -
+pub fn normalize(name: &str) -> String {
+    let mut normalized = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !normalized.ends_with('-') {
+                normalized.push('-');
+            }
+        } else {
+            normalized.push(c.to_ascii_lowercase());
+        }
+    }
+    normalized
+}
+
+/// Realm of the Basic challenge for requests to an index or its packages, whose clients such as
+/// pip only send Basic credentials
+pub fn realm(config: &Config, path: &str) -> Option<String> {
+    let (bucket, key) = path.trim_start_matches('/').split_once('/')?;
+    let index = config.package_indexes.get(bucket)?;
+    let served = key.starts_with(index.path.trim_end_matches('/')) || key.starts_with(&index.prefix);
+    served.then(|| bucket.to_string())
+}
+
+fn redirect(location: String) -> Response {
+    let mut headers = HeaderMap::new();
+    if let Ok(location) = location.parse() {
+        headers.insert(http::header::LOCATION, location);
+    }
+    (StatusCode::MOVED_PERMANENTLY, headers).into_response()
+}
+
+fn page(title: &str, links: &[(String, String)]) -> Response {
+    let anchors: Vec<String> = links
+        .iter()
+        .map(|(href, label)| format!(r#"<a href="{}">{}</a><br/>"#, html_escape(href), html_escape(label)))
+        .collect();
+    let title = html_escape(title);
+    let html = format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta name="pypi:repository-version" content="1.0">
+<title>{title}</title>
+</head>
+<body>
+<h1>{title}</h1>
+{anchors}
+</body>
+</html>
+"#,
+        title = title,
+        anchors = anchors.join("\n")
+    );
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "text/html; charset=utf-8".parse().unwrap());
+    (StatusCode::OK, headers, html).into_response()
+}
+
+/// Project directories under the prefix, by the name each normalizes to
+async fn projects(state: &AppState, bucket: &str, index: &PackageIndexConfig) -> Result<Vec<(String, String)>> {
+    let (_, prefixes) = union::list(state, bucket, &index.prefix, true).await?;
+    Ok(prefixes
+        .iter()
+        .filter_map(|directory| {
+            let name = directory.strip_prefix(&index.prefix)?.trim_end_matches('/');
+            Some((normalize(name), directory.clone()))
+        })
+        .collect())
+}
+
+/// Serves `rest`, the part of the key after the index path
+pub async fn serve(state: &AppState, bucket: &str, index: &PackageIndexConfig, rest: &str) -> Result<Response> {
+    let root = format!("/{}/{}", uri_encode(bucket, true), uri_encode(&index.path, false));
+
+    if rest.is_empty() {
+        let names: BTreeSet<String> = projects(state, bucket, index).await?.into_iter().map(|(name, _)| name).collect();
+        let links: Vec<(String, String)> = names
+            .into_iter()
+            .map(|name| (format!("{}{}/", root, uri_encode(&name, true)), name))
+            .collect();
+        return Ok(page("Simple index", &links));
+    }
+
+    let project = match rest.strip_suffix('/') {
+        Some(project) if !project.contains('/') => project,
+        // Clients must use the canonical URL, which ends with a slash
+        None if !rest.contains('/') => return Ok(redirect(format!("{}{}/", root, uri_encode(&normalize(rest), true)))),
+        _ => return Err(AppError::ObjectNotFound(bucket.to_string(), format!("{}{}", index.path, rest))),
+    };
+    let name = normalize(project);
+    if project != name {
+        return Ok(redirect(format!("{}{}/", root, uri_encode(&name, true))));
+    }
+
+    let mut links = Vec::new();
+    for (_, directory) in projects(state, bucket, index).await?.into_iter().filter(|(other, _)| *other == name) {
+        let (objects, _) = union::list(state, bucket, &directory, false).await?;
+        for object in objects {
+            let key = object.key().unwrap_or_default();
+            let file = key.strip_prefix(directory.as_str()).unwrap_or(key);
+            // Only files directly in the project directory are distributions
+            if file.is_empty() || file.contains('/') {
+                continue;
+            }
+            let href = format!("/{}/{}", uri_encode(bucket, true), uri_encode(key, false));
+            links.push((href, file.to_string()));
+        }
+    }
+    if links.is_empty() {
+        return Err(AppError::ObjectNotFound(bucket.to_string(), format!("{}{}/", index.path, name)));
+    }
+    links.sort_by(|a, b| a.1.cmp(&b.1));
+    Ok(page(&format!("Links for {}", name), &links))
+}
@@ -0,0 +1,383 @@
+use aws_sdk_s3::primitives::{ByteStream, DateTime as S3DateTime};
+use aws_sdk_s3::types::Object;
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use crate::cache::ByteRange;
+use crate::config::{Config, PackingConfig};
+use crate::error::{AppError, Result};
+use crate::etags;
+use crate::s3::{ObjectPart, S3Client};
+use crate::server::AppState;
+
+lazy_static! {
+    /// Where each packed object lives, by bucket and key
+    static ref INDEX: RwLock<HashMap<String, BTreeMap<String, Location>>> = RwLock::new(HashMap::new());
+    /// Writes waiting for their segment, by bucket
+    static ref PENDING: Mutex<HashMap<String, Batch>> = Mutex::new(HashMap::new());
+    /// Segments are written one at a time so their names order the index updates
+    static ref FLUSH_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+static NEXT_BATCH: AtomicU64 = AtomicU64::new(0);
+
+/// The bytes of a packed object within a segment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Location {
+    segment: String,
+    offset: u64,
+    length: u64,
+    etag: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    last_modified: DateTime<Utc>,
+}
+
+impl Location {
+    fn listing_entry(&self, key: &str) -> Object {
+        Object::builder()
+            .key(key)
+            .size(self.length as i64)
+            .e_tag(&self.etag)
+            .last_modified(S3DateTime::from_secs(self.last_modified.timestamp()))
+            .build()
+    }
+}
+
+/// One line of a segment index; no location removes the key
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexRecord {
+    key: String,
+    location: Option<Location>,
+}
+
+enum Change {
+    Store { body: Bytes, content_type: Option<String> },
+    Set(Option<Location>),
+}
+
+enum Write {
+    Packed { offset: u64, length: u64, etag: String, content_type: Option<String> },
+    Set(Option<Location>),
+}
+
+struct Batch {
+    id: u64,
+    data: BytesMut,
+    writes: Vec<(String, Write)>,
+    waiters: Vec<oneshot::Sender<std::result::Result<(), String>>>,
+}
+
+fn lookup(bucket: &str, key: &str) -> Option<Location> {
+    INDEX.read().unwrap().get(bucket).and_then(|objects| objects.get(key)).cloned()
+}
+
+fn apply(bucket: &str, records: Vec<IndexRecord>) {
+    let mut index = INDEX.write().unwrap();
+    let objects = index.entry(bucket.to_string()).or_default();
+    for record in records {
+        match record.location {
+            Some(location) => objects.insert(record.key, location),
+            None => objects.remove(&record.key),
+        };
+    }
+}
+
+/// Reads the index of every segment in the packed buckets, oldest first
+pub async fn load(state: &AppState) -> Result<()> {
+    for (bucket, packing) in &state.config.packing {
+        let (_, client) = &state.get_account_and_client(bucket)?;
+        let mut indexes: Vec<String> = client
+            .list_objects(bucket, Some(packing.prefix.clone()))
+            .await?
+            .into_iter()
+            .filter_map(|object| object.key)
+            .filter(|key| key.ends_with(".json"))
+            .collect();
+        indexes.sort();
+        for key in &indexes {
+            let part = client.get_object_range(bucket, key, None, None).await?;
+            let records: Vec<IndexRecord> = serde_json::from_slice(&part.body)
+                .map_err(|e| AppError::InternalError(format!("Invalid segment index {}/{}: {}", bucket, key, e)))?;
+            apply(bucket, records);
+        }
+        let packed = INDEX.read().unwrap().get(bucket).map_or(0, BTreeMap::len);
+        info!("Loaded {} packed objects in {} from {} segments", packed, bucket, indexes.len());
+    }
+    Ok(())
+}
+
+/// Segments and their indexes are managed by the proxy alone
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    match config.packing.get(bucket) {
+        Some(packing) if key.starts_with(&packing.prefix) => Err(AppError::InvalidRequest(format!(
+            "{} is reserved for packed segments",
+            packing.prefix
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether an upload of `size` bytes to `bucket` is packed rather than stored on its own
+pub fn accepts(config: &Config, bucket: &str, size: usize) -> bool {
+    config.packing.get(bucket).is_some_and(|packing| size as u64 <= packing.max_object_size)
+}
+
+/// Adds changes to the bucket's open segment and waits until the segment is written
+async fn submit(client: &Arc<S3Client>, packing: &PackingConfig, bucket: &str, changes: Vec<(String, Change)>) -> Result<()> {
+    let (sender, receiver) = oneshot::channel();
+    let full = {
+        let mut pending = PENDING.lock().unwrap();
+        let batch = pending.entry(bucket.to_string()).or_insert_with(|| {
+            let id = NEXT_BATCH.fetch_add(1, Ordering::Relaxed);
+            let (client, packing, bucket) = (client.clone(), packing.clone(), bucket.to_string());
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(packing.flush_interval_ms)).await;
+                let batch = {
+                    let mut pending = PENDING.lock().unwrap();
+                    match pending.get(&bucket) {
+                        Some(batch) if batch.id == id => pending.remove(&bucket),
+                        _ => None,
+                    }
+                };
+                if let Some(batch) = batch {
+                    flush(&client, &packing, &bucket, batch).await;
+                }
+            });
+            Batch { id, data: BytesMut::new(), writes: Vec::new(), waiters: Vec::new() }
+        });
+
+        for (key, change) in changes {
+            let write = match change {
+                Change::Store { body, content_type } => {
+                    let offset = batch.data.len() as u64;
+                    batch.data.extend_from_slice(&body);
+                    Write::Packed { offset, length: body.len() as u64, etag: etags::from_body(&body), content_type }
+                }
+                Change::Set(location) => Write::Set(location),
+            };
+            batch.writes.push((key, write));
+        }
+        batch.waiters.push(sender);
+        if batch.data.len() as u64 >= packing.segment_size {
+            pending.remove(bucket)
+        } else {
+            None
+        }
+    };
+
+    // Spawned so the segment is written even if this request goes away
+    if let Some(batch) = full {
+        let (client, packing, bucket) = (client.clone(), packing.clone(), bucket.to_string());
+        tokio::spawn(async move { flush(&client, &packing, &bucket, batch).await });
+    }
+    receiver
+        .await
+        .map_err(|_| AppError::InternalError("Segment write was abandoned".to_string()))?
+        .map_err(|e| AppError::InternalError(format!("Writing segment failed: {}", e)))
+}
+
+async fn flush(client: &S3Client, packing: &PackingConfig, bucket: &str, batch: Batch) {
+    let _guard = FLUSH_LOCK.lock().await;
+    let segment = format!(
+        "{:020}-{}",
+        Utc::now().timestamp_nanos_opt().unwrap_or_default(),
+        uuid::Uuid::new_v4().simple()
+    );
+    let result = write_segment(client, packing, bucket, &segment, &batch).await;
+    let result = match result {
+        Ok(records) => {
+            info!(
+                "Wrote segment {}/{}{} with {} changes in {} bytes",
+                bucket,
+                packing.prefix,
+                segment,
+                records.len(),
+                batch.data.len()
+            );
+            apply(bucket, records);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Writing segment {}/{}{} failed: {}", bucket, packing.prefix, segment, e);
+            Err(e.to_string())
+        }
+    };
+    for waiter in batch.waiters {
+        let _ = waiter.send(result.clone());
+    }
+}
+
+/// Uploads the packed bytes, then the index that makes them visible
+async fn write_segment(
+    client: &S3Client,
+    packing: &PackingConfig,
+    bucket: &str,
+    segment: &str,
+    batch: &Batch,
+) -> Result<Vec<IndexRecord>> {
+    let now = Utc::now();
+    let records: Vec<IndexRecord> = batch
+        .writes
+        .iter()
+        .map(|(key, write)| IndexRecord {
+            key: key.clone(),
+            location: match write {
+                Write::Packed { offset, length, etag, content_type } => Some(Location {
+                    segment: segment.to_string(),
+                    offset: *offset,
+                    length: *length,
+                    etag: etag.clone(),
+                    content_type: content_type.clone(),
+                    last_modified: now,
+                }),
+                Write::Set(location) => location.clone(),
+            },
+        })
+        .collect();
+
+    if !batch.data.is_empty() {
+        let data = ByteStream::from(batch.data.clone().freeze());
+        client.put_object(bucket, &format!("{}{}", packing.prefix, segment), data, None).await?;
+    }
+    let index = serde_json::to_vec(&records).map_err(|e| AppError::InternalError(e.to_string()))?;
+    client
+        .put_object(
+            bucket,
+            &format!("{}{}.json", packing.prefix, segment),
+            ByteStream::from(index),
+            Some("application/json".to_string()),
+        )
+        .await?;
+    Ok(records)
+}
+
+/// Packs a small upload into the bucket's next segment
+pub async fn put(
+    client: &Arc<S3Client>,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+    body: Bytes,
+    content_type: Option<String>,
+) -> Result<Option<String>> {
+    let Some(packing) = config.packing.get(bucket) else {
+        return Err(AppError::InternalError(format!("Bucket {} does not pack objects", bucket)));
+    };
+    let etag = etags::from_body(&body);
+    submit(client, packing, bucket, vec![(key.to_string(), Change::Store { body, content_type })]).await?;
+    Ok(Some(etag))
+}
+
+/// Drops a packed object from the index, returning whether there was one
+pub async fn remove(client: &Arc<S3Client>, config: &Config, bucket: &str, key: &str) -> Result<bool> {
+    let Some(packing) = config.packing.get(bucket) else {
+        return Ok(false);
+    };
+    if lookup(bucket, key).is_none() {
+        return Ok(false);
+    }
+    submit(client, packing, bucket, vec![(key.to_string(), Change::Set(None))]).await?;
+    Ok(true)
+}
+
+/// Points `destination` at the bytes of a packed object, returning whether `key` was packed
+pub async fn rename(client: &Arc<S3Client>, config: &Config, bucket: &str, key: &str, destination: &str) -> Result<bool> {
+    let Some(packing) = config.packing.get(bucket) else {
+        return Ok(false);
+    };
+    let Some(location) = lookup(bucket, key) else {
+        return Ok(false);
+    };
+    let changes = vec![
+        (destination.to_string(), Change::Set(Some(location))),
+        (key.to_string(), Change::Set(None)),
+    ];
+    submit(client, packing, bucket, changes).await?;
+    Ok(true)
+}
+
+/// Reads a packed object, or the requested range of it, from its segment
+pub async fn read(
+    client: &S3Client,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+    range: Option<ByteRange>,
+) -> Result<Option<ObjectPart>> {
+    let Some(packing) = config.packing.get(bucket) else {
+        return Ok(None);
+    };
+    let Some(location) = lookup(bucket, key) else {
+        return Ok(None);
+    };
+    if location.length == 0 {
+        return Ok(Some(ObjectPart { body: Bytes::new(), etag: Some(location.etag), content_range: None, total_size: 0, proxy_etag: None }));
+    }
+
+    let (first, last, content_range) = match range {
+        Some(range) => {
+            let (first, last) = range.resolve(location.length).ok_or_else(|| {
+                AppError::RangeNotSatisfiable(format!("{}/{} does not contain the requested range", bucket, key))
+            })?;
+            (first, last, Some(format!("bytes {}-{}/{}", first, last, location.length)))
+        }
+        None => (0, location.length - 1, None),
+    };
+    let segment = format!("{}{}", packing.prefix, location.segment);
+    let segment_range = format!("bytes={}-{}", location.offset + first, location.offset + last);
+    let part = client.get_object_range(bucket, &segment, Some(segment_range), None).await?;
+    Ok(Some(ObjectPart {
+        body: part.body,
+        etag: Some(location.etag),
+        content_range,
+        total_size: location.length,
+        proxy_etag: None,
+    }))
+}
+
+/// Replaces segments in a listing with the packed objects they hold
+pub fn merge_listing(
+    config: &Config,
+    bucket: &str,
+    prefix: &str,
+    directories: bool,
+    objects: &mut Vec<Object>,
+    prefixes: &mut Vec<String>,
+) {
+    let Some(packing) = config.packing.get(bucket) else {
+        return;
+    };
+    let index = INDEX.read().unwrap();
+    let packed = index.get(bucket);
+    objects.retain(|object| {
+        let key = object.key().unwrap_or_default();
+        !key.starts_with(&packing.prefix) && !packed.is_some_and(|packed| packed.contains_key(key))
+    });
+    prefixes.retain(|common| !common.starts_with(&packing.prefix));
+    let Some(packed) = packed else {
+        return;
+    };
+
+    for (key, location) in packed.range(prefix.to_string()..).take_while(|(key, _)| key.starts_with(prefix)) {
+        match key[prefix.len()..].find('/') {
+            Some(slash) if directories => {
+                let common = key[..prefix.len() + slash + 1].to_string();
+                if !prefixes.contains(&common) {
+                    prefixes.push(common);
+                }
+            }
+            _ => objects.push(location.listing_entry(key)),
+        }
+    }
+    objects.sort_by(|a, b| a.key().cmp(&b.key()));
+    prefixes.sort();
+}
@@ -0,0 +1,278 @@
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, Method, Uri};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use std::collections::HashMap;
+
+use crate::auth::{AuthState, Signer};
+use crate::config::DownloadPartsConfig;
+use crate::error::{AppError, Result};
+use crate::etags;
+use crate::server::AppState;
+use crate::sigv4;
+use crate::tenants;
+use crate::union;
+
+pub use s3_proxy_client::types::{Part, PartsManifest};
+
+/// A part URL whose signature checked out
+pub struct SignedPart {
+    pub username: String,
+    pub bucket: String,
+    /// Issued to an LDAP user, who has no config entry to check the URL against
+    pub ldap: bool,
+}
+
+/// Percent-encodes everything but unreserved characters, and slashes when they separate key segments
+fn encode(value: &str, keep_slashes: bool) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) || (keep_slashes && b == b'/') {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+fn payload(username: &str, ldap: bool, path: &str, range: &str, etag: &str, expires: i64) -> String {
+    let source = if ldap { "\nldap" } else { "" };
+    format!("{}\n{}\n{}\n{}\n{}{}", username, path, range, etag, expires, source)
+}
+
+/// Splits an object into at most `requested` ranges, none smaller than the configured minimum
+/// except the last
+fn ranges(config: &DownloadPartsConfig, size: u64, requested: u64) -> Vec<(u64, u64)> {
+    if size == 0 {
+        return Vec::new();
+    }
+    let count = requested
+        .clamp(1, config.max_parts.max(1))
+        .min(size.div_ceil(config.min_part_bytes.max(1)));
+    let part_size = size.div_ceil(count);
+    (0..size)
+        .step_by(part_size as usize)
+        .map(|start| (start, (start + part_size).min(size) - 1))
+        .collect()
+}
+
+/// Lists the ranges of an object with a signed URL for each, which fetches the range without an
+/// API key until the URLs expire
+pub async fn manifest(
+    state: &AppState,
+    auth: &AuthState,
+    requested_bucket: &str,
+    requested_key: &str,
+    key: &str,
+    requested: u64,
+) -> Result<PartsManifest> {
+    let (Some(config), Some(signer)) = (&state.config.download_parts, &state.part_signer) else {
+        return Err(AppError::InvalidRequest("Parallel downloads are not enabled".to_string()));
+    };
+    // A one-byte read is the cheapest way to the size and ETag through every storage layout
+    let probe = match union::read(state, requested_bucket, key, Some("bytes=0-0")).await {
+        Err(AppError::RangeNotSatisfiable(_)) => union::read(state, requested_bucket, key, None).await?,
+        probe => probe?,
+    };
+    let etag = etags::served(&state.config, &probe);
+
+    let (username, ldap) = (auth.username.as_str(), auth.is_ldap());
+    let expires_at = Utc::now() + chrono::Duration::seconds(config.ttl_secs as i64);
+    let expires = expires_at.timestamp();
+    let path = format!("/{}/{}", encode(requested_bucket, false), encode(requested_key, true));
+    let base_url = config.base_url.as_deref().unwrap_or_default().trim_end_matches('/');
+    let mut parts = Vec::new();
+    for (index, (start, end)) in ranges(config, probe.total_size, requested).into_iter().enumerate() {
+        let range = format!("{}-{}", start, end);
+        let etag = etag.as_deref().unwrap_or_default();
+        let signature = signer.sign(payload(username, ldap, &path, &range, etag, expires).as_bytes())?;
+        let url = format!(
+            "{}{}{}?range={}&etag={}&expires={}&user={}{}&signature={}",
+            base_url,
+            tenants::url_prefix(),
+            path,
+            range,
+            encode(etag, false),
+            expires,
+            encode(username, false),
+            if ldap { "&source=ldap" } else { "" },
+            URL_SAFE_NO_PAD.encode(signature)
+        );
+        parts.push(Part { index: index as u64, start, end, url });
+    }
+    Ok(PartsManifest {
+        bucket: requested_bucket.to_string(),
+        key: requested_key.to_string(),
+        size: probe.total_size,
+        etag,
+        expires_at,
+        parts,
+    })
+}
+
+/// The query of a part URL; anything else, like ?parts or ?preview, would reach past the signed range
+const SIGNED_PARAMS: [&str; 6] = ["range", "etag", "expires", "user", "source", "signature"];
+
+/// Checks a part URL; None when the request does not carry a signature
+pub fn verify(signer: &Signer, method: &Method, uri: &Uri) -> Option<Result<SignedPart>> {
+    let Query(params) = Query::<HashMap<String, String>>::try_from_uri(uri).ok()?;
+    let signature = params.get("signature")?;
+    let invalid = || AppError::Unauthorized("Invalid part URL".to_string());
+    let result = (|| {
+        if method != Method::GET && method != Method::HEAD {
+            return Err(AppError::Unauthorized("Part URLs only allow GET and HEAD".to_string()));
+        }
+        if let Some(name) = params.keys().find(|name| !SIGNED_PARAMS.contains(&name.as_str())) {
+            return Err(AppError::Unauthorized(format!("Part URLs only fetch their signed range, not ?{}", name)));
+        }
+        let field = |name: &str| params.get(name).ok_or_else(invalid);
+        let (username, range, etag) = (field("user")?, field("range")?, field("etag")?);
+        let expires: i64 = field("expires")?.parse().map_err(|_| invalid())?;
+        let ldap = params.get("source").is_some_and(|source| source == "ldap");
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        if !signer.verify(payload(username, ldap, uri.path(), range, etag, expires).as_bytes(), &signature)? {
+            return Err(invalid());
+        }
+        if expires <= Utc::now().timestamp() {
+            return Err(AppError::Unauthorized("Part URL expired".to_string()));
+        }
+        let bucket = uri.path().trim_start_matches('/').split('/').next().unwrap_or_default();
+        Ok(SignedPart { username: username.clone(), bucket: bucket.to_string(), ldap })
+    })();
+    Some(result)
+}
+
+/// The query with a part URL's signature masked, as it is a bearer credential until it expires
+pub fn redact_query(query: &str) -> String {
+    query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some((name, _)) if sigv4::percent_decode(name) == "signature" => format!("{}=***REDACTED***", name),
+            _ => pair.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// The request headers of a part URL, with the Range and If-Match it was signed for; None for
+/// other requests
+pub fn request_headers(params: &HashMap<String, String>, headers: &HeaderMap) -> Result<Option<HeaderMap>> {
+    let (Some(range), Some(etag), true) = (params.get("range"), params.get("etag"), params.contains_key("signature")) else {
+        return Ok(None);
+    };
+    let invalid = || AppError::InvalidRequest("Invalid part URL".to_string());
+    let mut headers = headers.clone();
+    headers.insert(header::RANGE, format!("bytes={}", range).parse().map_err(|_| invalid())?);
+    if !etag.is_empty() {
+        headers.insert(header::IF_MATCH, etag.parse().map_err(|_| invalid())?);
+    }
+    Ok(Some(headers))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{header, HeaderMap, Method, Uri};
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use chrono::Utc;
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    use super::{payload, ranges, redact_query, request_headers, verify};
+    use crate::auth::Signer;
+    use crate::config::DownloadPartsConfig;
+
+    fn signer() -> Signer {
+        Signer::new(&serde_json::from_value(json!({ "secret": "test" })).unwrap()).unwrap()
+    }
+
+    fn part_url(signer: &Signer, extra: &str) -> Uri {
+        let expires = Utc::now().timestamp() + 60;
+        let signature = signer.sign(payload("alice", false, "/b/video.mp4", "0-9", "\"e\"", expires).as_bytes()).unwrap();
+        let url = format!(
+            "/b/video.mp4?range=0-9&etag=%22e%22&expires={}&user=alice&signature={}{}",
+            expires,
+            URL_SAFE_NO_PAD.encode(signature),
+            extra
+        );
+        url.parse().unwrap()
+    }
+
+    #[test]
+    fn part_urls_verify_for_their_range() {
+        let signer = signer();
+        let part = verify(&signer, &Method::GET, &part_url(&signer, "")).unwrap().unwrap();
+        assert_eq!((part.username.as_str(), part.bucket.as_str(), part.ldap), ("alice", "b", false));
+        assert!(verify(&signer, &Method::PUT, &part_url(&signer, "")).unwrap().is_err());
+        let tampered = part_url(&signer, "").to_string().replace("range=0-9", "range=0-99");
+        assert!(verify(&signer, &Method::GET, &tampered.parse().unwrap()).unwrap().is_err());
+        assert!(verify(&signer, &Method::GET, &"/b/video.mp4".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn part_urls_cannot_mint_manifests_or_reach_other_operations() {
+        let signer = signer();
+        for extra in ["&parts=4", "&preview", "&response-content-type=text/html", "&upload_id=x"] {
+            let result = verify(&signer, &Method::GET, &part_url(&signer, extra)).unwrap();
+            assert!(result.is_err(), "{} was allowed", extra);
+        }
+    }
+
+    #[test]
+    fn part_urls_are_bound_to_their_object_user_signer_and_expiry() {
+        let signer = signer();
+        let url = part_url(&signer, "").to_string();
+        for forged in [url.replace("/b/video.mp4", "/b/other.mp4"), url.replace("user=alice", "user=bob")] {
+            assert!(verify(&signer, &Method::GET, &forged.parse().unwrap()).unwrap().is_err(), "{}", forged);
+        }
+        let other = Signer::new(&serde_json::from_value(json!({ "secret": "other" })).unwrap()).unwrap();
+        assert!(verify(&other, &Method::GET, &url.parse().unwrap()).unwrap().is_err());
+
+        let expired = Utc::now().timestamp() - 1;
+        let signature = signer.sign(payload("alice", false, "/b/video.mp4", "0-9", "", expired).as_bytes()).unwrap();
+        let url = format!(
+            "/b/video.mp4?range=0-9&etag=&expires={}&user=alice&signature={}",
+            expired,
+            URL_SAFE_NO_PAD.encode(signature)
+        );
+        let Some(Err(error)) = verify(&signer, &Method::GET, &url.parse().unwrap()) else {
+            panic!("an expired part URL was accepted");
+        };
+        assert!(error.to_string().contains("expired"), "{}", error);
+    }
+
+    #[test]
+    fn part_requests_fetch_the_signed_range_of_the_signed_version() {
+        let params: HashMap<String, String> = [("range", "0-9"), ("etag", "\"e\""), ("signature", "s")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut client = HeaderMap::new();
+        client.insert(header::RANGE, "bytes=0-999".parse().unwrap());
+        let headers = request_headers(&params, &client).unwrap().unwrap();
+        assert_eq!(headers[header::RANGE], "bytes=0-9");
+        assert_eq!(headers[header::IF_MATCH], "\"e\"");
+        assert!(request_headers(&HashMap::new(), &client).unwrap().is_none());
+    }
+
+    #[test]
+    fn ranges_cover_the_object_without_gaps() {
+        let config: DownloadPartsConfig = serde_json::from_value(json!({ "min_part_bytes": 10, "max_parts": 4 })).unwrap();
+        assert_eq!(ranges(&config, 0, 4), vec![]);
+        assert_eq!(ranges(&config, 25, 8), vec![(0, 8), (9, 17), (18, 24)]);
+        assert_eq!(ranges(&config, 25, 1), vec![(0, 24)]);
+        assert_eq!(ranges(&config, 15, 4), vec![(0, 7), (8, 14)]);
+    }
+
+    #[test]
+    fn redact_query_masks_only_the_signature() {
+        assert_eq!(
+            redact_query("range=0-9&expires=1&signature=abc&user=alice"),
+            "range=0-9&expires=1&signature=***REDACTED***&user=alice"
+        );
+        assert_eq!(redact_query("signatur%65=abc"), "signatur%65=***REDACTED***");
+        assert_eq!(redact_query("prefix=signature"), "prefix=signature");
+    }
+}
@@ -0,0 +1,186 @@
+use libloading::Library;
+use std::ffi::c_void;
+use std::os::raw::c_ulong;
+use std::sync::Mutex;
+use tracing::info;
+
+use crate::config::Pkcs11Config;
+use crate::error::{AppError, Result};
+
+type Rv = c_ulong;
+type Handle = c_ulong;
+
+const CKR_OK: Rv = 0;
+const CKR_USER_ALREADY_LOGGED_IN: Rv = 0x100;
+const CKR_CRYPTOKI_ALREADY_INITIALIZED: Rv = 0x191;
+const CKF_SERIAL_SESSION: c_ulong = 1 << 2;
+const CKU_USER: c_ulong = 1;
+const CKA_CLASS: c_ulong = 0;
+const CKA_LABEL: c_ulong = 3;
+const CKO_SECRET_KEY: c_ulong = 4;
+const CKM_SHA256_HMAC: c_ulong = 0x251;
+const HMAC_SHA256_LENGTH: usize = 32;
+
+// Cryptoki structures are packed on Windows and use the platform's C layout elsewhere
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct Attribute {
+    kind: c_ulong,
+    value: *mut c_void,
+    value_len: c_ulong,
+}
+
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct Mechanism {
+    mechanism: c_ulong,
+    parameter: *mut c_void,
+    parameter_len: c_ulong,
+}
+
+/// The start of CK_FUNCTION_LIST up to C_Sign; functions the proxy does not call are only
+/// placeholders keeping the offsets right
+#[cfg_attr(windows, repr(C, packed(1)))]
+#[cfg_attr(not(windows), repr(C))]
+struct FunctionList {
+    version: [u8; 2],
+    initialize: unsafe extern "C" fn(*mut c_void) -> Rv,
+    _finalize_to_get_function_list: [*const c_void; 3],
+    get_slot_list: unsafe extern "C" fn(u8, *mut c_ulong, *mut c_ulong) -> Rv,
+    _get_slot_info_to_set_pin: [*const c_void; 7],
+    open_session: unsafe extern "C" fn(c_ulong, c_ulong, *mut c_void, *const c_void, *mut Handle) -> Rv,
+    _close_session_to_set_operation_state: [*const c_void; 5],
+    login: unsafe extern "C" fn(Handle, c_ulong, *const u8, c_ulong) -> Rv,
+    _logout_to_set_attribute_value: [*const c_void; 7],
+    find_objects_init: unsafe extern "C" fn(Handle, *mut Attribute, c_ulong) -> Rv,
+    find_objects: unsafe extern "C" fn(Handle, *mut Handle, c_ulong, *mut c_ulong) -> Rv,
+    find_objects_final: unsafe extern "C" fn(Handle) -> Rv,
+    _encrypt_init_to_digest_final: [*const c_void; 13],
+    sign_init: unsafe extern "C" fn(Handle, *mut Mechanism, Handle) -> Rv,
+    sign: unsafe extern "C" fn(Handle, *const u8, c_ulong, *mut u8, *mut c_ulong) -> Rv,
+}
+
+fn check(operation: &str, rv: Rv) -> Result<()> {
+    if rv != CKR_OK {
+        return Err(AppError::InternalError(format!("PKCS#11 {} failed with CKR 0x{:X}", operation, rv)));
+    }
+    Ok(())
+}
+
+/// An HMAC-SHA256 key held in a PKCS#11 token, used through one session for the lifetime of the proxy
+pub struct HmacKey {
+    functions: *const FunctionList,
+    /// Cryptoki is initialized without locking callbacks, so calls are serialized here
+    session: Mutex<Handle>,
+    key: Handle,
+    /// Keeps the module loaded while its functions are in use
+    _library: Library,
+}
+
+// The function list is static data of the loaded module and every call goes through the mutex
+unsafe impl Send for HmacKey {}
+unsafe impl Sync for HmacKey {}
+
+impl HmacKey {
+    pub fn open(config: &Pkcs11Config) -> Result<Self> {
+        let failed = |e: libloading::Error| AppError::InternalError(format!("Cannot load PKCS#11 module {}: {}", config.module, e));
+        // SAFETY: loading a PKCS#11 module runs its initializers, which is what the config asks for
+        let library = unsafe { Library::new(&config.module) }.map_err(failed)?;
+        let mut functions: *const FunctionList = std::ptr::null();
+        // SAFETY: C_GetFunctionList has this signature in every PKCS#11 module
+        unsafe {
+            let get_function_list = library
+                .get::<unsafe extern "C" fn(*mut *const FunctionList) -> Rv>(b"C_GetFunctionList")
+                .map_err(failed)?;
+            check("C_GetFunctionList", get_function_list(&mut functions))?;
+        }
+        if functions.is_null() {
+            return Err(AppError::InternalError(format!("PKCS#11 module {} has no function list", config.module)));
+        }
+        // SAFETY: the module returned a valid function list that lives as long as the library
+        let list = unsafe { &*functions };
+
+        // SAFETY: each call follows the PKCS#11 signature with buffers of the sizes passed along
+        let (session, key) = unsafe {
+            let rv = (list.initialize)(std::ptr::null_mut());
+            if rv != CKR_CRYPTOKI_ALREADY_INITIALIZED {
+                check("C_Initialize", rv)?;
+            }
+
+            let slot = match config.slot {
+                Some(slot) => slot as c_ulong,
+                None => {
+                    let mut slots = [0 as c_ulong; 16];
+                    let mut count = slots.len() as c_ulong;
+                    check("C_GetSlotList", (list.get_slot_list)(1, slots.as_mut_ptr(), &mut count))?;
+                    if count == 0 {
+                        return Err(AppError::InternalError(format!("PKCS#11 module {} has no token", config.module)));
+                    }
+                    slots[0]
+                }
+            };
+
+            let mut session: Handle = 0;
+            check(
+                "C_OpenSession",
+                (list.open_session)(slot, CKF_SERIAL_SESSION, std::ptr::null_mut(), std::ptr::null(), &mut session),
+            )?;
+
+            if let Some(pin_env) = &config.pin_env {
+                let pin = std::env::var(pin_env)
+                    .map_err(|_| AppError::InternalError(format!("PKCS#11 PIN variable {} is not set", pin_env)))?;
+                let rv = (list.login)(session, CKU_USER, pin.as_ptr(), pin.len() as c_ulong);
+                if rv != CKR_USER_ALREADY_LOGGED_IN {
+                    check("C_Login", rv)?;
+                }
+            }
+
+            let mut class = CKO_SECRET_KEY;
+            let mut label = config.label.clone().into_bytes();
+            let mut template = [
+                Attribute {
+                    kind: CKA_CLASS,
+                    value: &mut class as *mut c_ulong as *mut c_void,
+                    value_len: std::mem::size_of::<c_ulong>() as c_ulong,
+                },
+                Attribute { kind: CKA_LABEL, value: label.as_mut_ptr() as *mut c_void, value_len: label.len() as c_ulong },
+            ];
+            check(
+                "C_FindObjectsInit",
+                (list.find_objects_init)(session, template.as_mut_ptr(), template.len() as c_ulong),
+            )?;
+            let mut keys = [0 as Handle; 2];
+            let mut found: c_ulong = 0;
+            let rv = (list.find_objects)(session, keys.as_mut_ptr(), keys.len() as c_ulong, &mut found);
+            check("C_FindObjectsFinal", (list.find_objects_final)(session))?;
+            check("C_FindObjects", rv)?;
+            match found {
+                0 => return Err(AppError::InternalError(format!("PKCS#11 token has no secret key {}", config.label))),
+                1 => {}
+                _ => return Err(AppError::InternalError(format!("PKCS#11 token has several secret keys {}", config.label))),
+            }
+            (session, keys[0])
+        };
+
+        info!("Signing with PKCS#11 key {} from {}", config.label, config.module);
+        Ok(Self { functions, session: Mutex::new(session), key, _library: library })
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Result<Vec<u8>> {
+        let session = self.session.lock().unwrap();
+        let mut mechanism = Mechanism { mechanism: CKM_SHA256_HMAC, parameter: std::ptr::null_mut(), parameter_len: 0 };
+        let mut signature = vec![0u8; HMAC_SHA256_LENGTH];
+        let mut length = signature.len() as c_ulong;
+        // SAFETY: the library is still loaded and the session is only used under the lock
+        unsafe {
+            let list = &*self.functions;
+            check("C_SignInit", (list.sign_init)(*session, &mut mechanism, self.key))?;
+            check(
+                "C_Sign",
+                (list.sign)(*session, data.as_ptr(), data.len() as c_ulong, signature.as_mut_ptr(), &mut length),
+            )?;
+        }
+        signature.truncate(length as usize);
+        Ok(signature)
+    }
+}
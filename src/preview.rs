@@ -0,0 +1,331 @@
+use bytes::{Buf, Bytes, BytesMut};
+use parquet::errors::ParquetError;
+use parquet::file::reader::{ChunkReader, FileReader, Length};
+use parquet::file::serialized_reader::SerializedFileReader;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::config::PreviewConfig;
+use crate::error::{AppError, Result};
+use crate::s3::ObjectPart;
+use crate::server::AppState;
+use crate::union;
+
+/// Bytes fetched per ranged read of a text format, doubled until enough rows are read
+const FIRST_READ: u64 = 64 * 1024;
+
+/// Enough for the footer of most Parquet files, which is fetched again when it is larger
+const PARQUET_TAIL: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Tsv,
+    Jsonl,
+    Parquet,
+}
+
+impl Format {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "csv" => Some(Format::Csv),
+            "tsv" => Some(Format::Tsv),
+            "jsonl" | "ndjson" => Some(Format::Jsonl),
+            "parquet" => Some(Format::Parquet),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Format::Csv => "csv",
+            Format::Tsv => "tsv",
+            Format::Jsonl => "jsonl",
+            Format::Parquet => "parquet",
+        }
+    }
+
+    /// The ?format= parameter, or else the key's extension
+    pub fn detect(key: &str, requested: Option<&str>) -> Result<Self> {
+        let name = requested.or_else(|| key.rsplit_once('.').map(|(_, extension)| extension));
+        name.and_then(Format::parse).ok_or_else(|| {
+            AppError::InvalidRequest(format!("Cannot preview {}, pass ?format=csv, tsv, jsonl or parquet", key))
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Preview {
+    pub bucket: String,
+    pub key: String,
+    pub format: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub columns: Option<Vec<String>>,
+    pub rows: Vec<Value>,
+    /// Object bytes fetched to produce the preview
+    pub bytes_read: u64,
+    pub size: u64,
+}
+
+/// Object bytes read from the start, in growing ranges
+struct Prefix {
+    data: BytesMut,
+    size: u64,
+}
+
+impl Prefix {
+    fn complete(&self) -> bool {
+        self.data.len() as u64 >= self.size
+    }
+}
+
+async fn read_range(state: &AppState, bucket: &str, key: &str, range: String) -> Result<ObjectPart> {
+    union::read(state, bucket, key, Some(&range)).await
+}
+
+/// Reads the next range after what `prefix` already holds, or the first one when it is None
+async fn read_more(state: &AppState, bucket: &str, key: &str, prefix: Option<Prefix>, max_bytes: u64) -> Result<Prefix> {
+    let (mut data, have) = match prefix {
+        Some(prefix) => {
+            let have = prefix.data.len() as u64;
+            (prefix.data, have)
+        }
+        None => (BytesMut::new(), 0),
+    };
+    if have >= max_bytes {
+        return Err(AppError::InvalidRequest(format!(
+            "Not enough rows found in the first {} bytes",
+            max_bytes
+        )));
+    }
+    let length = FIRST_READ.max(have).min(max_bytes - have);
+    match read_range(state, bucket, key, format!("bytes={}-{}", have, have + length - 1)).await {
+        Ok(part) => {
+            data.extend_from_slice(&part.body);
+            Ok(Prefix { data, size: part.total_size })
+        }
+        // Empty objects have no satisfiable range
+        Err(AppError::RangeNotSatisfiable(_)) if have == 0 => Ok(Prefix { data, size: 0 }),
+        Err(e) => Err(e),
+    }
+}
+
+/// Rows of a delimited text, without a possibly cut-off last record unless the whole object was read
+fn parse_delimited(data: &[u8], delimiter: u8, complete: bool) -> Result<(Vec<String>, Vec<Vec<String>>)> {
+    let mut reader = csv::ReaderBuilder::new().delimiter(delimiter).flexible(true).from_reader(data);
+    let columns = match reader.headers() {
+        Ok(headers) => headers.iter().map(String::from).collect(),
+        Err(_) if !complete => return Ok((Vec::new(), Vec::new())),
+        Err(e) => return Err(AppError::InvalidRequest(format!("Invalid header row: {}", e))),
+    };
+    let mut records = Vec::new();
+    for record in reader.records() {
+        match record {
+            Ok(record) => records.push(record.iter().map(String::from).collect()),
+            Err(_) if !complete => break,
+            Err(e) => return Err(AppError::InvalidRequest(format!("Invalid row: {}", e))),
+        }
+    }
+    if !complete {
+        records.pop();
+    }
+    Ok((columns, records))
+}
+
+fn parse_jsonl(data: &[u8], complete: bool) -> Result<Vec<Value>> {
+    let mut lines: Vec<&[u8]> = data.split(|&b| b == b'\n').collect();
+    if !complete {
+        lines.pop();
+    }
+    lines
+        .into_iter()
+        .enumerate()
+        .filter(|(_, line)| !line.trim_ascii().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_slice(line)
+                .map_err(|e| AppError::InvalidRequest(format!("Line {} is not JSON: {}", index + 1, e)))
+        })
+        .collect()
+}
+
+async fn preview_text(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    format: Format,
+    rows: usize,
+    config: &PreviewConfig,
+) -> Result<Preview> {
+    let mut prefix = read_more(state, bucket, key, None, config.max_bytes).await?;
+    loop {
+        let complete = prefix.complete();
+        let (columns, values) = if format == Format::Jsonl {
+            (None, parse_jsonl(&prefix.data, complete)?)
+        } else {
+            let delimiter = if format == Format::Tsv { b'\t' } else { b',' };
+            let (columns, records) = parse_delimited(&prefix.data, delimiter, complete)?;
+            let values = records
+                .into_iter()
+                .map(|record| {
+                    let object = record
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, value)| {
+                            let column = columns.get(index).cloned().unwrap_or_else(|| format!("column_{}", index + 1));
+                            (column, Value::String(value))
+                        })
+                        .collect();
+                    Value::Object(object)
+                })
+                .collect();
+            (Some(columns), values)
+        };
+        if complete || values.len() >= rows {
+            return Ok(Preview {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                format: format.name(),
+                columns,
+                rows: values.into_iter().take(rows).collect(),
+                bytes_read: prefix.data.len() as u64,
+                size: prefix.size,
+            });
+        }
+        prefix = read_more(state, bucket, key, Some(prefix), config.max_bytes).await?;
+    }
+}
+
+/// The parts of a Parquet file that were fetched, by offset
+struct Fetched {
+    size: u64,
+    pieces: Vec<(u64, Bytes)>,
+}
+
+impl Fetched {
+    fn find(&self, start: u64, length: usize) -> parquet::errors::Result<Bytes> {
+        // A read from the end of one piece belongs to the next
+        let end = start + length.max(1) as u64;
+        self.pieces
+            .iter()
+            .find(|(offset, data)| start >= *offset && end <= offset + data.len() as u64)
+            .map(|(offset, data)| data.slice((start - offset) as usize..))
+            .ok_or_else(|| ParquetError::General(format!("Bytes {}+{} were not fetched", start, length)))
+    }
+
+    fn bytes_read(&self) -> u64 {
+        self.pieces.iter().map(|(_, data)| data.len() as u64).sum()
+    }
+}
+
+impl Length for Fetched {
+    fn len(&self) -> u64 {
+        self.size
+    }
+}
+
+impl ChunkReader for Fetched {
+    type T = bytes::buf::Reader<Bytes>;
+
+    fn get_read(&self, start: u64) -> parquet::errors::Result<Self::T> {
+        Ok(self.find(start, 0)?.reader())
+    }
+
+    fn get_bytes(&self, start: u64, length: usize) -> parquet::errors::Result<Bytes> {
+        Ok(self.find(start, length)?.slice(..length))
+    }
+}
+
+fn invalid_parquet(e: ParquetError) -> AppError {
+    AppError::InvalidRequest(format!("Invalid Parquet file: {}", e))
+}
+
+/// Reads the footer, then only the row groups holding the first `rows` rows
+async fn preview_parquet(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    rows: usize,
+    config: &PreviewConfig,
+) -> Result<Preview> {
+    let tail = read_range(state, bucket, key, format!("bytes=-{}", PARQUET_TAIL)).await?;
+    let size = tail.total_size;
+    let footer = tail.body.len();
+    if footer < 8 || &tail.body[footer - 4..] != b"PAR1" {
+        return Err(AppError::InvalidRequest(format!("{} is not a Parquet file", key)));
+    }
+    let metadata_length = u32::from_le_bytes(tail.body[footer - 8..footer - 4].try_into().unwrap()) as u64;
+    let tail = if metadata_length + 8 > footer as u64 {
+        read_range(state, bucket, key, format!("bytes=-{}", metadata_length + 8)).await?
+    } else {
+        tail
+    };
+    let footer = (size - tail.body.len() as u64, tail.body);
+    let metadata = SerializedFileReader::new(Fetched { size, pieces: vec![footer.clone()] })
+        .map_err(invalid_parquet)?
+        .metadata()
+        .clone();
+
+    let mut fetched = Fetched { size, pieces: vec![footer] };
+    let mut needed = 0;
+    let mut groups = Vec::new();
+    for (index, group) in metadata.row_groups().iter().enumerate() {
+        if needed >= rows {
+            break;
+        }
+        let ranges = group.columns().iter().map(|column| column.byte_range());
+        let start = ranges.clone().map(|(start, _)| start).min().unwrap_or(0);
+        let end = ranges.map(|(start, length)| start + length).max().unwrap_or(0);
+        // Row groups of small files often came along with the footer
+        if end > start && fetched.find(start, (end - start) as usize).is_err() {
+            if fetched.bytes_read() + (end - start) > config.max_bytes {
+                return Err(AppError::InvalidRequest(format!(
+                    "The row groups holding the first {} rows exceed {} bytes",
+                    rows, config.max_bytes
+                )));
+            }
+            let part = read_range(state, bucket, key, format!("bytes={}-{}", start, end - 1)).await?;
+            fetched.pieces.push((start, part.body));
+        }
+        groups.push(index);
+        needed += group.num_rows().max(0) as usize;
+    }
+    let bytes_read = fetched.bytes_read();
+    let reader = SerializedFileReader::new(fetched).map_err(invalid_parquet)?;
+
+    let mut values = Vec::new();
+    for index in groups {
+        let group = reader.get_row_group(index).map_err(invalid_parquet)?;
+        for row in group.get_row_iter(None).map_err(invalid_parquet)? {
+            if values.len() >= rows {
+                break;
+            }
+            values.push(row.map_err(invalid_parquet)?.to_json_value());
+        }
+    }
+    let columns = metadata
+        .file_metadata()
+        .schema()
+        .get_fields()
+        .iter()
+        .map(|field| field.name().to_string())
+        .collect();
+    Ok(Preview {
+        bucket: bucket.to_string(),
+        key: key.to_string(),
+        format: Format::Parquet.name(),
+        columns: Some(columns),
+        rows: values,
+        bytes_read,
+        size,
+    })
+}
+
+/// The first `rows` rows of a dataset object, read with as few bytes as the format allows
+pub async fn preview(state: &AppState, bucket: &str, key: &str, format: Format, rows: usize) -> Result<Preview> {
+    let config = &state.config.preview;
+    let rows = rows.min(config.max_rows);
+    match format {
+        Format::Parquet => preview_parquet(state, bucket, key, rows, config).await,
+        _ => preview_text(state, bucket, key, format, rows, config).await,
+    }
+}
@@ -0,0 +1,74 @@
+use aws_sdk_s3::primitives::ByteStream;
+use bytes::Bytes;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::config::{Config, PublishingConfig};
+use crate::error::{AppError, Result};
+use crate::etags;
+use crate::s3::S3Client;
+
+#[derive(Debug, Serialize)]
+pub struct Published {
+    pub bucket: String,
+    pub key: String,
+    pub sha256: String,
+    pub size: u64,
+    pub url: String,
+    /// False when identical content had been published before
+    pub created: bool,
+}
+
+/// Published objects never change, so clients may only write them through ?publish
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    match config.publishing.get(bucket) {
+        Some(publishing) if key.starts_with(&publishing.prefix) => Err(AppError::InvalidRequest(format!(
+            "{} is reserved for published content",
+            publishing.prefix
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The hex SHA-256 of the body and the key it is published under
+pub fn address(publishing: &PublishingConfig, body: &[u8]) -> (String, String) {
+    let sha256 = hex::encode(Sha256::digest(body));
+    let key = format!("{}{}", publishing.prefix, sha256);
+    (sha256, key)
+}
+
+/// Where clients fetch a published object, under the bucket name they used
+pub fn url(publishing: &PublishingConfig, bucket: &str, key: &str) -> String {
+    let path = format!("/{}/{}", bucket, key);
+    match &publishing.base_url {
+        Some(base_url) => format!("{}{}", base_url.trim_end_matches('/'), path),
+        None => path,
+    }
+}
+
+/// Stores the body at its content address unless it is already there; true when it was stored now
+pub async fn publish(
+    client: &S3Client,
+    config: &Config,
+    bucket: &str,
+    key: &str,
+    body: Bytes,
+    content_type: Option<String>,
+) -> Result<bool> {
+    // The key is the content hash, so an existing object already holds exactly this body
+    match client.head_object(bucket, key).await {
+        Ok(_) => return Ok(false),
+        Err(AppError::ObjectNotFound(_, _)) => {}
+        Err(e) => return Err(e),
+    }
+    let size = body.len();
+    if etags::is_stable(config, bucket) {
+        let etag = etags::from_body(&body);
+        client.put_object_with_metadata(bucket, key, body, etags::metadata(&etag), content_type).await?;
+    } else {
+        client.put_object(bucket, key, ByteStream::from(body), content_type).await?;
+    }
+    info!("Published {} bytes to {}/{}", size, bucket, key);
+    Ok(true)
+}
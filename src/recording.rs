@@ -0,0 +1,199 @@
+use aws_smithy_runtime_api::client::http::{HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_runtime_api::http::StatusCode;
+use aws_smithy_types::body::SdkBody;
+use bytes::Bytes;
+use http_body_util::BodyExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::{debug, info};
+
+use crate::config::{RecordingConfig, RecordingMode};
+
+/// One upstream request and the response it produced, stored as JSON
+#[derive(Debug, Serialize, Deserialize)]
+struct Interaction {
+    method: String,
+    uri: String,
+    /// None for streaming request bodies that could not be hashed up front
+    request_body_sha256: Option<String>,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body_sha256: String,
+    body_size: usize,
+}
+
+#[derive(Debug)]
+struct Recorder {
+    account_id: String,
+    directory: PathBuf,
+    mode: RecordingMode,
+    store_bodies: bool,
+    /// Unused in replay mode
+    inner: SharedHttpConnector,
+    /// How often each request was seen, so repeated requests map to successive recordings
+    occurrences: Mutex<HashMap<String, usize>>,
+}
+
+/// Records every upstream interaction of an account to disk, or answers from those recordings
+#[derive(Clone, Debug)]
+struct RecordingConnector(Arc<Recorder>);
+
+fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+fn connector_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> ConnectorError {
+    ConnectorError::other(e.into(), None)
+}
+
+impl Recorder {
+    fn interaction_path(&self, key: &str, occurrence: usize) -> PathBuf {
+        self.directory
+            .join(&self.account_id)
+            .join(format!("{}-{}.json", &sha256_hex(key.as_bytes())[..32], occurrence))
+    }
+
+    fn body_path(&self, sha256: &str) -> PathBuf {
+        self.directory.join("bodies").join(sha256)
+    }
+
+    async fn record(
+        &self,
+        request: HttpRequest,
+        key: String,
+        occurrence: usize,
+        interaction: Interaction,
+    ) -> Result<HttpResponse, ConnectorError> {
+        let mut response = self.inner.call(request).await?;
+
+        // Bodies are buffered so they can be hashed and written before being handed to the SDK
+        let body = response
+            .take_body()
+            .collect()
+            .await
+            .map_err(connector_error)?
+            .to_bytes();
+
+        let interaction = Interaction {
+            status: response.status().as_u16(),
+            headers: response
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            body_sha256: sha256_hex(&body),
+            body_size: body.len(),
+            ..interaction
+        };
+
+        if self.store_bodies {
+            write_file(&self.body_path(&interaction.body_sha256), &body).await?;
+        }
+        let path = self.interaction_path(&key, occurrence);
+        let json = serde_json::to_vec_pretty(&interaction).map_err(connector_error)?;
+        write_file(&path, &json).await?;
+        debug!("Recorded {} {} to {}", interaction.method, interaction.uri, path.display());
+
+        *response.body_mut() = SdkBody::from(body);
+        Ok(response)
+    }
+
+    async fn replay(&self, key: &str, occurrence: usize) -> Result<HttpResponse, ConnectorError> {
+        // Requests repeated more often than during recording get the last recorded response
+        let mut found = None;
+        for n in (0..=occurrence).rev() {
+            if let Ok(json) = tokio::fs::read(self.interaction_path(key, n)).await {
+                found = Some(json);
+                break;
+            }
+        }
+        let json = found.ok_or_else(|| connector_error(format!("No recorded response for {}", key)))?;
+        let interaction: Interaction = serde_json::from_slice(&json).map_err(connector_error)?;
+
+        let body = if interaction.body_size == 0 || !self.store_bodies {
+            Bytes::new()
+        } else {
+            let body = tokio::fs::read(self.body_path(&interaction.body_sha256))
+                .await
+                .map_err(connector_error)?;
+            Bytes::from(body)
+        };
+
+        let status = StatusCode::try_from(interaction.status).map_err(connector_error)?;
+        let mut response = HttpResponse::new(status, SdkBody::from(body));
+        for (name, value) in interaction.headers {
+            response.headers_mut().append(name, value);
+        }
+        debug!("Replayed {} {}", interaction.method, interaction.uri);
+        Ok(response)
+    }
+}
+
+async fn write_file(path: &Path, data: &[u8]) -> Result<(), ConnectorError> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await.map_err(connector_error)?;
+    }
+    tokio::fs::write(path, data).await.map_err(connector_error)
+}
+
+impl HttpConnector for RecordingConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let recorder = self.0.clone();
+        HttpConnectorFuture::new(async move {
+            // Match on method, path and body only; signatures and dates differ between runs
+            let uri: http::Uri = request.uri().parse().map_err(connector_error)?;
+            let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/").to_string();
+            let request_body_sha256 = request.body().bytes().map(sha256_hex);
+            let key = format!(
+                "{} {} {}",
+                request.method(),
+                path,
+                request_body_sha256.as_deref().unwrap_or("streaming")
+            );
+
+            let occurrence = {
+                let mut occurrences = recorder.occurrences.lock().unwrap();
+                let count = occurrences.entry(key.clone()).or_default();
+                *count += 1;
+                *count - 1
+            };
+
+            match recorder.mode {
+                RecordingMode::Replay => recorder.replay(&key, occurrence).await,
+                RecordingMode::Record => {
+                    let interaction = Interaction {
+                        method: request.method().to_string(),
+                        uri: path,
+                        request_body_sha256,
+                        status: 0,
+                        headers: Vec::new(),
+                        body_sha256: String::new(),
+                        body_size: 0,
+                    };
+                    recorder.record(request, key, occurrence, interaction).await
+                }
+            }
+        })
+    }
+}
+
+/// Wraps an account's connector so its traffic is recorded or replayed
+pub fn wrap(account_id: &str, config: &RecordingConfig, inner: SharedHttpConnector) -> SharedHttpConnector {
+    info!(
+        "Account {} upstream traffic in {:?} mode using {}",
+        account_id, config.mode, config.directory
+    );
+    SharedHttpConnector::new(RecordingConnector(Arc::new(Recorder {
+        account_id: account_id.to_string(),
+        directory: PathBuf::from(&config.directory),
+        mode: config.mode,
+        store_bodies: config.store_bodies,
+        inner,
+        occurrences: Mutex::new(HashMap::new()),
+    })))
+}
@@ -0,0 +1,90 @@
+use bytes::Bytes;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::config::{Config, RedactionRule, UserRole};
+use crate::content;
+use crate::error::{AppError, Result};
+use crate::etags;
+use crate::s3::ObjectPart;
+
+/// Rules of `bucket` that apply when `role` reads `key`, in config order
+pub fn rules<'a>(config: &'a Config, bucket: &str, key: &str, role: UserRole) -> Vec<&'a RedactionRule> {
+    config
+        .redactions
+        .get(bucket)
+        .into_iter()
+        .flatten()
+        .filter(|rule| rule.roles.contains(&role) && key.starts_with(&rule.prefix))
+        .collect()
+}
+
+fn unredactable(key: &str, reason: &str) -> AppError {
+    warn!("Refusing to serve {} unredacted: {}", key, reason);
+    AppError::Unauthorized(format!("{} cannot be redacted for this user", key))
+}
+
+/// Replaces a whole object with its sanitized variant, which gets an ETag of its own so caches
+/// never mix it up with the original
+pub fn apply(rules: &[&RedactionRule], key: &str, part: &mut ObjectPart) -> Result<()> {
+    let mut body = part.body.clone();
+    for rule in rules {
+        if rule.strip_exif {
+            body = content::strip_metadata(body).ok_or_else(|| unredactable(key, "malformed image"))?;
+        }
+        if !rule.json_fields.is_empty() {
+            body = redact_json(key, body, rule)?;
+        }
+    }
+    part.total_size = body.len() as u64;
+    part.proxy_etag = Some(etags::from_body(&body));
+    part.body = body;
+    Ok(())
+}
+
+/// Replaces the configured fields of JSON documents; other content is left as is
+fn redact_json(key: &str, body: Bytes, rule: &RedactionRule) -> Result<Bytes> {
+    let looks_like_json = body
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .is_some_and(|b| *b == b'{' || *b == b'[');
+    if !looks_like_json {
+        return Ok(body);
+    }
+    let mut document: Value = serde_json::from_slice(&body).map_err(|_| unredactable(key, "invalid JSON"))?;
+    for field in &rule.json_fields {
+        let path: Vec<&str> = field.split('.').collect();
+        redact_field(&mut document, &path, &rule.replacement);
+    }
+    serde_json::to_vec(&document)
+        .map(Bytes::from)
+        .map_err(|e| AppError::InternalError(e.to_string()))
+}
+
+/// Walks a dotted path, where `*` matches any field and arrays apply the rest of the path to every
+/// element
+fn redact_field(value: &mut Value, path: &[&str], replacement: &Value) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+    match value {
+        Value::Array(items) => {
+            for item in items {
+                redact_field(item, path, replacement);
+            }
+        }
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if *first != "*" && name != first {
+                    continue;
+                }
+                if rest.is_empty() {
+                    *field = replacement.clone();
+                } else {
+                    redact_field(field, rest, replacement);
+                }
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,46 @@
+use axum::http::{header, HeaderMap};
+use tracing::warn;
+
+use crate::config::{wildcard_match, Config};
+use crate::error::{AppError, Result};
+
+/// scheme://host[:port] of a Referer URL
+fn origin_of(referer: &str) -> Option<&str> {
+    let (scheme, rest) = referer.split_once("://")?;
+    let host = rest.split(['/', '?', '#']).next()?;
+    (!host.is_empty()).then(|| &referer[..scheme.len() + 3 + host.len()])
+}
+
+/// The page a request comes from: its Origin, or else the origin of its Referer
+fn request_origin(headers: &HeaderMap) -> Option<String> {
+    let origin = headers
+        .get(header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .filter(|origin| *origin != "null");
+    let referer = headers
+        .get(header::REFERER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(origin_of);
+    origin.or(referer).map(|origin| origin.to_ascii_lowercase())
+}
+
+/// Rejects reads of a bucket with an allowlist from pages it does not list, so assets cannot be
+/// hotlinked from other sites
+pub fn check(config: &Config, bucket: &str, headers: &HeaderMap) -> Result<()> {
+    let Some(referrers) = config.referrers.get(bucket) else {
+        return Ok(());
+    };
+    match request_origin(headers) {
+        // Direct requests, e.g. from scripts or typed URLs, carry neither header
+        None if referrers.allow_missing => Ok(()),
+        None => {
+            warn!("Rejected read of {} without Origin or Referer", bucket);
+            Err(AppError::Unauthorized(format!("Reads of {} need an allowed Origin or Referer", bucket)))
+        }
+        Some(origin) if referrers.allowed.iter().any(|pattern| wildcard_match(pattern, &origin)) => Ok(()),
+        Some(origin) => {
+            warn!("Rejected read of {} from {}", bucket, origin);
+            Err(AppError::Unauthorized(format!("Reads of {} are not allowed from {}", bucket, origin)))
+        }
+    }
+}
@@ -0,0 +1,549 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Extension, Path, Query, State},
+    http::{HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use aws_sdk_s3::primitives::ByteStream;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::BytesMut;
+use futures::StreamExt;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, instrument};
+
+use crate::auth::{check_bucket_access, check_write_permission, AuthState};
+use crate::config::{Config, RegistryConfig};
+use crate::error::{AppError, Result};
+use crate::metrics;
+use crate::s3::S3Client;
+use crate::server::AppState;
+
+const API_VERSION: &str = "docker-distribution-api-version";
+const CONTENT_DIGEST: &str = "docker-content-digest";
+const UPLOAD_UUID: &str = "docker-upload-uuid";
+
+/// Manifests are small JSON documents, larger ones are refused like other registries do
+const MAX_MANIFEST_SIZE: usize = 4 * 1024 * 1024;
+
+/// Request body bytes collected before they are handed to the upload session
+const APPEND_SIZE: usize = 1024 * 1024;
+
+/// Metadata key holding the media type a manifest was pushed with
+const MEDIA_TYPE_METADATA: &str = "media-type";
+
+lazy_static! {
+    static ref NAME: Regex =
+        Regex::new(r"^[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*(/[a-z0-9]+((\.|_|__|-+)[a-z0-9]+)*)*$").unwrap();
+    static ref TAG: Regex = Regex::new(r"^[a-zA-Z0-9_][a-zA-Z0-9._-]{0,127}$").unwrap();
+}
+
+/// What a path below /v2/{name}/ addresses
+enum Target {
+    Tags,
+    Manifest(String),
+    Blob(String),
+    /// None when starting an upload
+    Upload(Option<String>),
+}
+
+impl Target {
+    /// Distribution API error code for a failed request
+    fn error_code(&self, error: &AppError) -> &'static str {
+        match (self, error) {
+            (_, AppError::Unauthorized(_) | AppError::BucketDenied(_)) => "DENIED",
+            (_, AppError::TooManyRequests(_)) => "TOOMANYREQUESTS",
+            (Target::Upload(_), AppError::UploadNotFound(_)) => "BLOB_UPLOAD_UNKNOWN",
+            (Target::Upload(_), AppError::InvalidRequest(message)) if message.contains("checksum") => "DIGEST_INVALID",
+            (Target::Upload(_), AppError::InvalidRequest(_) | AppError::RangeNotSatisfiable(_)) => "BLOB_UPLOAD_INVALID",
+            (Target::Blob(_), AppError::ObjectNotFound(_, _)) => "BLOB_UNKNOWN",
+            (Target::Blob(_), AppError::InvalidRequest(_)) => "DIGEST_INVALID",
+            (Target::Manifest(_), AppError::ObjectNotFound(_, _) | AppError::ManifestNotFound(_)) => "MANIFEST_UNKNOWN",
+            (Target::Manifest(_), AppError::InvalidRequest(_)) => "MANIFEST_INVALID",
+            (Target::Tags, AppError::ObjectNotFound(_, _)) => "NAME_UNKNOWN",
+            _ => "UNKNOWN",
+        }
+    }
+}
+
+/// Splits the path after /v2/ into the repository name and what it addresses
+fn parse_path(path: &str) -> Result<(String, Target)> {
+    let (name, target) = if let Some(name) = path.strip_suffix("/tags/list") {
+        (name, Target::Tags)
+    } else if let Some((name, reference)) = path.rsplit_once("/manifests/") {
+        (name, Target::Manifest(reference.to_string()))
+    } else if let Some(name) = path.strip_suffix("/blobs/uploads/").or_else(|| path.strip_suffix("/blobs/uploads")) {
+        (name, Target::Upload(None))
+    } else if let Some((name, id)) = path.rsplit_once("/blobs/uploads/") {
+        (name, Target::Upload(Some(id.to_string())))
+    } else if let Some((name, digest)) = path.rsplit_once("/blobs/") {
+        (name, Target::Blob(digest.to_string()))
+    } else {
+        return Err(AppError::InvalidRequest(format!("Unsupported registry path: /v2/{}", path)));
+    };
+    if !NAME.is_match(name) {
+        return Err(AppError::InvalidRequest(format!("Invalid repository name: {}", name)));
+    }
+    Ok((name.to_string(), target))
+}
+
+/// The hex SHA-256 of a "sha256:{hex}" digest; other algorithms are not supported
+fn parse_digest(digest: &str) -> Result<String> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) => {
+            Ok(hex.to_string())
+        }
+        _ => Err(AppError::InvalidRequest(format!("Unsupported digest: {}", digest))),
+    }
+}
+
+fn blob_key(registry: &RegistryConfig, hex: &str) -> String {
+    format!("{}blobs/sha256/{}", registry.prefix, hex)
+}
+
+fn manifest_key(registry: &RegistryConfig, name: &str, hex: &str) -> String {
+    format!("{}repositories/{}/manifests/sha256/{}", registry.prefix, name, hex)
+}
+
+fn tags_prefix(registry: &RegistryConfig, name: &str) -> String {
+    format!("{}repositories/{}/tags/", registry.prefix, name)
+}
+
+fn upload_key(registry: &RegistryConfig, id: &str) -> String {
+    format!("{}uploads/{}", registry.prefix, id)
+}
+
+/// Registry objects are only written through the /v2/ API
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    match &config.registry {
+        Some(registry) if registry.bucket == bucket && key.starts_with(&registry.prefix) => Err(AppError::InvalidRequest(
+            format!("{} is reserved for the container registry", registry.prefix),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn error_response(target: &Target, error: AppError) -> Response {
+    let code = target.error_code(&error);
+    let (status, message, _) = error.into_public();
+    (status, Json(json!({ "errors": [{ "code": code, "message": message }] }))).into_response()
+}
+
+fn with_headers(mut response: Response, headers: &[(&'static str, String)]) -> Response {
+    for (name, value) in headers {
+        if let Ok(value) = value.parse() {
+            response.headers_mut().insert(*name, value);
+        }
+    }
+    response
+}
+
+/// GET /v2/ tells clients the registry API is served and that their credentials work
+#[axum::debug_handler]
+pub async fn version_check() -> Response {
+    with_headers(Json(json!({})).into_response(), &[(API_VERSION, "registry/2.0".to_string())])
+}
+
+/// Every request below /v2/{name}/, which is matched by hand since names contain slashes
+#[axum::debug_handler]
+#[instrument(skip(state, auth, params, headers, body), fields(user = %auth.username))]
+pub async fn dispatch(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    method: Method,
+    Path(path): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let response = match parse_path(&path) {
+        Ok((name, target)) => match handle(&state, &auth, &method, &name, &target, &params, &headers, body).await {
+            Ok(response) => response,
+            Err(e) => error_response(&target, e),
+        },
+        Err(e) => {
+            let message = e.to_string();
+            let status = e.into_response().status();
+            (status, Json(json!({ "errors": [{ "code": "NAME_INVALID", "message": message }] }))).into_response()
+        }
+    };
+    with_headers(response, &[(API_VERSION, "registry/2.0".to_string())])
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle(
+    state: &AppState,
+    auth: &AuthState,
+    method: &Method,
+    name: &str,
+    target: &Target,
+    params: &HashMap<String, String>,
+    headers: &HeaderMap,
+    body: Body,
+) -> Result<Response> {
+    let registry = state
+        .config
+        .registry
+        .as_ref()
+        .ok_or_else(|| AppError::InternalError("Registry is not configured".to_string()))?;
+    check_bucket_access(auth, &registry.bucket)?;
+    if !matches!(*method, Method::GET | Method::HEAD) {
+        check_write_permission(auth)?;
+    }
+    let (_, client) = &state.get_account_and_client(&registry.bucket)?;
+    let registry = Registry { state, client, config: registry, name, owner: &auth.username };
+
+    match (target, method.clone()) {
+        (Target::Tags, Method::GET) => registry.list_tags(params).await,
+        (Target::Manifest(reference), Method::GET) => registry.get_manifest(reference, true).await,
+        (Target::Manifest(reference), Method::HEAD) => registry.get_manifest(reference, false).await,
+        (Target::Manifest(reference), Method::PUT) => registry.put_manifest(reference, headers, body).await,
+        (Target::Blob(digest), Method::GET) => registry.get_blob(digest, headers, true).await,
+        (Target::Blob(digest), Method::HEAD) => registry.get_blob(digest, headers, false).await,
+        (Target::Upload(None), Method::POST) => registry.start_upload(params, body).await,
+        (Target::Upload(Some(id)), Method::GET) => registry.upload_status(id, params).await,
+        (Target::Upload(Some(id)), Method::PATCH) => registry.append_upload(id, params, headers, body).await,
+        (Target::Upload(Some(id)), Method::PUT) => registry.complete_upload(id, params, body).await,
+        (Target::Upload(Some(id)), Method::DELETE) => registry.abort_upload(id, params).await,
+        _ => Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    }
+}
+
+/// One repository of the registry, on behalf of the requesting user
+struct Registry<'a> {
+    state: &'a AppState,
+    client: &'a S3Client,
+    config: &'a RegistryConfig,
+    name: &'a str,
+    owner: &'a str,
+}
+
+impl Registry<'_> {
+    fn bucket(&self) -> &str {
+        &self.config.bucket
+    }
+
+    /// Resolves a tag to the digest it points at; digests resolve to themselves
+    async fn resolve(&self, reference: &str) -> Result<String> {
+        if reference.starts_with("sha256:") {
+            parse_digest(reference)?;
+            return Ok(reference.to_string());
+        }
+        if !TAG.is_match(reference) {
+            return Err(AppError::InvalidRequest(format!("Invalid tag: {}", reference)));
+        }
+        let key = format!("{}{}", tags_prefix(self.config, self.name), reference);
+        let tag = self.client.get_object_range(self.bucket(), &key, None, None).await?;
+        Ok(String::from_utf8_lossy(&tag.body).trim().to_string())
+    }
+
+    async fn blob_exists(&self, hex: &str) -> Result<bool> {
+        match self.client.head_object(self.bucket(), &blob_key(self.config, hex)).await {
+            Ok(_) => Ok(true),
+            Err(AppError::ObjectNotFound(_, _)) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn blob_created(&self, digest: &str) -> Response {
+        with_headers(
+            StatusCode::CREATED.into_response(),
+            &[
+                ("location", format!("/v2/{}/blobs/{}", self.name, digest)),
+                (CONTENT_DIGEST, digest.to_string()),
+            ],
+        )
+    }
+
+    /// Where the client continues an upload; the session travels in the query like the reference registry's state
+    fn upload_location(&self, id: &str, session: &str) -> String {
+        format!("/v2/{}/blobs/uploads/{}?session={}", self.name, id, session)
+    }
+
+    fn upload_progress(&self, status: StatusCode, id: &str, session: &str, offset: u64) -> Response {
+        with_headers(
+            status.into_response(),
+            &[
+                ("location", self.upload_location(id, session)),
+                // Range is inclusive, so an empty upload reports 0-0 like other registries
+                ("range", format!("0-{}", offset.saturating_sub(1))),
+                (UPLOAD_UUID, id.to_string()),
+            ],
+        )
+    }
+
+    async fn list_tags(&self, params: &HashMap<String, String>) -> Result<Response> {
+        let prefix = tags_prefix(self.config, self.name);
+        let objects = self.client.list_objects(self.bucket(), Some(prefix.clone())).await?;
+        if objects.is_empty() {
+            return Err(AppError::ObjectNotFound(self.bucket().to_string(), self.name.to_string()));
+        }
+        let mut tags: Vec<String> = objects
+            .iter()
+            .filter_map(|object| object.key()?.strip_prefix(&prefix).map(String::from))
+            .collect();
+        tags.sort();
+        if let Some(last) = params.get("last") {
+            tags.retain(|tag| tag > last);
+        }
+        let limit = params.get("n").and_then(|n| n.parse().ok());
+        let next = match limit {
+            Some(n) if tags.len() > n => {
+                tags.truncate(n);
+                tags.last().map(|last| format!("</v2/{}/tags/list?n={}&last={}>; rel=\"next\"", self.name, n, last))
+            }
+            _ => None,
+        };
+        let response = Json(json!({ "name": self.name, "tags": tags })).into_response();
+        Ok(match next {
+            Some(link) => with_headers(response, &[("link", link)]),
+            None => response,
+        })
+    }
+
+    async fn get_manifest(&self, reference: &str, with_body: bool) -> Result<Response> {
+        let digest = self.resolve(reference).await?;
+        let key = manifest_key(self.config, self.name, &parse_digest(&digest)?);
+        let media_type = self
+            .client
+            .object_metadata(self.bucket(), &key)
+            .await?
+            .remove(MEDIA_TYPE_METADATA)
+            .unwrap_or_else(|| "application/vnd.oci.image.manifest.v1+json".to_string());
+        let mut headers = vec![("content-type", media_type), (CONTENT_DIGEST, digest)];
+        if !with_body {
+            let (_, size) = self.client.head_object(self.bucket(), &key).await?;
+            headers.push(("content-length", size.to_string()));
+            return Ok(with_headers(StatusCode::OK.into_response(), &headers));
+        }
+        let manifest = self.client.get_object_range(self.bucket(), &key, None, None).await?;
+        metrics::record_download(self.bucket(), manifest.body.len());
+        Ok(with_headers(manifest.body.into_response(), &headers))
+    }
+
+    /// Every blob and manifest a manifest refers to must already be in the registry
+    async fn check_references(&self, manifest: &Value) -> Result<()> {
+        let digests = |field: &str| -> Vec<String> {
+            match &manifest[field] {
+                Value::Array(entries) => entries.iter().filter_map(|e| e["digest"].as_str().map(String::from)).collect(),
+                Value::Object(entry) => entry.get("digest").and_then(Value::as_str).map(String::from).into_iter().collect(),
+                _ => Vec::new(),
+            }
+        };
+        for digest in digests("config").into_iter().chain(digests("layers")) {
+            if !self.blob_exists(&parse_digest(&digest)?).await? {
+                return Err(AppError::InvalidRequest(format!("Manifest refers to unknown blob {}", digest)));
+            }
+        }
+        for digest in digests("manifests") {
+            let key = manifest_key(self.config, self.name, &parse_digest(&digest)?);
+            if let Err(AppError::ObjectNotFound(_, _)) = self.client.head_object(self.bucket(), &key).await {
+                return Err(AppError::InvalidRequest(format!("Index refers to unknown manifest {}", digest)));
+            }
+        }
+        Ok(())
+    }
+
+    async fn put_manifest(&self, reference: &str, headers: &HeaderMap, body: Body) -> Result<Response> {
+        let body = axum::body::to_bytes(body, MAX_MANIFEST_SIZE)
+            .await
+            .map_err(|_| AppError::InvalidRequest(format!("Manifests are limited to {} bytes", MAX_MANIFEST_SIZE)))?;
+        let manifest: Value = serde_json::from_slice(&body)
+            .map_err(|e| AppError::InvalidRequest(format!("Manifest is not valid JSON: {}", e)))?;
+        let media_type = headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .or_else(|| manifest["mediaType"].as_str())
+            .ok_or_else(|| AppError::InvalidRequest("Manifest media type is missing".to_string()))?
+            .to_string();
+
+        let hex = hex::encode(Sha256::digest(&body));
+        let digest = format!("sha256:{}", hex);
+        let tag = if reference.starts_with("sha256:") {
+            if reference != digest {
+                return Err(AppError::InvalidRequest(format!("Manifest digest is {}, not {}", digest, reference)));
+            }
+            None
+        } else if TAG.is_match(reference) {
+            Some(reference)
+        } else {
+            return Err(AppError::InvalidRequest(format!("Invalid tag: {}", reference)));
+        };
+        self.check_references(&manifest).await?;
+
+        let key = manifest_key(self.config, self.name, &hex);
+        let metadata = HashMap::from([(MEDIA_TYPE_METADATA.to_string(), media_type.clone())]);
+        let size = body.len();
+        self.client.put_object_with_metadata(self.bucket(), &key, body, metadata, Some(media_type)).await?;
+        if let Some(tag) = tag {
+            let tag_key = format!("{}{}", tags_prefix(self.config, self.name), tag);
+            let pointer = ByteStream::from(Bytes::from(digest.clone()));
+            self.client.put_object(self.bucket(), &tag_key, pointer, Some("text/plain".to_string())).await?;
+            self.state.invalidate_cache(self.bucket(), Some(&tag_key), None);
+        }
+        metrics::record_upload(self.bucket(), size);
+        info!("Pushed manifest {}:{} as {}", self.name, reference, digest);
+        Ok(with_headers(
+            StatusCode::CREATED.into_response(),
+            &[
+                ("location", format!("/v2/{}/manifests/{}", self.name, digest)),
+                (CONTENT_DIGEST, digest),
+            ],
+        ))
+    }
+
+    async fn get_blob(&self, digest: &str, headers: &HeaderMap, with_body: bool) -> Result<Response> {
+        let key = blob_key(self.config, &parse_digest(digest)?);
+        let mut response_headers = vec![
+            ("content-type", "application/octet-stream".to_string()),
+            (CONTENT_DIGEST, digest.to_string()),
+            ("accept-ranges", "bytes".to_string()),
+        ];
+        if !with_body {
+            let (_, size) = self.client.head_object(self.bucket(), &key).await?;
+            response_headers.push(("content-length", size.to_string()));
+            return Ok(with_headers(StatusCode::OK.into_response(), &response_headers));
+        }
+
+        let range = headers.get(http::header::RANGE).and_then(|v| v.to_str().ok());
+        let blob = self.state.read_object(self.client, self.bucket(), &key, range).await?;
+        metrics::record_download(self.bucket(), blob.body.len());
+        let status = match blob.content_range {
+            Some(content_range) => {
+                response_headers.push(("content-range", content_range));
+                StatusCode::PARTIAL_CONTENT
+            }
+            None => StatusCode::OK,
+        };
+        Ok(with_headers((status, blob.body).into_response(), &response_headers))
+    }
+
+    /// POST starts a chunked upload, or stores the body at once when ?digest= is given
+    async fn start_upload(&self, params: &HashMap<String, String>, body: Body) -> Result<Response> {
+        // Blobs are shared by every repository, so mounting one only needs it to exist
+        if let Some(digest) = params.get("mount") {
+            if self.blob_exists(&parse_digest(digest)?).await? {
+                return Ok(self.blob_created(digest));
+            }
+        }
+
+        if let Some(digest) = params.get("digest") {
+            let hex = parse_digest(digest)?;
+            let limit = self.state.config.max_file_size as usize;
+            let body = axum::body::to_bytes(body, limit)
+                .await
+                .map_err(|_| AppError::InvalidRequest(format!("Upload exceeds the maximum size of {} bytes", limit)))?;
+            if hex::encode(Sha256::digest(&body)) != hex {
+                return Err(AppError::InvalidRequest(format!("Object checksum mismatch for {}", digest)));
+            }
+            if !self.blob_exists(&hex).await? {
+                let size = body.len();
+                self.client
+                    .put_object(self.bucket(), &blob_key(self.config, &hex), ByteStream::from(body), None)
+                    .await?;
+                metrics::record_upload(self.bucket(), size);
+            }
+            return Ok(self.blob_created(digest));
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let key = upload_key(self.config, &id);
+        let upload = self.state.uploads.create(self.client, self.bucket(), &key, self.owner, None, None).await?;
+        Ok(self.upload_progress(StatusCode::ACCEPTED, &id, &upload.upload_id, 0))
+    }
+
+    fn session<'p>(&self, id: &str, params: &'p HashMap<String, String>) -> Result<&'p str> {
+        params
+            .get("session")
+            .map(String::as_str)
+            .ok_or_else(|| AppError::UploadNotFound(id.to_string()))
+    }
+
+    /// Streams the request body into the upload session and returns the new offset
+    async fn append(&self, key: &str, session: &str, mut offset: u64, body: Body) -> Result<u64> {
+        let max_size = self.state.config.max_file_size;
+        let mut stream = body.into_data_stream();
+        let mut pending = BytesMut::new();
+        loop {
+            let frame = stream.next().await.transpose().map_err(|e| AppError::InvalidRequest(e.to_string()))?;
+            let done = frame.is_none();
+            if let Some(frame) = frame {
+                pending.extend_from_slice(&frame);
+            }
+            if !pending.is_empty() && (done || pending.len() >= APPEND_SIZE) {
+                let chunk = pending.split().freeze();
+                offset = self
+                    .state
+                    .uploads
+                    .append(self.client, session, self.bucket(), key, self.owner, offset, None, chunk, max_size)
+                    .await?;
+            }
+            if done {
+                return Ok(offset);
+            }
+        }
+    }
+
+    async fn upload_status(&self, id: &str, params: &HashMap<String, String>) -> Result<Response> {
+        let session = self.session(id, params)?;
+        let key = upload_key(self.config, id);
+        let upload = self.state.uploads.status(session, self.bucket(), &key, self.owner).await?;
+        Ok(self.upload_progress(StatusCode::NO_CONTENT, id, session, upload.offset))
+    }
+
+    async fn append_upload(&self, id: &str, params: &HashMap<String, String>, headers: &HeaderMap, body: Body) -> Result<Response> {
+        let session = self.session(id, params)?;
+        let key = upload_key(self.config, id);
+        let offset = self.state.uploads.status(session, self.bucket(), &key, self.owner).await?.offset;
+        // Chunks must continue exactly where the upload stands
+        if let Some(range) = headers.get("content-range").and_then(|v| v.to_str().ok()) {
+            let start = range.split('-').next().and_then(|start| start.trim().parse::<u64>().ok());
+            if start != Some(offset) {
+                return Err(AppError::RangeNotSatisfiable(format!(
+                    "Chunk {} does not continue the upload at offset {}",
+                    range, offset
+                )));
+            }
+        }
+        let offset = self.append(&key, session, offset, body).await?;
+        Ok(self.upload_progress(StatusCode::ACCEPTED, id, session, offset))
+    }
+
+    /// PUT ?digest= appends a final chunk, checks the digest and moves the upload to its blob
+    async fn complete_upload(&self, id: &str, params: &HashMap<String, String>, body: Body) -> Result<Response> {
+        let session = self.session(id, params)?;
+        let digest = params
+            .get("digest")
+            .ok_or_else(|| AppError::InvalidRequest("Completing an upload requires ?digest=".to_string()))?;
+        let hex = parse_digest(digest)?;
+        let key = upload_key(self.config, id);
+        let offset = self.state.uploads.status(session, self.bucket(), &key, self.owner).await?.offset;
+        let size = self.append(&key, session, offset, body).await?;
+
+        let bytes = hex::decode(&hex).map_err(|e| AppError::InvalidRequest(e.to_string()))?;
+        let checksum = format!("sha256 {}", STANDARD.encode(bytes));
+        self.state
+            .uploads
+            .complete(self.client, session, self.bucket(), &key, self.owner, Some(&checksum))
+            .await?;
+        if !self.blob_exists(&hex).await? {
+            self.client.copy_object(self.bucket(), &key, &blob_key(self.config, &hex)).await?;
+        }
+        self.client.delete_object(self.bucket(), &key).await?;
+        metrics::record_upload(self.bucket(), size as usize);
+        info!("Stored blob {} of {} bytes for {}", digest, size, self.name);
+        Ok(self.blob_created(digest))
+    }
+
+    async fn abort_upload(&self, id: &str, params: &HashMap<String, String>) -> Result<Response> {
+        let session = self.session(id, params)?;
+        let key = upload_key(self.config, id);
+        self.state.uploads.abort(self.client, session, self.bucket(), &key, self.owner).await?;
+        Ok(StatusCode::NO_CONTENT.into_response())
+    }
+}
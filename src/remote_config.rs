@@ -0,0 +1,218 @@
+use aws_lc_rs::signature::{UnparsedPublicKey, ED25519};
+use base64::Engine;
+use bytes::Bytes;
+use http::{header, Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::alerts::{self, WebhookClient};
+use crate::config::{AccountConfig, RemoteConfigSource, UserConfig};
+use crate::error::{AppError, Result};
+use crate::kubernetes;
+use crate::server::AppState;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sections a remote document may set; the rest of the config stays local
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Document {
+    #[serde(default)]
+    users: Option<HashMap<String, UserConfig>>,
+    #[serde(default)]
+    accounts: Option<HashMap<String, AccountConfig>>,
+}
+
+enum Location {
+    Http(String),
+    /// An object in a bucket the proxy serves
+    S3 { bucket: String, key: String },
+}
+
+impl Location {
+    /// Plain http:// is only accepted for `signed` documents, as anyone on the path could
+    /// otherwise hand out users and API keys
+    fn parse(url: &str, signed: bool) -> Result<Self> {
+        if let Some(path) = url.strip_prefix("s3://") {
+            match path.split_once('/') {
+                Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+                    return Ok(Self::S3 { bucket: bucket.to_string(), key: key.to_string() })
+                }
+                _ => {}
+            }
+        } else if url.starts_with("https://") {
+            return Ok(Self::Http(url.to_string()));
+        } else if url.starts_with("http://") {
+            if !signed {
+                return Err(AppError::InternalError(format!(
+                    "Remote config {} is fetched over plain HTTP, which needs a public_key to verify it",
+                    url
+                )));
+            }
+            return Ok(Self::Http(url.to_string()));
+        }
+        Err(AppError::InternalError(format!(
+            "Remote config {} is neither an http(s):// URL nor s3://bucket/key",
+            url
+        )))
+    }
+
+    /// Where the detached signature of the document is read from
+    fn signature(&self) -> Self {
+        match self {
+            Self::Http(url) => Self::Http(format!("{}.sig", url)),
+            Self::S3 { bucket, key } => Self::S3 { bucket: bucket.clone(), key: format!("{}.sig", key) },
+        }
+    }
+}
+
+/// A body and the ETag it was served with
+struct Fetched {
+    body: Bytes,
+    etag: Option<String>,
+}
+
+/// The version of the document last applied
+struct Seen {
+    etag: Option<String>,
+    digest: Vec<u8>,
+}
+
+struct Remote {
+    source: RemoteConfigSource,
+    location: Location,
+    public_key: Option<Vec<u8>>,
+    client: WebhookClient,
+}
+
+impl Remote {
+    fn new(source: &RemoteConfigSource) -> Result<Self> {
+        let public_key = source
+            .public_key
+            .as_deref()
+            .map(|key| base64::engine::general_purpose::STANDARD.decode(key.trim()))
+            .transpose()
+            .map_err(|e| AppError::InternalError(format!("Invalid remote config public key: {}", e)))?;
+        Ok(Self {
+            source: source.clone(),
+            location: Location::parse(&source.url, public_key.is_some())?,
+            public_key,
+            client: alerts::webhook_client()?,
+        })
+    }
+
+    /// The body at `location`, or None while its ETag is still `etag`
+    async fn fetch(&self, state: &AppState, location: &Location, etag: Option<&str>) -> Result<Option<Fetched>> {
+        match location {
+            Location::Http(url) => {
+                let mut request = Request::get(url.as_str());
+                if let Some(etag) = etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                let request = request
+                    .body(Full::new(Bytes::new()))
+                    .map_err(|e| AppError::InternalError(format!("Invalid remote config URL: {}", e)))?;
+                let response = tokio::time::timeout(FETCH_TIMEOUT, self.client.request(request))
+                    .await
+                    .map_err(|_| AppError::InternalError(format!("Fetching {} timed out", url)))?
+                    .map_err(|e| AppError::InternalError(format!("Fetching {} failed: {}", url, e)))?;
+                if response.status() == StatusCode::NOT_MODIFIED {
+                    return Ok(None);
+                }
+                if !response.status().is_success() {
+                    return Err(AppError::InternalError(format!("{} returned {}", url, response.status())));
+                }
+                let etag = response.headers().get(header::ETAG).and_then(|etag| etag.to_str().ok()).map(String::from);
+                let body = tokio::time::timeout(FETCH_TIMEOUT, response.into_body().collect())
+                    .await
+                    .map_err(|_| AppError::InternalError(format!("Fetching {} timed out", url)))?
+                    .map_err(|e| AppError::InternalError(format!("Fetching {} failed: {}", url, e)))?
+                    .to_bytes();
+                Ok(Some(Fetched { body, etag }))
+            }
+            Location::S3 { bucket, key } => {
+                let (_, client) = state.get_account_and_client(bucket)?;
+                let (current, _) = client.head_object(bucket, key).await?;
+                if current.is_some() && current.as_deref() == etag {
+                    return Ok(None);
+                }
+                // Pinned to the ETag just seen, so a write in between is picked up on the next poll
+                let object = client.get_object_range(bucket, key, None, current).await?;
+                Ok(Some(Fetched { body: object.body, etag: object.etag }))
+            }
+        }
+    }
+
+    /// Checks the document against its detached base64 Ed25519 signature
+    async fn verify(&self, state: &AppState, body: &[u8]) -> Result<()> {
+        let Some(public_key) = &self.public_key else {
+            return Ok(());
+        };
+        let invalid = |reason: String| AppError::InternalError(format!("{}: {}", self.source.url, reason));
+        let signature = self
+            .fetch(state, &self.location.signature(), None)
+            .await?
+            .ok_or_else(|| invalid("no signature".to_string()))?;
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(signature.body.trim_ascii())
+            .map_err(|e| invalid(format!("unreadable signature: {}", e)))?;
+        UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(body, &signature)
+            .map_err(|_| invalid("signature does not match the public key".to_string()))
+    }
+
+    /// Fetches the document and applies it when it changed since the version last seen
+    async fn sync(&self, state: &AppState, seen: &mut Option<Seen>) -> Result<()> {
+        let etag = seen.as_ref().and_then(|seen| seen.etag.as_deref());
+        let Some(fetched) = self.fetch(state, &self.location, etag).await? else {
+            return Ok(());
+        };
+        // Servers without ETags send the document on every poll
+        let digest = Sha256::digest(&fetched.body).to_vec();
+        if seen.as_ref().is_some_and(|seen| seen.digest == digest) {
+            *seen = Some(Seen { etag: fetched.etag, digest });
+            return Ok(());
+        }
+        self.verify(state, &fetched.body).await?;
+        let document: Document = serde_json::from_slice(&fetched.body)
+            .map_err(|e| AppError::InternalError(format!("Invalid remote config {}: {}", self.source.url, e)))?;
+        if let Some(accounts) = document.accounts {
+            kubernetes::apply_accounts(state, accounts, &self.source.url).await?;
+        }
+        if let Some(users) = document.users {
+            kubernetes::apply_users(state, users, &self.source.url);
+        }
+        *seen = Some(Seen { etag: fetched.etag, digest });
+        Ok(())
+    }
+}
+
+/// Applies the remote document before requests are served, failing startup when it cannot be
+/// read, then polls it for changes; a change that fails to apply keeps the previous version
+pub async fn start(state: Arc<AppState>) -> Result<()> {
+    let Some(source) = &state.config.remote_config else {
+        return Ok(());
+    };
+    let remote = Remote::new(source)?;
+    let mut seen = None;
+    remote.sync(&state, &mut seen).await?;
+    info!("Polling remote config {} every {}s", source.url, source.poll_interval_secs);
+
+    let interval = Duration::from_secs(source.poll_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = remote.sync(&state, &mut seen).await {
+                warn!("Keeping the previous remote config: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
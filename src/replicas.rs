@@ -0,0 +1,164 @@
+use futures::future::join_all;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+use crate::config::Config;
+use crate::consistency;
+use crate::error::{AppError, Result};
+use crate::hedging;
+use crate::s3::ObjectPart;
+use crate::server::AppState;
+
+/// Weight of the newest probe in the smoothed round trip
+const SMOOTHING: f64 = 0.3;
+
+lazy_static! {
+    static ref ENDPOINTS: Mutex<HashMap<String, Endpoint>> = Mutex::new(HashMap::new());
+}
+
+/// What the probes found out about the endpoint of one bucket
+#[derive(Default)]
+struct Endpoint {
+    /// Smoothed round trip of successful probes
+    rtt: Option<Duration>,
+    /// Cleared by a failed probe or read, set again by the next successful probe
+    healthy: bool,
+}
+
+/// Reads of these layouts need the proxy's own index or manifests, which exist only for the primary
+fn is_plain(config: &Config, bucket: &str) -> bool {
+    !config.packing.contains_key(bucket) && !config.chunking.contains_key(bucket) && !config.dedup.contains_key(bucket)
+}
+
+/// The primary and its replicas, healthy ones by round trip first; unprobed ones keep config order
+fn candidates(config: &Config, bucket: &str) -> Vec<String> {
+    let Some(group) = config.read_replicas.get(bucket) else {
+        return vec![bucket.to_string()];
+    };
+    let mut candidates: Vec<String> = std::iter::once(bucket.to_string()).chain(group.replicas.iter().cloned()).collect();
+    let endpoints = ENDPOINTS.lock().unwrap();
+    // Stable, so ties and unprobed buckets prefer the primary
+    candidates.sort_by_key(|candidate| match endpoints.get(candidate) {
+        Some(Endpoint { rtt: Some(rtt), healthy: true }) => (0, *rtt),
+        Some(Endpoint { healthy: false, .. }) => (2, Duration::ZERO),
+        _ => (1, Duration::ZERO),
+    });
+    candidates
+}
+
+fn mark_unhealthy(bucket: &str) {
+    ENDPOINTS.lock().unwrap().entry(bucket.to_string()).or_default().healthy = false;
+}
+
+/// One read of `key` from `copy`, a replica of `bucket` or the primary itself
+async fn read_copy(state: &AppState, bucket: &str, copy: &str, key: &str, range: Option<&str>) -> Result<ObjectPart> {
+    let (_, client) = &state.get_account_and_client(copy)?;
+    if copy == bucket {
+        state.read_object(client, bucket, key, range).await
+    } else {
+        // Straight from the copy: the cache and its invalidation are keyed by the primary
+        client.get_object_range(copy, key, range.map(String::from), None).await
+    }
+}
+
+/// Like `read_copy`, racing a read still running after the bucket's hedge delay by one from `alternate`
+async fn read_hedged(
+    state: &AppState,
+    bucket: &str,
+    copy: &str,
+    alternate: &str,
+    key: &str,
+    range: Option<&str>,
+) -> Result<ObjectPart> {
+    let original = read_copy(state, bucket, copy, key, range);
+    match state.config.hedged_reads.get(bucket) {
+        Some(hedge) => {
+            let delay = Duration::from_millis(hedge.delay_ms);
+            hedging::race(bucket, delay, original, read_copy(state, bucket, alternate, key, range)).await
+        }
+        None => original.await,
+    }
+}
+
+/// Reads `key` from the nearest healthy copy of `bucket`, falling back to the others and finally
+/// the primary when a copy fails or does not have the key yet
+pub async fn read(state: &AppState, bucket: &str, key: &str, range: Option<&str>) -> Result<ObjectPart> {
+    // Replication lags behind, so keys this proxy just changed are only current on the primary
+    if !state.config.read_replicas.contains_key(bucket)
+        || !is_plain(&state.config, bucket)
+        || consistency::is_recent(&state.config, &state.find_account_for_bucket(bucket).unwrap_or_default(), bucket, key)
+    {
+        return read_hedged(state, bucket, bucket, bucket, key, range).await;
+    }
+
+    let candidates = candidates(&state.config, bucket);
+    let mut last_error = None;
+    for (index, candidate) in candidates.iter().enumerate() {
+        // Hedges go to the next copy, or to the same one when there is none
+        let alternate = candidates.get(index + 1).unwrap_or(candidate);
+        match read_hedged(state, bucket, candidate, alternate, key, range).await {
+            Ok(part) => return Ok(part),
+            Err(e @ (AppError::ObjectNotFound(_, _) | AppError::RangeNotSatisfiable(_))) if candidate == bucket => {
+                return Err(e)
+            }
+            // The copy may still hold an older, shorter version
+            Err(AppError::ObjectNotFound(_, _) | AppError::RangeNotSatisfiable(_)) => {
+                debug!("{}/{} not replicated to {} yet", bucket, key, candidate)
+            }
+            Err(e) => {
+                warn!("Reading {}/{} from {} failed, trying the next copy: {}", bucket, key, candidate, e);
+                mark_unhealthy(candidate);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.unwrap_or_else(|| AppError::ObjectNotFound(bucket.to_string(), key.to_string())))
+}
+
+async fn probe(state: &AppState, bucket: &str) {
+    let started = Instant::now();
+    let result = match state.get_account_and_client(bucket) {
+        Ok((_, client)) => client.head_bucket(bucket).await,
+        Err(e) => Err(e),
+    };
+    let mut endpoints = ENDPOINTS.lock().unwrap();
+    let first = !endpoints.contains_key(bucket);
+    let endpoint = endpoints.entry(bucket.to_string()).or_default();
+    match result {
+        Ok(()) => {
+            let rtt = started.elapsed();
+            endpoint.rtt = Some(match endpoint.rtt {
+                Some(previous) => previous.mul_f64(1.0 - SMOOTHING) + rtt.mul_f64(SMOOTHING),
+                None => rtt,
+            });
+            endpoint.healthy = true;
+        }
+        Err(e) => {
+            if endpoint.healthy || first {
+                warn!("Replica probe of {} failed: {}", bucket, e);
+            }
+            endpoint.healthy = false;
+        }
+    }
+}
+
+/// Measures the round trip to every copy of each replicated bucket, so reads go to the nearest
+pub fn spawn_probes(state: Arc<AppState>) {
+    for (bucket, group) in &state.config.read_replicas {
+        info!("Probing {} and its replicas {:?} every {}s", bucket, group.replicas, group.probe_interval_secs);
+        let state = state.clone();
+        let bucket = bucket.clone();
+        let interval = Duration::from_secs(group.probe_interval_secs.max(1));
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let candidates = candidates(&state.config, &bucket);
+                join_all(candidates.iter().map(|candidate| probe(&state, candidate))).await;
+            }
+        });
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::s3::ObjectPart;
+use crate::tenants;
+
+/// A download without further requests for this long is finished or abandoned
+const IDLE: Duration = Duration::from_secs(600);
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Downloads followed at once per replica; further ones are counted but not followed
+const MAX_TRACKED: usize = 100_000;
+
+/// What a response was to the download it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RangeKind {
+    /// No Range, the whole object
+    Full,
+    /// A range from the start of the object
+    Initial,
+    /// A range continuing bytes the same user already fetched
+    Resume,
+    /// A range elsewhere, such as a video player seeking
+    Seek,
+}
+
+impl RangeKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            RangeKind::Full => "full",
+            RangeKind::Initial => "initial",
+            RangeKind::Resume => "resume",
+            RangeKind::Seek => "seek",
+        }
+    }
+}
+
+/// Requests one user made for one version of an object
+struct Download {
+    tenant: Option<String>,
+    bucket: String,
+    etag: Option<String>,
+    size: u64,
+    /// End of the bytes fetched from the start of the object without gaps
+    contiguous: u64,
+    resumed: bool,
+    last_seen: Instant,
+}
+
+impl Download {
+    fn finish(&self) {
+        if self.size > 0 {
+            let ratio = self.contiguous as f64 / self.size as f64;
+            metrics::record_download_completion(self.tenant.as_deref(), &self.bucket, ratio, self.resumed);
+        }
+    }
+}
+
+struct Downloads {
+    active: HashMap<(String, String, String), Download>,
+    swept_at: Instant,
+}
+
+lazy_static::lazy_static! {
+    static ref DOWNLOADS: Mutex<Downloads> = Mutex::new(Downloads { active: HashMap::new(), swept_at: Instant::now() });
+}
+
+/// First byte of a `bytes start-end/total` Content-Range
+fn range_start(content_range: &str) -> Option<u64> {
+    content_range.strip_prefix("bytes ")?.split('-').next()?.parse().ok()
+}
+
+/// Follows the requests of a download across reconnects and records how it continued
+pub fn record(username: &str, bucket: &str, key: &str, part: &ObjectPart) {
+    let start = part.content_range.as_deref().and_then(range_start);
+    let end = start.unwrap_or(0) + part.body.len() as u64;
+    let id = (username.to_string(), bucket.to_string(), key.to_string());
+
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    if downloads.swept_at.elapsed() >= SWEEP_INTERVAL {
+        downloads.swept_at = Instant::now();
+        downloads.active.retain(|_, download| {
+            let idle = download.last_seen.elapsed() >= IDLE;
+            if idle {
+                download.finish();
+            }
+            !idle
+        });
+    }
+
+    // A new version of the object starts a new download
+    if let Some(download) = downloads.active.get(&id).filter(|download| download.etag != part.etag) {
+        download.finish();
+        downloads.active.remove(&id);
+    }
+    let kind = match (start, downloads.active.get(&id)) {
+        (None, _) => RangeKind::Full,
+        (Some(0), _) => RangeKind::Initial,
+        (Some(start), Some(download)) if start <= download.contiguous => RangeKind::Resume,
+        (Some(_), _) => RangeKind::Seek,
+    };
+    metrics::record_range_request(bucket, kind.as_str());
+
+    let tracked = downloads.active.len();
+    let download = match downloads.active.get_mut(&id) {
+        Some(download) => download,
+        None if tracked >= MAX_TRACKED => return,
+        None => downloads.active.entry(id).or_insert(Download {
+            tenant: tenants::current(),
+            bucket: bucket.to_string(),
+            etag: part.etag.clone(),
+            size: part.total_size,
+            contiguous: 0,
+            resumed: false,
+            last_seen: Instant::now(),
+        }),
+    };
+    if start.unwrap_or(0) <= download.contiguous {
+        download.contiguous = download.contiguous.max(end);
+    }
+    download.resumed |= kind == RangeKind::Resume;
+    download.last_seen = Instant::now();
+}
@@ -0,0 +1,76 @@
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::config::{Config, KeyRewriteRule};
+
+lazy_static! {
+    /// Compiled rule patterns, checked when the config is loaded
+    static ref PATTERNS: Mutex<HashMap<String, Regex>> = Mutex::new(HashMap::new());
+}
+
+fn compiled(pattern: &str) -> Option<Regex> {
+    let mut patterns = PATTERNS.lock().unwrap();
+    if let Some(regex) = patterns.get(pattern) {
+        return Some(regex.clone());
+    }
+    match Regex::new(pattern) {
+        Ok(regex) => {
+            patterns.insert(pattern.to_string(), regex.clone());
+            Some(regex)
+        }
+        Err(e) => {
+            warn!("Skipping key rewrite pattern {}: {}", pattern, e);
+            None
+        }
+    }
+}
+
+fn fill_template(template: &str, key: &str, now: DateTime<Utc>) -> String {
+    template
+        .replace("{yyyy}", &now.format("%Y").to_string())
+        .replace("{mm}", &now.format("%m").to_string())
+        .replace("{dd}", &now.format("%d").to_string())
+        .replace("{hh}", &now.format("%H").to_string())
+        .replace("{key}", key)
+}
+
+/// The index of the rule that applies to `key` and the key it becomes
+pub fn evaluate(rules: &[KeyRewriteRule], key: &str, write: bool, now: DateTime<Utc>) -> Option<(usize, String)> {
+    for (index, rule) in rules.iter().enumerate() {
+        if rule.writes_only && !write {
+            continue;
+        }
+        let Some(pattern) = &rule.pattern else {
+            return Some((index, fill_template(&rule.template, key, now)));
+        };
+        let Some(regex) = compiled(pattern) else {
+            continue;
+        };
+        if let Some(captures) = regex.captures(key) {
+            // $ in the original key must not be read as a capture reference
+            let template = fill_template(&rule.template, &key.replace('$', "$$"), now);
+            let mut rewritten = String::new();
+            captures.expand(&template, &mut rewritten);
+            return Some((index, rewritten));
+        }
+    }
+    None
+}
+
+/// The key a request for `key` in `bucket` is served from
+pub fn apply(config: &Config, bucket: &str, key: &str, write: bool) -> String {
+    let Some(rules) = config.key_rewrites.get(bucket) else {
+        return key.to_string();
+    };
+    match evaluate(rules, key, write, Utc::now()) {
+        Some((index, rewritten)) => {
+            debug!("Rule {} of bucket {} rewrote {} to {}", index, bucket, key, rewritten);
+            rewritten
+        }
+        None => key.to_string(),
+    }
+}
@@ -0,0 +1,90 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::debug;
+
+use crate::config::{wildcard_match, Config, RoutingRule};
+use crate::tenants;
+
+tokio::task_local! {
+    /// Indexes of the routing rules matching the client of the request being handled on this task
+    static MATCHED: Arc<Vec<usize>>;
+}
+
+fn matches(rule: &RoutingRule, peer: Option<IpAddr>, headers: &HeaderMap) -> bool {
+    let source = rule.source_cidrs.is_empty()
+        || peer.is_some_and(|ip| rule.source_cidrs.iter().any(|net| net.contains(&ip)));
+    let header = rule.header.as_ref().is_none_or(|name| {
+        headers.get_all(name.as_str()).iter().any(|value| {
+            rule.values.is_empty()
+                || value
+                    .to_str()
+                    .is_ok_and(|value| rule.values.iter().any(|wanted| wanted.eq_ignore_ascii_case(value.trim())))
+        })
+    });
+    source && header
+}
+
+/// Matches the client against the routing rules once, for every account lookup of the request
+pub async fn route(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    if config.routing_rules.is_empty() {
+        return next.run(request).await;
+    }
+    // Dual-stack listeners report IPv4 clients as mapped IPv6 addresses
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    let matched: Vec<usize> = config
+        .routing_rules
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| matches(rule, peer, request.headers()))
+        .map(|(index, _)| index)
+        .collect();
+    MATCHED.scope(Arc::new(matched), next.run(request)).await
+}
+
+/// The rules the client of this task's request matched, for tasks the request spawns
+pub fn current() -> Option<Arc<Vec<usize>>> {
+    MATCHED.try_with(Arc::clone).ok()
+}
+
+/// Runs a task spawned by a request with that request's routing, so it reads from and caches the
+/// same accounts instead of falling back to the defaults
+pub async fn scope<F: Future>(matched: Option<Arc<Vec<usize>>>, task: F) -> F::Output {
+    match matched {
+        Some(matched) => MATCHED.scope(matched, task).await,
+        None => task.await,
+    }
+}
+
+/// Account the first matching rule picks for `bucket`; None outside requests, such as in background
+/// tasks, and when no rule names an account having the bucket
+pub fn account_for(config: &Config, bucket: &str) -> Option<String> {
+    let account = MATCHED
+        .try_with(|matched| {
+            let accounts = config.accounts.current();
+            matched
+                .iter()
+                .map(|index| &config.routing_rules[*index])
+                .find(|rule| {
+                    rule.bucket.as_ref().is_none_or(|pattern| wildcard_match(pattern, bucket))
+                        && tenants::owns(config, &rule.account)
+                        && accounts.get(&rule.account).is_some_and(|account| {
+                            account.buckets.iter().any(|name| name == bucket) || account.aliases.contains_key(bucket)
+                        })
+                })
+                .map(|rule| rule.account.clone())
+        })
+        .ok()
+        .flatten()?;
+    debug!("Routing {} to account {}", bucket, account);
+    Some(account)
+}
@@ -1,44 +1,120 @@
-use aws_config::{BehaviorVersion, Region};
+use aws_config::{AppName, BehaviorVersion, Region};
 use aws_sdk_s3::{
     config::Credentials,
+    operation::head_object::HeadObjectOutput,
     primitives::ByteStream,
-    types::Object,
+    types::{BucketLocationConstraint, CompletedMultipartUpload, CompletedPart, CreateBucketConfiguration, MetadataDirective, Object, ObjectLockMode},
     Client,
-    error::SdkError,
+    error::{ProvideErrorMetadata, SdkError},
 };
-use tracing::{info, instrument};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{info, instrument, warn};
 
+use crate::config::{AccountConfig, Config, RecordingConfig, UpstreamTracingConfig};
 use crate::error::{AppError, Result};
+use crate::etags;
+use crate::memory;
+use crate::metrics;
+use crate::sigv4::uri_encode;
+use crate::upstream;
+
+/// CopyObject is limited to 5 GiB, larger objects are copied in parts
+const MAX_SINGLE_COPY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+const COPY_PART_SIZE: u64 = 512 * 1024 * 1024;
+
+/// An object body, or the part of it selected by a Range request
+pub struct ObjectPart {
+    pub body: Bytes,
+    pub etag: Option<String>,
+    /// Set when only part of the object was returned
+    pub content_range: Option<String>,
+    pub total_size: u64,
+    /// ETag the proxy stored in metadata when the object was written
+    pub proxy_etag: Option<String>,
+}
+
+/// Reads a response body into one buffer sized from its Content-Length, charging the buffer to the
+/// memory budget as it fills
+async fn read_body(mut body: ByteStream, length: Option<i64>) -> Result<(Bytes, memory::Reservation)> {
+    let mut buffer = memory::BodyBuffer::charged(length.and_then(|length| u64::try_from(length).ok()));
+    while let Some(chunk) = body.try_next().await.map_err(|e| AppError::InternalError(e.to_string()))? {
+        buffer.push(chunk)?;
+    }
+    Ok(buffer.finish())
+}
+
+/// A client for each of the given accounts
+pub async fn connect(config: &Config, accounts: &HashMap<String, AccountConfig>) -> Result<HashMap<String, Arc<S3Client>>> {
+    let mut clients = HashMap::new();
+    for (account_id, account) in accounts {
+        info!("Initializing S3 client for account {}", account_id);
+        let client = S3Client::new(account_id, account, config.recording.as_ref(), &config.upstream_tracing).await?;
+        clients.insert(account_id.clone(), Arc::new(client));
+    }
+    Ok(clients)
+}
 
 pub struct S3Client {
     client: Client,
+    account_id: String,
+    region: String,
+    /// Client-facing bucket name to upstream bucket name
+    aliases: HashMap<String, String>,
 }
 
 impl S3Client {
-    #[instrument(skip(endpoint_url, region, access_key_id, secret_access_key))]
+    #[instrument(skip(account, recording, tracing))]
     pub async fn new(
-        endpoint_url: String,
-        region: String,
-        access_key_id: String,
-        secret_access_key: String,
+        account_id: &str,
+        account: &AccountConfig,
+        recording: Option<&RecordingConfig>,
+        tracing: &UpstreamTracingConfig,
     ) -> Result<Self> {
-        info!("Creating new S3 client for endpoint {}", endpoint_url);
-        
+        info!("Creating new S3 client for endpoint {}", account.endpoint_url);
+
+        let app_name = AppName::new(tracing.app_name.clone())
+            .map_err(|e| AppError::InternalError(format!("Invalid upstream_tracing.app_name: {}", e)))?;
         let config = aws_config::defaults(BehaviorVersion::latest())
-            .endpoint_url(endpoint_url)
-            .region(Region::new(region))
+            .app_name(app_name)
+            .endpoint_url(account.endpoint_url.clone())
+            .region(Region::new(account.region.clone()))
             .credentials_provider(Credentials::new(
-                access_key_id,
-                secret_access_key,
+                account.access_key_id.clone(),
+                account.secret_access_key.clone(),
                 None,
                 None,
                 "s3-proxy",
             ))
+            .http_client(upstream::http_client(account_id, account, recording)?)
             .load()
             .await;
 
-        let client = Client::new(&config);
-        Ok(Self { client })
+        // MinIO and Ceph RGW usually need bucket names in the path rather than the hostname
+        let s3_config = aws_sdk_s3::config::Builder::from(&config)
+            .force_path_style(account.force_path_style)
+            .build();
+
+        let client = Client::from_conf(s3_config);
+        Ok(Self {
+            client,
+            account_id: account_id.to_string(),
+            region: account.region.clone(),
+            aliases: account.aliases.clone(),
+        })
+    }
+
+    /// The account this client talks to; the same bucket name may live on several accounts
+    pub fn account_id(&self) -> &str {
+        &self.account_id
+    }
+
+    /// The name `bucket` has on the endpoint; clients only ever see the alias
+    fn upstream_bucket<'a>(&'a self, bucket: &'a str) -> &'a str {
+        self.aliases.get(bucket).map(String::as_str).unwrap_or(bucket)
     }
 
     #[instrument(skip(self), fields(bucket = %bucket))]
@@ -49,14 +125,16 @@ impl S3Client {
         let mut continuation_token = None;
 
         loop {
+            let started = Instant::now();
             let response = self
                 .client
                 .list_objects_v2()
-                .bucket(bucket)
+                .bucket(self.upstream_bucket(bucket))
                 .set_prefix(prefix.clone())
                 .set_continuation_token(continuation_token)
                 .send()
                 .await?;
+            metrics::record_upstream_ttfb(&self.account_id, "list", started.elapsed());
 
             if let Some(contents) = response.contents {
                 objects.extend(contents);
@@ -72,30 +150,224 @@ impl S3Client {
         Ok(objects)
     }
 
+    /// Lists the objects directly under `prefix` and the sub-prefixes below it
+    #[instrument(skip(self), fields(bucket = %bucket))]
+    pub async fn list_directory(&self, bucket: &str, prefix: &str) -> Result<(Vec<Object>, Vec<String>)> {
+        info!("Listing directory {}/{}", bucket, prefix);
+
+        let mut objects = Vec::new();
+        let mut prefixes = Vec::new();
+        let mut continuation_token = None;
+
+        loop {
+            let started = Instant::now();
+            let response = self
+                .client
+                .list_objects_v2()
+                .bucket(self.upstream_bucket(bucket))
+                .prefix(prefix)
+                .delimiter("/")
+                .set_continuation_token(continuation_token)
+                .send()
+                .await?;
+            metrics::record_upstream_ttfb(&self.account_id, "list", started.elapsed());
+
+            if let Some(contents) = response.contents {
+                objects.extend(contents);
+            }
+            if let Some(common_prefixes) = response.common_prefixes {
+                prefixes.extend(common_prefixes.into_iter().filter_map(|p| p.prefix));
+            }
+
+            continuation_token = response.next_continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok((objects, prefixes))
+    }
+
     #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
     pub async fn get_object(&self, bucket: &str, key: &str) -> Result<ByteStream> {
         info!("Getting object {}/{}", bucket, key);
         
+        // The body is streamed afterwards, so send() resolving marks the first byte
+        let started = Instant::now();
         match self
             .client
             .get_object()
-            .bucket(bucket)
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .send()
+            .await
+        {
+            Ok(response) => {
+                metrics::record_upstream_ttfb(&self.account_id, "get", started.elapsed());
+                Ok(response.body)
+            }
+            Err(e) => {
+                if let SdkError::ServiceError(context) = &e {
+                    if context.err().is_no_such_key() {
+                        return Err(AppError::ObjectNotFound(bucket.to_string(), key.to_string()));
+                    }
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Fetches a whole object, or the given HTTP Range of it, optionally only if its ETag still matches
+    #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
+    pub async fn get_object_range(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<String>,
+        if_match: Option<String>,
+    ) -> Result<ObjectPart> {
+        Ok(self.fetch(bucket, key, range, if_match).await?.0)
+    }
+
+    /// Like [`Self::get_object_range`], but also returns what the body holds of the memory budget,
+    /// for callers that keep it charged until they have stored the body
+    #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
+    pub async fn get_object_reserved(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<String>,
+        if_match: Option<String>,
+    ) -> Result<(ObjectPart, memory::Reservation)> {
+        self.fetch(bucket, key, range, if_match).await
+    }
+
+    async fn fetch(
+        &self,
+        bucket: &str,
+        key: &str,
+        range: Option<String>,
+        if_match: Option<String>,
+    ) -> Result<(ObjectPart, memory::Reservation)> {
+        info!("Getting object {}/{} range {:?}", bucket, key, range);
+
+        let started = Instant::now();
+        let response = match self
+            .client
+            .get_object()
+            .bucket(self.upstream_bucket(bucket))
             .key(key)
+            .set_range(range)
+            .set_if_match(if_match)
             .send()
             .await
         {
-            Ok(response) => Ok(response.body),
+            Ok(response) => response,
             Err(e) => {
                 if let SdkError::ServiceError(context) = &e {
                     if context.err().is_no_such_key() {
                         return Err(AppError::ObjectNotFound(bucket.to_string(), key.to_string()));
                     }
+                    match context.err().code() {
+                        Some("PreconditionFailed") => {
+                            return Err(AppError::PreconditionFailed(format!("{}/{} has changed", bucket, key)))
+                        }
+                        Some("InvalidRange") => {
+                            return Err(AppError::RangeNotSatisfiable(format!("{}/{} does not contain the requested range", bucket, key)))
+                        }
+                        _ => {}
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+        metrics::record_upstream_ttfb(&self.account_id, "get", started.elapsed());
+
+        let etag = response.e_tag;
+        let proxy_etag = response.metadata.and_then(|mut metadata| metadata.remove(etags::METADATA));
+        let content_range = response.content_range;
+        let (body, reservation) = read_body(response.body, response.content_length).await?;
+        // Content-Range is "bytes start-end/total"
+        let total_size = content_range
+            .as_deref()
+            .and_then(|r| r.rsplit('/').next())
+            .and_then(|total| total.parse().ok())
+            .unwrap_or(body.len() as u64);
+
+        Ok((ObjectPart { body, etag, content_range, total_size, proxy_etag }, reservation))
+    }
+
+    /// Returns the object's ETag and size
+    #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
+    pub async fn head_object(&self, bucket: &str, key: &str) -> Result<(Option<String>, u64)> {
+        let response = self.head(bucket, key).await?;
+        Ok((response.e_tag, response.content_length.unwrap_or(0).max(0) as u64))
+    }
+
+    /// User-defined metadata of an object, without the x-amz-meta- prefix
+    #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
+    pub async fn object_metadata(&self, bucket: &str, key: &str) -> Result<HashMap<String, String>> {
+        Ok(self.head(bucket, key).await?.metadata.unwrap_or_default())
+    }
+
+    async fn head(&self, bucket: &str, key: &str) -> Result<HeadObjectOutput> {
+        match self.client.head_object().bucket(self.upstream_bucket(bucket)).key(key).send().await {
+            Ok(response) => Ok(response),
+            Err(e) => {
+                if let SdkError::ServiceError(context) = &e {
+                    if context.err().is_not_found() {
+                        return Err(AppError::ObjectNotFound(bucket.to_string(), key.to_string()));
+                    }
                 }
                 Err(e.into())
             }
         }
     }
 
+    /// Writes an object along with user-defined metadata
+    #[instrument(skip(self, body, metadata), fields(bucket = %bucket, key = %key))]
+    pub async fn put_object_with_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Bytes,
+        metadata: HashMap<String, String>,
+        content_type: Option<String>,
+    ) -> Result<Option<String>> {
+        let started = Instant::now();
+        let response = self
+            .client
+            .put_object()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .set_metadata(Some(metadata))
+            .set_content_type(content_type)
+            .body(ByteStream::from(body))
+            .send()
+            .await?;
+        metrics::record_upstream_ttfb(&self.account_id, "put", started.elapsed());
+        Ok(response.e_tag)
+    }
+
+    #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
+    pub async fn get_object_tags(&self, bucket: &str, key: &str) -> Result<Vec<(String, String)>> {
+        let started = Instant::now();
+        let response = self
+            .client
+            .get_object_tagging()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        metrics::record_upstream_ttfb(&self.account_id, "get", started.elapsed());
+        Ok(response
+            .tag_set()
+            .iter()
+            .map(|tag| (tag.key().to_string(), tag.value().to_string()))
+            .collect())
+    }
+
     #[instrument(skip(self, body), fields(bucket = %bucket, key = %key))]
     pub async fn put_object(
         &self,
@@ -103,13 +375,13 @@ impl S3Client {
         key: &str,
         body: ByteStream,
         content_type: Option<String>,
-    ) -> Result<()> {
+    ) -> Result<Option<String>> {
         info!("Putting object {}/{}", bucket, key);
         
         let mut request = self
             .client
             .put_object()
-            .bucket(bucket)
+            .bucket(self.upstream_bucket(bucket))
             .key(key)
             .body(body);
 
@@ -117,8 +389,381 @@ impl S3Client {
             request = request.content_type(content_type);
         }
 
-        request.send().await?;
+        let started = Instant::now();
+        let response = request.send().await?;
+        metrics::record_upstream_ttfb(&self.account_id, "put", started.elapsed());
         info!("Successfully put object {}/{}", bucket, key);
+        Ok(response.e_tag)
+    }
+
+    /// Writes an object only if the key is free; false when another writer got there first
+    pub async fn put_object_if_absent(&self, bucket: &str, key: &str, body: Bytes, content_type: Option<String>) -> Result<bool> {
+        self.put_object_conditionally(bucket, key, body, content_type, Some("*"), None, None).await
+    }
+
+    /// Replaces an object only if its ETag is still the given one; false when it has changed
+    pub async fn put_object_if_match(&self, bucket: &str, key: &str, body: Bytes, content_type: Option<String>, etag: &str) -> Result<bool> {
+        self.put_object_conditionally(bucket, key, body, content_type, None, Some(etag), None).await
+    }
+
+    /// Writes an object only if the key is free, under a compliance mode Object Lock until
+    /// `retain_until` when given, so not even the account owner can change it before then
+    pub async fn put_object_write_once(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Bytes,
+        content_type: Option<String>,
+        retain_until: Option<DateTime<Utc>>,
+    ) -> Result<bool> {
+        self.put_object_conditionally(bucket, key, body, content_type, Some("*"), None, retain_until).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(skip(self, body), fields(bucket = %bucket, key = %key))]
+    async fn put_object_conditionally(
+        &self,
+        bucket: &str,
+        key: &str,
+        body: Bytes,
+        content_type: Option<String>,
+        if_none_match: Option<&str>,
+        if_match: Option<&str>,
+        retain_until: Option<DateTime<Utc>>,
+    ) -> Result<bool> {
+        let started = Instant::now();
+        match self
+            .client
+            .put_object()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .set_if_none_match(if_none_match.map(String::from))
+            .set_if_match(if_match.map(String::from))
+            .set_object_lock_mode(retain_until.map(|_| ObjectLockMode::Compliance))
+            .set_object_lock_retain_until_date(retain_until.map(|until| aws_sdk_s3::primitives::DateTime::from_secs(until.timestamp())))
+            .set_content_type(content_type)
+            .body(ByteStream::from(body))
+            .send()
+            .await
+        {
+            Ok(_) => {
+                metrics::record_upstream_ttfb(&self.account_id, "put", started.elapsed());
+                Ok(true)
+            }
+            Err(e) => {
+                if let SdkError::ServiceError(context) = &e {
+                    if matches!(context.err().code(), Some("PreconditionFailed" | "ConditionalRequestConflict")) {
+                        return Ok(false);
+                    }
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self), fields(bucket = %bucket, key = %key))]
+    pub async fn delete_object(&self, bucket: &str, key: &str) -> Result<()> {
+        info!("Deleting object {}/{}", bucket, key);
+
+        let started = Instant::now();
+        self.client.delete_object().bucket(self.upstream_bucket(bucket)).key(key).send().await?;
+        metrics::record_upstream_ttfb(&self.account_id, "delete", started.elapsed());
+        info!("Successfully deleted object {}/{}", bucket, key);
+        Ok(())
+    }
+
+    /// Server-side copy of an object within a bucket, pinned to the version seen when it started
+    #[instrument(skip(self), fields(bucket = %bucket))]
+    pub async fn copy_object(&self, bucket: &str, from: &str, to: &str) -> Result<()> {
+        info!("Copying object {}/{} to {}", bucket, from, to);
+
+        let head = self.head(bucket, from).await?;
+        let (etag, size) = (head.e_tag.clone(), head.content_length.unwrap_or(0).max(0) as u64);
+        let source = format!("{}/{}", self.upstream_bucket(bucket), uri_encode(from, false));
+        if size > MAX_SINGLE_COPY_SIZE {
+            return self.copy_multipart(bucket, &source, to, head, size).await;
+        }
+
+        let started = Instant::now();
+        let result = self
+            .client
+            .copy_object()
+            .bucket(self.upstream_bucket(bucket))
+            .key(to)
+            .copy_source(source)
+            .set_copy_source_if_match(etag)
+            .send()
+            .await;
+        metrics::record_upstream_ttfb(&self.account_id, "copy", started.elapsed());
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                if let SdkError::ServiceError(context) = &e {
+                    match context.err().code() {
+                        Some("NoSuchKey") => return Err(AppError::ObjectNotFound(bucket.to_string(), from.to_string())),
+                        Some("PreconditionFailed") => {
+                            return Err(AppError::PreconditionFailed(format!("{}/{} changed during the copy", bucket, from)))
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Rewrites an object's metadata in place with a server-side copy, up to 5 GiB
+    #[instrument(skip(self, metadata), fields(bucket = %bucket, key = %key))]
+    pub async fn replace_metadata(
+        &self,
+        bucket: &str,
+        key: &str,
+        metadata: HashMap<String, String>,
+        content_type: Option<String>,
+    ) -> Result<()> {
+        let source = format!("{}/{}", self.upstream_bucket(bucket), uri_encode(key, false));
+        let started = Instant::now();
+        self.client
+            .copy_object()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .copy_source(source)
+            .metadata_directive(MetadataDirective::Replace)
+            .set_metadata(Some(metadata))
+            .set_content_type(content_type)
+            .send()
+            .await?;
+        metrics::record_upstream_ttfb(&self.account_id, "copy", started.elapsed());
+        Ok(())
+    }
+
+    /// Copies an object too large for CopyObject part by part; unlike CopyObject, the new upload
+    /// starts without the source's headers and metadata, so they are carried over from `head`
+    async fn copy_multipart(&self, bucket: &str, source: &str, to: &str, head: HeadObjectOutput, size: u64) -> Result<()> {
+        info!("Copying {} bytes from {} in {} byte parts", size, source, COPY_PART_SIZE);
+
+        let etag = head.e_tag;
+        let upload_id = self
+            .client
+            .create_multipart_upload()
+            .bucket(self.upstream_bucket(bucket))
+            .key(to)
+            .set_content_type(head.content_type)
+            .set_content_encoding(head.content_encoding)
+            .set_content_disposition(head.content_disposition)
+            .set_content_language(head.content_language)
+            .set_cache_control(head.cache_control)
+            .set_storage_class(head.storage_class)
+            .set_metadata(head.metadata)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?
+            .upload_id
+            .ok_or_else(|| AppError::InternalError("CreateMultipartUpload returned no upload ID".to_string()))?;
+        let mut parts = Vec::new();
+        let mut result = Ok(());
+        for (index, start) in (0..size).step_by(COPY_PART_SIZE as usize).enumerate() {
+            let end = (start + COPY_PART_SIZE).min(size) - 1;
+            let part_number = index as i32 + 1;
+            match self
+                .client
+                .upload_part_copy()
+                .bucket(self.upstream_bucket(bucket))
+                .key(to)
+                .upload_id(&upload_id)
+                .part_number(part_number)
+                .copy_source(source)
+                .copy_source_range(format!("bytes={}-{}", start, end))
+                .set_copy_source_if_match(etag.clone())
+                .send()
+                .await
+            {
+                Ok(response) => parts.push(
+                    CompletedPart::builder()
+                        .part_number(part_number)
+                        .set_e_tag(response.copy_part_result.and_then(|part| part.e_tag))
+                        .build(),
+                ),
+                Err(e) => {
+                    result = Err(aws_sdk_s3::Error::from(e).into());
+                    break;
+                }
+            }
+        }
+
+        if result.is_ok() {
+            result = self.complete_multipart_upload(bucket, to, &upload_id, parts).await.map(|_| ());
+        }
+        if result.is_err() {
+            self.abort_multipart_upload(bucket, to, &upload_id).await;
+        }
+        result
+    }
+
+    pub async fn create_multipart_upload(&self, bucket: &str, key: &str, content_type: Option<String>) -> Result<String> {
+        let upload = self
+            .client
+            .create_multipart_upload()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .set_content_type(content_type)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        upload
+            .upload_id
+            .ok_or_else(|| AppError::InternalError("CreateMultipartUpload returned no upload ID".to_string()))
+    }
+
+    #[instrument(skip(self, body), fields(bucket = %bucket, key = %key))]
+    pub async fn upload_part(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        body: Bytes,
+    ) -> Result<CompletedPart> {
+        let started = Instant::now();
+        let response = self
+            .client
+            .upload_part()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(part_number)
+            .body(ByteStream::from(body))
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        metrics::record_upstream_ttfb(&self.account_id, "put", started.elapsed());
+        Ok(CompletedPart::builder()
+            .part_number(part_number)
+            .set_e_tag(response.e_tag)
+            .build())
+    }
+
+    /// Returns the ETag of the assembled object
+    pub async fn complete_multipart_upload(
+        &self,
+        bucket: &str,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> Result<Option<String>> {
+        let response = self
+            .client
+            .complete_multipart_upload()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .upload_id(upload_id)
+            .multipart_upload(CompletedMultipartUpload::builder().set_parts(Some(parts)).build())
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
+        Ok(response.e_tag)
+    }
+
+    /// Best effort, failures are only logged since the upload is being abandoned anyway
+    pub async fn abort_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str) {
+        if let Err(e) = self
+            .client
+            .abort_multipart_upload()
+            .bucket(self.upstream_bucket(bucket))
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await
+        {
+            warn!("Failed to abort multipart upload to {}/{}: {}", bucket, key, e);
+        }
+    }
+
+    /// Succeeds if the bucket exists and is reachable; its round trip measures the endpoint
+    #[instrument(skip(self), fields(bucket = %bucket))]
+    pub async fn head_bucket(&self, bucket: &str) -> Result<()> {
+        self.client
+            .head_bucket()
+            .bucket(self.upstream_bucket(bucket))
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)?;
         Ok(())
     }
-} 
\ No newline at end of file
+
+    #[instrument(skip(self))]
+    /// Bucket names as clients see them, aliased buckets under their aliases
+    pub async fn list_buckets(&self) -> Result<Vec<String>> {
+        let response = self.client.list_buckets().send().await?;
+        Ok(response
+            .buckets()
+            .iter()
+            .filter_map(|b| b.name())
+            .flat_map(|name| {
+                let aliases: Vec<String> = self
+                    .aliases
+                    .iter()
+                    .filter(|(_, upstream)| upstream.as_str() == name)
+                    .map(|(alias, _)| alias.clone())
+                    .collect();
+                if aliases.is_empty() { vec![name.to_string()] } else { aliases }
+            })
+            .collect())
+    }
+
+    #[instrument(skip(self), fields(bucket = %bucket))]
+    pub async fn create_bucket(&self, bucket: &str) -> Result<()> {
+        info!("Creating bucket {}", bucket);
+
+        let mut request = self.client.create_bucket().bucket(self.upstream_bucket(bucket));
+
+        // us-east-1 is the only region that rejects an explicit location constraint
+        if self.region != "us-east-1" {
+            request = request.create_bucket_configuration(
+                CreateBucketConfiguration::builder()
+                    .location_constraint(BucketLocationConstraint::from(self.region.as_str()))
+                    .build(),
+            );
+        }
+
+        match request.send().await {
+            Ok(_) => {
+                info!("Successfully created bucket {}", bucket);
+                Ok(())
+            }
+            Err(e) => {
+                if let SdkError::ServiceError(context) = &e {
+                    let err = context.err();
+                    if err.is_bucket_already_exists() || err.is_bucket_already_owned_by_you() {
+                        return Err(AppError::Conflict(format!("Bucket already exists: {}", bucket)));
+                    }
+                }
+                Err(e.into())
+            }
+        }
+    }
+
+    #[instrument(skip(self), fields(bucket = %bucket))]
+    pub async fn delete_bucket(&self, bucket: &str) -> Result<()> {
+        info!("Deleting bucket {}", bucket);
+
+        match self.client.delete_bucket().bucket(self.upstream_bucket(bucket)).send().await {
+            Ok(_) => {
+                info!("Successfully deleted bucket {}", bucket);
+                Ok(())
+            }
+            Err(e) => {
+                if let SdkError::ServiceError(context) = &e {
+                    match context.err().code() {
+                        Some("NoSuchBucket") => return Err(AppError::BucketNotFound(bucket.to_string())),
+                        Some("BucketNotEmpty") => {
+                            return Err(AppError::Conflict(format!("Bucket not empty: {}", bucket)))
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use axum::{
+    extract::{Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::{Level, Metadata};
+
+use crate::config::{wildcard_match, Config, UserRole};
+use crate::sigv4;
+
+/// How much of a request's tracing output is kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// Warnings and errors only
+    Dropped,
+    /// Everything the log filter allows
+    Sampled,
+    /// Everything down to debug, requested by an admin
+    Debug,
+}
+
+tokio::task_local! {
+    /// Decision for the client request being handled on this task
+    static SAMPLING: Sampling;
+}
+
+/// The current request's decision; work outside any request, such as background tasks, is always kept
+fn current() -> Sampling {
+    SAMPLING.try_with(|sampling| *sampling).unwrap_or(Sampling::Sampled)
+}
+
+/// Per-layer filter keeping spans and events of sampled requests, for use with the log filter
+pub fn sampled(metadata: &Metadata<'_>) -> bool {
+    *metadata.level() <= Level::WARN || current() != Sampling::Dropped
+}
+
+/// Per-layer filter letting debug output of elevated requests past the log filter
+pub fn elevated(metadata: &Metadata<'_>) -> bool {
+    *metadata.level() <= Level::DEBUG && current() == Sampling::Debug
+}
+
+/// Name of the config user a request claims to come from; not verified, so only good for sampling
+fn caller(config: &Config, headers: &HeaderMap) -> Option<String> {
+    if let Some(api_key) = headers.get("x-api-key").and_then(|v| v.to_str().ok()) {
+        return config.find_user_by_api_key(api_key).map(|(username, _)| username);
+    }
+    let access_key_id = sigv4::access_key_id(headers)?;
+    config.find_user_by_access_key_id(access_key_id).map(|(username, _)| username)
+}
+
+fn decide(config: &Config, request: &Request) -> Sampling {
+    let Some(sampling) = &config.trace_sampling else {
+        return Sampling::Sampled;
+    };
+    let headers = request.headers();
+    // The header only counts with an admin's API key, which authenticates the caller outright
+    let debug_requested = headers.contains_key(sampling.debug_header.as_str());
+    let admin_key = headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|api_key| config.find_user_by_api_key(api_key))
+        .is_some_and(|(_, user)| user.role == UserRole::Admin);
+    if debug_requested && admin_key {
+        return Sampling::Debug;
+    }
+
+    let bucket = request.uri().path().trim_start_matches('/').split('/').next().unwrap_or_default();
+    let user = caller(config, headers);
+    let rate = sampling
+        .rules
+        .iter()
+        .find(|rule| {
+            rule.bucket.as_ref().is_none_or(|pattern| wildcard_match(pattern, bucket))
+                && rule.user.as_ref().is_none_or(|name| Some(name) == user.as_ref())
+        })
+        .map_or(sampling.rate, |rule| rule.rate);
+    // The low 64 bits of a random UUID are uniformly distributed
+    let draw = uuid::Uuid::new_v4().as_u128() as u64 as f64 / u64::MAX as f64;
+    if draw < rate {
+        Sampling::Sampled
+    } else {
+        Sampling::Dropped
+    }
+}
+
+/// Decides whether a request is traced before any of its spans are created
+pub async fn sample(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let sampling = decide(&config, &request);
+    SAMPLING.scope(sampling, next.run(request)).await
+}
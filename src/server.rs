@@ -1,104 +1,984 @@
 use axum::{
-    body::Bytes,
+    body::{Body, Bytes},
     extract::{Path, Query, State, Extension},
     http::{HeaderMap, StatusCode},
-    response::IntoResponse,
-    routing::{get, put},
-    Router,
+    response::{IntoResponse, Response},
+    routing::{any, delete, get, patch, post, put, MethodRouter},
+    Json, Router,
 };
+use chrono::Utc;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use tower_http::catch_panic::CatchPanicLayer;
 use tower_http::trace::TraceLayer;
 use aws_sdk_s3::primitives::ByteStream;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
+use s3_proxy_client::types::{AuthzCheckRequest, AuthzCheckResponse, CachePinRequest, LegalHoldRequest};
 
-use crate::config::Config;
-use crate::s3::S3Client;
-use crate::error::{AppError, Result};
-use crate::auth::{AuthState, auth_middleware, check_bucket_access, check_write_permission};
+use crate::config::{CachePin, Config, DirectoryConfig, DirectoryMode, UserConfig};
+use crate::s3::{ObjectPart, S3Client};
+use crate::error::{self, AppError, Result};
+use crate::etags;
+use crate::anomalies;
+use crate::firewall;
+use crate::bandwidth;
+use crate::auth::{self, AuthState, Signer, auth_middleware, check_bucket_access, check_operation, check_write_permission};
+use crate::buckets::BucketRegistry;
+use crate::aggregate;
+use crate::backends;
+use crate::cache::{self, ByteRange, ObjectCache};
+use crate::compliance::{self, ComplianceAction};
+use crate::config::UserRole;
+use crate::config_store::ConfigStore;
+use crate::kubernetes;
+use crate::tenants;
+use crate::consistency;
+use crate::content;
+use crate::chunking;
+use crate::dedup;
+use crate::packing;
+use crate::parts;
+use crate::package_index;
+use crate::holds::LegalHolds;
+use crate::ingest::{self, Ingestor};
+use crate::keys::{DelegatedKeys, KeyRequest};
+use crate::audit::{self, AuditLog, AuditQuery};
+use crate::audit_chain;
+use crate::leader::Leader;
+use crate::cold::{self, ColdQuery};
+use crate::costs;
+use crate::invalidation::InvalidationBus;
+use crate::listing;
+use crate::manifests;
+use crate::media;
+use crate::memory;
+use crate::redaction;
+use crate::metrics;
+use crate::openapi;
+use crate::trace_context;
+use crate::trash;
+use crate::preview;
+use crate::publishing;
+use crate::referrers;
+use crate::resumption;
+use crate::registry;
+use crate::rewrite;
+use crate::routing;
+use crate::session;
+use crate::stats::StatsCache;
+use crate::terraform;
+use crate::timeouts;
+use crate::union;
+use crate::uploads::{self, UploadSessions};
+
+const UPLOAD_OFFSET: &str = "upload-offset";
+const UPLOAD_CHECKSUM: &str = "upload-checksum";
+const UPLOAD_LENGTH: &str = "upload-length";
+const DEFAULT_PREVIEW_ROWS: usize = 100;
 
 pub struct AppState {
     pub config: Arc<Config>,
-    pub clients: HashMap<String, Arc<S3Client>>,
+    /// Replaced when the accounts are reloaded; requests keep the client they started with
+    pub clients: RwLock<HashMap<String, Arc<S3Client>>>,
+    pub buckets: BucketRegistry,
+    pub cache: Option<ObjectCache>,
+    pub invalidation: Option<InvalidationBus>,
+    pub uploads: UploadSessions,
+    pub bucket_stats: StatsCache,
+    pub holds: LegalHolds,
+    pub ingest: Ingestor,
+    pub leader: Option<Leader>,
+    pub keys: Option<DelegatedKeys>,
+    pub config_store: Option<Arc<ConfigStore>>,
+    pub audit: Option<AuditLog>,
+    pub session_signer: Option<Signer>,
+    pub part_signer: Option<Signer>,
 }
 
 impl AppState {
-    fn get_account_and_client(&self, bucket: &str) -> Result<(&str, &Arc<S3Client>)> {
-        let (account_id, _account_config) = self.config
+    pub fn find_account_for_bucket(&self, bucket: &str) -> Option<String> {
+        if let Some(account_id) = routing::account_for(&self.config, bucket) {
+            return Some(account_id);
+        }
+        match self.buckets.lookup(bucket) {
+            // Runtime routes are keyed by bucket name, so another tenant's route is not this one's
+            Some(account_id) if account_id.as_deref().is_none_or(|id| tenants::owns(&self.config, id)) => account_id,
+            _ => self.config.find_account_for_bucket(bucket),
+        }
+    }
+
+    /// Whether fleet-wide background tasks run here; always without leader election
+    pub fn is_leader(&self) -> bool {
+        self.leader.as_ref().is_none_or(|leader| leader.is_leader())
+    }
+
+    pub fn get_account_and_client(&self, bucket: &str) -> Result<(String, Arc<S3Client>)> {
+        let account_id = self
             .find_account_for_bucket(bucket)
             .ok_or_else(|| AppError::BucketNotFound(bucket.to_string()))?;
 
-        let client = self.clients
-            .get(account_id)
-            .ok_or_else(|| AppError::InternalError("S3 client not found".to_string()))?;
+        let client = self.get_client(&account_id)?;
 
         Ok((account_id, client))
     }
+
+    /// Drops a written object, or a whole bucket, from this replica's cache and all others
+    pub fn invalidate_cache(&self, bucket: &str, key: Option<&str>, etag: Option<&str>) {
+        if let Some(cache) = &self.cache {
+            match key {
+                Some(key) => cache.invalidate(bucket, key),
+                None => cache.invalidate_bucket(bucket),
+            }
+        }
+        if let Some(bus) = &self.invalidation {
+            bus.publish(bucket, key, etag);
+        }
+    }
+
+    /// Reads an object, or the given Range of it, whichever layout it is stored in
+    pub async fn read_object(&self, client: &S3Client, bucket: &str, key: &str, range: Option<&str>) -> Result<ObjectPart> {
+        let parsed = range.and_then(ByteRange::parse);
+        if let Some(part) = consistency::read(&self.config, client.account_id(), bucket, key, parsed)? {
+            return Ok(part);
+        }
+        if let Some(part) = packing::read(client, &self.config, bucket, key, parsed).await? {
+            return Ok(part);
+        }
+        if let Some(part) = chunking::read(client, &self.config, bucket, key, parsed).await? {
+            return Ok(part);
+        }
+        let key = dedup::resolve(client, &self.config, bucket, key).await?;
+        match &self.cache {
+            Some(cache) => cache.read(client, bucket, &key, parsed).await,
+            None => client.get_object_range(bucket, &key, range.map(String::from), None).await,
+        }
+    }
+
+    fn get_client(&self, account_id: &str) -> Result<Arc<S3Client>> {
+        self.clients
+            .read()
+            .unwrap()
+            .get(account_id)
+            .cloned()
+            .ok_or_else(|| AppError::InternalError("S3 client not found".to_string()))
+    }
 }
 
-pub async fn create_router(state: AppState) -> Router {
-    Router::new()
+pub async fn create_router(state: Arc<AppState>) -> Router {
+    let mut router = Router::new();
+    // Registered only when enabled so buckets named v2, ingest or session keep working otherwise
+    if state.config.registry.is_some() {
+        router = router
+            .route("/v2/", get(registry::version_check))
+            .route("/v2/*path", any(registry::dispatch));
+    }
+    if state.config.ingest.is_some() {
+        router = router.route("/ingest/:bucket/:stream", post(ingest_events));
+    }
+    if state.config.sessions.is_some() {
+        router = router.route("/session", post(create_session));
+    }
+    if state.config.delegated_keys.is_some() {
+        router = router
+            .route("/keys", get(list_keys).post(issue_key))
+            .route("/keys/:id", delete(revoke_key));
+    }
+    if state.config_store.is_some() {
+        router = router
+            .route("/admin/users", get(list_stored_users))
+            .route("/admin/users/:name", put(put_stored_user).delete(delete_stored_user))
+            .route("/admin/buckets/:bucket", put(put_bucket_route).delete(delete_bucket_route));
+    }
+    let router = router
+        .route("/metrics", get(prometheus_metrics))
+        .route("/openapi.json", get(openapi_document))
+        .route("/authz/check", post(authz_check))
+        .route("/aggregate", get(aggregate_listing))
+        .route("/uploads/:id/status", get(upload_progress))
+        .route("/admin/cache/pins", get(list_cache_pins).put(add_cache_pin).delete(remove_cache_pin))
+        .route("/admin/costs", get(cost_report))
+        .route("/admin/cold", get(cold_report))
+        .route("/admin/audit", get(audit_log))
+        .route("/admin/audit/verify", get(verify_audit_chain))
+        .route("/admin/backends", get(backend_report))
+        .route("/admin/rewrites/check", post(rewrite_check))
+        .route("/admin/holds", get(list_legal_holds).put(place_legal_hold).delete(release_legal_hold))
+        .route("/admin/compliance/export", post(compliance_export))
+        .route("/admin/compliance/erase", post(compliance_erase))
         .route("/:bucket/*key", get(get_object))
         .route("/:bucket/*key", put(put_object))
+        .route("/:bucket/*key", delete(delete_object))
+        .route("/:bucket/*key", post(post_object))
+        .route("/:bucket/*key", patch(patch_object))
+        .route("/:bucket/*key", MethodRouter::new().fallback(lock_object))
+        .route("/:bucket/", get(get_bucket_root))
         .route("/:bucket", get(list_objects))
-        .layer(TraceLayer::new_for_http())
+        .route("/:bucket", put(create_bucket))
+        .route("/:bucket", delete(delete_bucket))
+        .route("/:bucket", post(post_bucket))
+        // Innermost so download sizes are read before bandwidth throttling turns bodies into streams
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            anomalies::detect,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            bandwidth::throttle,
+        ))
+        // Outside throttling so request bodies are watched as the client sends them
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            timeouts::enforce,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            costs::track,
+        ))
+        // Inside the trace so the 500 carries the request's trace id
+        .layer(CatchPanicLayer::custom(error::recover_panic))
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            trace_context::propagate,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            memory::guard,
+        ))
+        // Inside auth, which it takes the user from; requests shed for memory are still recorded
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            audit::record,
+        ))
         .layer(axum::middleware::from_fn_with_state(
             state.config.clone(),
+            error::reveal_details,
+        ))
+        .layer(TraceLayer::new_for_http())
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
             auth_middleware,
         ))
-        .with_state(Arc::new(state))
+        // Before auth, so blocked clients cost no credential checks and never count as failed logins
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            firewall::check,
+        ))
+        .layer(axum::middleware::from_fn(metrics::track_requests))
+        // Outermost, so every account lookup of the request sees the client's rules
+        .layer(axum::middleware::from_fn_with_state(
+            state.config.clone(),
+            routing::route,
+        ))
+        .with_state(state.clone());
+    if state.config.tenants.is_empty() {
+        return router;
+    }
+    // Around the whole router, so requests are routed by the path without the /t/<tenant> prefix
+    Router::new().fallback_service(router).layer(axum::middleware::from_fn_with_state(
+        state.config.clone(),
+        tenants::resolve,
+    ))
+}
+
+/// Normalizes a key from the request path and applies the bucket's rewrite rules
+pub fn request_key(config: &Config, bucket: &str, key: &str, write: bool) -> String {
+    rewrite::apply(config, bucket, &normalize_key(&config.directories, key), write)
+}
+
+/// Applies the configured slash handling to a key taken from the request path
+fn normalize_key(config: &DirectoryConfig, key: &str) -> String {
+    if !config.collapse_slashes {
+        return key.to_string();
+    }
+    let mut normalized = String::with_capacity(key.len());
+    for c in key.trim_start_matches('/').chars() {
+        if !(c == '/' && normalized.ends_with('/')) {
+            normalized.push(c);
+        }
+    }
+    normalized
 }
 
+#[utoipa::path(
+    get,
+    path = "/{bucket}/{key}",
+    tag = "objects",
+    params(
+        ("bucket" = String, Path),
+        ("key" = String, Path, description = "Object key, which may contain slashes"),
+        ("parts" = Option<u64>, Query, description = "Return a manifest of up to this many signed byte ranges instead of the object"),
+    ),
+    responses(
+        (status = 200, description = "The object, or its parts manifest with ?parts", content(
+            (Vec<u8> = "application/octet-stream"),
+            (parts::PartsManifest = "application/json"),
+        )),
+        (status = 206, description = "The requested Range of the object", body = Vec<u8>, content_type = "application/octet-stream"),
+        (status = 404, description = "No such object"),
+    )
+)]
 #[axum::debug_handler]
-#[instrument(skip(state), fields(bucket = %bucket, key = %key))]
+#[instrument(skip(state, headers), fields(bucket = %bucket, key = %key))]
 async fn get_object(
     State(state): State<Arc<AppState>>,
     Extension(auth): Extension<AuthState>,
     Path((bucket, key)): Path<(String, String)>,
-) -> Result<impl IntoResponse> {
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    if let Some(upload_id) = params.get("upload_id") {
+        return upload_status(&state, &auth, &bucket, &key, upload_id).await;
+    }
     info!("Getting object {}/{}", bucket, key);
-    
+    get_path(&state, &auth, &bucket, &key, &params, &headers).await
+}
+
+/// GET /{bucket}/ is the root directory of the bucket
+#[axum::debug_handler]
+#[instrument(skip(state, headers), fields(bucket = %bucket))]
+async fn get_bucket_root(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    info!("Getting root directory of bucket {}", bucket);
+    get_path(&state, &auth, &bucket, "", &params, &headers).await
+}
+
+async fn get_path(
+    state: &Arc<AppState>,
+    auth: &AuthState,
+    bucket: &str,
+    requested_key: &str,
+    params: &HashMap<String, String>,
+    request_headers: &HeaderMap,
+) -> Result<Response> {
     // Check bucket access
-    check_bucket_access(&state.config, &auth.username, &bucket)?;
-    
-    let (_, client) = state.get_account_and_client(&bucket)?;
-    let body = client.get_object(&bucket, &key).await?;
-    let bytes = body.collect().await.map_err(|e| AppError::InternalError(e.to_string()))?.to_vec();
+    check_bucket_access(auth, bucket)?;
+    referrers::check(&state.config, bucket, request_headers)?;
+
+    let directories = &state.config.directories;
+    let mut key = request_key(&state.config, bucket, requested_key, false);
+    if let Some(index) = state.config.package_indexes.get(bucket) {
+        if let Some(rest) = key.strip_prefix(&index.path) {
+            return package_index::serve(state, bucket, index, rest).await;
+        }
+    }
+    let redactions = redaction::rules(&state.config, bucket, &key, auth.role);
+    if params.contains_key("preview") {
+        // Previews read the original object
+        if !redactions.is_empty() {
+            return Err(AppError::Unauthorized(format!("Previews of {} are not available for this user", key)));
+        }
+        let format = preview::Format::detect(&key, params.get("format").map(String::as_str))?;
+        let rows = match params.get("rows") {
+            Some(rows) => rows
+                .parse()
+                .map_err(|_| AppError::InvalidRequest(format!("Invalid rows: {}", rows)))?,
+            None => DEFAULT_PREVIEW_ROWS,
+        };
+        let preview = preview::preview(state, bucket, &key, format, rows).await?;
+        metrics::record_download(bucket, preview.bytes_read as usize);
+        return Ok(Json(preview).into_response());
+    }
+    if let Some(requested) = params.get("parts") {
+        // Ranges of a redacted variant cannot be cut from the original
+        if !redactions.is_empty() {
+            return Err(AppError::Unauthorized(format!("Parallel downloads of {} are not available for this user", key)));
+        }
+        let requested = requested
+            .parse()
+            .map_err(|_| AppError::InvalidRequest(format!("Invalid parts: {}", requested)))?;
+        let manifest = parts::manifest(state, auth, bucket, requested_key, &key, requested).await?;
+        return Ok(Json(manifest).into_response());
+    }
+    // Signed part URLs carry their range and the ETag of the version they were issued for
+    let signed_headers = parts::request_headers(params, request_headers)?;
+    if signed_headers.is_some() && !redactions.is_empty() {
+        return Err(AppError::Unauthorized(format!("Parallel downloads of {} are not available for this user", key)));
+    }
+    let request_headers = signed_headers.as_ref().unwrap_or(request_headers);
+    // Part URLs read the object they were signed for, never a listing or index document
+    if signed_headers.is_none() && (key.is_empty() || key.ends_with('/')) {
+        match directories.mode {
+            DirectoryMode::Listing => {
+                let (objects, prefixes) = union::list(state, bucket, &key, true).await?;
+                let html = listing::wants_html(request_headers);
+                return Ok(listing_response(bucket, &key, Some("/"), &objects, &prefixes, html));
+            }
+            DirectoryMode::Index => key.push_str(&directories.index_document),
+            DirectoryMode::Off => {}
+        }
+    }
+
+    // Validate overrides before fetching so bad links fail fast
+    let overrides = response_overrides(params)?;
+
+    // Ranges of a redacted variant cannot be cut from the original, so the whole variant is sent
+    let range = request_headers
+        .get(http::header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .filter(|_| redactions.is_empty());
+    // Part URLs cover exactly the range in the manifest
+    let range = match signed_headers {
+        Some(_) => range.map(String::from),
+        None => media::range(&state.config, &key, range),
+    };
+    let prefetch = media::needs_prefetch(state, bucket, &key);
+    let mut part = union::read(state, bucket, &key, range.as_deref()).await?;
+    if prefetch {
+        media::spawn_prefetch(state.clone(), bucket, &key);
+    }
+    if !redactions.is_empty() {
+        redaction::apply(&redactions, &key, &mut part)?;
+    }
+    let etag = etags::served(&state.config, &part);
+    if etags::not_modified(request_headers, etag.as_deref())? {
+        let mut headers = HeaderMap::new();
+        if let Some(etag) = etag.as_deref().and_then(|etag| etag.parse().ok()) {
+            headers.insert(http::header::ETAG, etag);
+        }
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+    metrics::record_download(bucket, part.body.len());
+    // Parallel part downloads are one download split up, not a client resuming
+    if signed_headers.is_none() {
+        resumption::record(&auth.username, bucket, &key, &part);
+    }
     
     let mut headers = HeaderMap::new();
     headers.insert("content-type", "application/octet-stream".parse().unwrap());
+    let accept_ranges = if redactions.is_empty() { "bytes" } else { "none" };
+    headers.insert(http::header::ACCEPT_RANGES, accept_ranges.parse().unwrap());
+    if let Some(etag) = etag.as_deref().and_then(|etag| etag.parse().ok()) {
+        headers.insert(http::header::ETAG, etag);
+    }
+    let status = match part.content_range.as_deref().and_then(|range| range.parse().ok()) {
+        Some(content_range) => {
+            headers.insert(http::header::CONTENT_RANGE, content_range);
+            StatusCode::PARTIAL_CONTENT
+        }
+        None => StatusCode::OK,
+    };
+    headers.extend(overrides);
     
-    Ok((StatusCode::OK, headers, bytes))
+    Ok((status, headers, part.body).into_response())
+}
+
+/// Rejects keys under the prefixes where blobs, segments, chunks, published content and registry objects are kept
+fn check_reserved_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    dedup::check_key(config, bucket, key)?;
+    packing::check_key(config, bucket, key)?;
+    chunking::check_key(config, bucket, key)?;
+    publishing::check_key(config, bucket, key)?;
+    registry::check_key(config, bucket, key)?;
+    terraform::check_key(config, bucket, key)
+}
+
+/// Types a browser would execute as a page on the proxy's origin
+const ACTIVE_CONTENT_TYPES: &[&str] = &["text/html", "application/xhtml+xml", "image/svg+xml", "text/xml", "application/xml"];
+
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Validates the S3 response-content-type and response-content-disposition query parameters
+fn response_overrides(params: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+
+    if let Some(content_type) = params.get("response-content-type") {
+        let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let valid = matches!(essence.split_once('/'), Some((kind, subtype)) if is_token(kind) && is_token(subtype));
+        if !valid {
+            return Err(AppError::InvalidRequest(format!("Invalid response-content-type: {}", content_type)));
+        }
+        if ACTIVE_CONTENT_TYPES.contains(&essence.as_str()) {
+            return Err(AppError::InvalidRequest(format!("response-content-type {} is not allowed", essence)));
+        }
+        let value = content_type
+            .parse()
+            .map_err(|_| AppError::InvalidRequest(format!("Invalid response-content-type: {}", content_type)))?;
+        headers.insert(http::header::CONTENT_TYPE, value);
+    }
+
+    if let Some(disposition) = params.get("response-content-disposition") {
+        let kind = disposition.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if kind != "inline" && kind != "attachment" {
+            return Err(AppError::InvalidRequest(format!(
+                "response-content-disposition must be inline or attachment: {}",
+                disposition
+            )));
+        }
+        // Non-ASCII filenames have to use the filename*=UTF-8''... form
+        let value = disposition
+            .parse()
+            .map_err(|_| AppError::InvalidRequest(format!("Invalid response-content-disposition: {}", disposition)))?;
+        headers.insert(http::header::CONTENT_DISPOSITION, value);
+    }
+
+    Ok(headers)
 }
 
 #[axum::debug_handler]
-#[instrument(skip(state, body), fields(bucket = %bucket, key = %key))]
+#[instrument(skip(state, headers, body), fields(bucket = %bucket, key = %key))]
 async fn put_object(
     State(state): State<Arc<AppState>>,
     Extension(auth): Extension<AuthState>,
     Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
     headers: HeaderMap,
-    body: Bytes,
+    body: Body,
 ) -> Result<impl IntoResponse> {
     info!("Putting object {}/{}", bucket, key);
     
     // Check bucket access and write permission
-    check_bucket_access(&state.config, &auth.username, &bucket)?;
-    check_write_permission(&state.config, &auth.username)?;
+    check_bucket_access(&auth, &bucket)?;
+    check_write_permission(&auth)?;
+    let key = request_key(&state.config, &bucket, &key, true);
+    let bucket = union::writable(&state.config, &bucket);
     
-    let (_, client) = state.get_account_and_client(&bucket)?;
+    let (_, client) = &state.get_account_and_client(&bucket)?;
+    state.holds.check(&bucket, Some(&key))?;
 
     let content_type = headers
         .get("content-type")
         .and_then(|v| v.to_str().ok())
         .map(String::from);
+    content::check_upload(&state.config, &bucket, &key, content_type.as_deref())?;
+    let body = read_upload(&state.config, &headers, body).await?;
+    content::verify_magic_bytes(&state.config, &bucket, content_type.as_deref(), &body, 0, true)?;
+    let body = content::strip_upload(&state.config, &bucket, body)?;
+    check_reserved_key(&state.config, &bucket, &key)?;
+    terraform::check_write(client, &state.config, &bucket, &key, params.get("ID").map(String::as_str)).await?;
 
-    let body = ByteStream::from(body);
-    
-    client.put_object(&bucket, &key, body, content_type).await?;
+    let size = body.len();
+    let written = body.clone();
+    let replaced = chunking::manifest(client, &state.config, &bucket, &key).await?;
+    let etag = if packing::accepts(&state.config, &bucket, size) {
+        packing::put(client, &state.config, &bucket, &key, body, content_type).await?
+    } else {
+        let etag = match (state.config.chunking.get(&bucket), state.config.dedup.get(&bucket)) {
+            (Some(chunking), _) if chunking::accepts(&state.config, &bucket, size) => {
+                chunking::put(client, chunking, &bucket, &key, body, content_type).await?
+            }
+            (_, Some(dedup)) => dedup::put(client, dedup, &bucket, &key, body, content_type).await?,
+            _ if etags::is_stable(&state.config, &bucket) => {
+                let etag = etags::from_body(&body);
+                client.put_object_with_metadata(&bucket, &key, body, etags::metadata(&etag), content_type).await?;
+                Some(etag)
+            }
+            _ => client.put_object(&bucket, &key, ByteStream::from(body), content_type).await?,
+        };
+        // The new object would otherwise stay hidden behind a packed one
+        packing::remove(client, &state.config, &bucket, &key).await?;
+        etag
+    };
+    chunking::release(client, &bucket, replaced).await;
+    metrics::record_upload(&bucket, size);
+    consistency::written(&state.config, client.account_id(), &bucket, &key, &written, etag.as_deref());
+    state.invalidate_cache(&bucket, Some(&key), etag.as_deref());
+    Ok(StatusCode::OK)
+}
+
+/// Reads an upload into one buffer sized from its Content-Length, up to `max_file_size`. The memory
+/// guard has already charged the body as it arrives.
+async fn read_upload(config: &Config, headers: &HeaderMap, body: Body) -> Result<Bytes> {
+    let declared = headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    let mut buffer = memory::BodyBuffer::new(declared);
+    let mut chunks = body.into_data_stream();
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk.map_err(|e| AppError::InvalidRequest(format!("Failed to read request body: {}", e)))?;
+        buffer.push(chunk)?;
+        if buffer.len() as u64 > config.max_file_size {
+            return Err(AppError::InvalidRequest(format!(
+                "File size exceeds maximum allowed size of {} bytes",
+                config.max_file_size
+            )));
+        }
+    }
+    Ok(buffer.finish().0)
+}
+
+#[axum::debug_handler]
+#[instrument(skip(state), fields(bucket = %bucket, key = %key))]
+async fn delete_object(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse> {
+    check_bucket_access(&auth, &bucket)?;
+    check_write_permission(&auth)?;
+    let key = request_key(&state.config, &bucket, &key, false);
+    let bucket = union::writable(&state.config, &bucket);
+
+    if let Some(upload_id) = params.get("upload_id") {
+        let (_, client) = &state.get_account_and_client(&bucket)?;
+        state.uploads.abort(client, upload_id, &bucket, &key, &auth.username).await?;
+        return Ok(StatusCode::NO_CONTENT);
+    }
+    info!("Deleting object {}/{}", bucket, key);
+
+    let (_, client) = &state.get_account_and_client(&bucket)?;
+    state.holds.check(&bucket, Some(&key))?;
+    terraform::check_write(client, &state.config, &bucket, &key, params.get("ID").map(String::as_str)).await?;
+    // Blobs, segments and chunks may back other keys and published content is immutable, so only admins may remove them
+    if check_reserved_key(&state.config, &bucket, &key).is_err() {
+        require_admin(&auth)?;
+    }
+    // Packed objects only leave the index; the trash does not apply to them
+    if packing::remove(client, &state.config, &bucket, &key).await? {
+        consistency::deleted(&state.config, client.account_id(), &bucket, &key);
+        state.invalidate_cache(&bucket, Some(&key), None);
+        return Ok(StatusCode::NO_CONTENT);
+    }
+
+    match state.config.soft_delete.buckets.get(&bucket) {
+        // Emptying the trash is permanent, so only admins may do it
+        Some(trash) if trash::original_key(trash, &key).is_some() => {
+            require_admin(&auth)?;
+            chunking::delete(client, &state.config, &bucket, &key).await?;
+        }
+        Some(trash) => {
+            let trashed = trash::trash_key(trash, &key);
+            state.holds.check(&bucket, Some(&trashed))?;
+            client.copy_object(&bucket, &key, &trashed).await?;
+            client.delete_object(&bucket, &key).await?;
+            state.invalidate_cache(&bucket, Some(&trashed), None);
+            info!("Moved {}/{} to the trash", bucket, key);
+        }
+        None => chunking::delete(client, &state.config, &bucket, &key).await?,
+    }
+    consistency::deleted(&state.config, client.account_id(), &bucket, &key);
+    state.invalidate_cache(&bucket, Some(&key), None);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// POST /{bucket}/{key}?rename={destination} moves an object, ?undelete restores a soft-deleted one,
+/// ?uploads starts a resumable upload, ?upload_id={id} completes it and ?lock or ?unlock guard Terraform state
+#[axum::debug_handler]
+#[instrument(skip(state, headers, body), fields(bucket = %bucket, key = %key))]
+async fn post_object(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    check_bucket_access(&auth, &bucket)?;
+    let key = request_key(&state.config, &bucket, &key, false);
+    let destination = params.get("rename").map(|destination| request_key(&state.config, &bucket, destination, false));
+    let bucket = union::writable(&state.config, &bucket);
+
+    if let Some(destination) = destination {
+        return rename_object(&state, &auth, &bucket, &key, &destination).await.map(IntoResponse::into_response);
+    }
+    if params.contains_key("undelete") {
+        return undelete_object(&state, &auth, &bucket, &key).await.map(IntoResponse::into_response);
+    }
+    if params.contains_key("uploads") {
+        return create_upload(&state, &auth, &bucket, &key, &headers).await;
+    }
+    if let Some(upload_id) = params.get("upload_id") {
+        return complete_upload(&state, &auth, &bucket, &key, upload_id, &headers).await;
+    }
+    if params.contains_key("lock") || params.contains_key("unlock") {
+        return lock_state(&state, &auth, &bucket, &key, body, params.contains_key("unlock")).await;
+    }
+    Err(AppError::InvalidRequest(
+        "Unsupported POST operation, expected ?rename=, ?undelete, ?uploads, ?upload_id=, ?lock or ?unlock".to_string(),
+    ))
+}
+
+/// LOCK and UNLOCK /{bucket}/{key}, the default lock methods of Terraform's HTTP backend
+#[axum::debug_handler]
+#[instrument(skip(state, body), fields(bucket = %bucket, key = %key))]
+async fn lock_object(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    method: http::Method,
+    Path((bucket, key)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<Response> {
+    let unlock = match method.as_str() {
+        "LOCK" => false,
+        "UNLOCK" => true,
+        _ => return Ok(StatusCode::METHOD_NOT_ALLOWED.into_response()),
+    };
+    check_bucket_access(&auth, &bucket)?;
+    let key = request_key(&state.config, &bucket, &key, false);
+    let bucket = union::writable(&state.config, &bucket);
+    lock_state(&state, &auth, &bucket, &key, body, unlock).await
+}
+
+/// Takes or releases the lock of a Terraform state; a lock held by someone else is answered with its lock info
+async fn lock_state(state: &AppState, auth: &AuthState, bucket: &str, key: &str, info: Bytes, unlock: bool) -> Result<Response> {
+    check_write_permission(auth)?;
+    let terraform = state
+        .config
+        .terraform
+        .get(bucket)
+        .ok_or_else(|| AppError::InvalidRequest(format!("Terraform state locking is not enabled for bucket {}", bucket)))?;
+    let (_, client) = &state.get_account_and_client(bucket)?;
+
+    let outcome = if unlock {
+        terraform::unlock(client, terraform, bucket, key, info).await?
+    } else {
+        terraform::lock(client, terraform, bucket, key, info).await?
+    };
+    match outcome {
+        terraform::LockOutcome::Done => Ok(StatusCode::OK.into_response()),
+        terraform::LockOutcome::Held(holder) => {
+            let status = if unlock { StatusCode::CONFLICT } else { StatusCode::LOCKED };
+            Ok((status, [(http::header::CONTENT_TYPE, "application/json")], holder).into_response())
+        }
+    }
+}
+
+fn upload_checksum(headers: &HeaderMap) -> Option<&str> {
+    headers.get(UPLOAD_CHECKSUM).and_then(|v| v.to_str().ok())
+}
+
+fn upload_response(status: StatusCode, upload: &uploads::UploadStatus) -> Response {
+    let mut response = (status, Json(upload)).into_response();
+    response.headers_mut().insert(UPLOAD_OFFSET, upload.offset.into());
+    response
+}
+
+async fn create_upload(state: &AppState, auth: &AuthState, bucket: &str, key: &str, headers: &HeaderMap) -> Result<Response> {
+    check_write_permission(auth)?;
+    if key.is_empty() || key.ends_with('/') {
+        return Err(AppError::InvalidRequest(format!("Invalid upload key: {}", key)));
+    }
+    state.holds.check(bucket, Some(key))?;
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    let content_type = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    content::check_upload(&state.config, bucket, key, content_type.as_deref())?;
+    content::check_resumable(&state.config, bucket)?;
+    check_reserved_key(&state.config, bucket, key)?;
+    // Optional, lets progress reports estimate the time left
+    let length = headers
+        .get(UPLOAD_LENGTH)
+        .map(|v| {
+            v.to_str()
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .ok_or_else(|| AppError::InvalidRequest("Invalid Upload-Length header".to_string()))
+        })
+        .transpose()?;
+    if let Some(length) = length.filter(|length| *length > state.config.max_file_size) {
+        return Err(AppError::InvalidRequest(format!(
+            "Upload-Length {} exceeds the maximum size of {} bytes",
+            length, state.config.max_file_size
+        )));
+    }
+
+    let upload = state.uploads.create(client, bucket, key, &auth.username, content_type, length).await?;
+    Ok(upload_response(StatusCode::CREATED, &upload))
+}
+
+async fn upload_status(state: &AppState, auth: &AuthState, bucket: &str, key: &str, upload_id: &str) -> Result<Response> {
+    check_bucket_access(auth, bucket)?;
+    let key = request_key(&state.config, bucket, key, false);
+    let bucket = union::writable(&state.config, bucket);
+    let upload = state.uploads.status(upload_id, &bucket, &key, &auth.username).await?;
+    Ok(upload_response(StatusCode::OK, &upload))
+}
+
+/// PATCH /{bucket}/{key}?upload_id={id} appends a chunk at the Upload-Offset header
+#[axum::debug_handler]
+#[instrument(skip(state, headers, body), fields(bucket = %bucket, key = %key))]
+async fn patch_object(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path((bucket, key)): Path<(String, String)>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    check_bucket_access(&auth, &bucket)?;
+    check_write_permission(&auth)?;
+    let key = request_key(&state.config, &bucket, &key, false);
+    let bucket = union::writable(&state.config, &bucket);
+
+    let upload_id = params
+        .get("upload_id")
+        .ok_or_else(|| AppError::InvalidRequest("PATCH requires ?upload_id=".to_string()))?;
+    let offset = headers
+        .get(UPLOAD_OFFSET)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| AppError::InvalidRequest("Missing or invalid Upload-Offset header".to_string()))?;
+    let (_, client) = &state.get_account_and_client(&bucket)?;
+    state.holds.check(&bucket, Some(&key))?;
+    // Signatures sit at the start of the object, so only the first chunks can contradict them
+    if offset < content::SIGNATURE_LENGTH {
+        let upload = state.uploads.status(upload_id, &bucket, &key, &auth.username).await?;
+        content::verify_magic_bytes(&state.config, &bucket, upload.content_type.as_deref(), &body, offset as usize, false)?;
+    }
+
+    let size = body.len();
+    let offset = state
+        .uploads
+        .append(
+            client,
+            upload_id,
+            &bucket,
+            &key,
+            &auth.username,
+            offset,
+            upload_checksum(&headers),
+            body,
+            state.config.max_file_size,
+        )
+        .await?;
+    metrics::record_upload(&bucket, size);
+
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    response.headers_mut().insert(UPLOAD_OFFSET, offset.into());
+    Ok(response)
+}
+
+async fn complete_upload(
+    state: &AppState,
+    auth: &AuthState,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+    headers: &HeaderMap,
+) -> Result<Response> {
+    check_write_permission(auth)?;
+    state.holds.check(bucket, Some(key))?;
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    let replaced = chunking::manifest(client, &state.config, bucket, key).await?;
+
+    let completed = state
+        .uploads
+        .complete(client, upload_id, bucket, key, &auth.username, upload_checksum(headers))
+        .await?;
+    let mut etag = completed.etag;
+    // Multipart ETags depend on the part size, so the stable one is added once the content is known
+    if etags::is_stable(&state.config, bucket) {
+        let stable = etags::from_digest(&completed.sha256);
+        match client.replace_metadata(bucket, key, etags::metadata(&stable), completed.content_type).await {
+            Ok(()) => etag = Some(stable),
+            Err(e) => warn!("Storing the stable ETag of {}/{} failed, serving the upstream one: {}", bucket, key, e),
+        }
+    }
+    packing::remove(client, &state.config, bucket, key).await?;
+    chunking::release(client, bucket, replaced).await;
+    consistency::forget(client.account_id(), bucket, key);
+    state.invalidate_cache(bucket, Some(key), etag.as_deref());
+
+    let mut response = StatusCode::OK.into_response();
+    if let Some(value) = etag.and_then(|etag| etag.parse().ok()) {
+        response.headers_mut().insert(http::header::ETAG, value);
+    }
+    Ok(response)
+}
+
+/// Copy then delete; if the source cannot be deleted the copy is removed again
+async fn rename_object(state: &AppState, auth: &AuthState, bucket: &str, key: &str, destination: &str) -> Result<StatusCode> {
+    info!("Renaming {}/{} to {}", bucket, key, destination);
+    check_write_permission(auth)?;
+
+    if destination.is_empty() || destination.ends_with('/') {
+        return Err(AppError::InvalidRequest(format!("Invalid rename destination: {}", destination)));
+    }
+    if destination == key {
+        return Err(AppError::InvalidRequest("Rename destination is the source key".to_string()));
+    }
+    state.holds.check(bucket, Some(key))?;
+    state.holds.check(bucket, Some(destination))?;
+    content::check_key(&state.config, bucket, destination)?;
+    // Moving reserved objects away would break what they back, or change published content
+    check_reserved_key(&state.config, bucket, key)?;
+    check_reserved_key(&state.config, bucket, destination)?;
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    // Renames never replace an object, so a failed rename can only remove what it created
+    if object_exists(state, client, bucket, destination).await? {
+        return Err(AppError::Conflict(format!("{}/{} already exists", bucket, destination)));
+    }
+
+    if packing::rename(client, &state.config, bucket, key, destination).await? {
+        consistency::renamed(&state.config, client.account_id(), bucket, key, destination);
+        state.invalidate_cache(bucket, Some(destination), None);
+        state.invalidate_cache(bucket, Some(key), None);
+        return Ok(StatusCode::OK);
+    }
+    client.copy_object(bucket, key, destination).await?;
+    packing::remove(client, &state.config, bucket, destination).await?;
+    state.invalidate_cache(bucket, Some(destination), None);
+
+    if let Err(e) = client.delete_object(bucket, key).await {
+        warn!("Deleting {}/{} after copying it failed, removing the copy: {}", bucket, key, e);
+        if let Err(rollback) = client.delete_object(bucket, destination).await {
+            warn!("Rollback failed, {}/{} and {} both exist: {}", bucket, key, destination, rollback);
+        }
+        return Err(e);
+    }
+    consistency::renamed(&state.config, client.account_id(), bucket, key, destination);
+    state.invalidate_cache(bucket, Some(key), None);
+    Ok(StatusCode::OK)
+}
+
+/// Whether `key` exists in any storage layout, including recent writes not listed yet
+async fn object_exists(state: &AppState, client: &S3Client, bucket: &str, key: &str) -> Result<bool> {
+    match state.read_object(client, bucket, key, Some("bytes=0-0")).await {
+        // Empty objects have no first byte
+        Ok(_) | Err(AppError::RangeNotSatisfiable(_)) => Ok(true),
+        Err(AppError::ObjectNotFound(_, _)) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+async fn undelete_object(state: &AppState, auth: &AuthState, bucket: &str, key: &str) -> Result<StatusCode> {
+    info!("Restoring {}/{} from the trash", bucket, key);
+    require_admin(auth)?;
+
+    let trash = state
+        .config
+        .soft_delete
+        .buckets
+        .get(bucket)
+        .ok_or_else(|| AppError::InvalidRequest(format!("Soft delete is not enabled for bucket {}", bucket)))?;
+    let (_, client) = &state.get_account_and_client(bucket)?;
+
+    let trashed = trash::trash_key(trash, key);
+    state.holds.check(bucket, Some(key))?;
+    state.holds.check(bucket, Some(&trashed))?;
+    client.copy_object(bucket, &trashed, key).await?;
+    client.delete_object(bucket, &trashed).await?;
+    consistency::forget(client.account_id(), bucket, key);
+    state.invalidate_cache(bucket, Some(key), None);
+    state.invalidate_cache(bucket, Some(&trashed), None);
     Ok(StatusCode::OK)
 }
 
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
 fn format_xml_content(objects: &[aws_sdk_s3::types::Object]) -> String {
     objects
         .iter()
@@ -109,7 +989,7 @@ fn format_xml_content(objects: &[aws_sdk_s3::types::Object]) -> String {
             <Size>{}</Size>
             <LastModified>{}</LastModified>
         </Contents>"#,
-                obj.key().unwrap_or_default(),
+                xml_escape(obj.key().unwrap_or_default()),
                 obj.size().unwrap_or(0),
                 obj.last_modified().map(|dt| dt.to_string()).unwrap_or_default()
             )
@@ -118,37 +998,1044 @@ fn format_xml_content(objects: &[aws_sdk_s3::types::Object]) -> String {
         .join("\n")
 }
 
-#[axum::debug_handler]
-#[instrument(skip(state), fields(bucket = %bucket))]
-async fn list_objects(
-    State(state): State<Arc<AppState>>,
-    Extension(auth): Extension<AuthState>,
-    Path(bucket): Path<String>,
-    Query(params): Query<HashMap<String, String>>,
-) -> Result<impl IntoResponse> {
-    info!("Listing objects in bucket {}", bucket);
-    
-    // Check bucket access
-    check_bucket_access(&state.config, &auth.username, &bucket)?;
-    
-    let (_, client) = state.get_account_and_client(&bucket)?;
-    let prefix = params.get("prefix").cloned();
-    let objects = client.list_objects(&bucket, prefix.clone()).await?;
-    
+fn format_xml_prefixes(prefixes: &[String]) -> String {
+    prefixes
+        .iter()
+        .map(|prefix| {
+            format!(
+                r#"        <CommonPrefixes>
+            <Prefix>{}</Prefix>
+        </CommonPrefixes>"#,
+                xml_escape(prefix)
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn listing_response(
+    bucket: &str,
+    prefix: &str,
+    delimiter: Option<&str>,
+    objects: &[aws_sdk_s3::types::Object],
+    prefixes: &[String],
+    html: bool,
+) -> Response {
+    if html {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/html; charset=utf-8".parse().unwrap());
+        return (StatusCode::OK, headers, listing::render_html(bucket, prefix, objects, prefixes)).into_response();
+    }
+
+    let mut entries = format_xml_content(objects);
+    if !prefixes.is_empty() {
+        entries.push('\n');
+        entries.push_str(&format_xml_prefixes(prefixes));
+    }
+    let delimiter = delimiter
+        .map(|d| format!("    <Delimiter>{}</Delimiter>\n", xml_escape(d)))
+        .unwrap_or_default();
+
     let xml = format!(
         r#"<?xml version="1.0" encoding="UTF-8"?>
 <ListBucketResult>
     <Name>{}</Name>
     <Prefix>{}</Prefix>
-{}
+{}{}
 </ListBucketResult>"#,
-        bucket,
-        prefix.unwrap_or_default(),
-        format_xml_content(&objects)
+        xml_escape(bucket),
+        xml_escape(prefix),
+        delimiter,
+        entries
     );
     
     let mut headers = HeaderMap::new();
     headers.insert("content-type", "application/xml".parse().unwrap());
     
-    Ok((StatusCode::OK, headers, xml))
-} 
\ No newline at end of file
+    (StatusCode::OK, headers, xml).into_response()
+}
+
+#[axum::debug_handler]
+#[instrument(skip(state, headers), fields(bucket = %bucket))]
+async fn list_objects(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    info!("Listing objects in bucket {}", bucket);
+    
+    // Check bucket access
+    check_bucket_access(&auth, &bucket)?;
+
+    // Stats and manifest reports cover the whole bucket, which no key prefix does
+    if !auth.key_prefixes.is_empty() && (params.contains_key("stats") || params.contains_key("manifest")) {
+        auth.record_rule(format!("denied: outside {}.prefixes", auth.grant_source));
+        return Err(AppError::Unauthorized("Not allowed outside the key's prefixes".to_string()));
+    }
+    if let Some(manifest_id) = params.get("manifest") {
+        let bucket = union::writable(&state.config, &bucket);
+        return Ok(Json(manifests::report(&bucket, manifest_id)?).into_response());
+    }
+    if params.contains_key("stats") {
+        return Ok(match state.bucket_stats.stats(&state, &bucket) {
+            Some(stats) => Json(stats.as_ref()).into_response(),
+            // The first inventory of the bucket is still running
+            None => (StatusCode::ACCEPTED, [(http::header::RETRY_AFTER, "5")]).into_response(),
+        });
+    }
+    if params.contains_key("du") {
+        let mut prefix = params.get("prefix").cloned().unwrap_or_default();
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        return Ok(match state.bucket_stats.usage(&state, &bucket, &prefix) {
+            Some(usage) => Json(usage.as_ref()).into_response(),
+            None => (StatusCode::ACCEPTED, [(http::header::RETRY_AFTER, "5")]).into_response(),
+        });
+    }
+    
+    let prefix = params.get("prefix").cloned().unwrap_or_default();
+    let (objects, _) = union::list(&state, &bucket, &prefix, false).await?;
+
+    let html = listing::wants_html(&headers);
+    Ok(listing_response(&bucket, &prefix, None, &objects, &[], html))
+}
+
+/// GET /aggregate?buckets=a,b&prefix=p lists one prefix across several buckets as one view
+#[utoipa::path(
+    get,
+    path = "/aggregate",
+    tag = "objects",
+    params(
+        ("buckets" = String, Query, description = "Comma-separated buckets, at most 32"),
+        ("prefix" = Option<String>, Query),
+    ),
+    responses((status = 200, body = aggregate::AggregateListing))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn aggregate_listing(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse> {
+    let mut buckets: Vec<String> = Vec::new();
+    for bucket in params.get("buckets").map(String::as_str).unwrap_or_default().split(',') {
+        let bucket = bucket.trim();
+        if !bucket.is_empty() && !buckets.iter().any(|b| b == bucket) {
+            buckets.push(bucket.to_string());
+        }
+    }
+    for bucket in &buckets {
+        check_bucket_access(&auth, bucket)?;
+    }
+    let prefix = params.get("prefix").cloned().unwrap_or_default();
+    let reveal = error::details_allowed(&state.config, &auth);
+    Ok(Json(aggregate::list(&state, buckets, &prefix, reveal).await?))
+}
+
+/// POST /{bucket}?manifest verifies stored objects against a manifest of SHA-256 hashes by key,
+/// ?publish stores the body under its SHA-256
+#[axum::debug_handler]
+#[instrument(skip(state, headers, body), fields(bucket = %bucket))]
+async fn post_bucket(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response> {
+    check_bucket_access(&auth, &bucket)?;
+    if params.contains_key("publish") {
+        return publish_object(&state, &auth, &bucket, &headers, body).await;
+    }
+    if !params.contains_key("manifest") {
+        return Err(AppError::InvalidRequest("Unsupported POST operation, expected ?manifest or ?publish".to_string()));
+    }
+    state.get_account_and_client(&union::writable(&state.config, &bucket))?;
+
+    let json = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    let entries = manifests::parse(&body, json)?
+        .into_iter()
+        .map(|(key, hash)| (request_key(&state.config, &bucket, &key, false), hash))
+        .collect();
+    let bucket = union::writable(&state.config, &bucket);
+    let report = manifests::start(state.clone(), &bucket, &auth.username, entries);
+    Ok((StatusCode::ACCEPTED, Json(report)).into_response())
+}
+
+async fn publish_object(state: &AppState, auth: &AuthState, requested: &str, headers: &HeaderMap, body: Bytes) -> Result<Response> {
+    check_write_permission(auth)?;
+    let bucket = union::writable(&state.config, requested);
+    let publishing = state
+        .config
+        .publishing
+        .get(&bucket)
+        .ok_or_else(|| AppError::InvalidRequest(format!("Publishing is not enabled for bucket {}", bucket)))?;
+    let (_, client) = &state.get_account_and_client(&bucket)?;
+
+    let content_type = headers
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+    content::verify_magic_bytes(&state.config, &bucket, content_type.as_deref(), &body, 0, true)?;
+    // Addressed by the content actually stored
+    let body = content::strip_upload(&state.config, &bucket, body)?;
+    let (sha256, key) = publishing::address(publishing, &body);
+    content::check_upload(&state.config, &bucket, &key, content_type.as_deref())?;
+
+    let size = body.len();
+    let created = publishing::publish(client, &state.config, &bucket, &key, body, content_type).await?;
+    if created {
+        metrics::record_upload(&bucket, size);
+        state.invalidate_cache(&bucket, Some(&key), None);
+    }
+
+    let url = publishing::url(publishing, requested, &key);
+    let published = publishing::Published { bucket: requested.to_string(), key, sha256, size: size as u64, url, created };
+    let status = if created { StatusCode::CREATED } else { StatusCode::OK };
+    let mut response = (status, Json(&published)).into_response();
+    if let Ok(location) = published.url.parse() {
+        response.headers_mut().insert(http::header::LOCATION, location);
+    }
+    Ok(response)
+}
+
+/// POST /ingest/{bucket}/{stream} buffers newline-delimited events that are written in compressed batches
+#[utoipa::path(
+    post,
+    path = "/ingest/{bucket}/{stream}",
+    tag = "ingest",
+    params(("bucket" = String, Path), ("stream" = String, Path)),
+    request_body(content = String, description = "Newline-delimited events", content_type = "application/x-ndjson"),
+    responses((status = 202, body = ingest::Accepted))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, body), fields(bucket = %bucket, stream = %stream))]
+async fn ingest_events(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path((bucket, stream)): Path<(String, String)>,
+    body: Bytes,
+) -> Result<impl IntoResponse> {
+    let config = state
+        .config
+        .ingest
+        .as_ref()
+        .ok_or_else(|| AppError::InternalError("Ingestion is not configured".to_string()))?;
+    check_bucket_access(&auth, &bucket)?;
+    check_write_permission(&auth)?;
+    let bucket = union::writable(&state.config, &bucket);
+    // Unknown buckets fail now rather than when the batch is written
+    state.get_account_and_client(&bucket)?;
+
+    let (accepted, full) = state.ingest.append(config, &bucket, &stream, &body)?;
+    if let Some(batch) = full {
+        ingest::flush(&state, &bucket, &stream, batch).await;
+    }
+    Ok((StatusCode::ACCEPTED, Json(accepted)))
+}
+
+/// POST /session sets a signed cookie that lets the browser read the session buckets without an API key
+#[utoipa::path(
+    post,
+    path = "/session",
+    tag = "access",
+    responses((status = 200, description = "The session, with its cookie in Set-Cookie", body = session::Session))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state))]
+async fn create_session(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    let config = state
+        .config
+        .sessions
+        .as_ref()
+        .ok_or_else(|| AppError::InternalError("Sessions are not configured".to_string()))?;
+    let signer = state
+        .session_signer
+        .as_ref()
+        .ok_or_else(|| AppError::InternalError("Sessions are not configured".to_string()))?;
+    let (session, cookie) = session::issue(config, signer, &auth)?;
+    info!("Issued session for {} to {:?} until {}", session.username, session.buckets, session.expires_at);
+    let mut headers = HeaderMap::new();
+    headers.insert(http::header::SET_COOKIE, cookie.parse().map_err(|_| AppError::InternalError("Invalid cookie".to_string()))?);
+    Ok((headers, Json(session)))
+}
+
+fn delegated_keys(state: &AppState) -> Result<&DelegatedKeys> {
+    state
+        .keys
+        .as_ref()
+        .ok_or_else(|| AppError::InternalError("Delegated keys are not configured".to_string()))
+}
+
+/// Keys are managed by users from the config with their own credentials, never through a delegated
+/// key or a session, so a key cannot outlive or outgrow the user behind it
+fn require_config_user(state: &AppState, auth: &AuthState) -> Result<UserConfig> {
+    let user = (auth.grant_source == format!("users.{}", auth.username))
+        .then(|| state.config.find_user(&auth.username))
+        .flatten();
+    match user {
+        Some(user) => Ok(user),
+        None => {
+            auth.record_rule(format!("denied: {} cannot manage keys", auth.grant_source));
+            Err(AppError::Unauthorized("Keys can only be managed with a user's own API key".to_string()))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/keys",
+    tag = "access",
+    request_body = KeyRequest,
+    responses((status = 201, description = "The new key; api_key is only ever returned here", body = crate::keys::IssuedKey))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth, request))]
+async fn issue_key(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(request): Json<KeyRequest>,
+) -> Result<impl IntoResponse> {
+    let user = require_config_user(&state, &auth)?;
+    // Saving the keys writes a file or the config store, which block
+    let issued = tokio::task::spawn_blocking(move || {
+        delegated_keys(&state)?.issue(&auth.username, &user, request, state.config.strict)
+    })
+    .await
+    .map_err(|e| AppError::InternalError(format!("Issuing a key failed: {}", e)))??;
+    Ok((StatusCode::CREATED, Json(issued)))
+}
+
+/// A user's own keys, or every user's for admins
+#[utoipa::path(get, path = "/keys", tag = "access", responses((status = 200, body = Vec<crate::keys::DelegatedKey>)))]
+async fn list_keys(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    let user = require_config_user(&state, &auth)?;
+    let owner = (user.role != UserRole::Admin).then_some(auth.username.as_str());
+    let mut keys = delegated_keys(&state)?.list(owner);
+    // Admins of a tenant see only its keys
+    keys.retain(|key| tenants::owns(&state.config, &key.owner));
+    Ok(Json(keys))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/keys/{id}",
+    tag = "access",
+    params(("id" = String, Path)),
+    responses((status = 204, description = "Revoked"))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn revoke_key(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse> {
+    let user = require_config_user(&state, &auth)?;
+    let owner = (user.role != UserRole::Admin).then_some(auth.username.as_str());
+    let keys = delegated_keys(&state)?;
+    if keys.list(owner).iter().any(|key| key.id == id && !tenants::owns(&state.config, &key.owner)) {
+        return Err(AppError::InvalidRequest(format!("No key {}", id)));
+    }
+    let owner = owner.map(str::to_string);
+    tokio::task::spawn_blocking(move || delegated_keys(&state)?.revoke(&id, owner.as_deref(), &auth.username))
+        .await
+        .map_err(|e| AppError::InternalError(format!("Revoking a key failed: {}", e)))??;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Progress of a resumable upload, for the user who started it or an admin
+#[utoipa::path(
+    get,
+    path = "/uploads/{id}/status",
+    tag = "objects",
+    params(("id" = String, Path)),
+    responses((status = 200, body = uploads::UploadProgress))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn upload_progress(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse> {
+    let progress = state.uploads.progress(&id, &auth.username, auth.role == UserRole::Admin).await?;
+    check_bucket_access(&auth, &progress.bucket)?;
+    Ok(Json(progress))
+}
+
+fn require_admin(auth: &AuthState) -> Result<()> {
+    if auth.role != UserRole::Admin {
+        auth.record_rule(format!("denied: {}.role {:?} is not admin", auth.grant_source, auth.role));
+        return Err(AppError::Unauthorized("Admin role required".to_string()));
+    }
+    auth.record_rule(format!("{}.role: Admin", auth.grant_source));
+    Ok(())
+}
+
+#[axum::debug_handler]
+#[instrument(skip(state, auth), fields(bucket = %bucket))]
+async fn create_bucket(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse> {
+    info!("Creating bucket {}", bucket);
+
+    require_admin(&auth)?;
+    check_bucket_access(&auth, &bucket)?;
+
+    if union::is_virtual(&state.config, &bucket) {
+        return Err(AppError::Conflict(format!("{} is a virtual bucket", bucket)));
+    }
+    if let Some(account_id) = state.find_account_for_bucket(&bucket) {
+        return Err(AppError::Conflict(format!("Bucket {} is already routed to account {}", bucket, account_id)));
+    }
+
+    let accounts = state.config.accounts.current();
+    let owned: Vec<&String> = accounts.keys().filter(|id| tenants::owns(&state.config, id)).collect();
+    let account_id = match params.get("account") {
+        Some(account_id) => tenants::qualify(account_id),
+        None => match &state.config.bucket_management.default_account {
+            Some(account_id) if tenants::owns(&state.config, account_id) => account_id.clone(),
+            _ if owned.len() == 1 => owned[0].clone(),
+            _ => return Err(AppError::InvalidRequest("No account specified for new bucket".to_string())),
+        },
+    };
+    let client = state.clients
+        .read()
+        .unwrap()
+        .get(&account_id)
+        .filter(|_| tenants::owns(&state.config, &account_id))
+        .cloned()
+        .ok_or_else(|| AppError::InvalidRequest(format!("Unknown account: {}", account_id)))?;
+    if tenants::claimed_elsewhere(&state.config, &state.buckets, &bucket, &account_id).is_some() {
+        return Err(AppError::Conflict(format!("Bucket name {} is taken", bucket)));
+    }
+
+    client.create_bucket(&bucket).await?;
+
+    if state.config.bucket_management.auto_register {
+        state.buckets.register(&bucket, &account_id);
+        if let Some(store) = &state.config_store {
+            let route = bucket.clone();
+            store.run(move |store| store.put_route(&route, Some(&account_id))).await?;
+        }
+    }
+    Ok(StatusCode::OK)
+}
+
+#[axum::debug_handler]
+#[instrument(skip(state, auth), fields(bucket = %bucket))]
+async fn delete_bucket(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+) -> Result<impl IntoResponse> {
+    info!("Deleting bucket {}", bucket);
+
+    require_admin(&auth)?;
+    check_bucket_access(&auth, &bucket)?;
+    state.holds.check(&bucket, None)?;
+
+    let (_, client) = &state.get_account_and_client(&bucket)?;
+    client.delete_bucket(&bucket).await?;
+    state.invalidate_cache(&bucket, None, None);
+
+    state.buckets.deregister(&bucket);
+    if let Some(store) = &state.config_store {
+        let route = bucket.clone();
+        store.run(move |store| store.put_route(&route, None)).await?;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "admin",
+    responses((status = 200, description = "Prometheus text format", body = String, content_type = "text/plain"))
+)]
+#[axum::debug_handler]
+#[instrument(skip(auth))]
+async fn prometheus_metrics(Extension(auth): Extension<AuthState>) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "text/plain; version=0.0.4".parse().unwrap());
+    Ok((StatusCode::OK, headers, metrics::render()))
+}
+
+#[axum::debug_handler]
+async fn openapi_document(State(state): State<Arc<AppState>>) -> Json<utoipa::openapi::OpenApi> {
+    Json(openapi::document(&state.config))
+}
+
+#[utoipa::path(get, path = "/admin/costs", tag = "admin", responses((status = 200, body = costs::CostReport)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn cost_report(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let config = state
+        .config
+        .costs
+        .as_ref()
+        .ok_or_else(|| AppError::InvalidRequest("Cost estimates are not enabled".to_string()))?;
+    Ok(Json(costs::report(config)))
+}
+
+/// Objects of a bucket not read or written through the proxy for a number of days
+#[utoipa::path(get, path = "/admin/cold", tag = "admin", params(ColdQuery), responses((status = 200, body = cold::ColdReport)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn cold_report(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Query(query): Query<ColdQuery>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    Ok(Json(cold::report(&state, query).await?))
+}
+
+#[utoipa::path(get, path = "/admin/audit", tag = "admin", params(AuditQuery), responses((status = 200, body = audit::AuditPage)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn audit_log(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    if state.audit.is_none() {
+        return Err(AppError::InvalidRequest("The audit log is not enabled".to_string()));
+    }
+    // SQLite blocks, so the query runs off the async workers
+    let page = tokio::task::spawn_blocking(move || state.audit.as_ref().expect("checked above").query(&query))
+        .await
+        .map_err(|e| AppError::InternalError(format!("Audit query failed: {}", e)))??;
+    Ok(Json(page))
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ChainQuery {
+    /// Check the chain a tenant ships to its own bucket instead
+    tenant: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/audit/verify",
+    tag = "admin",
+    params(ChainQuery),
+    responses((status = 200, body = audit_chain::ChainReport))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn verify_audit_chain(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Query(query): Query<ChainQuery>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    Ok(Json(audit_chain::verify(&state, query.tenant).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/backends",
+    tag = "admin",
+    responses((status = 200, description = "By account", body = std::collections::BTreeMap<String, backends::BackendReport>))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn backend_report(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    Ok(Json(backends::report(&state.config)))
+}
+
+/// Step one takes a JSON selector and returns the plan, step two repeats the call with `?confirm={token}`
+fn compliance_selector(params: &HashMap<String, String>, body: &[u8]) -> Result<Option<compliance::Selector>> {
+    if params.contains_key("confirm") {
+        return Ok(None);
+    }
+    serde_json::from_slice(body)
+        .map(Some)
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid selector: {}", e)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/compliance/export",
+    tag = "admin",
+    params(("confirm" = Option<String>, Query, description = "Token of the plan to carry out")),
+    request_body(content = Option<compliance::Selector>, description = "Objects to export, without ?confirm"),
+    responses(
+        (status = 200, description = "The plan, or with ?confirm a tar archive of the objects and the audit report", content(
+            (compliance::Plan = "application/json"),
+            (Vec<u8> = "application/x-tar"),
+        )),
+    )
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth, body))]
+async fn compliance_export(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Query(params): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Result<Response> {
+    require_admin(&auth)?;
+    if let Some(selector) = compliance_selector(&params, &body)? {
+        let plan = compliance::plan(&state, &auth, ComplianceAction::Export, selector).await?;
+        return Ok(Json(plan).into_response());
+    }
+
+    let token = &params["confirm"];
+    let archive = compliance::export(state.clone(), &auth, token)?;
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/x-tar".parse().unwrap());
+    if let Ok(value) = format!("attachment; filename=\"export-{}.tar\"", token).parse() {
+        headers.insert("content-disposition", value);
+    }
+    Ok((StatusCode::OK, headers, archive).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/admin/compliance/erase",
+    tag = "admin",
+    params(("confirm" = Option<String>, Query, description = "Token of the plan to carry out")),
+    request_body(content = Option<compliance::Selector>, description = "Objects to erase, without ?confirm"),
+    responses(
+        (status = 200, description = "The plan, or with ?confirm the audit report", content(
+            (compliance::Plan = "application/json"),
+            (compliance::AuditReport = "application/json"),
+        )),
+    )
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth, body))]
+async fn compliance_erase(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Query(params): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Result<Response> {
+    require_admin(&auth)?;
+    if let Some(selector) = compliance_selector(&params, &body)? {
+        let plan = compliance::plan(&state, &auth, ComplianceAction::Erase, selector).await?;
+        return Ok(Json(plan).into_response());
+    }
+    Ok(Json(compliance::erase(&state, &auth, &params["confirm"]).await?).into_response())
+}
+
+fn require_cache(state: &AppState) -> Result<&ObjectCache> {
+    state
+        .cache
+        .as_ref()
+        .ok_or_else(|| AppError::InvalidRequest("The object cache is not enabled".to_string()))
+}
+
+#[utoipa::path(get, path = "/admin/cache/pins", tag = "admin", responses((status = 200, body = cache::PinStatus)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn list_cache_pins(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    Ok(Json(require_cache(&state)?.pin_status()))
+}
+
+#[utoipa::path(put, path = "/admin/cache/pins", tag = "admin", request_body = CachePinRequest, responses((status = 200)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn add_cache_pin(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(request): Json<CachePinRequest>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    check_bucket_access(&auth, &request.pin.bucket)?;
+    state.get_account_and_client(&request.pin.bucket)?;
+
+    info!("Pinning {}/{} in the cache", request.pin.bucket, request.pin.prefix);
+    require_cache(&state)?.pin(request.pin.clone());
+    if request.prefetch {
+        cache::spawn_prefetch(state.clone(), request.pin);
+    }
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(delete, path = "/admin/cache/pins", tag = "admin", request_body = CachePin, responses((status = 204)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn remove_cache_pin(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(pin): Json<CachePin>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+
+    info!("Unpinning {}/{} in the cache", pin.bucket, pin.prefix);
+    if !require_cache(&state)?.unpin(&pin) {
+        return Err(AppError::InvalidRequest(format!("No pin for {}/{}", pin.bucket, pin.prefix)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(get, path = "/admin/holds", tag = "admin", responses((status = 200, body = Vec<crate::holds::LegalHold>)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn list_legal_holds(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    Ok(Json(state.holds.list()))
+}
+
+#[utoipa::path(
+    put,
+    path = "/admin/holds",
+    tag = "admin",
+    request_body = LegalHoldRequest,
+    responses((status = 201, body = crate::holds::LegalHold))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn place_legal_hold(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(request): Json<LegalHoldRequest>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    check_bucket_access(&auth, &request.bucket)?;
+    state.get_account_and_client(&request.bucket)?;
+
+    let hold = state.holds.place(&request.bucket, &request.prefix, request.reason, &auth.username)?;
+    Ok((StatusCode::CREATED, Json(hold)))
+}
+
+#[utoipa::path(delete, path = "/admin/holds", tag = "admin", request_body = LegalHoldRequest, responses((status = 204)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn release_legal_hold(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(request): Json<LegalHoldRequest>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    check_bucket_access(&auth, &request.bucket)?;
+
+    state.holds.release(&request.bucket, &request.prefix, &auth.username)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn require_config_store(state: &AppState) -> Result<&Arc<ConfigStore>> {
+    state
+        .config_store
+        .as_ref()
+        .ok_or_else(|| AppError::InvalidRequest("The config store is not enabled".to_string()))
+}
+
+/// A user of the config store, without its credentials
+#[derive(Debug, Serialize, ToSchema)]
+struct StoredUser {
+    name: String,
+    role: UserRole,
+    allowed_buckets: Vec<String>,
+}
+
+#[utoipa::path(get, path = "/admin/users", tag = "admin", responses((status = 200, body = Vec<StoredUser>)))]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn list_stored_users(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let mut users: Vec<StoredUser> = require_config_store(&state)?
+        .run(|store| store.users())
+        .await?
+        .into_iter()
+        .map(|(name, user)| StoredUser { name, role: user.role, allowed_buckets: user.allowed_buckets })
+        .collect();
+    users.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(Json(users))
+}
+
+/// Adds or replaces a user; the body is an entry of `users` in config.json
+#[utoipa::path(
+    put,
+    path = "/admin/users/{name}",
+    tag = "admin",
+    params(("name" = String, Path)),
+    request_body = serde_json::Value,
+    responses((status = 204))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth, user))]
+async fn put_stored_user(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(name): Path<String>,
+    Json(user): Json<UserConfig>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let store = require_config_store(&state)?;
+
+    info!("Storing user {}", name);
+    let users = store
+        .run(move |store| {
+            store.put_user(&name, &user)?;
+            store.users()
+        })
+        .await?;
+    kubernetes::apply_users(&state, users, "the config store");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/admin/users/{name}",
+    tag = "admin",
+    params(("name" = String, Path)),
+    responses((status = 204))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn delete_stored_user(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let store = require_config_store(&state)?;
+
+    info!("Deleting user {}", name);
+    let users = store
+        .run(move |store| {
+            if !store.delete_user(&name)? {
+                return Err(AppError::InvalidRequest(format!("No user {} in the config store", name)));
+            }
+            store.users()
+        })
+        .await?;
+    kubernetes::apply_users(&state, users, "the config store");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct BucketRouteRequest {
+    account: String,
+}
+
+/// Routes a bucket to an account, overriding config.json
+#[utoipa::path(
+    put,
+    path = "/admin/buckets/{bucket}",
+    tag = "admin",
+    params(("bucket" = String, Path)),
+    request_body = BucketRouteRequest,
+    responses((status = 204))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth, request), fields(bucket = %bucket))]
+async fn put_bucket_route(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+    Json(request): Json<BucketRouteRequest>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let store = require_config_store(&state)?;
+    if !state.config.accounts.current().contains_key(&request.account) {
+        return Err(AppError::InvalidRequest(format!("Unknown account: {}", request.account)));
+    }
+    if let Some(account_id) = tenants::claimed_elsewhere(&state.config, &state.buckets, &bucket, &request.account) {
+        return Err(AppError::Conflict(format!(
+            "Bucket {} is served by account {} of another tenant",
+            bucket, account_id
+        )));
+    }
+
+    let (route, account_id) = (bucket.clone(), request.account.clone());
+    store.run(move |store| store.put_route(&route, Some(&account_id))).await?;
+    state.buckets.register(&bucket, &request.account);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Drops the stored route of a bucket, so config.json decides again
+#[utoipa::path(
+    delete,
+    path = "/admin/buckets/{bucket}",
+    tag = "admin",
+    params(("bucket" = String, Path)),
+    responses((status = 204))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth), fields(bucket = %bucket))]
+async fn delete_bucket_route(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Path(bucket): Path<String>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let route = bucket.clone();
+    if !require_config_store(&state)?.run(move |store| store.delete_route(&route)).await? {
+        return Err(AppError::InvalidRequest(format!("No stored route for bucket {}", bucket)));
+    }
+    state.buckets.remove(&bucket);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+struct RewriteCheckRequest {
+    bucket: String,
+    key: String,
+    /// Evaluate as a plain upload, which also applies writes_only rules
+    #[serde(default)]
+    write: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+struct RewriteCheckResponse {
+    bucket: String,
+    key: String,
+    write: bool,
+    /// Index of the matching rule in key_rewrites.{bucket}
+    rule: Option<usize>,
+    rewritten: String,
+}
+
+/// Shows what the bucket's rewrite rules make of a key without touching S3
+#[utoipa::path(
+    post,
+    path = "/admin/rewrites/check",
+    tag = "admin",
+    request_body = RewriteCheckRequest,
+    responses((status = 200, body = RewriteCheckResponse))
+)]
+#[axum::debug_handler]
+#[instrument(skip(state, auth))]
+async fn rewrite_check(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(check): Json<RewriteCheckRequest>,
+) -> Result<impl IntoResponse> {
+    require_admin(&auth)?;
+    let key = normalize_key(&state.config.directories, &check.key);
+    let rules = state.config.key_rewrites.get(&check.bucket).map(Vec::as_slice).unwrap_or_default();
+    let (rule, rewritten) = match rewrite::evaluate(rules, &key, check.write, Utc::now()) {
+        Some((rule, rewritten)) => (Some(rule), rewritten),
+        None => (None, key),
+    };
+    Ok(Json(RewriteCheckResponse { bucket: check.bucket, key: check.key, write: check.write, rule, rewritten }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/authz/check",
+    tag = "access",
+    request_body = AuthzCheckRequest,
+    responses((status = 200, body = AuthzCheckResponse))
+)]
+#[axum::debug_handler]
+// The body may carry a delegated key's secret, so it stays out of the span
+#[instrument(skip(state, auth, check), fields(bucket = %check.bucket))]
+async fn authz_check(
+    State(state): State<Arc<AppState>>,
+    Extension(auth): Extension<AuthState>,
+    Json(check): Json<AuthzCheckRequest>,
+) -> Result<impl IntoResponse> {
+    // Callers may check their own permissions, only admins may check other users
+    let mut account_active = true;
+    let subject = match (check.api_key.as_deref(), check.user.as_deref()) {
+        (Some(api_key), _) => {
+            let key = state
+                .keys
+                .as_ref()
+                .and_then(|keys| keys.find(api_key))
+                .filter(|key| tenants::owns(&state.config, &key.owner))
+                .ok_or_else(|| AppError::InvalidRequest("Unknown delegated key".to_string()))?;
+            if key.owner != auth.username && auth.role != UserRole::Admin {
+                return Err(AppError::Unauthorized("Only admins can check keys of other users".to_string()));
+            }
+            let (owner, source) = (key.owner.clone(), format!("keys.{}", key.id));
+            // The same grants the middleware gives requests made with the key
+            let mut subject = match auth::for_delegated_key(&state.config, key) {
+                Ok(subject) => subject,
+                Err(e) => {
+                    account_active = false;
+                    let subject = AuthState::new(owner, UserRole::Readonly, Vec::new(), source);
+                    subject.record_rule(format!("denied: {}", e));
+                    subject
+                }
+            };
+            subject.strict = state.config.strict;
+            subject
+        }
+        (None, None) => auth.without_rules(),
+        (None, Some(user)) if tenants::qualify(user) == auth.username => auth.without_rules(),
+        (None, Some(user)) => {
+            if auth.role != UserRole::Admin {
+                return Err(AppError::Unauthorized("Only admins can check other users".to_string()));
+            }
+            // Users of other tenants are answered like missing ones, so their names do not leak
+            let name = tenants::qualify(user);
+            let user_config = Some(&name)
+                .filter(|name| tenants::owns(&state.config, name))
+                .and_then(|name| state.config.find_user(name))
+                .ok_or_else(|| AppError::UserNotFound(user.to_string()))?;
+            let user = name.as_str();
+            let mut subject = AuthState::for_config_user(user, &user_config);
+            subject.strict = state.config.strict;
+            if user_config.is_expired(Utc::now()) {
+                subject.record_rule(format!("denied: users.{} is expired or not yet valid", user));
+                account_active = false;
+            }
+            if !user_config.is_within_access_window(Utc::now()) {
+                subject.record_rule(format!("denied: users.{} is outside its access windows", user));
+                account_active = false;
+            }
+            subject
+        }
+    };
+
+    let allowed = check_operation(&subject, check.operation, &check.bucket).is_ok()
+        && auth::check_operation_prefixes(&subject, check.operation, check.key.as_deref()).is_ok()
+        && account_active;
+    info!("Authz check for {} {:?} on {}: {}", subject.username, check.operation, check.bucket, allowed);
+
+    Ok(Json(AuthzCheckResponse {
+        allowed,
+        user: subject.username.clone(),
+        operation: check.operation,
+        bucket: check.bucket,
+        key: check.key,
+        rules: subject.matched_rules(),
+    }))
+}
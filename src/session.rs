@@ -0,0 +1,87 @@
+use axum::http::{header, HeaderMap, Method};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use tracing::warn;
+
+use crate::auth::{AuthState, Signer};
+use crate::config::{matching_bucket_grant, SessionConfig};
+use crate::error::{AppError, Result};
+
+pub use s3_proxy_client::types::Session;
+
+fn cookie(config: &SessionConfig, value: &str, max_age: i64) -> String {
+    let secure = if config.secure { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        config.cookie_name, value, max_age, secure
+    )
+}
+
+/// Issues a session for the caller, returning it with the Set-Cookie header value
+pub fn issue(config: &SessionConfig, signer: &Signer, auth: &AuthState) -> Result<(Session, String)> {
+    let buckets: Vec<String> = config
+        .buckets
+        .iter()
+        .filter(|bucket| matching_bucket_grant(&auth.allowed_buckets, bucket, auth.strict).is_some())
+        .cloned()
+        .collect();
+    if buckets.is_empty() {
+        return Err(AppError::Unauthorized(format!(
+            "{} has no access to any bucket served with session cookies",
+            auth.username
+        )));
+    }
+    let session = Session {
+        username: auth.username.clone(),
+        buckets,
+        expires_at: Utc::now() + chrono::Duration::seconds(config.ttl_secs as i64),
+        ldap: auth.is_ldap(),
+    };
+    let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&session).map_err(|e| AppError::InternalError(e.to_string()))?);
+    let signature = URL_SAFE_NO_PAD.encode(signer.sign(payload.as_bytes())?);
+    let value = format!("{}.{}", payload, signature);
+    Ok((session, cookie(config, &value, config.ttl_secs as i64)))
+}
+
+fn cookie_value<'a>(config: &SessionConfig, headers: &'a HeaderMap) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == config.cookie_name)
+        .map(|(_, value)| value)
+}
+
+fn verify(signer: &Signer, value: &str) -> Result<Session> {
+    let invalid = || AppError::Unauthorized("Invalid session cookie".to_string());
+    let (payload, signature) = value.split_once('.').ok_or_else(invalid)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    if !signer.verify(payload.as_bytes(), &signature)? {
+        return Err(invalid());
+    }
+    let session: Session = URL_SAFE_NO_PAD
+        .decode(payload)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(invalid)?;
+    if session.expires_at <= Utc::now() {
+        return Err(AppError::Unauthorized("Session expired".to_string()));
+    }
+    Ok(session)
+}
+
+/// The verified session of a cookie, for GET and HEAD requests only; None without a cookie
+pub fn authenticate(config: &SessionConfig, signer: &Signer, method: &Method, headers: &HeaderMap) -> Option<Result<Session>> {
+    if method != Method::GET && method != Method::HEAD {
+        return None;
+    }
+    let value = cookie_value(config, headers)?;
+    let result = verify(signer, value);
+    if let Err(e) = &result {
+        warn!("Rejected session cookie: {}", e);
+    }
+    Some(result)
+}
@@ -0,0 +1,199 @@
+use chrono::Utc;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::config::BucketStatsConfig;
+use crate::error::Result;
+use crate::routing;
+use crate::server::AppState;
+use crate::union;
+
+pub use s3_proxy_client::types::{BucketStats, DiskUsage, ObjectSize, UsageEntry};
+
+/// Prefixes whose usage is kept at once; prefixes come from clients, so they are bounded
+const MAX_USAGE_ENTRIES: usize = 1000;
+
+struct Entry<T> {
+    value: Option<Arc<T>>,
+    computed: Option<Instant>,
+    refreshing: bool,
+}
+
+impl<T> Default for Entry<T> {
+    fn default() -> Self {
+        Self { value: None, computed: None, refreshing: false }
+    }
+}
+
+/// Results of background listings, served until they are older than `refresh` and then recomputed
+struct Entries<K, T> {
+    refresh: Duration,
+    entries: Mutex<HashMap<K, Entry<T>>>,
+}
+
+impl<K: Eq + Hash + Clone, T> Entries<K, T> {
+    fn new(refresh: Duration) -> Self {
+        Self { refresh, entries: Mutex::new(HashMap::new()) }
+    }
+
+    /// The cached value, and whether the caller should start computing a new one
+    fn get(&self, key: &K, max_entries: usize) -> (Option<Arc<T>>, bool) {
+        let mut entries = self.entries.lock().unwrap();
+        if !entries.contains_key(key) && entries.len() >= max_entries {
+            let oldest = entries
+                .iter()
+                .filter(|(_, entry)| !entry.refreshing)
+                .min_by_key(|(_, entry)| entry.computed)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(oldest) => entries.remove(&oldest),
+                // Every entry is being computed, so this one has to wait
+                None => return (None, false),
+            };
+        }
+        let entry = entries.entry(key.clone()).or_default();
+        let stale = entry.computed.is_none_or(|computed| computed.elapsed() >= self.refresh);
+        let start = stale && !entry.refreshing;
+        entry.refreshing |= start;
+        (entry.value.clone(), start)
+    }
+
+    fn finish(&self, key: &K, value: Option<T>) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(key.clone()).or_default();
+        entry.refreshing = false;
+        // A failed listing keeps serving the previous value and is retried on the next request
+        if let Some(value) = value {
+            entry.value = Some(Arc::new(value));
+            entry.computed = Some(Instant::now());
+        }
+    }
+}
+
+/// Bucket stats and prefix usage from the last listing of each, so clients need not list buckets
+/// themselves; kept by account as well, since routing rules may send one bucket name to several
+pub struct StatsCache {
+    largest: usize,
+    stats: Entries<(String, String), BucketStats>,
+    usage: Entries<(String, String, String), DiskUsage>,
+}
+
+impl StatsCache {
+    pub fn new(config: &BucketStatsConfig) -> Self {
+        let refresh = Duration::from_secs(config.refresh_secs);
+        Self { largest: config.largest, stats: Entries::new(refresh), usage: Entries::new(refresh) }
+    }
+
+    /// The cached stats, starting an inventory in the background when they are missing or stale;
+    /// None until the first inventory of the bucket finishes
+    pub fn stats(&self, state: &Arc<AppState>, bucket: &str) -> Option<Arc<BucketStats>> {
+        let key = (state.find_account_for_bucket(bucket).unwrap_or_default(), bucket.to_string());
+        let (stats, start) = self.stats.get(&key, usize::MAX);
+        if start {
+            spawn_inventory(state.clone(), key);
+        }
+        stats
+    }
+
+    /// Like `stats`, for the size breakdown one level under `prefix`
+    pub fn usage(&self, state: &Arc<AppState>, bucket: &str, prefix: &str) -> Option<Arc<DiskUsage>> {
+        let account = state.find_account_for_bucket(bucket).unwrap_or_default();
+        let key = (account, bucket.to_string(), prefix.to_string());
+        let (usage, start) = self.usage.get(&key, MAX_USAGE_ENTRIES);
+        if start {
+            spawn_usage(state.clone(), key);
+        }
+        usage
+    }
+}
+
+async fn inventory(state: &AppState, bucket: &str, largest: usize) -> Result<BucketStats> {
+    let (objects, _) = union::list(state, bucket, "", false).await?;
+    let mut sizes: Vec<ObjectSize> = objects
+        .iter()
+        .map(|object| ObjectSize {
+            key: object.key().unwrap_or_default().to_string(),
+            size: object.size().unwrap_or_default().max(0) as u64,
+        })
+        .collect();
+    let total_bytes = sizes.iter().map(|object| object.size).sum();
+    sizes.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.key.cmp(&b.key)));
+    sizes.truncate(largest);
+    Ok(BucketStats {
+        bucket: bucket.to_string(),
+        objects: objects.len() as u64,
+        total_bytes,
+        largest: sizes,
+        computed_at: Utc::now(),
+    })
+}
+
+/// Sums the objects under `prefix` by the next path segment after it, like `du` one level deep
+async fn usage(state: &AppState, bucket: &str, prefix: &str) -> Result<DiskUsage> {
+    let (objects, _) = union::list(state, bucket, prefix, false).await?;
+    let mut children: BTreeMap<&str, UsageEntry> = BTreeMap::new();
+    let mut total_bytes = 0;
+    for object in &objects {
+        let key = object.key().unwrap_or_default();
+        let size = object.size().unwrap_or_default().max(0) as u64;
+        let rest = key.strip_prefix(prefix).unwrap_or(key);
+        let (name, directory) = match rest.find('/') {
+            Some(end) => (&key[..prefix.len() + end + 1], true),
+            None => (key, false),
+        };
+        let child = children.entry(name).or_insert_with(|| UsageEntry {
+            name: name.to_string(),
+            directory,
+            objects: 0,
+            bytes: 0,
+        });
+        child.objects += 1;
+        child.bytes += size;
+        total_bytes += size;
+    }
+    let mut entries: Vec<UsageEntry> = children.into_values().collect();
+    entries.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.name.cmp(&b.name)));
+    Ok(DiskUsage {
+        bucket: bucket.to_string(),
+        prefix: prefix.to_string(),
+        objects: objects.len() as u64,
+        total_bytes,
+        entries,
+        computed_at: Utc::now(),
+    })
+}
+
+fn spawn_inventory(state: Arc<AppState>, key: (String, String)) {
+    tokio::spawn(routing::scope(routing::current(), async move {
+        let bucket = &key.1;
+        let started = Instant::now();
+        let stats = match inventory(&state, bucket, state.bucket_stats.largest).await {
+            Ok(stats) => {
+                info!("Inventoried {} objects of {} in {:?}", stats.objects, bucket, started.elapsed());
+                Some(stats)
+            }
+            Err(e) => {
+                warn!("Inventory of {} failed: {}", bucket, e);
+                None
+            }
+        };
+        state.bucket_stats.stats.finish(&key, stats);
+    }));
+}
+
+fn spawn_usage(state: Arc<AppState>, key: (String, String, String)) {
+    tokio::spawn(routing::scope(routing::current(), async move {
+        let (_, bucket, prefix) = &key;
+        let usage = match usage(&state, bucket, prefix).await {
+            Ok(usage) => Some(usage),
+            Err(e) => {
+                warn!("Usage of {}/{} failed: {}", bucket, prefix, e);
+                None
+            }
+        };
+        state.bucket_stats.usage.finish(&key, usage);
+    }));
+}
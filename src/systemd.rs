@@ -0,0 +1,54 @@
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Sends a state change to systemd; does nothing unless started as a Type=notify service
+#[cfg(unix)]
+fn notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let sent = UnixDatagram::unbound().and_then(|socket| match path.to_str().and_then(|p| p.strip_prefix('@')) {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)
+        }
+        _ => socket.send_to(state.as_bytes(), &path),
+    });
+    if let Err(e) = sent {
+        warn!("Failed to notify systemd of {:?}: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn notify(_state: &str) {}
+
+/// Reports the proxy as started, with this process as the one to supervise, which moves
+/// supervision to the new process after a socket handover
+pub fn ready() {
+    notify(&format!("READY=1\nMAINPID={}", std::process::id()));
+}
+
+/// Pets the systemd watchdog at half its timeout while the runtime still picks up new tasks
+/// promptly, so a wedged proxy is restarted
+pub fn spawn_watchdog() {
+    // WATCHDOG_PID is not checked: the only process the proxy starts is its own successor, which
+    // takes over supervision
+    let Some(usec) = std::env::var("WATCHDOG_USEC").ok().and_then(|usec| usec.parse::<u64>().ok()) else {
+        return;
+    };
+    let interval = Duration::from_micros(usec / 2);
+    info!("Petting the systemd watchdog every {:?}", interval);
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            match tokio::time::timeout(interval, tokio::spawn(async {})).await {
+                Ok(Ok(())) => notify("WATCHDOG=1"),
+                _ => debug!("Health probe did not finish within {:?}, not petting the watchdog", interval),
+            }
+        }
+    });
+}
@@ -0,0 +1,343 @@
+use axum::{
+    extract::{Request, State},
+    http::{header, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+use tracing::{debug, warn, Span};
+
+use crate::buckets::BucketRegistry;
+use crate::config::{AccountConfig, Config};
+use crate::error::{AppError, Result};
+
+tokio::task_local! {
+    /// Tenant of the request being handled on this task, None for the default namespace
+    static CURRENT: Option<String>;
+}
+
+/// Paths served only in the default namespace, as they cover every tenant
+const GLOBAL_PATHS: &[&str] = &["/admin/", "/metrics"];
+
+fn invalid(message: String) -> AppError {
+    AppError::ConfigError(std::io::Error::new(std::io::ErrorKind::InvalidData, message))
+}
+
+/// Moves the accounts and users of each tenant into `accounts` and `users` as `<tenant>/<name>`,
+/// before the config is parsed
+pub fn flatten(path: &str, config: &mut Value) -> Result<()> {
+    let Some(root) = config.as_object_mut() else {
+        return Ok(());
+    };
+    let Some(Value::Object(tenants)) = root.get_mut("tenants") else {
+        return Ok(());
+    };
+    let mut moved: Vec<(&str, String, Map<String, Value>)> = Vec::new();
+    for (name, tenant) in tenants.iter_mut() {
+        if name.is_empty() || name.contains('/') {
+            return Err(invalid(format!("{}: tenant name {:?} must be non-empty and without /", path, name)));
+        }
+        let Some(tenant) = tenant.as_object_mut() else {
+            continue;
+        };
+        for section in ["accounts", "users"] {
+            if let Some(Value::Object(entries)) = tenant.shift_remove(section) {
+                moved.push((section, name.clone(), entries));
+            }
+        }
+    }
+    for (section, tenant, entries) in moved {
+        let Value::Object(target) = root.entry(section).or_insert_with(|| Value::Object(Map::new())) else {
+            return Err(invalid(format!("{}: {} must be an object", path, section)));
+        };
+        for (name, entry) in entries {
+            let name = format!("{}/{}", tenant, name);
+            if target.contains_key(&name) {
+                return Err(invalid(format!("{}: {}.{} is set both in the tenant and at the top", path, section, name)));
+            }
+            target.insert(name, entry);
+        }
+    }
+    Ok(())
+}
+
+/// Tenant an account or user belongs to by its name, None for the default namespace
+fn tenant_of<'a>(config: &Config, name: &'a str) -> Option<&'a str> {
+    name.split_once('/').map(|(tenant, _)| tenant).filter(|tenant| config.tenants.contains_key(*tenant))
+}
+
+/// Whether the account or user `name` belongs to the tenant of the request being handled; outside
+/// requests, such as in background tasks, every name does
+pub fn owns(config: &Config, name: &str) -> bool {
+    if config.tenants.is_empty() {
+        return true;
+    }
+    CURRENT.try_with(|current| current.as_deref() == tenant_of(config, name)).unwrap_or(true)
+}
+
+/// Tenant of the request being handled, None in the default namespace and outside requests
+pub fn current() -> Option<String> {
+    CURRENT.try_with(Clone::clone).ok().flatten()
+}
+
+/// Runs `task` as if handling a request of `tenant`, e.g. for background work done on its behalf
+pub async fn scope<F: Future>(tenant: Option<String>, task: F) -> F::Output {
+    CURRENT.scope(tenant, task).await
+}
+
+/// Full name of an account or user the request named within its tenant
+pub fn qualify(name: &str) -> String {
+    match current() {
+        Some(tenant) => format!("{}/{}", tenant, name),
+        None => name.to_string(),
+    }
+}
+
+/// Prefix of URLs the proxy hands out for the tenant of the request, empty outside tenants
+pub fn url_prefix() -> String {
+    match current() {
+        Some(tenant) => format!("/t/{}", tenant),
+        None => String::new(),
+    }
+}
+
+/// Refuses accounts serving a bucket name in more than one tenant: the cache, the read-after-write
+/// overlay, legal holds and other per-bucket state are keyed by bucket name alone, so tenants sharing
+/// a name would see each other's objects
+pub fn check_buckets(config: &Config, accounts: &HashMap<String, AccountConfig>) -> Result<()> {
+    if config.tenants.is_empty() {
+        return Ok(());
+    }
+    let mut served: HashMap<&str, Option<&str>> = HashMap::new();
+    for (account_id, account) in accounts {
+        let tenant = tenant_of(config, account_id);
+        for bucket in account.buckets.iter().chain(account.aliases.keys()) {
+            match served.insert(bucket, tenant) {
+                Some(other) if other != tenant => {
+                    return Err(invalid(format!(
+                        "Bucket {} is served in tenants {} and {}, bucket names must be unique across tenants",
+                        bucket,
+                        other.unwrap_or("(default)"),
+                        tenant.unwrap_or("(default)")
+                    )));
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Account of a tenant other than that of `account_id` which serves `bucket`, in config.json or
+/// through a runtime route; such a bucket must not be created or routed for `account_id`
+pub fn claimed_elsewhere(config: &Config, buckets: &BucketRegistry, bucket: &str, account_id: &str) -> Option<String> {
+    if config.tenants.is_empty() {
+        return None;
+    }
+    let tenant = tenant_of(config, account_id);
+    if let Some(Some(routed)) = buckets.lookup(bucket) {
+        if tenant_of(config, &routed) != tenant {
+            return Some(routed);
+        }
+    }
+    config
+        .accounts
+        .current()
+        .iter()
+        .filter(|(id, _)| tenant_of(config, id) != tenant)
+        .find(|(_, account)| account.buckets.iter().any(|name| name == bucket) || account.aliases.contains_key(bucket))
+        .map(|(id, _)| id.clone())
+}
+
+/// Checks the tenants of a config being loaded
+pub fn check(config: &Config) -> Result<()> {
+    check_buckets(config, &config.accounts.current())?;
+    let mut hostnames: HashMap<String, &str> = HashMap::new();
+    for (name, tenant) in &config.tenants {
+        if tenant.audit_shipping.is_some() && config.audit.is_none() {
+            warn!("Tenant {}: audit_shipping has no records to ship without an audit section", name);
+        }
+        for hostname in &tenant.hostnames {
+            if let Some(other) = hostnames.insert(hostname.to_ascii_lowercase(), name) {
+                warn!("Hostname {} is mapped to tenants {} and {}, one of them wins", hostname, other, name);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Tenant whose hostnames include the one the request was sent to
+fn tenant_for_host<'a>(config: &'a Config, request: &Request) -> Option<&'a String> {
+    let host = request
+        .uri()
+        .host()
+        .or_else(|| request.headers().get(header::HOST).and_then(|host| host.to_str().ok()))?;
+    let host = host.split(':').next().unwrap_or(host);
+    config
+        .tenants
+        .iter()
+        .find(|(_, tenant)| tenant.hostnames.iter().any(|name| name.eq_ignore_ascii_case(host)))
+        .map(|(name, _)| name)
+}
+
+/// Removes a /t/<tenant> prefix from the request path and returns the tenant it named
+fn strip_prefix(request: &mut Request) -> Option<String> {
+    let rest = request.uri().path().strip_prefix("/t/")?;
+    let (tenant, path) = match rest.split_once('/') {
+        Some((tenant, path)) => (tenant.to_string(), format!("/{}", path)),
+        None => (rest.to_string(), "/".to_string()),
+    };
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    *request.uri_mut() = Uri::from_parts(parts).ok()?;
+    Some(tenant)
+}
+
+/// Picks the tenant of the request from its /t/<tenant> prefix, which is removed before routing,
+/// or from the host it was sent to; requests matching neither are in the default namespace
+pub async fn resolve(State(config): State<Arc<Config>>, mut request: Request, next: Next) -> Response {
+    let host_tenant = tenant_for_host(&config, &request).cloned();
+    let tenant = match strip_prefix(&mut request) {
+        Some(tenant) if !config.tenants.contains_key(&tenant) => {
+            return AppError::InvalidRequest(format!("Unknown tenant {}", tenant)).into_response();
+        }
+        Some(tenant) if host_tenant.as_ref().is_some_and(|host_tenant| *host_tenant != tenant) => {
+            return AppError::InvalidRequest(format!("Tenant {} is not served on this host", tenant)).into_response();
+        }
+        Some(tenant) => Some(tenant),
+        None => host_tenant,
+    };
+    if let Some(tenant) = &tenant {
+        let path = request.uri().path();
+        if GLOBAL_PATHS.iter().any(|global| path.starts_with(global)) {
+            return AppError::Unauthorized(format!("{} is not served to tenant {}", path, tenant)).into_response();
+        }
+        Span::current().record("tenant", tenant.as_str());
+        debug!("Request for tenant {}", tenant);
+    }
+    CURRENT.scope(tenant, next.run(request)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use axum::{body::Body, extract::Request};
+
+    use super::{claimed_elsewhere, flatten, owns, qualify, scope, strip_prefix, url_prefix};
+    use crate::buckets::BucketRegistry;
+    use crate::config::Config;
+
+    fn account(bucket: &str) -> serde_json::Value {
+        json!({
+            "endpoint_url": "http://localhost:9000",
+            "region": "us-east-1",
+            "access_key_id": "key",
+            "secret_access_key": "secret",
+            "buckets": [bucket]
+        })
+    }
+
+    fn load(acme: &str, globex: &str) -> crate::error::Result<Config> {
+        let config = json!({
+            "accounts": {},
+            "users": {},
+            "server": { "host": "127.0.0.1", "port": 8080 },
+            "tenants": {
+                "acme": { "accounts": { "main": account(acme) } },
+                "globex": { "accounts": { "main": account(globex) } }
+            }
+        });
+        let path = std::env::temp_dir().join(format!("s3-proxy-tenants-{}-{}.json", acme, globex));
+        std::fs::write(&path, config.to_string()).unwrap();
+        let loaded = Config::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn two_tenants_may_not_serve_the_same_bucket_name() {
+        let error = load("data", "data").expect_err("a bucket shared by two tenants is refused");
+        assert!(error.to_string().contains("Bucket data is served in tenants"), "{}", error);
+    }
+
+    #[test]
+    fn a_tenant_may_not_take_a_bucket_name_of_another() {
+        let config = load("acme-data", "globex-data").expect("distinct bucket names load");
+        let buckets = BucketRegistry::default();
+        assert_eq!(
+            claimed_elsewhere(&config, &buckets, "acme-data", "globex/main").as_deref(),
+            Some("acme/main")
+        );
+        assert_eq!(claimed_elsewhere(&config, &buckets, "acme-data", "acme/main"), None);
+
+        buckets.register("fresh", "globex/main");
+        assert_eq!(claimed_elsewhere(&config, &buckets, "fresh", "acme/main").as_deref(), Some("globex/main"));
+        assert_eq!(claimed_elsewhere(&config, &buckets, "other", "acme/main"), None);
+    }
+
+    #[test]
+    fn tenant_accounts_and_users_are_named_after_their_tenant() {
+        let mut config = json!({
+            "accounts": { "main": account("shared") },
+            "users": {},
+            "tenants": { "acme": { "accounts": { "main": account("acme-data") }, "users": { "ci": {} } } }
+        });
+        flatten("config.json", &mut config).unwrap();
+        let accounts = config["accounts"].as_object().unwrap();
+        assert!(accounts.contains_key("main") && accounts.contains_key("acme/main"));
+        assert!(config["users"].as_object().unwrap().contains_key("acme/ci"));
+        assert!(config["tenants"]["acme"].get("accounts").is_none());
+
+        let mut clash = json!({
+            "accounts": { "acme/main": account("a") },
+            "tenants": { "acme": { "accounts": { "main": account("b") } } }
+        });
+        assert!(flatten("config.json", &mut clash).is_err());
+        let mut nested = json!({ "tenants": { "a/b": {} } });
+        assert!(flatten("config.json", &mut nested).is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_only_see_accounts_of_their_tenant() {
+        let config = load("acme-files", "globex-files").unwrap();
+        assert!(owns(&config, "acme/main") && owns(&config, "globex/main"), "background work sees every account");
+
+        scope(Some("acme".to_string()), async {
+            assert!(owns(&config, "acme/main"));
+            assert!(!owns(&config, "globex/main"));
+            assert!(!owns(&config, "main"), "default namespace accounts are not shared with tenants");
+            assert_eq!(qualify("ci"), "acme/ci");
+            assert_eq!(url_prefix(), "/t/acme");
+        })
+        .await;
+        scope(None, async {
+            assert!(owns(&config, "main"));
+            assert!(!owns(&config, "acme/main"));
+            assert_eq!(qualify("ci"), "ci");
+        })
+        .await;
+    }
+
+    #[test]
+    fn the_tenant_prefix_is_removed_before_routing() {
+        let mut request = Request::builder().uri("/t/acme/data/a/b.txt?versions").body(Body::empty()).unwrap();
+        assert_eq!(strip_prefix(&mut request).as_deref(), Some("acme"));
+        assert_eq!(request.uri(), "/data/a/b.txt?versions");
+
+        let mut request = Request::builder().uri("/t/acme").body(Body::empty()).unwrap();
+        assert_eq!(strip_prefix(&mut request).as_deref(), Some("acme"));
+        assert_eq!(request.uri(), "/");
+
+        let mut request = Request::builder().uri("/data/t/acme").body(Body::empty()).unwrap();
+        assert_eq!(strip_prefix(&mut request), None);
+        assert_eq!(request.uri(), "/data/t/acme");
+    }
+}
@@ -0,0 +1,108 @@
+use bytes::Bytes;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::{Config, TerraformConfig};
+use crate::error::{AppError, Result};
+use crate::s3::S3Client;
+
+/// How a LOCK or UNLOCK ended; Held carries the current holder's lock info for Terraform to show
+pub enum LockOutcome {
+    Done,
+    Held(Bytes),
+}
+
+fn lock_key(terraform: &TerraformConfig, key: &str) -> String {
+    format!("{}{}", key, terraform.lock_suffix)
+}
+
+/// The ID of a Terraform lock info document
+fn lock_id(body: &[u8]) -> Result<String> {
+    let info: Value = serde_json::from_slice(body)
+        .map_err(|e| AppError::InvalidRequest(format!("Invalid lock info: {}", e)))?;
+    info["ID"]
+        .as_str()
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .ok_or_else(|| AppError::InvalidRequest("Lock info has no ID".to_string()))
+}
+
+/// Lock objects change only through LOCK and UNLOCK
+pub fn check_key(config: &Config, bucket: &str, key: &str) -> Result<()> {
+    match config.terraform.get(bucket) {
+        Some(terraform) if key.ends_with(&terraform.lock_suffix) => Err(AppError::InvalidRequest(format!(
+            "Keys ending in {} are reserved for Terraform state locks",
+            terraform.lock_suffix
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Realm of the Basic challenge for state in a Terraform bucket, as its HTTP backend only sends Basic credentials
+pub fn realm(config: &Config, path: &str) -> Option<String> {
+    let (bucket, _) = path.trim_start_matches('/').split_once('/')?;
+    config.terraform.contains_key(bucket).then(|| bucket.to_string())
+}
+
+async fn current_lock(client: &S3Client, bucket: &str, lock_key: &str) -> Result<Option<Bytes>> {
+    match client.get_object_range(bucket, lock_key, None, None).await {
+        Ok(lock) => Ok(Some(lock.body)),
+        Err(AppError::ObjectNotFound(_, _)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Takes the lock of the state at `key`; the lock object is created only if absent, so
+/// concurrent lockers through any replica cannot both win
+pub async fn lock(client: &S3Client, terraform: &TerraformConfig, bucket: &str, key: &str, info: Bytes) -> Result<LockOutcome> {
+    let id = lock_id(&info)?;
+    let lock_key = lock_key(terraform, key);
+    if client.put_object_if_absent(bucket, &lock_key, info, Some("application/json".to_string())).await? {
+        info!("Locked Terraform state {}/{} as {}", bucket, key, id);
+        return Ok(LockOutcome::Done);
+    }
+    match current_lock(client, bucket, &lock_key).await? {
+        // Locking again with the same ID succeeds, e.g. when a response was lost
+        Some(held) if lock_id(&held).ok().as_deref() == Some(id.as_str()) => Ok(LockOutcome::Done),
+        Some(held) => Ok(LockOutcome::Held(held)),
+        // Released in between, the caller may simply retry
+        None => Err(AppError::Conflict(format!("Lock of {}/{} changed while locking, retry", bucket, key))),
+    }
+}
+
+/// Releases the lock of the state at `key`; only the lock with the given ID is removed, except
+/// that `terraform force-unlock` sends no lock info and removes whichever lock is held
+pub async fn unlock(client: &S3Client, terraform: &TerraformConfig, bucket: &str, key: &str, info: Bytes) -> Result<LockOutcome> {
+    let lock_key = lock_key(terraform, key);
+    if info.is_empty() {
+        client.delete_object(bucket, &lock_key).await?;
+        warn!("Force-unlocked Terraform state {}/{}", bucket, key);
+        return Ok(LockOutcome::Done);
+    }
+    let id = lock_id(&info)?;
+    match current_lock(client, bucket, &lock_key).await? {
+        None => Ok(LockOutcome::Done),
+        Some(held) if lock_id(&held).ok().as_deref() == Some(id.as_str()) => {
+            client.delete_object(bucket, &lock_key).await?;
+            info!("Unlocked Terraform state {}/{} held as {}", bucket, key, id);
+            Ok(LockOutcome::Done)
+        }
+        Some(held) => Ok(LockOutcome::Held(held)),
+    }
+}
+
+/// Rejects changes to a locked state unless they carry the lock's ID, as Terraform sends in ?ID=
+pub async fn check_write(client: &S3Client, config: &Config, bucket: &str, key: &str, id: Option<&str>) -> Result<()> {
+    let Some(terraform) = config.terraform.get(bucket) else {
+        return Ok(());
+    };
+    let Some(held) = current_lock(client, bucket, &lock_key(terraform, key)).await? else {
+        return Ok(());
+    };
+    let holder = lock_id(&held).unwrap_or_default();
+    if id == Some(holder.as_str()) {
+        return Ok(());
+    }
+    warn!("Rejected change to {}/{}, the state is locked as {}", bucket, key, holder);
+    Err(AppError::Locked(format!("{}/{} is locked by Terraform lock {}", bucket, key, holder)))
+}
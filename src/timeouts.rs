@@ -0,0 +1,89 @@
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::{stream, StreamExt};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::config::{Config, TimeoutConfig};
+use crate::error::AppError;
+
+/// First path segments of the proxy's own endpoints, whose requests never move object data
+const NATIVE: &[&str] = &["admin", "authz", "keys", "session", "uploads"];
+
+/// Object reads and writes, registry blobs and ingested streams, whose duration grows with the data
+fn is_transfer(method: &Method, path: &str) -> bool {
+    let mut segments = path.trim_start_matches('/').splitn(2, '/');
+    let (first, rest) = (segments.next().unwrap_or_default(), segments.next().unwrap_or_default());
+    matches!(*method, Method::GET | Method::PUT | Method::POST | Method::PATCH)
+        && !rest.is_empty()
+        && !NATIVE.contains(&first)
+}
+
+fn budget(config: &TimeoutConfig, path: &str, transfer: bool) -> Duration {
+    let route = config
+        .routes
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, secs)| *secs);
+    let default = if transfer { config.transfer_secs } else { config.metadata_secs };
+    Duration::from_secs(route.unwrap_or(default).max(1))
+}
+
+/// Ends a body with an error once no data arrives for `idle`
+fn idle_timeout(body: Body, idle: Duration, direction: &'static str) -> Body {
+    let chunks = stream::unfold(Some(body.into_data_stream()), move |chunks| async move {
+        let mut chunks = chunks?;
+        match tokio::time::timeout(idle, chunks.next()).await {
+            Ok(Some(chunk)) => Some((chunk, Some(chunks))),
+            Ok(None) => None,
+            Err(_) => {
+                warn!("Gave up on a {} body idle for {:?}", direction, idle);
+                let error = axum::Error::new(format!("{} body idle for {:?}", direction, idle));
+                Some((Err(error), None))
+            }
+        }
+    });
+    Body::from_stream(chunks)
+}
+
+/// Answers 504 once a request exceeds the budget of its route, and stops transfers that stall
+pub async fn enforce(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    let Some(timeouts) = &config.timeouts else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path().to_string();
+    let transfer = is_transfer(request.method(), &path);
+    let budget = budget(timeouts, &path, transfer);
+    let idle = Duration::from_secs(timeouts.idle_secs.max(1));
+
+    let request = if transfer {
+        let (parts, body) = request.into_parts();
+        Request::from_parts(parts, idle_timeout(body, idle, "request"))
+    } else {
+        request
+    };
+    let response = match tokio::time::timeout(budget, next.run(request)).await {
+        Ok(response) => response,
+        Err(_) => {
+            warn!("Request for {} exceeded its {:?} budget", path, budget);
+            return AppError::Timeout(format!("No response within {:?}", budget)).into_response();
+        }
+    };
+    if !transfer {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    // The wrapped body no longer knows its length
+    if let Some(length) = body.size_hint().exact() {
+        parts.headers.entry(header::CONTENT_LENGTH).or_insert(length.into());
+    }
+    Response::from_parts(parts, idle_timeout(body, idle, "response"))
+}
@@ -0,0 +1,78 @@
+use axum::{
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::{IntoResponse, Redirect, Response},
+    Router,
+};
+use rustls::SupportedProtocolVersion;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{PlaintextMode, TlsConfig};
+use crate::error::{AppError, Result};
+
+/// First byte of a TLS record carrying a handshake; HTTP requests start with a method's letter
+const HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Server side TLS from a PEM certificate chain and private key
+pub fn server_config(
+    cert_path: &str,
+    key_path: &str,
+    versions: &[&'static SupportedProtocolVersion],
+    alpn: &[&[u8]],
+) -> Result<rustls::ServerConfig> {
+    let failed = |path: &str, e: &dyn std::fmt::Display| {
+        AppError::InternalError(format!("Failed to load the TLS certificate from {}: {}", path, e))
+    };
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| failed(cert_path, &e))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|e| failed(key_path, &e))?
+        .ok_or_else(|| failed(key_path, &"no private key"))?;
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
+        .with_protocol_versions(versions)
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| failed(cert_path, &e))?;
+    tls.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(tls)
+}
+
+/// TLS for the listening port, which also answers plaintext HTTP from the same clients
+#[derive(Clone)]
+pub struct Acceptor {
+    pub acceptor: TlsAcceptor,
+    pub plaintext: PlaintextMode,
+}
+
+impl Acceptor {
+    pub fn new(config: &TlsConfig) -> Result<Self> {
+        let tls = server_config(
+            &config.cert_path,
+            &config.key_path,
+            rustls::DEFAULT_VERSIONS,
+            &[b"h2", b"http/1.1"],
+        )?;
+        Ok(Self { acceptor: TlsAcceptor::from(Arc::new(tls)), plaintext: config.plaintext })
+    }
+}
+
+/// Whether the first byte a client sent starts a TLS handshake
+pub fn is_client_hello(first: u8) -> bool {
+    first == HANDSHAKE_RECORD
+}
+
+/// Sends plaintext requests to the same host and path over HTTPS
+async fn redirect(headers: HeaderMap, uri: Uri) -> Response {
+    let Some(host) = headers.get(header::HOST).and_then(|host| host.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Use HTTPS").into_response();
+    };
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    Redirect::permanent(&format!("https://{}{}", host, path)).into_response()
+}
+
+/// Serves plaintext connections when they are only redirected
+pub fn redirect_router() -> Router {
+    Router::new().fallback(redirect)
+}
@@ -0,0 +1,95 @@
+use aws_smithy_runtime_api::client::http::{HttpConnector, HttpConnectorFuture, SharedHttpConnector};
+use aws_smithy_runtime_api::client::orchestrator::HttpRequest;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::sync::Arc;
+use tracing::{info_span, Instrument};
+
+use crate::config::Config;
+
+tokio::task_local! {
+    /// Trace of the client request being handled on this task
+    static TRACE: TraceContext;
+}
+
+#[derive(Debug, Clone)]
+struct TraceContext {
+    trace_id: String,
+    flags: String,
+    state: Option<String>,
+}
+
+fn is_hex(value: &str, len: usize) -> bool {
+    value.len() == len && value.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+/// The trace id and flags of a version 00 W3C traceparent
+fn parse(traceparent: &str) -> Option<(String, String)> {
+    let mut fields = traceparent.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (fields.next()?, fields.next()?, fields.next()?, fields.next()?);
+    if version != "00" || fields.next().is_some() {
+        return None;
+    }
+    // All-zero ids are invalid and must not be continued
+    let valid = is_hex(trace_id, 32)
+        && is_hex(parent_id, 16)
+        && is_hex(flags, 2)
+        && trace_id.bytes().any(|b| b != b'0')
+        && parent_id.bytes().any(|b| b != b'0');
+    valid.then(|| (trace_id.to_string(), flags.to_string()))
+}
+
+fn span_id() -> String {
+    uuid::Uuid::new_v4().simple().to_string()[..16].to_string()
+}
+
+/// Trace id of the client request being handled, when traces are propagated
+pub fn trace_id() -> Option<String> {
+    TRACE.try_with(|trace| trace.trace_id.clone()).ok()
+}
+
+/// Continues the client's trace, or starts one, for the upstream requests made on its behalf
+pub async fn propagate(State(config): State<Arc<Config>>, request: Request, next: Next) -> Response {
+    if !config.upstream_tracing.traceparent {
+        return next.run(request).await;
+    }
+    let headers = request.headers();
+    let (trace_id, flags) = headers
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse)
+        .unwrap_or_else(|| (uuid::Uuid::new_v4().simple().to_string(), "01".to_string()));
+    let state = headers
+        .get("tracestate")
+        .and_then(|v| v.to_str().ok())
+        .map(String::from);
+
+    let span = info_span!("trace", trace_id = %trace_id);
+    let context = TraceContext { trace_id, flags, state };
+    TRACE.scope(context, next.run(request)).instrument(span).await
+}
+
+/// Adds the traceparent of the current client request to every upstream request
+#[derive(Debug, Clone)]
+struct TracingConnector(SharedHttpConnector);
+
+impl HttpConnector for TracingConnector {
+    fn call(&self, mut request: HttpRequest) -> HttpConnectorFuture {
+        // Background work such as prefetching runs outside any client request
+        let _ = TRACE.try_with(|trace| {
+            let headers = request.headers_mut();
+            headers.insert("traceparent", format!("00-{}-{}-{}", trace.trace_id, span_id(), trace.flags));
+            if let Some(state) = &trace.state {
+                headers.insert("tracestate", state.clone());
+            }
+        });
+        self.0.call(request)
+    }
+}
+
+pub fn wrap(inner: SharedHttpConnector) -> SharedHttpConnector {
+    SharedHttpConnector::new(TracingConnector(inner))
+}
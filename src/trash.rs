@@ -0,0 +1,73 @@
+use chrono::Utc;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::chunking;
+use crate::config::TrashConfig;
+use crate::error::Result;
+use crate::server::AppState;
+
+/// Where a deleted object is kept until it is purged
+pub fn trash_key(trash: &TrashConfig, key: &str) -> String {
+    format!("{}{}", trash.prefix, key)
+}
+
+/// Original key of an object in the trash, None for keys outside it
+pub fn original_key<'a>(trash: &TrashConfig, key: &'a str) -> Option<&'a str> {
+    key.strip_prefix(trash.prefix.as_str()).filter(|original| !original.is_empty())
+}
+
+/// Periodically deletes trashed objects older than their bucket's retention
+pub fn spawn_purge(state: Arc<AppState>) {
+    let soft_delete = &state.config.soft_delete;
+    if soft_delete.buckets.is_empty() {
+        return;
+    }
+    info!(
+        "Soft delete enabled for {} buckets, purging every {}s",
+        soft_delete.buckets.len(),
+        soft_delete.purge_interval_secs
+    );
+
+    let interval = Duration::from_secs(soft_delete.purge_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            // With several replicas, purging on one of them is enough
+            if !state.is_leader() {
+                continue;
+            }
+            for (bucket, trash) in &state.config.soft_delete.buckets {
+                if let Err(e) = purge_bucket(&state, bucket, trash).await {
+                    warn!("Trash purge failed for bucket {}: {}", bucket, e);
+                }
+            }
+        }
+    });
+}
+
+async fn purge_bucket(state: &AppState, bucket: &str, trash: &TrashConfig) -> Result<()> {
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    // Copying into the trash resets LastModified, so it records the deletion time
+    let cutoff = Utc::now().timestamp() - (trash.retention_days * 86_400) as i64;
+
+    let mut purged = 0;
+    for object in client.list_objects(bucket, Some(trash.prefix.clone())).await? {
+        let (Some(key), Some(deleted_at)) = (object.key(), object.last_modified()) else {
+            continue;
+        };
+        // Held objects are kept past their retention until the hold is released
+        if deleted_at.secs() > cutoff || state.holds.is_held(bucket, key) {
+            continue;
+        }
+        chunking::delete(client, &state.config, bucket, key).await?;
+        state.invalidate_cache(bucket, Some(key), None);
+        purged += 1;
+    }
+    if purged > 0 {
+        info!("Purged {} objects from the trash of bucket {}", purged, bucket);
+    }
+    Ok(())
+}
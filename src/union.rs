@@ -0,0 +1,87 @@
+use aws_sdk_s3::types::Object;
+use futures::future::try_join_all;
+use std::collections::HashSet;
+
+use crate::config::Config;
+use crate::consistency;
+use crate::error::{AppError, Result};
+use crate::packing;
+use crate::replicas;
+use crate::s3::ObjectPart;
+use crate::server::AppState;
+
+pub fn is_virtual(config: &Config, bucket: &str) -> bool {
+    config.virtual_buckets.contains_key(bucket)
+}
+
+/// The real bucket that writes to `bucket` go to
+pub fn writable(config: &Config, bucket: &str) -> String {
+    match config.virtual_buckets.get(bucket) {
+        Some(virtual_bucket) => virtual_bucket.writable.clone(),
+        None => bucket.to_string(),
+    }
+}
+
+/// The real buckets reads of `bucket` look in, in order
+fn members<'a>(config: &'a Config, bucket: &'a str) -> Vec<&'a str> {
+    match config.virtual_buckets.get(bucket) {
+        Some(virtual_bucket) => virtual_bucket.members.iter().map(String::as_str).collect(),
+        None => vec![bucket],
+    }
+}
+
+/// Reads `key` from the first member that has it
+pub async fn read(state: &AppState, bucket: &str, key: &str, range: Option<&str>) -> Result<ObjectPart> {
+    for member in members(&state.config, bucket) {
+        match replicas::read(state, member, key, range).await {
+            Err(AppError::ObjectNotFound(_, _)) => continue,
+            result => return result,
+        }
+    }
+    Err(AppError::ObjectNotFound(bucket.to_string(), key.to_string()))
+}
+
+async fn list_member(
+    state: &AppState,
+    bucket: &str,
+    prefix: &str,
+    directories: bool,
+) -> Result<(Vec<Object>, Vec<String>)> {
+    let (_, client) = &state.get_account_and_client(bucket)?;
+    let (mut objects, mut prefixes) = if directories {
+        client.list_directory(bucket, prefix).await?
+    } else {
+        (client.list_objects(bucket, Some(prefix.to_string())).await?, Vec::new())
+    };
+    packing::merge_listing(&state.config, bucket, prefix, directories, &mut objects, &mut prefixes);
+    consistency::merge_listing(&state.config, client.account_id(), bucket, prefix, directories, &mut objects, &mut prefixes);
+    Ok((objects, prefixes))
+}
+
+/// Lists `prefix` in every member; a key in an earlier member hides the same key in later ones
+pub async fn list(state: &AppState, bucket: &str, prefix: &str, directories: bool) -> Result<(Vec<Object>, Vec<String>)> {
+    let members = members(&state.config, bucket);
+    let mut listings = try_join_all(members.iter().map(|member| list_member(state, member, prefix, directories))).await?;
+    if listings.len() == 1 {
+        return Ok(listings.remove(0));
+    }
+
+    let mut seen = HashSet::new();
+    let mut objects = Vec::new();
+    let mut prefixes = Vec::new();
+    for (member_objects, member_prefixes) in listings {
+        for object in member_objects {
+            if seen.insert(object.key().unwrap_or_default().to_string()) {
+                objects.push(object);
+            }
+        }
+        for prefix in member_prefixes {
+            if !prefixes.contains(&prefix) {
+                prefixes.push(prefix);
+            }
+        }
+    }
+    objects.sort_by(|a, b| a.key().cmp(&b.key()));
+    prefixes.sort();
+    Ok((objects, prefixes))
+}
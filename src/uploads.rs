@@ -0,0 +1,341 @@
+use aws_sdk_s3::types::CompletedPart;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::config::UploadsConfig;
+use crate::error::{AppError, Result};
+use crate::memory;
+use crate::s3::S3Client;
+use crate::server::AppState;
+
+pub use s3_proxy_client::types::UploadProgress;
+
+/// S3 rejects smaller parts other than the last one
+const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A resumable upload backed by an S3 multipart upload
+struct UploadSession {
+    bucket: String,
+    key: String,
+    owner: String,
+    content_type: Option<String>,
+    multipart_id: String,
+    /// Size of the object, when the client declared it with Upload-Length
+    length: Option<u64>,
+    /// Bytes received so far, uploaded or buffered
+    offset: u64,
+    /// Received bytes not yet sent upstream as a part
+    buffer: BytesMut,
+    /// Keeps the buffer counted against the memory budget between requests
+    reservation: memory::Reservation,
+    parts: Vec<CompletedPart>,
+    hasher: Sha256,
+    started_at: DateTime<Utc>,
+    updated_at: Instant,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadStatus {
+    pub upload_id: String,
+    pub bucket: String,
+    pub key: String,
+    pub offset: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub length: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+}
+
+/// An upload that has been assembled into its object
+pub struct CompletedUpload {
+    pub etag: Option<String>,
+    pub sha256: Vec<u8>,
+    pub content_type: Option<String>,
+}
+
+/// Parses an `Upload-Checksum: sha256 <base64>` header value
+fn parse_checksum(value: &str) -> Result<Vec<u8>> {
+    let (algorithm, digest) = value
+        .trim()
+        .split_once(' ')
+        .ok_or_else(|| AppError::InvalidRequest(format!("Invalid Upload-Checksum: {}", value)))?;
+    if !algorithm.eq_ignore_ascii_case("sha256") {
+        return Err(AppError::InvalidRequest(format!("Unsupported checksum algorithm: {}", algorithm)));
+    }
+    STANDARD
+        .decode(digest.trim())
+        .map_err(|_| AppError::InvalidRequest(format!("Invalid Upload-Checksum: {}", value)))
+}
+
+pub struct UploadSessions {
+    part_size: u64,
+    ttl: Duration,
+    max_sessions: usize,
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<UploadSession>>>>,
+}
+
+impl UploadSessions {
+    pub fn new(config: &UploadsConfig) -> Self {
+        Self {
+            part_size: config.part_size.max(MIN_PART_SIZE),
+            ttl: Duration::from_secs(config.session_ttl_secs),
+            max_sessions: config.max_sessions,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn create(
+        &self,
+        client: &S3Client,
+        bucket: &str,
+        key: &str,
+        owner: &str,
+        content_type: Option<String>,
+        length: Option<u64>,
+    ) -> Result<UploadStatus> {
+        if self.sessions.lock().unwrap().len() >= self.max_sessions {
+            return Err(AppError::Conflict("Too many upload sessions in progress".to_string()));
+        }
+
+        let multipart_id = client.create_multipart_upload(bucket, key, content_type.clone()).await?;
+        let upload_id = uuid::Uuid::new_v4().simple().to_string();
+        info!("Started upload session {} for {}/{}", upload_id, bucket, key);
+
+        self.sessions.lock().unwrap().insert(
+            upload_id.clone(),
+            Arc::new(tokio::sync::Mutex::new(UploadSession {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                owner: owner.to_string(),
+                content_type: content_type.clone(),
+                multipart_id,
+                length,
+                offset: 0,
+                buffer: BytesMut::new(),
+                reservation: memory::Reservation::empty(),
+                parts: Vec::new(),
+                hasher: Sha256::new(),
+                started_at: Utc::now(),
+                updated_at: Instant::now(),
+            })),
+        );
+        Ok(UploadStatus {
+            upload_id,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            offset: 0,
+            length,
+            content_type,
+        })
+    }
+
+    /// Looks up a session, which only its creator may use and only for the object it was created for
+    async fn session(
+        &self,
+        upload_id: &str,
+        bucket: &str,
+        key: &str,
+        owner: &str,
+    ) -> Result<Arc<tokio::sync::Mutex<UploadSession>>> {
+        let not_found = || AppError::UploadNotFound(upload_id.to_string());
+        let session = self.sessions.lock().unwrap().get(upload_id).cloned().ok_or_else(not_found)?;
+        {
+            let session = session.lock().await;
+            if session.bucket != bucket || session.key != key {
+                return Err(not_found());
+            }
+            if session.owner != owner {
+                return Err(AppError::Unauthorized("Upload session belongs to another user".to_string()));
+            }
+        }
+        Ok(session)
+    }
+
+    pub async fn status(&self, upload_id: &str, bucket: &str, key: &str, owner: &str) -> Result<UploadStatus> {
+        let session = self.session(upload_id, bucket, key, owner).await?;
+        let session = session.lock().await;
+        Ok(UploadStatus {
+            upload_id: upload_id.to_string(),
+            bucket: session.bucket.clone(),
+            key: session.key.clone(),
+            offset: session.offset,
+            length: session.length,
+            content_type: session.content_type.clone(),
+        })
+    }
+
+    /// How far along an upload is, for its creator or an admin
+    pub async fn progress(&self, upload_id: &str, username: &str, admin: bool) -> Result<UploadProgress> {
+        let not_found = || AppError::UploadNotFound(upload_id.to_string());
+        let session = self.sessions.lock().unwrap().get(upload_id).cloned().ok_or_else(not_found)?;
+        let session = session.lock().await;
+        if session.owner != username && !admin {
+            // Other users' upload ids are not confirmed to exist
+            return Err(not_found());
+        }
+
+        let elapsed = (Utc::now() - session.started_at).num_milliseconds().max(1) as u64;
+        let bytes_per_sec = session.offset * 1000 / elapsed;
+        let eta_secs = session
+            .length
+            .filter(|_| bytes_per_sec > 0)
+            .map(|length| length.saturating_sub(session.offset).div_ceil(bytes_per_sec));
+        Ok(UploadProgress {
+            upload_id: upload_id.to_string(),
+            bucket: session.bucket.clone(),
+            key: session.key.clone(),
+            bytes_received: session.offset,
+            bytes_total: session.length,
+            parts_completed: session.parts.len() as u64,
+            bytes_per_sec,
+            eta_secs,
+            started_at: session.started_at,
+        })
+    }
+
+    /// Appends a chunk at `offset` and returns the new offset; a rejected chunk leaves the session unchanged
+    #[allow(clippy::too_many_arguments)]
+    pub async fn append(
+        &self,
+        client: &S3Client,
+        upload_id: &str,
+        bucket: &str,
+        key: &str,
+        owner: &str,
+        offset: u64,
+        checksum: Option<&str>,
+        chunk: Bytes,
+        max_size: u64,
+    ) -> Result<u64> {
+        let session = self.session(upload_id, bucket, key, owner).await?;
+        let mut session = session.lock().await;
+
+        if offset != session.offset {
+            return Err(AppError::Conflict(format!(
+                "Upload-Offset {} does not match the current offset {}",
+                offset, session.offset
+            )));
+        }
+        if let Some(checksum) = checksum {
+            if Sha256::digest(&chunk)[..] != parse_checksum(checksum)?[..] {
+                return Err(AppError::InvalidRequest("Chunk checksum mismatch".to_string()));
+            }
+        }
+        if session.offset + chunk.len() as u64 > max_size {
+            return Err(AppError::InvalidRequest(format!("Upload exceeds the maximum size of {} bytes", max_size)));
+        }
+        if let Some(length) = session.length.filter(|length| session.offset + chunk.len() as u64 > *length) {
+            return Err(AppError::InvalidRequest(format!("Upload exceeds its Upload-Length of {} bytes", length)));
+        }
+
+        // Parts are only recorded once every full part of this chunk is upstream
+        let mut pending = session.buffer.clone();
+        pending.extend_from_slice(&chunk);
+        let mut parts = Vec::new();
+        while pending.len() as u64 >= self.part_size {
+            let data = pending.split_to(self.part_size as usize).freeze();
+            let part_number = (session.parts.len() + parts.len() + 1) as i32;
+            parts.push(client.upload_part(bucket, key, &session.multipart_id, part_number, data).await?);
+        }
+
+        session.reservation.resize(pending.len() as u64);
+        session.buffer = pending;
+        session.parts.extend(parts);
+        session.hasher.update(&chunk);
+        session.offset += chunk.len() as u64;
+        session.updated_at = Instant::now();
+        Ok(session.offset)
+    }
+
+    /// Uploads the remaining bytes and assembles the object, returning its ETag
+    pub async fn complete(
+        &self,
+        client: &S3Client,
+        upload_id: &str,
+        bucket: &str,
+        key: &str,
+        owner: &str,
+        checksum: Option<&str>,
+    ) -> Result<CompletedUpload> {
+        let session = self.session(upload_id, bucket, key, owner).await?;
+        let session = session.lock().await;
+
+        if let Some(length) = session.length.filter(|length| session.offset != *length) {
+            return Err(AppError::InvalidRequest(format!("Upload has {} of its {} bytes", session.offset, length)));
+        }
+        let sha256 = session.hasher.clone().finalize().to_vec();
+        if let Some(checksum) = checksum {
+            if sha256[..] != parse_checksum(checksum)?[..] {
+                return Err(AppError::InvalidRequest("Object checksum mismatch".to_string()));
+            }
+        }
+
+        let mut parts = session.parts.clone();
+        // An empty object still needs one part
+        if !session.buffer.is_empty() || parts.is_empty() {
+            let part_number = parts.len() as i32 + 1;
+            let data = session.buffer.clone().freeze();
+            parts.push(client.upload_part(bucket, key, &session.multipart_id, part_number, data).await?);
+        }
+        let etag = client.complete_multipart_upload(bucket, key, &session.multipart_id, parts).await?;
+
+        self.sessions.lock().unwrap().remove(upload_id);
+        info!("Completed upload session {} for {}/{} with {} bytes", upload_id, bucket, key, session.offset);
+        Ok(CompletedUpload {
+            etag,
+            sha256,
+            content_type: session.content_type.clone(),
+        })
+    }
+
+    pub async fn abort(&self, client: &S3Client, upload_id: &str, bucket: &str, key: &str, owner: &str) -> Result<()> {
+        let session = self.session(upload_id, bucket, key, owner).await?;
+        let session = session.lock().await;
+        client.abort_multipart_upload(bucket, key, &session.multipart_id).await;
+        self.sessions.lock().unwrap().remove(upload_id);
+        info!("Aborted upload session {} for {}/{}", upload_id, bucket, key);
+        Ok(())
+    }
+}
+
+/// Periodically aborts sessions that have been idle longer than the session TTL
+pub fn spawn_expiry(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(EXPIRY_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let uploads = &state.uploads;
+            let sessions: Vec<_> = uploads
+                .sessions
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, session)| (id.clone(), session.clone()))
+                .collect();
+
+            for (upload_id, session) in sessions {
+                // Sessions busy with a chunk are not idle
+                let Ok(session) = session.try_lock() else {
+                    continue;
+                };
+                if session.updated_at.elapsed() < uploads.ttl {
+                    continue;
+                }
+                uploads.sessions.lock().unwrap().remove(&upload_id);
+                if let Ok((_, client)) = state.get_account_and_client(&session.bucket) {
+                    client.abort_multipart_upload(&session.bucket, &session.key, &session.multipart_id).await;
+                }
+                info!("Expired upload session {} for {}/{}", upload_id, session.bucket, session.key);
+            }
+        }
+    });
+}
@@ -0,0 +1,405 @@
+use aws_smithy_runtime_api::client::http::{
+    http_client_fn, HttpConnector, HttpConnectorFuture, SharedHttpClient, SharedHttpConnector,
+};
+use aws_smithy_runtime_api::client::orchestrator::{HttpRequest, HttpResponse};
+use aws_smithy_runtime_api::client::result::ConnectorError;
+use aws_smithy_types::body::SdkBody;
+use http::{HeaderValue, Uri};
+use hyper::body::Incoming;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::proxy::Tunnel;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::client::legacy::{connect::HttpConnector as TcpConnector, Client};
+use hyper_util::client::proxy::matcher::Matcher;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tower::Service;
+use tracing::{debug, info, warn};
+
+use crate::backends;
+use crate::concurrency;
+use crate::config::{AccountConfig, RecordingConfig, RecordingMode};
+use crate::costs;
+use crate::error::{AppError, Result};
+use crate::pacing;
+use crate::recording;
+use crate::trace_context;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// TCP stream to the endpoint, or to the outbound proxy when one is in the way
+struct UpstreamStream {
+    inner: TokioIo<TcpStream>,
+    // Plain HTTP requests sent to a proxy must use absolute-form URIs
+    proxied: bool,
+}
+
+impl Read for UpstreamStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl Write for UpstreamStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        self.inner.connected().proxy(self.proxied)
+    }
+}
+
+/// Opens connections directly or through the account's outbound proxy
+#[derive(Clone, Debug)]
+struct ProxyAwareConnector {
+    tcp: TcpConnector,
+    proxy: Option<Arc<Matcher>>,
+}
+
+impl Service<Uri> for ProxyAwareConnector {
+    type Response = UpstreamStream;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = std::result::Result<UpstreamStream, BoxError>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<std::result::Result<(), BoxError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let mut tcp = self.tcp.clone();
+        let intercept = self.proxy.as_ref().and_then(|proxy| proxy.intercept(&dst));
+        Box::pin(async move {
+            match intercept {
+                None => Ok(UpstreamStream { inner: tcp.call(dst).await?, proxied: false }),
+                Some(intercept) if dst.scheme() == Some(&http::uri::Scheme::HTTPS) => {
+                    // TLS to the endpoint runs inside a CONNECT tunnel
+                    let mut tunnel = Tunnel::new(intercept.uri().clone(), tcp);
+                    if let Some(auth) = intercept.basic_auth() {
+                        tunnel = tunnel.with_auth(auth.clone());
+                    }
+                    Ok(UpstreamStream { inner: tunnel.call(dst).await?, proxied: false })
+                }
+                Some(intercept) => Ok(UpstreamStream {
+                    inner: tcp.call(intercept.uri().clone()).await?,
+                    proxied: true,
+                }),
+            }
+        })
+    }
+}
+
+/// HTTP connector used by the S3 SDK to reach an account's endpoint
+#[derive(Clone, Debug)]
+struct UpstreamConnector {
+    client: Client<HttpsConnector<ProxyAwareConnector>, SdkBody>,
+    proxy: Option<Arc<Matcher>>,
+}
+
+impl UpstreamConnector {
+    /// Credentials for plain HTTP requests forwarded by the proxy, CONNECT carries its own
+    fn proxy_authorization(&self, uri: &Uri) -> Option<HeaderValue> {
+        if uri.scheme() == Some(&http::uri::Scheme::HTTPS) {
+            return None;
+        }
+        let intercept = self.proxy.as_ref()?.intercept(uri)?;
+        intercept.basic_auth().cloned()
+    }
+
+    async fn send(
+        &self,
+        mut request: http::Request<SdkBody>,
+    ) -> std::result::Result<http::Response<Incoming>, hyper_util::client::legacy::Error> {
+        if let Some(auth) = self.proxy_authorization(request.uri()) {
+            request.headers_mut().insert(http::header::PROXY_AUTHORIZATION, auth);
+        }
+        self.client.request(request).await
+    }
+
+    /// Opens up to `count` pooled connections to the endpoint with concurrent HEAD requests
+    async fn warm(&self, endpoint: &Uri, count: usize) -> usize {
+        let requests = (0..count).map(|_| async {
+            let request = http::Request::head(endpoint.clone()).body(SdkBody::empty()).ok()?;
+            // Any response will do, the connection goes back to the pool
+            self.send(request).await.ok()
+        });
+        futures::future::join_all(requests).await.into_iter().flatten().count()
+    }
+}
+
+impl HttpConnector for UpstreamConnector {
+    fn call(&self, request: HttpRequest) -> HttpConnectorFuture {
+        let this = self.clone();
+        HttpConnectorFuture::new(async move {
+            let request = request
+                .try_into_http1x()
+                .map_err(|e| ConnectorError::user(e.into()))?;
+            let response = this.send(request).await.map_err(|e| {
+                if e.is_connect() {
+                    ConnectorError::io(e.into())
+                } else {
+                    ConnectorError::other(e.into(), None)
+                }
+            })?;
+            HttpResponse::try_from(response.map(SdkBody::from_body_1_x))
+                .map_err(|e| ConnectorError::other(e.into(), None))
+        })
+    }
+}
+
+/// Accepts any server certificate, only for self-signed internal endpoints
+#[derive(Debug)]
+struct NoCertificateVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.0.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn tls_file_error(path: &str, e: impl std::fmt::Display) -> AppError {
+    AppError::ConfigError(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+}
+
+fn root_store(account: &AccountConfig) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    if account.tls.native_roots {
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            debug!("Skipping native root certificate: {}", e);
+        }
+        roots.add_parsable_certificates(native.certs);
+    }
+
+    // Private PKI roots for internal clusters
+    for path in &account.tls.ca_certs {
+        let certs = CertificateDer::pem_file_iter(path)
+            .map_err(|e| tls_file_error(path, e))?
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| tls_file_error(path, e))?;
+        let (added, ignored) = roots.add_parsable_certificates(certs);
+        if added == 0 {
+            return Err(tls_file_error(path, "no usable CA certificates"));
+        }
+        info!("Loaded {} CA certificates from {} ({} ignored)", added, path, ignored);
+    }
+    Ok(roots)
+}
+
+fn client_identity(account: &AccountConfig) -> Result<Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>> {
+    let (cert_path, key_path) = match (&account.tls.client_cert, &account.tls.client_key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => return Ok(None),
+        _ => {
+            return Err(AppError::ConfigError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tls.client_cert and tls.client_key must be set together",
+            )))
+        }
+    };
+
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .map_err(|e| tls_file_error(cert_path, e))?
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| tls_file_error(cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| tls_file_error(key_path, e))?;
+    Ok(Some((certs, key)))
+}
+
+fn tls_config(account_id: &str, account: &AccountConfig) -> Result<ClientConfig> {
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| AppError::InternalError(format!("TLS setup failed: {}", e)))?;
+
+    let builder = if account.tls_skip_verify {
+        warn!(
+            "!!! TLS certificate verification is DISABLED for account {} ({}). \
+             Connections to this endpoint can be intercepted. Never use this outside trusted networks. !!!",
+            account_id, account.endpoint_url
+        );
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification(provider)))
+    } else {
+        builder.with_root_certificates(root_store(account)?)
+    };
+
+    match client_identity(account)? {
+        Some((certs, key)) => {
+            info!("Account {} presents a client certificate to {}", account_id, account.endpoint_url);
+            builder
+                .with_client_auth_cert(certs, key)
+                .map_err(|e| AppError::InternalError(format!("Invalid client certificate: {}", e)))
+        }
+        None => Ok(builder.with_no_client_auth()),
+    }
+}
+
+/// Builds the HTTP client the S3 SDK uses for one account
+pub fn http_client(
+    account_id: &str,
+    account: &AccountConfig,
+    recording: Option<&RecordingConfig>,
+) -> Result<SharedHttpClient> {
+    let mut tcp = TcpConnector::new();
+    tcp.enforce_http(false);
+    tcp.set_nodelay(true);
+    tcp.set_connect_timeout(Some(CONNECT_TIMEOUT));
+
+    let proxy = account.proxy.as_ref().map(|proxy| {
+        info!(
+            "Account {} reaches {} through proxy {} (no_proxy: {:?})",
+            account_id,
+            account.endpoint_url,
+            redact_userinfo(&proxy.url),
+            proxy.no_proxy
+        );
+        Arc::new(Matcher::builder().all(proxy.url.clone()).no(proxy.no_proxy.join(",")).build())
+    });
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config(account_id, account)?)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .wrap_connector(ProxyAwareConnector { tcp, proxy: proxy.clone() });
+
+    let pool = &account.connection_pool;
+    let connector = UpstreamConnector {
+        client: Client::builder(TokioExecutor::new())
+            .pool_idle_timeout(Duration::from_secs(pool.idle_timeout_secs))
+            .build(https),
+        proxy,
+    };
+
+    // Replayed traffic never reaches the endpoint
+    let replaying = recording.is_some_and(|r| r.mode == RecordingMode::Replay);
+    if pool.warm_connections > 0 && !replaying {
+        spawn_keep_warm(account_id, account, connector.clone())?;
+    }
+
+    // Counted beneath the recorder so replayed traffic, which never reaches the endpoint, costs nothing
+    let connector = costs::wrap(trace_context::wrap(SharedHttpConnector::new(connector)));
+    // Beneath pacing, so every retry waits for a slot of its own
+    let connector = match &account.concurrency {
+        Some(concurrency) => concurrency::wrap(account_id, concurrency.clone(), connector),
+        None => connector,
+    };
+    let connector = match &account.pacing {
+        Some(pacing) => pacing::wrap(account_id, pacing.clone(), connector),
+        None => connector,
+    };
+    let connector = backends::wrap(account_id, account.circuit_breaker.clone(), connector);
+    let connector = match recording {
+        Some(recording) => recording::wrap(account_id, recording, connector),
+        None => connector,
+    };
+    Ok(http_client_fn(move |_settings, _components| connector.clone()))
+}
+
+/// Pre-connects at startup and re-warms the pool before idle connections are dropped
+fn spawn_keep_warm(account_id: &str, account: &AccountConfig, connector: UpstreamConnector) -> Result<()> {
+    let pool = &account.connection_pool;
+    let endpoint: Uri = account
+        .endpoint_url
+        .parse()
+        .map_err(|e| AppError::InternalError(format!("Invalid endpoint URL {}: {}", account.endpoint_url, e)))?;
+    if pool.keep_warm_interval_secs >= pool.idle_timeout_secs {
+        warn!(
+            "Account {}: keep_warm_interval_secs should be below idle_timeout_secs or warm connections will expire",
+            account_id
+        );
+    }
+
+    let account_id = account_id.to_string();
+    let count = pool.warm_connections;
+    let interval = Duration::from_secs(pool.keep_warm_interval_secs.max(1));
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let started = std::time::Instant::now();
+            let warmed = connector.warm(&endpoint, count).await;
+            debug!(
+                "Warmed {}/{} connections for account {} in {:?}",
+                warmed, count, account_id, started.elapsed()
+            );
+        }
+    });
+    Ok(())
+}
+
+fn redact_userinfo(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme_end), Some(at)) if at > scheme_end => {
+            format!("{}***@{}", &url[..scheme_end + 3], &url[at + 1..])
+        }
+        _ => url.to_string(),
+    }
+}